@@ -30,6 +30,7 @@ pub fn data_abort_handler(ctx: &mut ContextFrame) {
         sign_ext: exception_data_abort_access_is_sign_ext(),
         reg: exception_data_abort_access_reg(),
         reg_width: exception_data_abort_access_reg_width(),
+        inst_len: exception_next_instruction_step(),
     };
     debug!(
         "data fault addr 0x{:x}, esr: 0x{:x}",
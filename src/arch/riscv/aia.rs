@@ -0,0 +1,103 @@
+//! RISC-V Advanced Interrupt Architecture (AIA) support.
+//!
+//! Besides its own S-level interrupt file, every hart's IMSIC provides GEILEN guest interrupt
+//! files. A vCPU is given one of the guest files of the hart it runs on and selects it through
+//! `hstatus.VGEIN`. The file's page is mapped into the guest as the vCPU's IMSIC, so the guest
+//! accesses it without traps and MSIs written to it are delivered straight to VS-mode.
+//!
+//! ref: The RISC-V Advanced Interrupt Architecture, v1.0
+use spin::{Mutex, Once};
+
+use super::{RiscvCsrTrait, CSR};
+use crate::{
+    logging::LogContext, memory::PAGE_SIZE_4K, vcpus::MAX_CPUS, HostPhysAddr, HyperCraftHal,
+    HyperError, HyperResult,
+};
+
+/// The AIA configuration of this platform, set up by `init_aia`.
+pub(crate) static AIA: Once<Aia> = Once::new();
+
+/// Initializes AIA support. `imsic_base` is the physical address of hart 0's S-level interrupt
+/// file and `hart_stride` the distance between the interrupt files of consecutive harts. Fails
+/// with `NotSupported` if the harts have no guest interrupt files, in which case VMs keep using
+/// the vPLIC.
+pub fn init_aia(imsic_base: HostPhysAddr, hart_stride: usize) -> HyperResult<()> {
+    if AIA.get().is_some() {
+        return Err(HyperError::BadState);
+    }
+    let geilen = detect_geilen();
+    if geilen == 0 {
+        hv_log!(
+            Warn,
+            Irq,
+            LogContext::NONE,
+            "no guest interrupt files, AIA is disabled"
+        );
+        return Err(HyperError::NotSupported);
+    }
+    hv_log!(
+        Info,
+        Irq,
+        LogContext::NONE,
+        "AIA initialized, IMSIC@{:#x}, GEILEN {}",
+        imsic_base,
+        geilen
+    );
+    let files = ((1 << geilen) - 1) << 1;
+    AIA.call_once(|| Aia {
+        imsic_base,
+        hart_stride,
+        free_files: Mutex::new([files; MAX_CPUS]),
+    });
+    Ok(())
+}
+
+/// Returns the number of guest external interrupts (GEILEN) implemented by this hart.
+pub(super) fn detect_geilen() -> usize {
+    // Bits 1..=GEILEN of hgeie are writable, all others are read-only zero.
+    let old = CSR.hgeie.atomic_replace(usize::MAX);
+    let geilen = CSR.hgeie.get_value().count_ones() as usize;
+    CSR.hgeie.write_value(old);
+    geilen
+}
+
+pub(crate) struct Aia {
+    imsic_base: HostPhysAddr,
+    hart_stride: usize,
+    /// Unassigned guest interrupt files of each hart, as bitmaps indexed by `VGEIN`.
+    free_files: Mutex<[usize; MAX_CPUS]>,
+}
+
+impl Aia {
+    /// Assigns a guest interrupt file of `hart`, returning its `VGEIN` number.
+    pub fn alloc_guest_file(&self, hart: usize) -> HyperResult<usize> {
+        let mut free_files = self.free_files.lock();
+        let files = free_files.get_mut(hart).ok_or(HyperError::InvalidParam)?;
+        if *files == 0 {
+            return Err(HyperError::NoMemory);
+        }
+        let vgein = files.trailing_zeros() as usize;
+        *files &= !(1 << vgein);
+        Ok(vgein)
+    }
+
+    /// Gives back the guest interrupt file `vgein` of `hart`.
+    pub fn free_guest_file(&self, hart: usize, vgein: usize) {
+        if let Some(files) = self.free_files.lock().get_mut(hart) {
+            *files |= 1 << vgein;
+        }
+    }
+
+    /// Physical address of the guest interrupt file `vgein` of `hart`.
+    pub fn guest_file_addr(&self, hart: usize, vgein: usize) -> HostPhysAddr {
+        self.imsic_base + hart * self.hart_stride + vgein * PAGE_SIZE_4K
+    }
+}
+
+/// Raises the external interrupt `eiid` in the interrupt file at `file` by writing its
+/// `seteipnum_le` register.
+pub(crate) fn send_msi<H: HyperCraftHal>(file: HostPhysAddr, eiid: u32) {
+    unsafe {
+        core::ptr::write_volatile(H::phys_to_virt(file) as *mut u32, eiid);
+    }
+}
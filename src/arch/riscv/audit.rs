@@ -0,0 +1,126 @@
+//! Auditing of the isolation between VMs.
+//!
+//! Static partitioning setups need to show that no VM can reach memory it wasn't given. The
+//! regions and second-stage mappings of each VM can be enumerated, and `audit_isolation`
+//! cross-checks them against the memory the hypervisor owns and the private memory of the other
+//! VMs, reporting every mapping that breaks isolation.
+use alloc::vec::Vec;
+
+use super::vm_pages::VmRegionType;
+use super::VM;
+use crate::{GuestPageTableTrait, GuestPhysAddr, HostPhysAddr, HyperCraftHal};
+
+/// What a region of a VM's guest physical address space is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// RAM private to the VM.
+    Ram,
+    /// RAM shared with the host.
+    SharedRam,
+    Rom,
+    /// Registers of a device emulated by the hypervisor, which must not be mapped.
+    Emulated,
+    /// Host device memory passed through to the guest.
+    Passthrough,
+    /// IMSIC guest interrupt files.
+    Imsic,
+    /// PCI BARs.
+    Pci,
+}
+
+impl From<VmRegionType> for RegionKind {
+    fn from(region_type: VmRegionType) -> Self {
+        match region_type {
+            VmRegionType::Confidential | VmRegionType::ConfidentialRemovable => Self::Ram,
+            VmRegionType::Shared(_) | VmRegionType::SharedRemovable => Self::SharedRam,
+            VmRegionType::Rom(_) => Self::Rom,
+            VmRegionType::Mmio => Self::Emulated,
+            VmRegionType::Passthrough => Self::Passthrough,
+            VmRegionType::Imsic => Self::Imsic,
+            VmRegionType::Pci => Self::Pci,
+        }
+    }
+}
+
+/// A registered region of a VM's guest physical address space, see `VM::guest_regions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestRegion {
+    pub start: GuestPhysAddr,
+    /// End (exclusive) of the region.
+    pub end: GuestPhysAddr,
+    pub kind: RegionKind,
+}
+
+/// Guest pages mapped to contiguous host memory in a VM's guest page table, see
+/// `VM::guest_mappings`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestMapping {
+    pub gpa: GuestPhysAddr,
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+    /// Kind of the region the pages are in.
+    pub kind: RegionKind,
+}
+
+/// How a mapping breaks isolation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The mapping overlaps memory the hypervisor owns.
+    HypervisorMemory,
+    /// The RAM pages are backed by host memory not assigned to the VM.
+    UnassignedMemory,
+    /// The mapping overlaps the private memory of the VM at this index.
+    OtherVm(usize),
+    /// The pages of an emulated device are mapped, so guest accesses bypass the device.
+    MappedEmulatedRegion,
+}
+
+/// A mapping of the VM at index `vm` that breaks isolation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsolationViolation {
+    pub vm: usize,
+    pub mapping: GuestMapping,
+    pub kind: ViolationKind,
+}
+
+/// Checks the mappings of `vms` against each other and against `hypervisor_memory`, the host
+/// physical ranges `[start, end)` the hypervisor owns, e.g. its image and heap. A VM's private
+/// memory is the host memory assigned to it and the pages allocated for its RAM on first touch.
+/// Memory shared between two VMs with `VM::share_region` is expected in both. Returns the
+/// mappings breaking isolation, none if the VMs are isolated.
+pub fn audit_isolation<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vms: &[&VM<H, G>],
+    hypervisor_memory: &[(HostPhysAddr, HostPhysAddr)],
+) -> Vec<IsolationViolation> {
+    let mut violations = Vec::new();
+    let private_memory: Vec<_> = vms.iter().map(|vm| vm.private_memory()).collect();
+    for (index, vm) in vms.iter().enumerate() {
+        for mapping in vm.guest_mappings() {
+            let (start, end) = (mapping.hpa, mapping.hpa + mapping.size);
+            let mut report = |kind| {
+                violations.push(IsolationViolation {
+                    vm: index,
+                    mapping,
+                    kind,
+                })
+            };
+            if mapping.kind == RegionKind::Emulated {
+                report(ViolationKind::MappedEmulatedRegion);
+            }
+            if mapping.kind == RegionKind::Ram && !vm.host_memory().contains(start, mapping.size) {
+                report(ViolationKind::UnassignedMemory);
+            }
+            if hypervisor_memory.iter().any(|&(s, e)| s < end && start < e) {
+                report(ViolationKind::HypervisorMemory);
+            }
+            for (other, other_vm) in vms.iter().enumerate() {
+                let shared = mapping.kind == RegionKind::SharedRam
+                    && vm.shared_from(other_vm.id()).contains(start, mapping.size);
+                if other != index && private_memory[other].overlaps(start, end) && !shared {
+                    report(ViolationKind::OtherVm(other));
+                }
+            }
+        }
+    }
+    violations
+}
@@ -0,0 +1,214 @@
+//! Sharing the CPU time of the harts between VMs.
+//!
+//! Mixed-criticality setups need more than a cap on a VM's total CPU time: a busy best-effort VM
+//! must not starve the others, and a critical VM must get its share whoever else runs. VMs
+//! competing for the same harts join a `CpuBandwidth`, each with a `CpuShare`. Time is divided in
+//! periods, and in each period a VM may run for a budget proportional to its weight, never less
+//! than its guaranteed minimum nor more than its cap. `VM::run_scheduled` cuts time slices short
+//! at the end of the VM's budget and returns once it's used up, the VM being throttled until the
+//! next period. The host scheduler picks the VM to run next with `CpuBandwidth::pick`, which
+//! serves VMs short of their guarantee first, then higher classes, then the VM that ran least for
+//! its weight.
+//!
+//! Every hart running a vCPU of the group charges it and checks its budget on each exit, so the
+//! accounting takes no lock: the members are an `RcuCell` only written as VMs join and leave, and
+//! the CPU time each used is an atomic counter. CPU time charged on other harts right as a period
+//! ends may be counted in either period.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::utils::RcuCell;
+use crate::{HyperError, HyperResult};
+
+/// Priority class of a VM, deciding which VM runs first among those with budget left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CpuClass {
+    Critical,
+    Normal,
+    BestEffort,
+}
+
+/// What a VM gets of the CPU time of a period, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuShare {
+    pub class: CpuClass,
+    /// Weight of the VM relative to the other VMs of the `CpuBandwidth`.
+    pub weight: u32,
+    /// CPU time the VM gets in each period whatever the weights of the others, in ticks of the
+    /// `time` CSR.
+    pub guaranteed: u64,
+    /// CPU time the VM may use at most in each period, in ticks of the `time` CSR. `None` leaves it
+    /// bounded by its weight only.
+    pub cap: Option<u64>,
+}
+
+/// A VM of a `CpuBandwidth`.
+struct Member {
+    share: CpuShare,
+    /// CPU time used in the current period.
+    used: AtomicU64,
+}
+
+type Members = Vec<Option<Arc<Member>>>;
+
+/// CPU time accounting of the VMs competing for the same harts, see the module documentation.
+pub struct CpuBandwidth {
+    /// Length of a period in ticks of the `time` CSR.
+    period: u64,
+    /// CPU time of all harts together in a period.
+    capacity: u64,
+    /// `time` CSR value the current period started at.
+    period_start: AtomicU64,
+    members: RcuCell<Members>,
+}
+
+impl CpuBandwidth {
+    /// Creates an empty group dividing the CPU time of `harts` harts in periods of `period` ticks
+    /// of the `time` CSR. Fails with `InvalidParam` if either is zero.
+    pub fn new(period: u64, harts: usize) -> HyperResult<Self> {
+        if period == 0 || harts == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            period,
+            capacity: period.saturating_mul(harts as u64),
+            period_start: AtomicU64::new(0),
+            members: RcuCell::default(),
+        })
+    }
+
+    /// Adds a VM getting `share`, returning its slot to hand to `VM::set_cpu_bandwidth`. Fails with
+    /// `InvalidParam` if the weight is zero or the cap is below the guarantee, and with
+    /// `OutOfRange` if the guarantees of all VMs no longer fit in a period.
+    pub fn add(&self, share: CpuShare) -> HyperResult<usize> {
+        if share.weight == 0 || share.cap.is_some_and(|cap| cap < share.guaranteed) {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut result = Err(HyperError::OutOfRange);
+        self.members.update(|members| {
+            let mut members = members.clone();
+            let guaranteed = present(&members).try_fold(share.guaranteed, |sum, (_, m)| {
+                sum.checked_add(m.share.guaranteed)
+            });
+            if guaranteed.map_or(true, |sum| sum > self.capacity) {
+                return members;
+            }
+            let member = Some(Arc::new(Member {
+                share,
+                used: AtomicU64::new(0),
+            }));
+            match members.iter().position(Option::is_none) {
+                Some(slot) => {
+                    members[slot] = member;
+                    result = Ok(slot);
+                }
+                None => {
+                    members.push(member);
+                    result = Ok(members.len() - 1);
+                }
+            }
+            members
+        });
+        result
+    }
+
+    /// Removes the VM in `slot`, whose budget goes to the others from the next period.
+    pub fn remove(&self, slot: usize) {
+        self.members.update(|members| {
+            let mut members = members.clone();
+            if let Some(member) = members.get_mut(slot) {
+                *member = None;
+            }
+            members
+        });
+    }
+
+    /// CPU time the VM in `slot` may use in each period: its weighted share of the CPU time of the
+    /// harts, within its guarantee and cap. Zero if there's no such VM.
+    pub fn budget(&self, slot: usize) -> u64 {
+        self.budget_in(&self.members.read(), slot)
+    }
+
+    /// CPU time the VM in `slot` has left in the period containing `now`.
+    pub fn remaining(&self, slot: usize, now: u64) -> u64 {
+        self.roll_period(now);
+        let members = self.members.read();
+        let used = match members.get(slot) {
+            Some(Some(member)) => member.used.load(Ordering::Relaxed),
+            _ => return 0,
+        };
+        self.budget_in(&members, slot).saturating_sub(used)
+    }
+
+    /// Charges the VM in `slot` with `ticks` of CPU time used up to `now`.
+    pub fn charge(&self, slot: usize, ticks: u64, now: u64) {
+        self.roll_period(now);
+        if let Some(Some(member)) = self.members.read().get(slot) {
+            member.used.fetch_add(ticks, Ordering::Relaxed);
+        }
+    }
+
+    /// When the period containing `now` ends, and throttled VMs get a new budget.
+    pub fn period_end(&self, now: u64) -> u64 {
+        self.roll_period(now);
+        self.period_start
+            .load(Ordering::Acquire)
+            .saturating_add(self.period)
+    }
+
+    /// Picks the VM to run next at `now` among those `runnable` says have a vCPU to run, see the
+    /// module documentation. Returns `None` if none of them has budget left.
+    pub fn pick(&self, now: u64, runnable: impl Fn(usize) -> bool) -> Option<usize> {
+        self.roll_period(now);
+        let members = self.members.read();
+        present(&members)
+            .map(|(slot, m)| (slot, m, m.used.load(Ordering::Relaxed)))
+            .filter(|&(slot, _, used)| runnable(slot) && used < self.budget_in(&members, slot))
+            .min_by_key(|&(_, m, used)| {
+                let guaranteed = used < m.share.guaranteed;
+                // CPU time used per unit of weight, the VM that ran least for its weight going
+                // first within a class.
+                let virtual_time = ((used as u128) << 16) / m.share.weight as u128;
+                (!guaranteed, m.share.class, virtual_time)
+            })
+            .map(|(slot, _, _)| slot)
+    }
+
+    fn budget_in(&self, members: &Members, slot: usize) -> u64 {
+        let Some(Some(member)) = members.get(slot) else {
+            return 0;
+        };
+        let total_weight: u64 = present(members).map(|(_, m)| m.share.weight as u64).sum();
+        let share = self.capacity as u128 * member.share.weight as u128 / total_weight as u128;
+        let budget = (share as u64).max(member.share.guaranteed);
+        member.share.cap.map_or(budget, |cap| budget.min(cap))
+    }
+
+    /// Starts a new period if `now` is past the current one, resetting the CPU time used. Of the
+    /// harts seeing the period end at once, the one moving its start on does the reset.
+    fn roll_period(&self, now: u64) {
+        let start = self.period_start.load(Ordering::Acquire);
+        if now.saturating_sub(start) < self.period {
+            return;
+        }
+        let new_start = now - (now - start) % self.period;
+        if self
+            .period_start
+            .compare_exchange(start, new_start, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            for member in self.members.read().iter().flatten() {
+                member.used.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The VMs of `members` with their slots.
+fn present(members: &Members) -> impl Iterator<Item = (usize, &Member)> {
+    members
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, m)| Some((slot, m.as_deref()?)))
+}
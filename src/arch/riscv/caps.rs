@@ -0,0 +1,93 @@
+//! Boot-time probe of what the hart offers for hosting guests.
+//!
+//! The hypervisor extension, a second-stage translation mode the nested page tables can use and
+//! the CSRs set up for every vCPU are required. Without the probe, a missing one only shows as an
+//! illegal instruction trap when the CSRs are first written or a guest first runs.
+use super::csrs::defs::CSR_HGATP;
+use super::detect::{detect_h_extension, detect_henvcfg};
+use super::ept::GuestPagingMode;
+use super::isa::IsaExtensions;
+use super::vmid::vmid_bits;
+use super::{aia::detect_geilen, per_cpu};
+use crate::{logging::LogContext, HyperError, HyperResult};
+
+/// The `MODE` field of `hgatp`.
+const HGATP_MODE_SHIFT: usize = 60;
+
+/// What the hart offers for hosting guests, as probed by `init`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostCapabilities {
+    /// Second-stage translation modes `hgatp` accepts, see `GuestPagingMode`.
+    pub sv39x4: bool,
+    pub sv48x4: bool,
+    pub sv57x4: bool,
+    /// Number of VMID bits in `hgatp`.
+    pub vmid_bits: u32,
+    /// Number of guest external interrupt files, 0 without AIA.
+    pub geilen: usize,
+    /// ISA extensions the host can expose to guests.
+    pub isa: IsaExtensions,
+}
+
+/// Probes the current hart, which should be the boot hart, for what hosting guests requires,
+/// then initializes the hypervisor runtime on it like `init_hv_runtime`. Returns what the hart
+/// offers, or fails with `NotSupported` if it lacks the hypervisor extension, Sv39x4 second-stage
+/// translation or the `henvcfg` CSR. Other harts are set up with `HypervisorPerCpu::init`.
+pub fn init() -> HyperResult<HostCapabilities> {
+    if !detect_h_extension() {
+        hv_log!(
+            Error,
+            Hart,
+            LogContext::NONE,
+            "hypervisor extension not supported"
+        );
+        return Err(HyperError::NotSupported);
+    }
+    if !detect_henvcfg() {
+        hv_log!(Error, Hart, LogContext::NONE, "henvcfg not supported");
+        return Err(HyperError::NotSupported);
+    }
+    let caps = HostCapabilities {
+        sv39x4: probe_hgatp_mode(GuestPagingMode::Sv39x4),
+        sv48x4: probe_hgatp_mode(GuestPagingMode::Sv48x4),
+        sv57x4: probe_hgatp_mode(GuestPagingMode::Sv57x4),
+        vmid_bits: vmid_bits(),
+        geilen: detect_geilen(),
+        isa: IsaExtensions::host(),
+    };
+    // Sv48x4 and Sv57x4 imply Sv39x4.
+    if !caps.sv39x4 {
+        hv_log!(Error, Hart, LogContext::NONE, "Sv39x4 not supported");
+        return Err(HyperError::NotSupported);
+    }
+    unsafe { per_cpu::setup_csrs() };
+    hv_log!(
+        Info,
+        Hart,
+        LogContext::NONE,
+        "host capabilities: {:?}",
+        caps
+    );
+    Ok(caps)
+}
+
+/// Whether `hgatp` accepts the translation mode `mode`. A write with an unsupported mode has no
+/// effect, so the mode is written over bare mode and read back.
+fn probe_hgatp_mode(mode: GuestPagingMode) -> bool {
+    let mode = mode.hgatp_mode();
+    let probed: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrrw {old}, {csr}, zero",
+            "csrw {csr}, {val}",
+            "csrr {probed}, {csr}",
+            "csrw {csr}, {old}",
+            "hfence.gvma",
+            csr = const CSR_HGATP,
+            val = in(reg) mode << HGATP_MODE_SHIFT,
+            old = out(reg) _,
+            probed = out(reg) probed,
+        );
+    }
+    probed >> HGATP_MODE_SHIFT == mode
+}
@@ -0,0 +1,95 @@
+//! Decoding of guest CSR instructions that trap to the hypervisor.
+//!
+//! VS-mode accesses to some CSRs raise a virtual instruction exception rather than reaching the
+//! hardware, e.g. `stimecmp` when the hypervisor doesn't enable Sstc for the guest. The trapping
+//! instruction is decoded here so the VM can emulate the access and resume the guest.
+use super::regs::GeneralPurposeRegisters;
+use crate::GprIndex;
+
+const OPCODE_SYSTEM: u32 = 0x73;
+
+/// How a CSR instruction updates the CSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CsrOp {
+    /// CSRRW and CSRRWI.
+    Write,
+    /// CSRRS and CSRRSI.
+    Set,
+    /// CSRRC and CSRRCI.
+    Clear,
+}
+
+/// Operand of a CSR instruction.
+#[derive(Clone, Copy, Debug)]
+enum CsrSource {
+    Reg(GprIndex),
+    /// The zero-extended 5-bit immediate of the immediate forms.
+    Imm(usize),
+}
+
+/// A decoded CSR instruction.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CsrInstruction {
+    pub csr: u16,
+    /// Register the old value of the CSR is written to.
+    pub rd: GprIndex,
+    pub op: CsrOp,
+    src: CsrSource,
+}
+
+impl CsrInstruction {
+    /// Decodes `inst`, returning `None` if it isn't a CSR instruction.
+    pub fn decode(inst: u32) -> Option<Self> {
+        if inst & 0x7f != OPCODE_SYSTEM {
+            return None;
+        }
+        let op = match (inst >> 12) & 0x3 {
+            1 => CsrOp::Write,
+            2 => CsrOp::Set,
+            3 => CsrOp::Clear,
+            _ => return None,
+        };
+        let field = (inst >> 15) & 0x1f;
+        let src = if inst & (1 << 14) != 0 {
+            CsrSource::Imm(field as usize)
+        } else {
+            CsrSource::Reg(GprIndex::from_raw(field)?)
+        };
+        Some(Self {
+            csr: (inst >> 20) as u16,
+            rd: GprIndex::from_raw((inst >> 7) & 0x1f)?,
+            op,
+            src,
+        })
+    }
+
+    /// Whether the instruction writes the CSR. CSRRS and CSRRC with `x0` or a zero immediate only
+    /// read it.
+    pub fn writes(&self) -> bool {
+        self.op == CsrOp::Write
+            || !matches!(self.src, CsrSource::Reg(GprIndex::Zero) | CsrSource::Imm(0))
+    }
+
+    /// Whether the CSR is read-only, as encoded in its number.
+    pub fn read_only(&self) -> bool {
+        self.csr >> 10 == 0b11
+    }
+
+    /// Whether user mode may access the CSR, as encoded in its number.
+    pub fn user_level(&self) -> bool {
+        (self.csr >> 8) & 0x3 == 0
+    }
+
+    /// The value the CSR holding `old` is updated to, with the source register read from `gprs`.
+    pub fn new_value(&self, old: usize, gprs: &GeneralPurposeRegisters) -> usize {
+        let src = match self.src {
+            CsrSource::Reg(reg) => gprs.reg(reg),
+            CsrSource::Imm(imm) => imm,
+        };
+        match self.op {
+            CsrOp::Write => src,
+            CsrOp::Set => old | src,
+            CsrOp::Clear => old & !src,
+        }
+    }
+}
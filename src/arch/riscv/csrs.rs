@@ -11,6 +11,7 @@ pub struct CSR {
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub hgeie: ReadWriteCsr<hgeie::Register, CSR_HGEIE>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -21,6 +22,7 @@ pub const CSR: &CSR = &CSR {
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    hgeie: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -268,6 +270,13 @@ pub mod defs {
         vsext OFFSET(10) NUMBITS(1) [],
     ]
     ];
+
+    // Hypervisor guest external interrupt enable register.
+    register_bitfields![usize,
+    pub hgeie [
+        gei OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
 }
 
 pub mod traps {
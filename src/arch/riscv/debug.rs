@@ -0,0 +1,435 @@
+//! Guest debugging through software breakpoints and single-stepping.
+//!
+//! A breakpoint replaces the guest instruction at a guest virtual address with `EBREAK`, or
+//! `C.EBREAK` over a compressed instruction, written to the guest physical memory the address
+//! translates to through the VS-stage page table of a vCPU. vCPUs in debug mode trap on `EBREAK`
+//! rather than the guest handling it, and those hitting a breakpoint are reported to the debugger,
+//! while the guest's own `EBREAK`s are reflected back to it.
+//!
+//! A single step places a temporary breakpoint on the instruction the vCPU executes next, which is
+//! computed from its registers. Other vCPUs must not run while one is stepped, as they would trap on
+//! that breakpoint as on one of the guest's own. An interrupt the guest takes before executing the
+//! instruction delays the step until it returns from the handler.
+use alloc::collections::BTreeMap;
+
+use super::regs::GprIndex;
+use super::VCpu;
+use crate::memory::PAGE_SIZE_4K;
+use crate::virtio::GuestMemory;
+use crate::{GuestPhysAddr, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// Why a vCPU in debug mode stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// The vCPU hit the breakpoint at the given address.
+    Breakpoint(GuestVirtAddr),
+    /// The vCPU executed one instruction after `VM::single_step`, stopping at the given address.
+    Step(GuestVirtAddr),
+}
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+const SRET: u32 = 0x1020_0073;
+
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV57: usize = 10;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_X: u64 = 1 << 3;
+
+/// An instruction replaced by a breakpoint, as halfwords at the guest physical addresses they
+/// were read from, which may lie on different pages.
+struct Patch {
+    halves: [(GuestPhysAddr, u16); 2],
+    len: usize,
+}
+
+impl Patch {
+    /// Replaces the instruction at `gva` with a breakpoint.
+    fn insert<H: HyperCraftHal>(
+        vcpu: &VCpu<H>,
+        gva: GuestVirtAddr,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<Self> {
+        let (inst, halves) = read_inst(vcpu, gva, mem)?;
+        let len = if inst & 0x3 == 0x3 { 2 } else { 1 };
+        let patch = Self { halves, len };
+        let ebreak = [EBREAK as u16, (EBREAK >> 16) as u16];
+        let code = if len == 1 { [C_EBREAK, 0] } else { ebreak };
+        for (&(gpa, _), half) in patch.halves[..len].iter().zip(code) {
+            mem.write(gpa, &half.to_le_bytes())?;
+        }
+        sync_icache();
+        Ok(patch)
+    }
+
+    /// Puts the original instruction back.
+    fn remove(&self, mem: &dyn GuestMemory) -> HyperResult<()> {
+        for &(gpa, half) in &self.halves[..self.len] {
+            mem.write(gpa, &half.to_le_bytes())?;
+        }
+        sync_icache();
+        Ok(())
+    }
+}
+
+/// A single step in progress.
+struct Step {
+    vcpu_id: usize,
+    /// Where the vCPU stops.
+    target: GuestVirtAddr,
+    /// The temporary breakpoint at `target`, unless there's a breakpoint there already or the
+    /// step is complete without running the vCPU.
+    patch: Option<Patch>,
+    /// A breakpoint at the instruction stepped over, removed for the step.
+    lifted: Option<GuestVirtAddr>,
+    /// Whether the step is complete without running the vCPU, as the instruction jumps to itself.
+    complete: bool,
+}
+
+/// Breakpoints and single steps of a VM.
+#[derive(Default)]
+pub(crate) struct GuestDebugger {
+    breakpoints: BTreeMap<GuestVirtAddr, Patch>,
+    step: Option<Step>,
+}
+
+impl GuestDebugger {
+    /// Inserts a breakpoint at `gva` in the address space of `vcpu`.
+    pub fn insert_breakpoint<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        gva: GuestVirtAddr,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if self.breakpoints.contains_key(&gva) || self.step.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let patch = Patch::insert(vcpu, gva, mem)?;
+        self.breakpoints.insert(gva, patch);
+        Ok(())
+    }
+
+    /// Removes the breakpoint at `gva`.
+    pub fn remove_breakpoint(
+        &mut self,
+        gva: GuestVirtAddr,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if self.step.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let patch = self.breakpoints.remove(&gva).ok_or(HyperError::NotFound)?;
+        patch.remove(mem)
+    }
+
+    /// Removes all breakpoints and cancels the step in progress.
+    pub fn clear(&mut self, mem: &dyn GuestMemory) -> HyperResult<()> {
+        if let Some(step) = self.step.take() {
+            if let Some(patch) = step.patch {
+                patch.remove(mem)?;
+            }
+        }
+        while let Some((_, patch)) = self.breakpoints.pop_first() {
+            patch.remove(mem)?;
+        }
+        Ok(())
+    }
+
+    /// Arms a single step of `vcpu`.
+    pub fn single_step<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if self.step.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let pc = vcpu.pc();
+        // The instruction to step over can't be read with a breakpoint in its place.
+        let lifted = match self.breakpoints.get(&pc) {
+            Some(patch) => {
+                patch.remove(mem)?;
+                Some(pc)
+            }
+            None => None,
+        };
+        let next = read_inst(vcpu, pc, mem).map(|(inst, _)| {
+            next_pc(pc, inst, vcpu.vsepc(), |index| {
+                vcpu.get_gpr(GprIndex::from_raw(index).unwrap())
+            })
+        });
+        let mut step = Step {
+            vcpu_id: vcpu.vcpu_id(),
+            target: next.as_ref().copied().unwrap_or(pc),
+            patch: None,
+            lifted,
+            complete: false,
+        };
+        let result = match next {
+            Ok(next) if next == pc => {
+                step.complete = true;
+                Ok(())
+            }
+            Ok(next) if self.breakpoints.contains_key(&next) => Ok(()),
+            Ok(next) => Patch::insert(vcpu, next, mem).map(|patch| step.patch = Some(patch)),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            if let Some(gva) = step.lifted {
+                self.reinsert(vcpu, gva, mem)?;
+            }
+            return Err(err);
+        }
+        self.step = Some(step);
+        Ok(())
+    }
+
+    /// Finishes the step of `vcpu` if it's complete without the vCPU running.
+    pub fn take_complete_step<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<Option<DebugEvent>> {
+        match &self.step {
+            Some(step) if step.vcpu_id == vcpu.vcpu_id() && step.complete => {
+                self.finish_step(vcpu, mem).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Handles an `EBREAK` `vcpu` executed at `pc`. Returns the event to report to
+    /// the debugger, or `None` if it's the guest's own.
+    pub fn on_ebreak<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        pc: GuestVirtAddr,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<Option<DebugEvent>> {
+        match &self.step {
+            Some(step) if step.vcpu_id == vcpu.vcpu_id() && step.target == pc => {
+                self.finish_step(vcpu, mem).map(Some)
+            }
+            _ if self.breakpoints.contains_key(&pc) => Ok(Some(DebugEvent::Breakpoint(pc))),
+            _ => Ok(None),
+        }
+    }
+
+    fn finish_step<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<DebugEvent> {
+        let step = self.step.take().unwrap();
+        if let Some(patch) = step.patch {
+            patch.remove(mem)?;
+        }
+        if let Some(gva) = step.lifted {
+            self.reinsert(vcpu, gva, mem)?;
+        }
+        Ok(DebugEvent::Step(step.target))
+    }
+
+    /// Inserts the breakpoint at `gva` again after it was lifted for a step.
+    fn reinsert<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &VCpu<H>,
+        gva: GuestVirtAddr,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        let patch = Patch::insert(vcpu, gva, mem)?;
+        self.breakpoints.insert(gva, patch);
+        Ok(())
+    }
+}
+
+/// Reads the instruction at `gva` in the address space of `vcpu`, along with its halfwords and
+/// their guest physical addresses.
+fn read_inst<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    gva: GuestVirtAddr,
+    mem: &dyn GuestMemory,
+) -> HyperResult<(u32, [(GuestPhysAddr, u16); 2])> {
+    let mut halves = [(0, 0); 2];
+    for (i, half) in halves.iter_mut().enumerate() {
+        let gpa = translate(vcpu.vsatp(), gva + i * 2, mem)?;
+        let mut buf = [0u8; 2];
+        mem.read(gpa, &mut buf)?;
+        *half = (gpa, u16::from_le_bytes(buf));
+        if half.1 & 0x3 != 0x3 {
+            break;
+        }
+    }
+    Ok((halves[0].1 as u32 | (halves[1].1 as u32) << 16, halves))
+}
+
+/// Reads the guest memory at `gva` in the address space of `vcpu` into `buf`.
+pub(crate) fn read_virt<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    gva: GuestVirtAddr,
+    buf: &mut [u8],
+    mem: &dyn GuestMemory,
+) -> HyperResult<()> {
+    for_each_page(vcpu, gva, buf.len(), mem, |gpa, offset, len| {
+        mem.read(gpa, &mut buf[offset..offset + len])
+    })
+}
+
+/// Writes `buf` to the guest memory at `gva` in the address space of `vcpu`, which may hold
+/// instructions.
+pub(crate) fn write_virt<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    gva: GuestVirtAddr,
+    buf: &[u8],
+    mem: &dyn GuestMemory,
+) -> HyperResult<()> {
+    for_each_page(vcpu, gva, buf.len(), mem, |gpa, offset, len| {
+        mem.write(gpa, &buf[offset..offset + len])
+    })?;
+    sync_icache();
+    Ok(())
+}
+
+/// Calls `f` with the guest physical address, offset and length of each piece of
+/// `[gva, gva + len)` within a page of the address space of `vcpu`.
+fn for_each_page<H: HyperCraftHal>(
+    vcpu: &VCpu<H>,
+    gva: GuestVirtAddr,
+    len: usize,
+    mem: &dyn GuestMemory,
+    mut f: impl FnMut(GuestPhysAddr, usize, usize) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let vsatp = vcpu.vsatp();
+    let mut offset = 0;
+    while offset < len {
+        let addr = gva.checked_add(offset).ok_or(HyperError::OutOfRange)?;
+        let chunk = core::cmp::min(PAGE_SIZE_4K - addr % PAGE_SIZE_4K, len - offset);
+        f(translate(vsatp, addr, mem)?, offset, chunk)?;
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Translates the guest virtual address `gva` through the VS-stage page table of `vsatp`.
+fn translate(
+    vsatp: usize,
+    gva: GuestVirtAddr,
+    mem: &dyn GuestMemory,
+) -> HyperResult<GuestPhysAddr> {
+    let mode = vsatp >> SATP_MODE_SHIFT;
+    if mode == SATP_MODE_BARE {
+        return Ok(gva);
+    }
+    if !(SATP_MODE_SV39..=SATP_MODE_SV57).contains(&mode) {
+        return Err(HyperError::NotSupported);
+    }
+    let levels = mode - SATP_MODE_SV39 + 3;
+    let mut table = (vsatp & SATP_PPN_MASK) << 12;
+    for level in (0..levels).rev() {
+        let shift = 12 + level * 9;
+        let mut pte = [0u8; 8];
+        mem.read(table + ((gva >> shift) & 0x1ff) * 8, &mut pte)?;
+        let pte = u64::from_le_bytes(pte);
+        if pte & PTE_V == 0 {
+            return Err(HyperError::PageFault);
+        }
+        let addr = ((pte >> 10) << 12) as usize;
+        if pte & (PTE_R | PTE_X) != 0 {
+            let offset_mask = (1 << shift) - 1;
+            return Ok(addr & !offset_mask | gva & offset_mask);
+        }
+        table = addr;
+    }
+    Err(HyperError::PageFault)
+}
+
+/// Computes the address of the instruction executed after `inst` at `pc`, given the value of
+/// `vsepc` and the general purpose registers through `reg`.
+fn next_pc(
+    pc: GuestVirtAddr,
+    inst: u32,
+    vsepc: usize,
+    reg: impl Fn(u32) -> usize,
+) -> GuestVirtAddr {
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // Sign-extends the `len`-bit immediate `imm`.
+    let sext = |imm: u32, len: u32| ((imm << (32 - len)) as i32 >> (32 - len)) as isize;
+    let offset = |imm: isize| pc.wrapping_add_signed(imm);
+
+    if inst & 0x3 != 0x3 {
+        let (quadrant, funct3) = (inst & 0x3, bits(15, 13));
+        return match (quadrant, funct3) {
+            // C.J
+            (1, 0b101) => {
+                let imm = bits(12, 12) << 11
+                    | bits(11, 11) << 4
+                    | bits(10, 9) << 8
+                    | bits(8, 8) << 10
+                    | bits(7, 7) << 6
+                    | bits(6, 6) << 7
+                    | bits(5, 3) << 1
+                    | bits(2, 2) << 5;
+                offset(sext(imm, 12))
+            }
+            // C.BEQZ and C.BNEZ
+            (1, 0b110 | 0b111) => {
+                let imm = bits(12, 12) << 8
+                    | bits(11, 10) << 3
+                    | bits(6, 5) << 6
+                    | bits(4, 3) << 1
+                    | bits(2, 2) << 5;
+                let zero = reg(8 + bits(9, 7)) == 0;
+                if zero == (funct3 == 0b110) {
+                    offset(sext(imm, 9))
+                } else {
+                    pc + 2
+                }
+            }
+            // C.JR and C.JALR
+            (2, 0b100) if bits(6, 2) == 0 && bits(11, 7) != 0 => reg(bits(11, 7)),
+            _ => pc + 2,
+        };
+    }
+    match inst & 0x7f {
+        // JAL
+        0x6f => {
+            let imm =
+                bits(31, 31) << 20 | bits(19, 12) << 12 | bits(20, 20) << 11 | bits(30, 21) << 1;
+            offset(sext(imm, 21))
+        }
+        // JALR
+        0x67 => reg(bits(19, 15)).wrapping_add_signed(sext(bits(31, 20), 12)) & !1,
+        // Branches
+        0x63 => {
+            let (a, b) = (reg(bits(19, 15)), reg(bits(24, 20)));
+            let taken = match bits(14, 12) {
+                0b000 => a == b,
+                0b001 => a != b,
+                0b100 => (a as isize) < (b as isize),
+                0b101 => (a as isize) >= (b as isize),
+                0b110 => a < b,
+                0b111 => a >= b,
+                _ => false,
+            };
+            let imm = bits(31, 31) << 12 | bits(7, 7) << 11 | bits(30, 25) << 5 | bits(11, 8) << 1;
+            if taken {
+                offset(sext(imm, 13))
+            } else {
+                pc + 4
+            }
+        }
+        _ if inst == SRET => vsepc,
+        _ => pc + 4,
+    }
+}
+
+/// Makes the instructions written to guest memory visible to instruction fetches on all harts.
+fn sync_icache() {
+    unsafe { core::arch::asm!("fence.i") };
+    let _ = sbi_rt::remote_fence_i(0, usize::MAX);
+}
@@ -19,6 +19,55 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if the henvcfg CSR exists on current hart environment
+//
+// This function tries to read henvcfg and returns false if the read operation failed.
+pub fn detect_henvcfg() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x60a", out(reg) _, options(nomem, nostack)); // 0x60a => henvcfg
+    });
+    ans != 2
+}
+
+// Detect if the Sscofpmf extension exists on current hart environment
+//
+// This function tries to read scountovf and returns false if the read operation failed.
+pub fn detect_sscofpmf() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0xda0", out(reg) _, options(nomem, nostack)); // 0xda0 => scountovf
+    });
+    ans != 2
+}
+
+// Detect if the F and D extensions exist on current hart environment
+//
+// This function turns sstatus.FS on, tries to read fcsr and returns false if the read failed.
+pub fn detect_fpu() -> bool {
+    detect_csr_with_status(1 << 13, || unsafe {
+        asm!("csrr  {}, 0x003", out(reg) _, options(nomem, nostack)); // 0x003 => fcsr
+    })
+}
+
+// Detect if the V extension exists on current hart environment
+//
+// This function turns sstatus.VS on, tries to read vlenb and returns false if the read failed.
+pub fn detect_vector() -> bool {
+    detect_csr_with_status(1 << 9, || unsafe {
+        asm!("csrr  {}, 0xc22", out(reg) _, options(nomem, nostack)); // 0xc22 => vlenb
+    })
+}
+
+// Runs the CSR read in `f` with the `status` bits set in sstatus, restoring them afterwards.
+fn detect_csr_with_status(status: usize, f: impl FnOnce()) -> bool {
+    let old: usize;
+    unsafe { asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) status) };
+    let ans = with_detect_trap(0, f);
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) status & !old);
+    }
+    ans != 2
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
@@ -0,0 +1,279 @@
+//! Emulated APLIC interrupt domain in MSI delivery mode.
+//!
+//! Wired interrupts routed to the guest are latched as pending here and forwarded as MSIs to the
+//! guest interrupt file of the vCPU targeted by the source's `target` register.
+//!
+//! ref: The RISC-V Advanced Interrupt Architecture, v1.0, chapter 4
+
+use crate::{
+    device::EmuDevice,
+    snapshot::{SectionBuilder, SectionReader, SnapshotReader},
+    virtio::GuestMemory,
+    GuestPhysAddr, HyperError, HyperResult,
+};
+use spin::{Mutex, MutexGuard};
+
+/// Size of the APLIC register region.
+pub const APLIC_SIZE: usize = 0x4000;
+
+/// Number of interrupt sources, including the reserved source 0.
+const APLIC_SOURCES: usize = 1024;
+
+const APLIC_DOMAINCFG: usize = 0x0000;
+const APLIC_SOURCECFG: usize = 0x0004;
+const APLIC_SETIP: usize = 0x1c00;
+const APLIC_SETIPNUM: usize = 0x1cdc;
+const APLIC_IN_CLRIP: usize = 0x1d00;
+const APLIC_CLRIPNUM: usize = 0x1ddc;
+const APLIC_SETIE: usize = 0x1e00;
+const APLIC_SETIENUM: usize = 0x1edc;
+const APLIC_CLRIE: usize = 0x1f00;
+const APLIC_CLRIENUM: usize = 0x1fdc;
+const APLIC_SETIPNUM_LE: usize = 0x2000;
+const APLIC_GENMSI: usize = 0x3000;
+const APLIC_TARGET: usize = 0x3004;
+
+// `domaincfg` fields. Reads always return 0x80 in the top byte, and MSI delivery mode is the only
+// one supported.
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+const DOMAINCFG_FIXED: u32 = 0x8000_0000 | DOMAINCFG_DM;
+
+// `sourcecfg` fields. Sources can't be delegated as the domain has no children.
+const SOURCECFG_SM_MASK: u32 = 0x7;
+
+// `target` and `genmsi` fields in MSI delivery mode.
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+/// An MSI to be sent to the interrupt file of a vCPU.
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    /// Target vCPU.
+    pub hart: usize,
+    /// External interrupt identity.
+    pub eiid: u32,
+}
+
+impl Msi {
+    fn from_target(target: u32) -> Self {
+        Self {
+            hart: (target >> TARGET_HART_SHIFT) as usize,
+            eiid: target & TARGET_EIID_MASK,
+        }
+    }
+}
+
+pub struct AplicState {
+    base: usize,
+    domaincfg: u32,
+    sourcecfg: [u32; APLIC_SOURCES],
+    pending: [u32; APLIC_SOURCES / 32],
+    enabled: [u32; APLIC_SOURCES / 32],
+    target: [u32; APLIC_SOURCES],
+    /// MSI requested through `genmsi` and not sent yet.
+    genmsi: Option<Msi>,
+}
+
+impl AplicState {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            domaincfg: 0,
+            sourcecfg: [0; APLIC_SOURCES],
+            pending: [0; APLIC_SOURCES / 32],
+            enabled: [0; APLIC_SOURCES / 32],
+            target: [0; APLIC_SOURCES],
+            genmsi: None,
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Whether `addr` is in the APLIC's register region.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + APLIC_SIZE).contains(&addr)
+    }
+
+    fn is_active(&self, irq: usize) -> bool {
+        (1..APLIC_SOURCES).contains(&irq)
+            && matches!(self.sourcecfg[irq] & SOURCECFG_SM_MASK, 1 | 4..=7)
+    }
+
+    /// Latches an input of the wired source `irq`. Inactive sources ignore their input.
+    pub fn set_pending(&mut self, irq: usize) {
+        if self.is_active(irq) {
+            self.pending[irq / 32] |= 1 << (irq % 32);
+        }
+    }
+
+    /// The vCPU the wired source `irq` is routed to, or `None` for an inactive source.
+    pub fn target_hart(&self, irq: usize) -> Option<usize> {
+        self.is_active(irq)
+            .then(|| Msi::from_target(self.target[irq]).hart)
+    }
+
+    fn set_enabled(&mut self, irq: usize, enabled: bool) {
+        if !self.is_active(irq) {
+            return;
+        }
+        if enabled {
+            self.enabled[irq / 32] |= 1 << (irq % 32);
+        } else {
+            self.enabled[irq / 32] &= !(1 << (irq % 32));
+        }
+    }
+
+    /// Returns the next MSI to be sent. Forwarding a pending and enabled interrupt clears its
+    /// pending bit, as in real MSI delivery mode.
+    pub fn next_msi(&mut self) -> Option<Msi> {
+        if let Some(msi) = self.genmsi.take() {
+            return Some(msi);
+        }
+        if self.domaincfg & DOMAINCFG_IE == 0 {
+            return None;
+        }
+        for (i, (pending, enabled)) in self.pending.iter_mut().zip(&self.enabled).enumerate() {
+            let ready = *pending & enabled;
+            if ready != 0 {
+                let irq = i * 32 + ready.trailing_zeros() as usize;
+                *pending &= !(1 << (irq % 32));
+                return Some(Msi::from_target(self.target[irq]));
+            }
+        }
+        None
+    }
+
+    /// Appends the register state to a snapshot section. MSIs requested through `genmsi` are sent
+    /// before the VM pauses and aren't saved.
+    pub fn save(&self, payload: &mut SectionBuilder) {
+        payload.put_u32(self.domaincfg);
+        let regs = self.sourcecfg.iter().chain(&self.pending);
+        for &reg in regs.chain(&self.enabled).chain(&self.target) {
+            payload.put_u32(reg);
+        }
+    }
+
+    /// Restores the register state saved by `save`.
+    pub fn restore<R: SnapshotReader>(
+        &mut self,
+        section: &mut SectionReader<'_, '_, R>,
+    ) -> HyperResult<()> {
+        self.domaincfg = section.read_u32()?;
+        let regs = self.sourcecfg.iter_mut().chain(&mut self.pending);
+        for reg in regs.chain(&mut self.enabled).chain(&mut self.target) {
+            *reg = section.read_u32()?;
+        }
+        self.genmsi = None;
+        Ok(())
+    }
+
+    pub fn read_u32(&mut self, addr: usize) -> u32 {
+        let offset = addr.wrapping_sub(self.base);
+        match offset {
+            APLIC_DOMAINCFG => self.domaincfg | DOMAINCFG_FIXED,
+            APLIC_SOURCECFG..=0x0ffc => self.sourcecfg[(offset - APLIC_SOURCECFG) / 4 + 1],
+            APLIC_SETIP..=0x1c7c => self.pending[(offset - APLIC_SETIP) / 4],
+            APLIC_SETIE..=0x1e7c => self.enabled[(offset - APLIC_SETIE) / 4],
+            APLIC_TARGET..=0x3ffc => self.target[(offset - APLIC_TARGET) / 4 + 1],
+            // Rectified inputs, `genmsi`'s busy bit and the write-only registers read as zero.
+            _ => 0,
+        }
+    }
+
+    pub fn write_u32(&mut self, addr: usize, val: u32) {
+        let offset = addr.wrapping_sub(self.base);
+        let val_irq = val as usize;
+        match offset {
+            APLIC_DOMAINCFG => self.domaincfg = val & DOMAINCFG_IE,
+            APLIC_SOURCECFG..=0x0ffc => {
+                let irq = (offset - APLIC_SOURCECFG) / 4 + 1;
+                self.sourcecfg[irq] = val & SOURCECFG_SM_MASK;
+                if !self.is_active(irq) {
+                    self.pending[irq / 32] &= !(1 << (irq % 32));
+                    self.enabled[irq / 32] &= !(1 << (irq % 32));
+                }
+            }
+            APLIC_SETIP..=0x1c7c => {
+                for bit in (0..32).filter(|bit| val & (1 << bit) != 0) {
+                    self.set_pending((offset - APLIC_SETIP) / 4 * 32 + bit);
+                }
+            }
+            APLIC_SETIPNUM | APLIC_SETIPNUM_LE => self.set_pending(val_irq),
+            APLIC_IN_CLRIP..=0x1d7c => self.pending[(offset - APLIC_IN_CLRIP) / 4] &= !val,
+            APLIC_CLRIPNUM if val_irq < APLIC_SOURCES => {
+                self.pending[val_irq / 32] &= !(1 << (val_irq % 32))
+            }
+            APLIC_SETIE..=0x1e7c => {
+                for bit in (0..32).filter(|bit| val & (1 << bit) != 0) {
+                    self.set_enabled((offset - APLIC_SETIE) / 4 * 32 + bit, true);
+                }
+            }
+            APLIC_SETIENUM => self.set_enabled(val_irq, true),
+            APLIC_CLRIE..=0x1f7c => self.enabled[(offset - APLIC_CLRIE) / 4] &= !val,
+            APLIC_CLRIENUM => self.set_enabled(val_irq, false),
+            APLIC_GENMSI => self.genmsi = Some(Msi::from_target(val)),
+            APLIC_TARGET..=0x3ffc => {
+                self.target[(offset - APLIC_TARGET) / 4 + 1] = val & !(0x3f << 12 | 1 << 11)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The APLIC of a VM. It raises no interrupt of its own: the VM sends the MSIs it becomes ready
+/// to deliver after each access, see `AplicState::next_msi`.
+pub struct EmuAplic {
+    state: Mutex<AplicState>,
+}
+
+impl EmuAplic {
+    pub fn new(base: usize) -> Self {
+        Self {
+            state: Mutex::new(AplicState::new(base)),
+        }
+    }
+
+    /// Locks the APLIC's state, e.g. to latch a device interrupt as pending.
+    pub fn lock(&self) -> MutexGuard<'_, AplicState> {
+        self.state.lock()
+    }
+}
+
+impl EmuDevice for EmuAplic {
+    fn contains(&self, addr: GuestPhysAddr) -> bool {
+        self.state.lock().contains(addr)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        if width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        Ok(self.state.lock().read_u32(addr) as u64)
+    }
+
+    fn handle_write(
+        &self,
+        addr: GuestPhysAddr,
+        width: usize,
+        val: u64,
+        _mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        self.state.lock().write_u32(addr, val as u32);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        *state = AplicState::new(state.base);
+    }
+
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        None
+    }
+}
@@ -1 +1,4 @@
+pub mod aplic;
 pub mod plic;
+pub mod rtc;
+pub mod uart;
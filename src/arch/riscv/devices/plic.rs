@@ -1,12 +1,28 @@
+use arrayvec::ArrayVec;
+
 use crate::{
     arch::csrs::{traps, RiscvCsrTrait, CSR},
+    snapshot::{SectionBuilder, SectionReader, SnapshotReader},
     vcpus::MAX_CPUS,
+    HyperError, HyperResult,
 };
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
 pub const MAX_CONTEXTS: usize = 2 * MAX_CPUS;
 
+/// Number of interrupt sources supported by the PLIC, including the reserved source 0.
+pub const MAX_SOURCES: usize = 1024;
+
+/// Maximum number of host interrupts that can be routed to a guest under a different number.
+const MAX_IRQ_ROUTES: usize = 32;
+
+/// Maximum number of interrupts raised by emulated devices.
+const MAX_VIRTUAL_IRQS: usize = 8;
+
+/// The host S-mode context external interrupts are claimed from.
+const HOST_CONTEXT: usize = 1;
+
 pub struct PlicState {
     base: usize,
     source_priority: [u32; 512],
@@ -14,6 +30,11 @@ pub struct PlicState {
     enable: [[u32; 32]; MAX_CONTEXTS],
     thresholds: [u32; MAX_CONTEXTS],
     pub claim_complete: [u32; MAX_CONTEXTS],
+    /// Host interrupts forwarded to the guest, as (host irq, guest irq). Interrupts without a
+    /// route are forwarded under their host number.
+    irq_routes: ArrayVec<(u32, u32), MAX_IRQ_ROUTES>,
+    /// Interrupts raised by emulated devices, whose completions aren't forwarded to the host.
+    virtual_irqs: ArrayVec<u32, MAX_VIRTUAL_IRQS>,
 }
 
 impl PlicState {
@@ -25,6 +46,8 @@ impl PlicState {
             enable: [[0; 32]; MAX_CONTEXTS],
             thresholds: [0; MAX_CONTEXTS],
             claim_complete: [0; MAX_CONTEXTS],
+            irq_routes: ArrayVec::new(),
+            virtual_irqs: ArrayVec::new(),
         }
     }
 
@@ -32,6 +55,97 @@ impl PlicState {
         self.base
     }
 
+    /// Forwards the host interrupt `host_irq` to the guest as `guest_irq` and enables it in the host
+    /// PLIC.
+    pub fn route_irq(&mut self, host_irq: u32, guest_irq: u32) -> HyperResult<()> {
+        let valid = 1..MAX_SOURCES as u32;
+        if !valid.contains(&host_irq) || !valid.contains(&guest_irq) {
+            return Err(HyperError::InvalidParam);
+        }
+        if self
+            .irq_routes
+            .iter()
+            .any(|&(host, guest)| host == host_irq || guest == guest_irq)
+        {
+            return Err(HyperError::BadState);
+        }
+        self.irq_routes
+            .try_push((host_irq, guest_irq))
+            .map_err(|_| HyperError::NoMemory)?;
+
+        let priority_addr = self.base + 4 * host_irq as usize;
+        let enable_addr = self.base + 0x2000 + 0x80 * HOST_CONTEXT + 4 * (host_irq as usize / 32);
+        unsafe {
+            core::ptr::write_volatile(priority_addr as *mut u32, 1);
+            let enable = core::ptr::read_volatile(enable_addr as *const u32);
+            core::ptr::write_volatile(enable_addr as *mut u32, enable | 1 << (host_irq % 32));
+        }
+        Ok(())
+    }
+
+    /// Reserves the guest interrupt `irq` for an emulated device.
+    pub fn add_virtual_irq(&mut self, irq: u32) -> HyperResult<()> {
+        if !(1..MAX_SOURCES as u32).contains(&irq) {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.virtual_irqs.contains(&irq)
+            || self.irq_routes.iter().any(|&(_, guest)| guest == irq)
+        {
+            return Err(HyperError::BadState);
+        }
+        self.virtual_irqs
+            .try_push(irq)
+            .map_err(|_| HyperError::NoMemory)
+    }
+
+    /// Whether any host interrupt is forwarded to the guest.
+    pub fn has_irq_routes(&self) -> bool {
+        !self.irq_routes.is_empty()
+    }
+
+    /// The number the guest sees the host interrupt `host_irq` as.
+    pub fn guest_irq(&self, host_irq: u32) -> u32 {
+        self.irq_routes
+            .iter()
+            .find(|&&(host, _)| host == host_irq)
+            .map_or(host_irq, |&(_, guest)| guest)
+    }
+
+    /// The host interrupt the guest interrupt `guest_irq` is backed by.
+    fn host_irq(&self, guest_irq: u32) -> u32 {
+        self.irq_routes
+            .iter()
+            .find(|&&(_, guest)| guest == guest_irq)
+            .map_or(guest_irq, |&(host, _)| host)
+    }
+
+    /// Appends the guest-visible register state to a snapshot section. Interrupt routes are host
+    /// configuration and must be set up again before restoring.
+    pub fn save(&self, payload: &mut SectionBuilder) {
+        let regs = self.source_priority.iter().chain(&self.pending);
+        let regs = regs.chain(self.enable.iter().flatten());
+        let regs = regs.chain(&self.thresholds).chain(&self.claim_complete);
+        for &reg in regs {
+            payload.put_u32(reg);
+        }
+    }
+
+    /// Restores the register state saved by `save`.
+    pub fn restore<R: SnapshotReader>(
+        &mut self,
+        section: &mut SectionReader<'_, '_, R>,
+    ) -> HyperResult<()> {
+        let regs = self.source_priority.iter_mut().chain(&mut self.pending);
+        let regs = regs.chain(self.enable.iter_mut().flatten());
+        let regs = regs
+            .chain(&mut self.thresholds)
+            .chain(&mut self.claim_complete);
+        for reg in regs {
+            *reg = section.read_u32()?;
+        }
+        Ok(())
+    }
+
     pub fn read_u32(&mut self, addr: usize) -> u32 {
         let offset = addr.wrapping_sub(self.base);
         if (0x20_0000..0x20_0000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
@@ -61,9 +175,12 @@ impl PlicState {
                     core::ptr::write_volatile(addr as *mut u32, val);
                 }
             } else if index == 1 {
-                // claim
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+                // complete
+                if !self.virtual_irqs.contains(&val) {
+                    let host_irq = self.host_irq(val);
+                    unsafe {
+                        core::ptr::write_volatile(addr as *mut u32, host_irq);
+                    }
                 }
                 self.claim_complete[hart] = 0;
                 // Send Interrupt to the hart
@@ -0,0 +1,163 @@
+//! Emulated Goldfish RTC reporting the host's wall-clock time.
+//!
+//! Time is in nanoseconds since the Unix epoch. Time the guest sets is kept as an offset from the
+//! host's clock, and the alarm fires once the guest's time reaches it.
+
+use crate::{device::EmuDevice, virtio::GuestMemory, GuestPhysAddr, HyperError, HyperResult};
+use spin::Mutex;
+
+/// Size of the RTC register region.
+pub const RTC_SIZE: usize = 0x1000;
+
+const RTC_TIME_LOW: usize = 0x00;
+const RTC_TIME_HIGH: usize = 0x04;
+const RTC_ALARM_LOW: usize = 0x08;
+const RTC_ALARM_HIGH: usize = 0x0c;
+const RTC_IRQ_ENABLED: usize = 0x10;
+const RTC_CLEAR_ALARM: usize = 0x14;
+const RTC_ALARM_STATUS: usize = 0x18;
+const RTC_CLEAR_INTERRUPT: usize = 0x1c;
+
+pub struct RtcState {
+    base: usize,
+    /// Guest time minus host time.
+    offset: u64,
+    /// High half of the time, latched when the low half is read.
+    time_high: u32,
+    /// High half of the time or alarm, taking effect when the low half is written.
+    write_high: u32,
+    /// The armed alarm, in guest time.
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// Whether an alarm fired and the guest hasn't cleared the interrupt yet.
+    interrupt: bool,
+}
+
+impl RtcState {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            offset: 0,
+            time_high: 0,
+            write_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            interrupt: false,
+        }
+    }
+
+    /// Whether `addr` is in the RTC's register region.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + RTC_SIZE).contains(&addr)
+    }
+
+    /// Whether the RTC raises its interrupt, i.e. an alarm fired with the interrupt enabled.
+    pub fn irq_pending(&self) -> bool {
+        self.irq_enabled && self.interrupt
+    }
+
+    /// Fires the alarm if the host time `now` has reached it.
+    pub fn update(&mut self, now: u64) {
+        if self.alarm.is_some_and(|alarm| self.time(now) >= alarm) {
+            self.alarm = None;
+            self.interrupt = true;
+        }
+    }
+
+    /// Reads the register at `addr`, `now` being the host time.
+    pub fn read_u32(&mut self, addr: usize, now: u64) -> u32 {
+        match addr - self.base {
+            RTC_TIME_LOW => {
+                let time = self.time(now);
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            }
+            RTC_TIME_HIGH => self.time_high,
+            RTC_ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            RTC_ALARM_HIGH => (self.alarm.unwrap_or(0) >> 32) as u32,
+            RTC_IRQ_ENABLED => self.irq_enabled as u32,
+            RTC_ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Writes `val` to the register at `addr`, `now` being the host time.
+    pub fn write_u32(&mut self, addr: usize, val: u32, now: u64) {
+        let full = (self.write_high as u64) << 32 | val as u64;
+        match addr - self.base {
+            RTC_TIME_LOW => self.offset = full.wrapping_sub(now),
+            RTC_TIME_HIGH | RTC_ALARM_HIGH => self.write_high = val,
+            RTC_ALARM_LOW => {
+                self.alarm = Some(full);
+                self.update(now);
+            }
+            RTC_IRQ_ENABLED => self.irq_enabled = val & 1 != 0,
+            RTC_CLEAR_ALARM => self.alarm = None,
+            RTC_CLEAR_INTERRUPT => self.interrupt = false,
+            _ => {}
+        }
+    }
+
+    fn time(&self, now: u64) -> u64 {
+        now.wrapping_add(self.offset)
+    }
+}
+
+/// The RTC of a VM, raising the guest interrupt `irq` and reading the host time from `clock`.
+pub struct EmuRtc {
+    state: Mutex<RtcState>,
+    irq: u32,
+    clock: fn() -> u64,
+}
+
+impl EmuRtc {
+    pub fn new(base: usize, irq: u32, clock: fn() -> u64) -> Self {
+        Self {
+            state: Mutex::new(RtcState::new(base)),
+            irq,
+            clock,
+        }
+    }
+}
+
+impl EmuDevice for EmuRtc {
+    fn contains(&self, addr: GuestPhysAddr) -> bool {
+        self.state.lock().contains(addr)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        if width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        Ok(self.state.lock().read_u32(addr, (self.clock)()) as u64)
+    }
+
+    fn handle_write(
+        &self,
+        addr: GuestPhysAddr,
+        width: usize,
+        val: u64,
+        _mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        self.state
+            .lock()
+            .write_u32(addr, val as u32, (self.clock)());
+        Ok(())
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        *state = RtcState::new(state.base);
+    }
+
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        Some((self.irq, self.state.lock().irq_pending()))
+    }
+
+    fn update(&self, _mem: &dyn GuestMemory) {
+        self.state.lock().update((self.clock)());
+    }
+}
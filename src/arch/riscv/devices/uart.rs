@@ -0,0 +1,191 @@
+//! Emulated 16550 UART backed by a console of the console multiplexer.
+//!
+//! Only what drivers need to transmit and receive bytes is emulated: the line and modem settings
+//! are stored but have no effect, the transmitter is always empty, and received data comes from
+//! the console's input.
+
+use crate::{
+    console::{self, ConsoleId},
+    device::EmuDevice,
+    virtio::GuestMemory,
+    GuestPhysAddr, HyperError, HyperResult,
+};
+use spin::Mutex;
+
+/// Size of the UART register region.
+pub const UART_SIZE: usize = 0x100;
+
+const UART_RBR_THR_DLL: usize = 0;
+const UART_IER_DLM: usize = 1;
+const UART_IIR_FCR: usize = 2;
+const UART_LCR: usize = 3;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+const UART_MSR: usize = 6;
+const UART_SCR: usize = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// Carrier detect, data set ready and clear to send, as with a connected terminal.
+const MSR_CONNECTED: u8 = 0xb0;
+
+pub struct UartState {
+    base: usize,
+    console: ConsoleId,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+}
+
+impl UartState {
+    pub fn new(base: usize, console: ConsoleId) -> Self {
+        Self {
+            base,
+            console,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+        }
+    }
+
+    /// Whether `addr` is in the UART's register region.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + UART_SIZE).contains(&addr)
+    }
+
+    /// Whether the UART raises its interrupt, i.e. received data or an empty transmitter has its
+    /// interrupt enabled.
+    pub fn irq_pending(&self) -> bool {
+        self.ier & IER_THRI != 0 || self.ier & IER_RDI != 0 && console::has_input(self.console)
+    }
+
+    pub fn read_u8(&mut self, addr: usize) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match addr.wrapping_sub(self.base) {
+            UART_RBR_THR_DLL if dlab => self.dll,
+            UART_RBR_THR_DLL => console::read_input(self.console).unwrap_or(0),
+            UART_IER_DLM if dlab => self.dlm,
+            UART_IER_DLM => self.ier,
+            UART_IIR_FCR => {
+                let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                let id = if self.ier & IER_RDI != 0 && console::has_input(self.console) {
+                    IIR_RDI
+                } else if self.ier & IER_THRI != 0 {
+                    IIR_THRI
+                } else {
+                    IIR_NO_INT
+                };
+                fifo | id
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                let ready = if console::has_input(self.console) {
+                    LSR_DR
+                } else {
+                    0
+                };
+                LSR_THRE | LSR_TEMT | ready
+            }
+            UART_MSR => MSR_CONNECTED,
+            UART_SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    pub fn write_u8(&mut self, addr: usize, val: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match addr.wrapping_sub(self.base) {
+            UART_RBR_THR_DLL if dlab => self.dll = val,
+            UART_RBR_THR_DLL => console::write_output(self.console, val),
+            UART_IER_DLM if dlab => self.dlm = val,
+            UART_IER_DLM => self.ier = val & 0x0f,
+            UART_IIR_FCR => self.fcr = val,
+            UART_LCR => self.lcr = val,
+            UART_MCR => self.mcr = val,
+            UART_SCR => self.scr = val,
+            _ => {}
+        }
+    }
+}
+
+/// The UART of a VM, raising the guest interrupt `irq`.
+pub struct EmuUart {
+    state: Mutex<UartState>,
+    irq: u32,
+}
+
+impl EmuUart {
+    pub fn new(base: usize, console: ConsoleId, irq: u32) -> Self {
+        Self {
+            state: Mutex::new(UartState::new(base, console)),
+            irq,
+        }
+    }
+
+    fn check_width(width: usize) -> HyperResult<()> {
+        // Registers are byte-wide, and may be accessed as words (`reg-io-width = 4`).
+        if width != 1 && width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        Ok(())
+    }
+}
+
+impl EmuDevice for EmuUart {
+    fn contains(&self, addr: GuestPhysAddr) -> bool {
+        self.state.lock().contains(addr)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        Self::check_width(width)?;
+        Ok(self.state.lock().read_u8(addr) as u64)
+    }
+
+    fn handle_write(
+        &self,
+        addr: GuestPhysAddr,
+        width: usize,
+        val: u64,
+        _mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        Self::check_width(width)?;
+        self.state.lock().write_u8(addr, val as u8);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        *state = UartState::new(state.base, state.console);
+    }
+
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        Some((self.irq, self.state.lock().irq_pending()))
+    }
+}
@@ -1,6 +1,9 @@
 use page_table::{PageTable64, PagingMetaData};
 use page_table_entry::riscv::Rv64PTE;
 
+use super::HostCapabilities;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
 pub struct Sv39GuestMetaData;
 
 impl PagingMetaData for Sv39GuestMetaData {
@@ -10,5 +13,84 @@ impl PagingMetaData for Sv39GuestMetaData {
     const VA_MAX_BITS: usize = 41;
 }
 
+pub struct Sv48GuestMetaData;
+
+impl PagingMetaData for Sv48GuestMetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 50;
+}
+
+pub struct Sv57GuestMetaData;
+
+impl PagingMetaData for Sv57GuestMetaData {
+    const LEVELS: usize = 5;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 59;
+}
+
 /// Nested page table define.
 pub type NestedPageTable<I> = PageTable64<Sv39GuestMetaData, Rv64PTE, I>;
+/// Nested page table with 4 levels, for guest physical address spaces up to 1 PiB.
+pub type NestedPageTableSv48<I> = PageTable64<Sv48GuestMetaData, Rv64PTE, I>;
+/// Nested page table with 5 levels, for guest physical address spaces up to 512 PiB.
+pub type NestedPageTableSv57<I> = PageTable64<Sv57GuestMetaData, Rv64PTE, I>;
+
+/// The `MODE` field of `hgatp`.
+const HGATP_MODE_SHIFT: usize = 60;
+
+/// A second-stage translation mode, which bounds the guest physical address space of a VM. The
+/// guest page table of a VM must be of the matching type, e.g. `NestedPageTableSv48` for Sv48x4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GuestPagingMode {
+    Sv39x4,
+    Sv48x4,
+    Sv57x4,
+}
+
+impl GuestPagingMode {
+    const ALL: [Self; 3] = [Self::Sv39x4, Self::Sv48x4, Self::Sv57x4];
+
+    /// Number of bits of the guest physical addresses the mode translates.
+    pub fn gpa_bits(self) -> usize {
+        match self {
+            Self::Sv39x4 => Sv39GuestMetaData::VA_MAX_BITS,
+            Self::Sv48x4 => Sv48GuestMetaData::VA_MAX_BITS,
+            Self::Sv57x4 => Sv57GuestMetaData::VA_MAX_BITS,
+        }
+    }
+
+    /// The value of `hgatp.MODE` selecting the mode.
+    pub fn hgatp_mode(self) -> usize {
+        match self {
+            Self::Sv39x4 => 8,
+            Self::Sv48x4 => 9,
+            Self::Sv57x4 => 10,
+        }
+    }
+
+    /// The mode an `hgatp` value, e.g. a guest page table token, selects.
+    pub fn from_hgatp(hgatp: usize) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.hgatp_mode() == hgatp >> HGATP_MODE_SHIFT)
+    }
+
+    /// Whether the host supports the mode.
+    pub fn supported(self, caps: &HostCapabilities) -> bool {
+        match self {
+            Self::Sv39x4 => caps.sv39x4,
+            Self::Sv48x4 => caps.sv48x4,
+            Self::Sv57x4 => caps.sv57x4,
+        }
+    }
+
+    /// The mode with the fewest levels that translates guest physical addresses up to `gpa_end`
+    /// (exclusive) and that the host supports. Fails with `OutOfRange` if none is large enough.
+    pub fn select(gpa_end: GuestPhysAddr, caps: &HostCapabilities) -> HyperResult<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| gpa_end <= 1 << mode.gpa_bits() && mode.supported(caps))
+            .ok_or(HyperError::OutOfRange)
+    }
+}
@@ -0,0 +1,151 @@
+//! Statistics and tracing of vCPU exits.
+//!
+//! Every exit of a vCPU is counted by its reason, along with the time the hypervisor spent
+//! handling it until the vCPU was entered again, so users can tell what a slow guest keeps trapping
+//! on. A ring buffer of the most recent exits can be enabled on top.
+use alloc::vec::Vec;
+
+use crate::{GuestVirtAddr, VmExitInfo};
+
+/// Category of a vCPU exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    Ecall,
+    PageFault,
+    VirtualInstruction,
+    HostInterrupt,
+    TimerInterrupt,
+    ExternalInterrupt,
+    Debug,
+    /// Traps the hypervisor has no handler for, which are fatal.
+    Unhandled,
+}
+
+impl ExitReason {
+    /// Number of exit reasons.
+    pub const COUNT: usize = 8;
+
+    /// All exit reasons.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::Ecall,
+        Self::PageFault,
+        Self::VirtualInstruction,
+        Self::HostInterrupt,
+        Self::TimerInterrupt,
+        Self::ExternalInterrupt,
+        Self::Debug,
+        Self::Unhandled,
+    ];
+}
+
+impl From<&VmExitInfo> for ExitReason {
+    fn from(info: &VmExitInfo) -> Self {
+        match info {
+            VmExitInfo::Ecall(_) => Self::Ecall,
+            VmExitInfo::PageFault { .. } => Self::PageFault,
+            VmExitInfo::VirtualInstruction { .. } => Self::VirtualInstruction,
+            VmExitInfo::HostInterruot(_) => Self::HostInterrupt,
+            VmExitInfo::TimerInterruptEmulation => Self::TimerInterrupt,
+            VmExitInfo::ExternalInterruptEmulation => Self::ExternalInterrupt,
+            VmExitInfo::DebugEvent { .. } => Self::Debug,
+            VmExitInfo::UnhandledTrap { .. } => Self::Unhandled,
+        }
+    }
+}
+
+/// Exit counts and handling times of a vCPU, per exit reason.
+#[derive(Clone, Debug, Default)]
+pub struct ExitStats {
+    counts: [u64; ExitReason::COUNT],
+    handling_times: [u64; ExitReason::COUNT],
+}
+
+impl ExitStats {
+    /// Number of exits for `reason`.
+    pub fn count(&self, reason: ExitReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    /// Time spent handling the exits for `reason` until the vCPU was entered again, in ticks of
+    /// the `time` CSR.
+    pub fn handling_time(&self, reason: ExitReason) -> u64 {
+        self.handling_times[reason as usize]
+    }
+
+    /// Number of exits for all reasons.
+    pub fn total_count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// An exit recorded in the trace.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitTraceEntry {
+    /// Value of the `time` CSR when the vCPU exited.
+    pub time: u64,
+    pub reason: ExitReason,
+    /// Guest PC the vCPU exited at.
+    pub pc: GuestVirtAddr,
+}
+
+/// Records the exits of a vCPU.
+#[derive(Default)]
+pub(crate) struct ExitRecorder {
+    stats: ExitStats,
+    /// Reason and time of the exit being handled.
+    handling: Option<(ExitReason, u64)>,
+    trace: Vec<ExitTraceEntry>,
+    trace_capacity: usize,
+    /// Index in `trace` the next exit is recorded at.
+    trace_next: usize,
+}
+
+impl ExitRecorder {
+    /// Accounts the handling of the last exit, the vCPU being entered again at `now`.
+    pub fn on_entry(&mut self, now: u64) {
+        if let Some((reason, exited)) = self.handling.take() {
+            self.stats.handling_times[reason as usize] += now.saturating_sub(exited);
+        }
+    }
+
+    /// Records an exit for `reason` at `pc` and `now`.
+    pub fn on_exit(&mut self, reason: ExitReason, pc: GuestVirtAddr, now: u64) {
+        self.stats.counts[reason as usize] += 1;
+        self.handling = Some((reason, now));
+        if self.trace_capacity == 0 {
+            return;
+        }
+        let entry = ExitTraceEntry {
+            time: now,
+            reason,
+            pc,
+        };
+        if self.trace.len() < self.trace_capacity {
+            self.trace.push(entry);
+        } else {
+            self.trace[self.trace_next] = entry;
+        }
+        self.trace_next = (self.trace_next + 1) % self.trace_capacity;
+    }
+
+    pub fn stats(&self) -> &ExitStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = ExitStats::default();
+    }
+
+    /// Keeps the last `capacity` exits in the trace, disabling it if 0. Clears the trace.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.trace = Vec::with_capacity(capacity);
+        self.trace_capacity = capacity;
+        self.trace_next = 0;
+    }
+
+    /// The exits in the trace, oldest first.
+    pub fn trace(&self) -> Vec<ExitTraceEntry> {
+        let (newest, oldest) = self.trace.split_at(self.trace_next);
+        oldest.iter().chain(newest).copied().collect()
+    }
+}
@@ -0,0 +1,332 @@
+//! Lazy switching of the floating-point and vector state between the host and vCPUs.
+//!
+//! The hypervisor doesn't use the FP and vector registers, so a loaded vCPU keeps its state in the
+//! hart's registers across VM exits. The state is only switched with the host's when the vCPU is
+//! activated and deactivated, and the guest's is only saved if `sstatus.FS` or `sstatus.VS` shows
+//! the guest wrote it since it was loaded. Guests without the extension exposed see it off.
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use super::isa::IsaExtensions;
+use crate::snapshot::{SectionBuilder, SectionReader, SnapshotReader};
+use crate::{HyperError, HyperResult};
+
+/// `sstatus.FS` and `sstatus.VS`, the state of the FP and vector registers.
+const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_VS: usize = 0b11 << 9;
+/// Values of the fields, in their lowest bits.
+const STATE_OFF: usize = 0;
+const STATE_INITIAL: usize = 1;
+const STATE_CLEAN: usize = 2;
+const STATE_DIRTY: usize = 3;
+
+/// Reads the state in `field` of `sstatus`.
+fn state(sstatus: usize, field: usize) -> usize {
+    (sstatus & field) / (field & field.wrapping_neg())
+}
+
+/// Sets the state in `field` of `sstatus` to `state`.
+fn set_state(sstatus: &mut usize, field: usize, state: usize) {
+    *sstatus = (*sstatus & !field) | state * (field & field.wrapping_neg());
+}
+
+/// The F and D registers.
+#[derive(Default)]
+#[repr(C)]
+struct FpRegs {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl FpRegs {
+    /// Saves the hart's registers. `sstatus.FS` must not be off.
+    unsafe fn save(&mut self) {
+        asm!(
+            "fsd f0, 0*8({0})", "fsd f1, 1*8({0})", "fsd f2, 2*8({0})", "fsd f3, 3*8({0})",
+            "fsd f4, 4*8({0})", "fsd f5, 5*8({0})", "fsd f6, 6*8({0})", "fsd f7, 7*8({0})",
+            "fsd f8, 8*8({0})", "fsd f9, 9*8({0})", "fsd f10, 10*8({0})", "fsd f11, 11*8({0})",
+            "fsd f12, 12*8({0})", "fsd f13, 13*8({0})", "fsd f14, 14*8({0})", "fsd f15, 15*8({0})",
+            "fsd f16, 16*8({0})", "fsd f17, 17*8({0})", "fsd f18, 18*8({0})", "fsd f19, 19*8({0})",
+            "fsd f20, 20*8({0})", "fsd f21, 21*8({0})", "fsd f22, 22*8({0})", "fsd f23, 23*8({0})",
+            "fsd f24, 24*8({0})", "fsd f25, 25*8({0})", "fsd f26, 26*8({0})", "fsd f27, 27*8({0})",
+            "fsd f28, 28*8({0})", "fsd f29, 29*8({0})", "fsd f30, 30*8({0})", "fsd f31, 31*8({0})",
+            "frcsr {1}",
+            in(reg) self.f.as_mut_ptr(),
+            out(reg) self.fcsr,
+        );
+    }
+
+    /// Loads the registers into the hart. `sstatus.FS` must not be off.
+    unsafe fn restore(&self) {
+        asm!(
+            "fld f0, 0*8({0})", "fld f1, 1*8({0})", "fld f2, 2*8({0})", "fld f3, 3*8({0})",
+            "fld f4, 4*8({0})", "fld f5, 5*8({0})", "fld f6, 6*8({0})", "fld f7, 7*8({0})",
+            "fld f8, 8*8({0})", "fld f9, 9*8({0})", "fld f10, 10*8({0})", "fld f11, 11*8({0})",
+            "fld f12, 12*8({0})", "fld f13, 13*8({0})", "fld f14, 14*8({0})", "fld f15, 15*8({0})",
+            "fld f16, 16*8({0})", "fld f17, 17*8({0})", "fld f18, 18*8({0})", "fld f19, 19*8({0})",
+            "fld f20, 20*8({0})", "fld f21, 21*8({0})", "fld f22, 22*8({0})", "fld f23, 23*8({0})",
+            "fld f24, 24*8({0})", "fld f25, 25*8({0})", "fld f26, 26*8({0})", "fld f27, 27*8({0})",
+            "fld f28, 28*8({0})", "fld f29, 29*8({0})", "fld f30, 30*8({0})", "fld f31, 31*8({0})",
+            "fscsr {1}",
+            in(reg) self.f.as_ptr(),
+            in(reg) self.fcsr,
+        );
+    }
+}
+
+/// The V registers and CSRs.
+#[derive(Default)]
+struct VectorRegs {
+    /// `v0` to `v31`, allocated on first save.
+    v: Vec<u8>,
+    vstart: usize,
+    vl: usize,
+    vtype: usize,
+    vcsr: usize,
+}
+
+impl VectorRegs {
+    /// Saves the hart's registers. `sstatus.VS` must not be off.
+    unsafe fn save(&mut self) {
+        let vlenb: usize;
+        asm!("csrr {}, vlenb", out(reg) vlenb);
+        self.v.resize(32 * vlenb, 0);
+        asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrr {vstart}, vstart",
+            "csrr {vl}, vl",
+            "csrr {vtype}, vtype",
+            "csrr {vcsr}, vcsr",
+            // Whole register stores ignore `vl`, but the saved `vtype` may be illegal.
+            "vsetvli {tmp}, x0, e8, m8, ta, ma",
+            "vs8r.v v0, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vs8r.v v8, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vs8r.v v16, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vs8r.v v24, ({ptr})",
+            ".option pop",
+            vstart = out(reg) self.vstart,
+            vl = out(reg) self.vl,
+            vtype = out(reg) self.vtype,
+            vcsr = out(reg) self.vcsr,
+            ptr = inout(reg) self.v.as_mut_ptr() => _,
+            group = in(reg) 8 * vlenb,
+            tmp = out(reg) _,
+        );
+    }
+
+    /// Loads the registers into the hart. `sstatus.VS` must not be off.
+    unsafe fn restore(&self) {
+        if self.v.is_empty() {
+            return;
+        }
+        asm!(
+            ".option push",
+            ".option arch, +v",
+            "vsetvli {tmp}, x0, e8, m8, ta, ma",
+            "vl8r.v v0, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vl8r.v v8, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vl8r.v v16, ({ptr})",
+            "add {ptr}, {ptr}, {group}",
+            "vl8r.v v24, ({ptr})",
+            "vsetvl x0, {vl}, {vtype}",
+            "csrw vstart, {vstart}",
+            "csrw vcsr, {vcsr}",
+            ".option pop",
+            ptr = inout(reg) self.v.as_ptr() => _,
+            group = in(reg) self.v.len() / 4,
+            vl = in(reg) self.vl,
+            vtype = in(reg) self.vtype,
+            vstart = in(reg) self.vstart,
+            vcsr = in(reg) self.vcsr,
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// The FP and vector state of a vCPU, and of the host while the vCPU is loaded.
+#[derive(Default)]
+pub(crate) struct FpContext {
+    host_fp: FpRegs,
+    guest_fp: FpRegs,
+    host_vector: VectorRegs,
+    guest_vector: VectorRegs,
+}
+
+impl FpContext {
+    /// Turns the guest's FP and vector state on or off in its `sstatus` as per `isa`. Registers
+    /// turned on start zeroed.
+    pub fn configure(&mut self, sstatus: &mut usize, isa: &IsaExtensions) {
+        for (field, exposed) in [(SSTATUS_FS, isa.fpu), (SSTATUS_VS, isa.vector)] {
+            match (state(*sstatus, field), exposed) {
+                (STATE_OFF, true) => set_state(sstatus, field, STATE_INITIAL),
+                (_, false) => set_state(sstatus, field, STATE_OFF),
+                _ => {}
+            }
+        }
+    }
+
+    /// Saves the host's FP and vector registers and loads the guest's, marking them clean in the
+    /// guest's `sstatus`. Only the registers the guest has on are switched. Called when the vCPU
+    /// is activated.
+    pub fn load(&mut self, sstatus: &mut usize) {
+        let guest_fp = state(*sstatus, SSTATUS_FS) != STATE_OFF;
+        let guest_vector = state(*sstatus, SSTATUS_VS) != STATE_OFF;
+        with_state_on(guest_fp, guest_vector, || unsafe {
+            if guest_fp {
+                self.host_fp.save();
+                self.guest_fp.restore();
+                set_state(sstatus, SSTATUS_FS, STATE_CLEAN);
+            }
+            if guest_vector {
+                self.host_vector.save();
+                self.guest_vector.restore();
+                set_state(sstatus, SSTATUS_VS, STATE_CLEAN);
+            }
+        });
+    }
+
+    /// Saves the guest's FP and vector registers if its `sstatus` shows it wrote them, and loads
+    /// the host's back. Called when the vCPU is deactivated.
+    pub fn put(&mut self, sstatus: &mut usize) {
+        let guest_fp = state(*sstatus, SSTATUS_FS) != STATE_OFF;
+        let guest_vector = state(*sstatus, SSTATUS_VS) != STATE_OFF;
+        with_state_on(guest_fp, guest_vector, || unsafe {
+            if guest_fp {
+                if state(*sstatus, SSTATUS_FS) == STATE_DIRTY {
+                    self.guest_fp.save();
+                    set_state(sstatus, SSTATUS_FS, STATE_CLEAN);
+                }
+                self.host_fp.restore();
+            }
+            if guest_vector {
+                if state(*sstatus, SSTATUS_VS) == STATE_DIRTY {
+                    self.guest_vector.save();
+                    set_state(sstatus, SSTATUS_VS, STATE_CLEAN);
+                }
+                self.host_vector.restore();
+            }
+        });
+    }
+
+    /// Saves the guest's FP and vector registers if its `sstatus` shows it wrote them, leaving
+    /// them loaded. Called while the vCPU is loaded, to read its state.
+    pub fn sync(&mut self, sstatus: &mut usize) {
+        let guest_fp = state(*sstatus, SSTATUS_FS) == STATE_DIRTY;
+        let guest_vector = state(*sstatus, SSTATUS_VS) == STATE_DIRTY;
+        with_state_on(guest_fp, guest_vector, || unsafe {
+            if guest_fp {
+                self.guest_fp.save();
+                set_state(sstatus, SSTATUS_FS, STATE_CLEAN);
+            }
+            if guest_vector {
+                self.guest_vector.save();
+                set_state(sstatus, SSTATUS_VS, STATE_CLEAN);
+            }
+        });
+    }
+
+    /// Loads the guest's FP and vector registers into the hart again, marking them clean in the
+    /// guest's `sstatus`. Called while the vCPU is loaded, after its state was replaced.
+    pub fn reload(&self, sstatus: &mut usize) {
+        let guest_fp = state(*sstatus, SSTATUS_FS) != STATE_OFF;
+        let guest_vector = state(*sstatus, SSTATUS_VS) != STATE_OFF;
+        with_state_on(guest_fp, guest_vector, || unsafe {
+            if guest_fp {
+                self.guest_fp.restore();
+                set_state(sstatus, SSTATUS_FS, STATE_CLEAN);
+            }
+            if guest_vector {
+                self.guest_vector.restore();
+                set_state(sstatus, SSTATUS_VS, STATE_CLEAN);
+            }
+        });
+    }
+
+    /// Appends the guest's FP and vector registers, as last saved, to a snapshot section. The
+    /// vector registers are led by their size, zero if the guest never wrote them.
+    pub fn save_state(&self, payload: &mut SectionBuilder) {
+        let fp = &self.guest_fp;
+        for f in fp.f {
+            payload.put_u64(f);
+        }
+        payload.put_u64(fp.fcsr as u64);
+        let vector = &self.guest_vector;
+        for val in [
+            vector.vstart,
+            vector.vl,
+            vector.vtype,
+            vector.vcsr,
+            vector.v.len(),
+        ] {
+            payload.put_u64(val as u64);
+        }
+        payload.put_bytes(&vector.v);
+    }
+
+    /// Restores the registers saved by `save_state`. Fails with `InvalidParam` if the snapshot has
+    /// vector registers while `isa` doesn't expose the vector extension, or of another length than
+    /// the hart's.
+    pub fn restore_state<R: SnapshotReader>(
+        &mut self,
+        section: &mut SectionReader<'_, '_, R>,
+        isa: &IsaExtensions,
+    ) -> HyperResult<()> {
+        let mut fp = FpRegs::default();
+        for f in fp.f.iter_mut() {
+            *f = section.read_u64()?;
+        }
+        fp.fcsr = section.read_u64()? as usize;
+        let mut vector = VectorRegs::default();
+        vector.vstart = section.read_u64()? as usize;
+        vector.vl = section.read_u64()? as usize;
+        vector.vtype = section.read_u64()? as usize;
+        vector.vcsr = section.read_u64()? as usize;
+        let len = section.read_u64()? as usize;
+        if len != 0 && (!isa.vector || len != 32 * vlenb()) {
+            return Err(HyperError::InvalidParam);
+        }
+        vector.v.resize(len, 0);
+        section.read(&mut vector.v)?;
+        self.guest_fp = fp;
+        self.guest_vector = vector;
+        Ok(())
+    }
+}
+
+/// Size in bytes of a vector register of the hart, which must have the vector extension.
+fn vlenb() -> usize {
+    let mut vlenb = 0;
+    with_state_on(false, true, || unsafe {
+        asm!("csrr {}, vlenb", out(reg) vlenb)
+    });
+    vlenb
+}
+
+/// Runs `f` with the hypervisor's `sstatus.FS` and `sstatus.VS` on as requested, so it can access
+/// the registers, then restores them.
+fn with_state_on(fp: bool, vector: bool, f: impl FnOnce()) {
+    let mut on = 0;
+    if fp {
+        set_state(&mut on, SSTATUS_FS, STATE_DIRTY);
+    }
+    if vector {
+        set_state(&mut on, SSTATUS_VS, STATE_DIRTY);
+    }
+    let old: usize;
+    unsafe { asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) on) };
+    f();
+    unsafe {
+        asm!(
+            "csrc sstatus, {}",
+            "csrs sstatus, {}",
+            in(reg) SSTATUS_FS | SSTATUS_VS,
+            in(reg) old & (SSTATUS_FS | SSTATUS_VS),
+        )
+    };
+}
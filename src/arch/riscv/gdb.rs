@@ -0,0 +1,348 @@
+//! GDB remote serial protocol stub, through which gdb debugs a guest.
+//!
+//! The stub serves a debugger connected over a byte stream the host provides, e.g. a serial port or
+//! a TCP connection, while a vCPU of the VM is stopped. It reads and writes the vCPU's general
+//! purpose registers and pc, reads and writes guest memory through the vCPU's page table, inserts
+//! and removes software breakpoints, and resumes or single-steps the vCPU.
+//!
+//! The host calls `GdbStub::handle_stop` when the debugger attaches and each time the vCPU stops
+//! on a `DebugEvent` reported to `VcpuScheduler::on_debug_event`, and runs the vCPU again when the
+//! debugger resumes it. The debugger can't interrupt a running vCPU.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::debug::DebugEvent;
+use super::regs::GprIndex;
+use super::VM;
+use crate::{GuestPageTableTrait, HyperCraftHal, HyperError, HyperResult, VCpu};
+
+/// Largest packet the debugger may send, as advertised to it.
+const PACKET_SIZE: usize = 0x1000;
+
+/// Registers described to the debugger: x0 to x31, then pc.
+const NUM_REGS: usize = 33;
+const PC_REG: usize = 32;
+
+/// ABI names of x0 to x31, as gdb expects them in the target description.
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Stop reply of a vCPU stopped by a breakpoint or step.
+const STOP_REPLY: &[u8] = b"S05";
+
+/// What the debugger does with a stopped vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GdbAction {
+    /// Run the vCPU until its next `DebugEvent`.
+    Resume,
+    /// The debugger detached, removing its breakpoints and taking the VM out of debug mode. The
+    /// vCPU may run freely.
+    Detach,
+    /// The debugger asked for the VM to be killed.
+    Kill,
+}
+
+/// Byte stream between the stub and the debugger.
+pub trait GdbConnection {
+    /// Reads the next byte from the debugger, waiting for it.
+    fn read_byte(&mut self) -> HyperResult<u8>;
+
+    /// Writes `buf` to the debugger.
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult<()>;
+}
+
+/// A GDB remote serial protocol stub serving a debugger over `C`.
+pub struct GdbStub<C: GdbConnection> {
+    conn: C,
+    /// Payload of the last packet received.
+    packet: Vec<u8>,
+}
+
+impl<C: GdbConnection> GdbStub<C> {
+    /// Creates a stub serving the debugger connected over `conn`.
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            packet: Vec::new(),
+        }
+    }
+
+    /// Serves the debugger while the vCPU `vcpu_id` of `vm` is stopped, until it resumes the vCPU
+    /// or ends the session. `event` is why the vCPU stopped after the debugger last resumed it,
+    /// `None` when the debugger attaches. Puts the VM in debug mode.
+    pub fn handle_stop<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &mut self,
+        vm: &mut VM<H, G>,
+        vcpu_id: usize,
+        event: Option<DebugEvent>,
+    ) -> HyperResult<GdbAction> {
+        vm.set_debug(true)?;
+        if event.is_some() {
+            self.send(STOP_REPLY)?;
+        }
+        loop {
+            self.receive()?;
+            let mut reply = Vec::new();
+            let action = match execute(vm, vcpu_id, &self.packet, &mut reply) {
+                Ok(action) => action,
+                Err(_) => {
+                    reply = b"E01".to_vec();
+                    None
+                }
+            };
+            match action {
+                None => self.send(&reply)?,
+                Some(GdbAction::Detach) => {
+                    self.send(&reply)?;
+                    return Ok(GdbAction::Detach);
+                }
+                // The stop reply follows once the vCPU stops again.
+                Some(action) => return Ok(action),
+            }
+        }
+    }
+
+    /// Receives the next packet into `self.packet`, acknowledging it.
+    fn receive(&mut self) -> HyperResult<()> {
+        loop {
+            // Skips acknowledgements and interrupt requests.
+            while self.conn.read_byte()? != b'$' {}
+            self.packet.clear();
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.conn.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                self.packet.push(byte);
+            }
+            let sent = [self.conn.read_byte()?, self.conn.read_byte()?];
+            if parse_hex(&sent) == Ok(checksum as usize) {
+                return self.conn.write_all(b"+");
+            }
+            // Asks for the packet again.
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet with `payload`, until the debugger acknowledges it.
+    fn send(&mut self, payload: &[u8]) -> HyperResult<()> {
+        let checksum = payload
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut packet = Vec::with_capacity(payload.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(payload);
+        packet.push(b'#');
+        push_hex(&mut packet, &[checksum]);
+        loop {
+            self.conn.write_all(&packet)?;
+            loop {
+                match self.conn.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Executes the command `packet` of the debugger on the vCPU `vcpu_id` of `vm`, putting the reply
+/// in `reply`. Returns the action ending the stop, if the command resumes the vCPU or ends the
+/// session. Unsupported commands get an empty reply.
+fn execute<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vm: &mut VM<H, G>,
+    vcpu_id: usize,
+    packet: &[u8],
+    reply: &mut Vec<u8>,
+) -> HyperResult<Option<GdbAction>> {
+    let Some((&command, args)) = packet.split_first() else {
+        return Ok(None);
+    };
+    match command {
+        b'?' => reply.extend_from_slice(STOP_REPLY),
+        b'g' => {
+            let vcpu = vm.vcpu(vcpu_id)?;
+            for index in 0..NUM_REGS {
+                push_hex(reply, &read_reg(vcpu, index).to_le_bytes());
+            }
+        }
+        b'G' => {
+            let values = decode_hex(args)?;
+            let vcpu = vm.vcpu(vcpu_id)?;
+            for (index, value) in values.chunks_exact(8).take(NUM_REGS).enumerate() {
+                write_reg(vcpu, index, usize::from_le_bytes(value.try_into().unwrap()));
+            }
+            reply.extend_from_slice(b"OK");
+        }
+        b'p' => {
+            let index = parse_hex(args)?;
+            if index >= NUM_REGS {
+                return Err(HyperError::InvalidParam);
+            }
+            push_hex(reply, &read_reg(vm.vcpu(vcpu_id)?, index).to_le_bytes());
+        }
+        b'P' => {
+            let (index, value) = split(args, b'=')?;
+            let index = parse_hex(index)?;
+            let value = decode_hex(value)?;
+            if index >= NUM_REGS || value.len() != 8 {
+                return Err(HyperError::InvalidParam);
+            }
+            let value = usize::from_le_bytes(value.try_into().unwrap());
+            write_reg(vm.vcpu(vcpu_id)?, index, value);
+            reply.extend_from_slice(b"OK");
+        }
+        b'm' => {
+            let (addr, len) = split(args, b',')?;
+            // Replies are sent in hex.
+            let len = core::cmp::min(parse_hex(len)?, PACKET_SIZE / 2);
+            let mut buf = vec![0u8; len];
+            vm.read_guest_virt(vcpu_id, parse_hex(addr)?, &mut buf)?;
+            push_hex(reply, &buf);
+        }
+        b'M' => {
+            let (range, data) = split(args, b':')?;
+            let (addr, len) = split(range, b',')?;
+            let data = decode_hex(data)?;
+            if data.len() != parse_hex(len)? {
+                return Err(HyperError::InvalidParam);
+            }
+            vm.write_guest_virt(vcpu_id, parse_hex(addr)?, &data)?;
+            reply.extend_from_slice(b"OK");
+        }
+        b'Z' | b'z' => {
+            let (kind, rest) = split(args, b',')?;
+            // Only software breakpoints are supported.
+            if kind != b"0" {
+                return Ok(None);
+            }
+            let (addr, _) = split(rest, b',')?;
+            let addr = parse_hex(addr)?;
+            if command == b'Z' {
+                vm.insert_breakpoint(vcpu_id, addr)?;
+            } else {
+                vm.remove_breakpoint(addr)?;
+            }
+            reply.extend_from_slice(b"OK");
+        }
+        b'c' | b's' => {
+            if !args.is_empty() {
+                vm.vcpu(vcpu_id)?.set_pc(parse_hex(args)?);
+            }
+            if command == b's' {
+                vm.single_step(vcpu_id)?;
+            }
+            return Ok(Some(GdbAction::Resume));
+        }
+        b'D' => {
+            vm.set_debug(false)?;
+            reply.extend_from_slice(b"OK");
+            return Ok(Some(GdbAction::Detach));
+        }
+        b'k' => return Ok(Some(GdbAction::Kill)),
+        // There's a single thread, the vCPU.
+        b'H' => reply.extend_from_slice(b"OK"),
+        b'q' => query(args, reply)?,
+        _ => {}
+    }
+    Ok(None)
+}
+
+/// Answers the general query `query`.
+fn query(query: &[u8], reply: &mut Vec<u8>) -> HyperResult<()> {
+    if query.starts_with(b"Supported") {
+        let features = alloc::format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        reply.extend_from_slice(features.as_bytes());
+    } else if query == b"Attached" {
+        // Detaching leaves the VM running rather than killing it.
+        reply.push(b'1');
+    } else if let Some(range) = query.strip_prefix(b"Xfer:features:read:target.xml:") {
+        let (offset, len) = split(range, b',')?;
+        let (offset, len) = (parse_hex(offset)?, parse_hex(len)?);
+        let xml = target_xml();
+        let xml = xml.as_bytes();
+        let start = core::cmp::min(offset, xml.len());
+        let end = core::cmp::min(start.saturating_add(len), xml.len());
+        reply.push(if end < xml.len() { b'm' } else { b'l' });
+        reply.extend_from_slice(&xml[start..end]);
+    }
+    Ok(())
+}
+
+/// Describes the registers to the debugger, in the order of the `g` packet.
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+        r#"<target version="1.0"><architecture>riscv:rv64</architecture>"#,
+        r#"<feature name="org.gnu.gdb.riscv.cpu">"#,
+    ));
+    for name in GPR_NAMES {
+        let reg_type = if name == "sp" { "data_ptr" } else { "int" };
+        let _ = write!(
+            xml,
+            r#"<reg name="{name}" bitsize="64" type="{reg_type}"/>"#
+        );
+    }
+    xml.push_str(r#"<reg name="pc" bitsize="64" type="code_ptr"/></feature></target>"#);
+    xml
+}
+
+/// Reads the register `index` of the debugger's numbering.
+fn read_reg<H: HyperCraftHal>(vcpu: &VCpu<H>, index: usize) -> usize {
+    match index {
+        PC_REG => vcpu.pc(),
+        _ => vcpu.get_gpr(GprIndex::from_raw(index as u32).unwrap()),
+    }
+}
+
+/// Writes the register `index` of the debugger's numbering.
+fn write_reg<H: HyperCraftHal>(vcpu: &mut VCpu<H>, index: usize, value: usize) {
+    match index {
+        // x0 is hardwired to zero.
+        0 => {}
+        PC_REG => vcpu.set_pc(value),
+        _ => vcpu.set_gpr(GprIndex::from_raw(index as u32).unwrap(), value),
+    }
+}
+
+/// Splits `args` at the first `separator`.
+fn split(args: &[u8], separator: u8) -> HyperResult<(&[u8], &[u8])> {
+    let at = args
+        .iter()
+        .position(|&byte| byte == separator)
+        .ok_or(HyperError::InvalidParam)?;
+    Ok((&args[..at], &args[at + 1..]))
+}
+
+/// Parses the hex number `hex`.
+fn parse_hex(hex: &[u8]) -> HyperResult<usize> {
+    let hex = core::str::from_utf8(hex).map_err(|_| HyperError::InvalidParam)?;
+    usize::from_str_radix(hex, 16).map_err(|_| HyperError::InvalidParam)
+}
+
+/// Decodes the bytes encoded in hex in `hex`.
+fn decode_hex(hex: &[u8]) -> HyperResult<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    hex.chunks(2)
+        .map(|byte| parse_hex(byte).map(|byte| byte as u8))
+        .collect()
+}
+
+/// Appends `bytes` encoded in hex to `out`.
+fn push_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &byte in bytes {
+        out.push(DIGITS[(byte >> 4) as usize]);
+        out.push(DIGITS[(byte & 0xf) as usize]);
+    }
+}
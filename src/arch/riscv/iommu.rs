@@ -0,0 +1,354 @@
+//! RISC-V IOMMU (riscv-iommu) support for passing DMA-capable devices through to guests.
+//!
+//! A passthrough device shares the G-stage page table of the VM it is attached to: its device
+//! context is programmed with the VM's `hgatp` token as `iohgatp`, so DMA addresses issued by the
+//! device are translated exactly like the guest's own physical addresses and can never reach
+//! memory that isn't mapped into the guest. The VMID in `iohgatp` is the GSCID the IOMMU tags the
+//! cached translations with, which the VM invalidates whenever it unmaps or restricts guest RAM.
+//!
+//! ref: The RISC-V IOMMU Architecture Specification, v1.0
+use core::sync::atomic::{fence, Ordering};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    logging::LogContext, memory::PAGE_SIZE_4K, HostVirtAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+// Offsets of the IOMMU registers in the memory-mapped register file.
+const IOMMU_CAPABILITIES: usize = 0x00;
+const IOMMU_DDTP: usize = 0x10;
+const IOMMU_CQB: usize = 0x18;
+const IOMMU_CQH: usize = 0x20;
+const IOMMU_CQT: usize = 0x24;
+const IOMMU_CQCSR: usize = 0x48;
+
+// `capabilities` register fields.
+const CAP_SV39X4: u64 = 1 << 17;
+const CAP_MSI_FLAT: u64 = 1 << 22;
+
+// `ddtp` register fields.
+const DDTP_MODE_MASK: u64 = 0xf;
+const DDTP_MODE_OFF: u64 = 0;
+const DDTP_MODE_3LVL: u64 = 4;
+const DDTP_BUSY: u64 = 1 << 4;
+
+// `cqcsr` register fields.
+const CQCSR_CQEN: u32 = 1 << 0;
+const CQCSR_CQMF: u32 = 1 << 8;
+const CQCSR_CMD_TO: u32 = 1 << 9;
+const CQCSR_CMD_ILL: u32 = 1 << 10;
+const CQCSR_CQON: u32 = 1 << 16;
+const CQCSR_BUSY: u32 = 1 << 17;
+
+// Command opcodes and their `func3` values.
+const CMD_IOTINVAL: u64 = 1;
+const CMD_IOTINVAL_GVMA: u64 = 1;
+const CMD_IOFENCE: u64 = 2;
+const CMD_IOFENCE_C: u64 = 0;
+const CMD_IODIR: u64 = 3;
+const CMD_IODIR_INVAL_DDT: u64 = 0;
+
+/// Number of 16-byte commands in the command queue, which occupies exactly one page.
+const CQ_ENTRIES: usize = PAGE_SIZE_4K / 16;
+
+/// Device IDs are at most 24 bits wide.
+const DEVICE_ID_BITS: u32 = 24;
+
+/// Valid bit of both non-leaf DDT entries and `DC.tc`.
+const DDT_VALID: u64 = 1;
+
+/// The IOMMU instance of this platform, set up by `init_iommu`.
+pub(crate) static IOMMU: Once<Mutex<Iommu>> = Once::new();
+
+/// Initializes the IOMMU whose register file is mapped at `base`. Must be called once before any
+/// device is attached to a VM.
+pub fn init_iommu<H: HyperCraftHal>(base: HostVirtAddr) -> HyperResult<()> {
+    if IOMMU.get().is_some() {
+        return Err(HyperError::BadState);
+    }
+    let iommu = Iommu::new::<H>(base)?;
+    IOMMU.call_once(|| Mutex::new(iommu));
+    Ok(())
+}
+
+/// A memory-mapped riscv-iommu with a 3-level device directory table and a command queue.
+pub struct Iommu {
+    base: HostVirtAddr,
+    /// Extended (64-byte) device contexts are used if the IOMMU supports MSI flat translation.
+    extended_dc: bool,
+    /// Host virtual address of the root page of the device directory table.
+    ddt_root: HostVirtAddr,
+    /// Host virtual address of the command queue.
+    cq_base: HostVirtAddr,
+    cq_tail: u32,
+}
+
+impl Iommu {
+    fn new<H: HyperCraftHal>(base: HostVirtAddr) -> HyperResult<Self> {
+        let mut iommu = Self {
+            base,
+            extended_dc: false,
+            ddt_root: 0,
+            cq_base: 0,
+            cq_tail: 0,
+        };
+        let caps = iommu.read_u64(IOMMU_CAPABILITIES);
+        if caps & CAP_SV39X4 == 0 {
+            hv_log!(
+                Error,
+                Iommu,
+                LogContext::NONE,
+                "IOMMU doesn't support Sv39x4, capabilities: {:#x}",
+                caps
+            );
+            return Err(HyperError::NotSupported);
+        }
+        iommu.extended_dc = caps & CAP_MSI_FLAT != 0;
+
+        // Turn translation off while the tables are built.
+        iommu.write_u64(IOMMU_DDTP, DDTP_MODE_OFF);
+        iommu.wait_ddtp_idle();
+
+        // Command queue.
+        iommu.cq_base = alloc_zeroed_page::<H>()?;
+        let cq_ppn = (H::virt_to_phys(iommu.cq_base) / PAGE_SIZE_4K) as u64;
+        let log2sz = CQ_ENTRIES.trailing_zeros() as u64;
+        iommu.write_u64(IOMMU_CQB, cq_ppn << 10 | (log2sz - 1));
+        iommu.write_u32(IOMMU_CQT, 0);
+        iommu.write_u32(IOMMU_CQCSR, CQCSR_CQEN);
+        while iommu.read_u32(IOMMU_CQCSR) & (CQCSR_CQON | CQCSR_BUSY) != CQCSR_CQON {
+            core::hint::spin_loop();
+        }
+
+        // Device directory table.
+        iommu.ddt_root = alloc_zeroed_page::<H>()?;
+        let ddt_ppn = (H::virt_to_phys(iommu.ddt_root) / PAGE_SIZE_4K) as u64;
+        iommu.write_u64(IOMMU_DDTP, ddt_ppn << 10 | DDTP_MODE_3LVL);
+        iommu.wait_ddtp_idle();
+        if iommu.read_u64(IOMMU_DDTP) & DDTP_MODE_MASK != DDTP_MODE_3LVL {
+            hv_log!(
+                Error,
+                Iommu,
+                LogContext::NONE,
+                "IOMMU doesn't support 3-level device directory table"
+            );
+            return Err(HyperError::NotSupported);
+        }
+
+        hv_log!(
+            Info,
+            Iommu,
+            LogContext::NONE,
+            "IOMMU@{:#x} initialized, {} device context",
+            base,
+            if iommu.extended_dc {
+                "extended"
+            } else {
+                "base"
+            }
+        );
+        Ok(iommu)
+    }
+
+    /// Points the device context of `device_id` at the G-stage page table described by
+    /// `iohgatp` (in `hgatp` format), enabling DMA translation for the device.
+    pub fn attach_device<H: HyperCraftHal>(
+        &mut self,
+        device_id: u32,
+        iohgatp: usize,
+    ) -> HyperResult<()> {
+        let dc = self.device_context::<H>(device_id, true)?;
+        // Safety: `dc` points to a device context inside a DDT page owned by this IOMMU.
+        unsafe {
+            if core::ptr::read_volatile(dc) & DDT_VALID != 0 {
+                return Err(HyperError::BadState);
+            }
+            // iohgatp, ta, fsc (bare first stage).
+            core::ptr::write_volatile(dc.add(1), iohgatp as u64);
+            core::ptr::write_volatile(dc.add(2), 0);
+            core::ptr::write_volatile(dc.add(3), 0);
+            if self.extended_dc {
+                // No MSI page table: MSIs are translated through the G-stage table as well.
+                for i in 4..8 {
+                    core::ptr::write_volatile(dc.add(i), 0);
+                }
+            }
+            // Make the context visible to the IOMMU before marking it valid.
+            fence(Ordering::SeqCst);
+            core::ptr::write_volatile(dc, DDT_VALID);
+        }
+        self.invalidate_device(device_id)
+    }
+
+    /// Disables DMA translation for `device_id` and drops any cached translation it used.
+    pub fn detach_device<H: HyperCraftHal>(&mut self, device_id: u32) -> HyperResult<()> {
+        let dc = self.device_context::<H>(device_id, false)?;
+        // Safety: `dc` points to a device context inside a DDT page owned by this IOMMU.
+        let iohgatp = unsafe {
+            if core::ptr::read_volatile(dc) & DDT_VALID == 0 {
+                return Err(HyperError::NotFound);
+            }
+            core::ptr::write_volatile(dc, 0);
+            core::ptr::read_volatile(dc.add(1))
+        };
+        self.invalidate_device(device_id)?;
+        self.invalidate_gscid(gscid_of(iohgatp as usize))
+    }
+
+    /// Points the device context of the attached `device_id` at `iohgatp` instead, e.g. once its
+    /// VM got another VMID, and drops the translations cached under the previous GSCID.
+    pub fn rebind_device<H: HyperCraftHal>(
+        &mut self,
+        device_id: u32,
+        iohgatp: usize,
+    ) -> HyperResult<()> {
+        let dc = self.device_context::<H>(device_id, false)?;
+        // Safety: `dc` points to a device context inside a DDT page owned by this IOMMU.
+        let old = unsafe {
+            if core::ptr::read_volatile(dc) & DDT_VALID == 0 {
+                return Err(HyperError::NotFound);
+            }
+            let old = core::ptr::read_volatile(dc.add(1));
+            core::ptr::write_volatile(dc.add(1), iohgatp as u64);
+            old
+        };
+        self.invalidate_device(device_id)?;
+        self.invalidate_gscid(gscid_of(old as usize))
+    }
+
+    /// Invalidates the IOMMU's cached G-stage translations for `gscid`. Must be called after
+    /// mappings of a VM with passthrough devices are removed or downgraded.
+    pub fn invalidate_gscid(&mut self, gscid: u16) -> HyperResult<()> {
+        // IOTINVAL.GVMA with GV=1, AV=0: all addresses of the given GSCID.
+        let cmd = CMD_IOTINVAL | CMD_IOTINVAL_GVMA << 7 | 1 << 33 | (gscid as u64) << 44;
+        self.submit(cmd, 0)?;
+        self.sync()
+    }
+
+    fn invalidate_device(&mut self, device_id: u32) -> HyperResult<()> {
+        // IODIR.INVAL_DDT with DV=1.
+        let cmd = CMD_IODIR | CMD_IODIR_INVAL_DDT << 7 | 1 << 33 | (device_id as u64) << 40;
+        self.submit(cmd, 0)?;
+        self.sync()
+    }
+
+    /// Returns a pointer to the device context of `device_id`, allocating the intermediate
+    /// directory pages if `alloc` is set.
+    fn device_context<H: HyperCraftHal>(
+        &mut self,
+        device_id: u32,
+        alloc: bool,
+    ) -> HyperResult<*mut u64> {
+        if device_id >= 1 << DEVICE_ID_BITS {
+            return Err(HyperError::InvalidParam);
+        }
+        let device_id = device_id as usize;
+        // DDI[0] indexes the leaf page of device contexts; DDI[1] and DDI[2] index non-leaf pages.
+        let (ddi0_bits, dc_words) = if self.extended_dc { (6, 8) } else { (7, 4) };
+        let ddi = [
+            device_id & ((1 << ddi0_bits) - 1),
+            (device_id >> ddi0_bits) & 0x1ff,
+            device_id >> (ddi0_bits + 9),
+        ];
+
+        let mut table = self.ddt_root;
+        for level in [2, 1] {
+            let entry = (table as *mut u64).wrapping_add(ddi[level]);
+            // Safety: `table` is a DDT page owned by this IOMMU and `ddi` stays within the page.
+            let val = unsafe { core::ptr::read_volatile(entry) };
+            table = if val & DDT_VALID != 0 {
+                H::phys_to_virt(((val >> 10) as usize) * PAGE_SIZE_4K)
+            } else if alloc {
+                let next = alloc_zeroed_page::<H>()?;
+                let ppn = (H::virt_to_phys(next) / PAGE_SIZE_4K) as u64;
+                fence(Ordering::SeqCst);
+                unsafe { core::ptr::write_volatile(entry, ppn << 10 | DDT_VALID) };
+                next
+            } else {
+                return Err(HyperError::NotFound);
+            };
+        }
+        Ok((table as *mut u64).wrapping_add(ddi[0] * dc_words))
+    }
+
+    /// Appends a command to the command queue.
+    fn submit(&mut self, dword0: u64, dword1: u64) -> HyperResult<()> {
+        let next_tail = (self.cq_tail + 1) % CQ_ENTRIES as u32;
+        // Wait for a free slot.
+        while self.read_u32(IOMMU_CQH) == next_tail {
+            self.check_cq_errors()?;
+            core::hint::spin_loop();
+        }
+        let slot = (self.cq_base as *mut u64).wrapping_add(self.cq_tail as usize * 2);
+        // Safety: `slot` lies within the command queue page owned by this IOMMU.
+        unsafe {
+            core::ptr::write_volatile(slot, dword0);
+            core::ptr::write_volatile(slot.add(1), dword1);
+        }
+        fence(Ordering::SeqCst);
+        self.cq_tail = next_tail;
+        self.write_u32(IOMMU_CQT, next_tail);
+        Ok(())
+    }
+
+    /// Issues an IOFENCE.C and waits until every previously submitted command has completed.
+    fn sync(&mut self) -> HyperResult<()> {
+        self.submit(CMD_IOFENCE | CMD_IOFENCE_C << 7, 0)?;
+        while self.read_u32(IOMMU_CQH) != self.cq_tail {
+            self.check_cq_errors()?;
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn check_cq_errors(&self) -> HyperResult<()> {
+        let cqcsr = self.read_u32(IOMMU_CQCSR);
+        if cqcsr & (CQCSR_CQMF | CQCSR_CMD_TO | CQCSR_CMD_ILL) != 0 {
+            hv_log!(
+                Error,
+                Iommu,
+                LogContext::NONE,
+                "IOMMU command queue error, cqcsr: {:#x}",
+                cqcsr
+            );
+            return Err(HyperError::Internal);
+        }
+        Ok(())
+    }
+
+    fn wait_ddtp_idle(&self) {
+        while self.read_u64(IOMMU_DDTP) & DDTP_BUSY != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_u32(&self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, val) }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u64) }
+    }
+
+    fn write_u64(&self, offset: usize, val: u64) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u64, val) }
+    }
+}
+
+/// The GSCID the IOMMU tags the translations of a device context with, the VMID of its
+/// `iohgatp`.
+pub fn gscid_of(iohgatp: usize) -> u16 {
+    ((iohgatp >> 44) & 0xffff) as u16
+}
+
+fn alloc_zeroed_page<H: HyperCraftHal>() -> HyperResult<HostVirtAddr> {
+    let page = H::alloc_page().ok_or(HyperError::NoMemory)?;
+    // Safety: the page was just allocated and is exclusively owned by the IOMMU.
+    unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE_4K) };
+    Ok(page)
+}
@@ -0,0 +1,125 @@
+//! Kicking vCPUs running on other harts out of the guest.
+//!
+//! A hart that needs a vCPU loaded elsewhere to act, e.g. to take an interrupt, fence its TLB or
+//! pause, posts the request to the vCPU's `IpiChannel` and rings the doorbell of the vCPU's hart:
+//! a supervisor software interrupt, sent through `HyperCraftHal::send_ipi`, which makes the guest
+//! exit. `VCpu::run` services the posted requests on the vCPU's hart and re-enters the guest
+//! without returning if nothing else is left to do. A vCPU outside the guest finds its requests
+//! before its next entry, and the host is told through `HyperCraftHal::vcpu_interrupt_pending` to
+//! wake it up in case it's blocked.
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::vcpu::IrqKind;
+
+/// `IpiState::hart` of a vCPU not in the guest.
+const NOT_IN_GUEST: usize = usize::MAX;
+
+/// Requests posted to a vCPU, shared with the harts posting them.
+struct IpiState {
+    /// `hvip` bits of the interrupts to inject.
+    irqs: AtomicUsize,
+    /// Whether the guest translations cached on the vCPU's hart are to be invalidated.
+    tlb_flush: AtomicBool,
+    /// Hart the vCPU is in the guest on, `NOT_IN_GUEST` otherwise.
+    hart: AtomicUsize,
+}
+
+impl Default for IpiState {
+    fn default() -> Self {
+        Self {
+            irqs: AtomicUsize::new(0),
+            tlb_flush: AtomicBool::new(false),
+            hart: AtomicUsize::new(NOT_IN_GUEST),
+        }
+    }
+}
+
+/// The requests taken by the vCPU, see `IpiChannel::take`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PostedRequests {
+    /// `hvip` bits of the interrupts to inject.
+    pub irqs: usize,
+    pub tlb_flush: bool,
+}
+
+impl PostedRequests {
+    pub fn is_empty(&self) -> bool {
+        self.irqs == 0 && !self.tlb_flush
+    }
+}
+
+/// Posts requests to a vCPU from any hart, kicking it out of the guest so it services them.
+#[derive(Clone)]
+pub struct IpiChannel {
+    vcpu_id: usize,
+    state: Arc<IpiState>,
+    /// Sends a supervisor software interrupt to a hart, see `HyperCraftHal::send_ipi`.
+    send_ipi: fn(usize),
+    /// Wakes the vCPU up, see `HyperCraftHal::vcpu_interrupt_pending`.
+    wake: fn(usize),
+}
+
+impl IpiChannel {
+    pub(crate) fn new(vcpu_id: usize, send_ipi: fn(usize), wake: fn(usize)) -> Self {
+        Self {
+            vcpu_id,
+            state: Arc::new(IpiState::default()),
+            send_ipi,
+            wake,
+        }
+    }
+
+    /// Makes the interrupt `kind` pending for the guest, which takes it once it enables it.
+    pub fn post_irq(&self, kind: IrqKind) {
+        self.state.irqs.fetch_or(kind.hvip_bit(), Ordering::SeqCst);
+        self.kick();
+    }
+
+    /// Invalidates the guest translations cached on the vCPU's hart before it next runs guest
+    /// code, e.g. after the VM's mappings changed.
+    pub fn request_tlb_flush(&self) {
+        self.state.tlb_flush.store(true, Ordering::SeqCst);
+        self.kick();
+    }
+
+    /// Forces the vCPU out of the guest if it's in it, and wakes it up otherwise, so it notices
+    /// the requests posted to it, including pause requests.
+    pub fn kick(&self) {
+        match self.state.hart.load(Ordering::SeqCst) {
+            NOT_IN_GUEST => (self.wake)(self.vcpu_id),
+            hart => (self.send_ipi)(hart),
+        }
+    }
+
+    /// Whether interrupts are posted and not taken yet.
+    pub(crate) fn irqs_posted(&self) -> bool {
+        self.state.irqs.load(Ordering::SeqCst) != 0
+    }
+
+    /// Marks the vCPU as entering the guest on `hart`, after which posting requests kicks it.
+    /// Returns false, leaving the vCPU out of the guest, if requests were posted in the meantime.
+    pub(crate) fn enter(&self, hart: usize) -> bool {
+        self.state.hart.store(hart, Ordering::SeqCst);
+        if self.state.irqs.load(Ordering::SeqCst) != 0
+            || self.state.tlb_flush.load(Ordering::SeqCst)
+        {
+            self.exit();
+            return false;
+        }
+        true
+    }
+
+    /// Marks the vCPU as out of the guest.
+    pub(crate) fn exit(&self) {
+        self.state.hart.store(NOT_IN_GUEST, Ordering::SeqCst);
+    }
+
+    /// Takes the requests posted so far.
+    pub(crate) fn take(&self) -> PostedRequests {
+        PostedRequests {
+            irqs: self.state.irqs.swap(0, Ordering::SeqCst),
+            tlb_flush: self.state.tlb_flush.swap(false, Ordering::SeqCst),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! ISA extensions of the host and the subset exposed to guests.
+//!
+//! Extensions guests use without trapping, like Sstc or Svpbmt, only work if the harts they run on
+//! implement them. The boot hart is probed once, and each VM exposes a subset of what it found to
+//! its guest: the subset is enabled in `henvcfg` and `hideleg` while the guest's vCPUs run, and
+//! reported to the guest through a firmware-specific SBI extension. Disabled extensions trap or
+//! read as absent, e.g. `stimecmp` is then emulated by the hypervisor. The FP and vector
+//! registers are turned off in `sstatus` for guests without F/D and V.
+use spin::Once;
+
+use super::csrs::defs::CSR_HENVCFG;
+use super::detect::{detect_fpu, detect_sscofpmf, detect_vector};
+
+// `henvcfg` fields, which are WARL, so those the hart doesn't implement read back clear.
+// Cache block invalidation executed by guests flushes the block.
+const HENVCFG_CBIE_FLUSH: usize = 0b01 << 4;
+const HENVCFG_CBCFE: usize = 1 << 6;
+const HENVCFG_CBZE: usize = 1 << 7;
+const HENVCFG_PBMTE: usize = 1 << 62;
+const HENVCFG_STCE: usize = 1 << 63;
+
+/// Local counter overflow interrupt of Sscofpmf, delegated to guests it's exposed to.
+pub(crate) const LCOFI: usize = 1 << 13;
+
+static HOST_EXTENSIONS: Once<IsaExtensions> = Once::new();
+
+/// A set of ISA extensions the hypervisor can expose to guests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IsaExtensions {
+    /// Supervisor timer compare: the guest programs `stimecmp` without trapping.
+    pub sstc: bool,
+    /// Counter overflow interrupts and mode-based counter filtering.
+    pub sscofpmf: bool,
+    /// Page-based memory types.
+    pub svpbmt: bool,
+    /// Cache block management instructions.
+    pub zicbom: bool,
+    /// Cache block zero instruction.
+    pub zicboz: bool,
+    /// Single and double precision floating point.
+    pub fpu: bool,
+    /// Vectors.
+    pub vector: bool,
+}
+
+impl IsaExtensions {
+    /// Extensions exposed to guests unless their VM is configured otherwise, where the host has
+    /// them. Sstc and Sscofpmf change how the guest's timer and counters work and are opt-in.
+    pub const GUEST_DEFAULT: Self = Self {
+        sstc: false,
+        sscofpmf: false,
+        svpbmt: true,
+        zicbom: true,
+        zicboz: true,
+        fpu: true,
+        vector: true,
+    };
+
+    /// Extensions implemented by the host, probed on the calling hart on first use, which should
+    /// be the boot hart. All harts hosting guests are assumed to implement the same.
+    pub fn host() -> Self {
+        *HOST_EXTENSIONS.call_once(|| {
+            let henvcfg = probe_henvcfg();
+            Self {
+                sstc: henvcfg & HENVCFG_STCE != 0,
+                sscofpmf: detect_sscofpmf(),
+                svpbmt: henvcfg & HENVCFG_PBMTE != 0,
+                zicbom: henvcfg & HENVCFG_CBCFE != 0,
+                zicboz: henvcfg & HENVCFG_CBZE != 0,
+                fpu: detect_fpu(),
+                vector: detect_vector(),
+            }
+        })
+    }
+
+    /// The extensions in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            sstc: self.sstc && other.sstc,
+            sscofpmf: self.sscofpmf && other.sscofpmf,
+            svpbmt: self.svpbmt && other.svpbmt,
+            zicbom: self.zicbom && other.zicbom,
+            zicboz: self.zicboz && other.zicboz,
+            fpu: self.fpu && other.fpu,
+            vector: self.vector && other.vector,
+        }
+    }
+
+    /// The extensions as reported to the guest through SBI, one bit each in field order.
+    pub fn bits(&self) -> usize {
+        self.sstc as usize
+            | (self.sscofpmf as usize) << 1
+            | (self.svpbmt as usize) << 2
+            | (self.zicbom as usize) << 3
+            | (self.zicboz as usize) << 4
+            | (self.fpu as usize) << 5
+            | (self.vector as usize) << 6
+    }
+
+    /// The `henvcfg` value enabling the extensions for a guest.
+    pub(crate) fn henvcfg(&self) -> usize {
+        let mut henvcfg = 0;
+        if self.sstc {
+            henvcfg |= HENVCFG_STCE;
+        }
+        if self.svpbmt {
+            henvcfg |= HENVCFG_PBMTE;
+        }
+        if self.zicbom {
+            henvcfg |= HENVCFG_CBIE_FLUSH | HENVCFG_CBCFE;
+        }
+        if self.zicboz {
+            henvcfg |= HENVCFG_CBZE;
+        }
+        henvcfg
+    }
+}
+
+/// Sets every `henvcfg` field of an extension and returns those the hart kept.
+fn probe_henvcfg() -> usize {
+    let probed: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrrw {old}, {csr}, {val}",
+            "csrr {probed}, {csr}",
+            "csrw {csr}, {old}",
+            csr = const CSR_HENVCFG,
+            val = in(reg) HENVCFG_STCE | HENVCFG_PBMTE | HENVCFG_CBCFE | HENVCFG_CBZE,
+            old = out(reg) _,
+            probed = out(reg) probed,
+        );
+    }
+    probed
+}
@@ -0,0 +1,76 @@
+//! Isolation of the host's memory from guests.
+//!
+//! Each VM tracks the host physical memory assigned to it: the memory its RAM was mapped to by the
+//! caller, and the pages the hypervisor allocated to back RAM on first touch. Guest RAM mappings,
+//! and the host addresses guest physical addresses translate to before the hypervisor accesses
+//! them on the guest's behalf, must fall within it, so a bogus mapping or guest-supplied address
+//! can't make the hypervisor touch memory the VM doesn't own.
+use alloc::vec::Vec;
+
+use crate::{memory::PAGE_SIZE_4K, HostPhysAddr, HyperError, HyperResult};
+
+/// A set of host physical address ranges, kept sorted and merged.
+#[derive(Clone, Default)]
+pub struct HostRangeSet {
+    // Disjoint, non-adjacent `[start, end)` ranges sorted by start.
+    ranges: Vec<(HostPhysAddr, HostPhysAddr)>,
+}
+
+impl HostRangeSet {
+    /// Adds `[start, end)`, which must be page-aligned, merging it with the ranges it overlaps or
+    /// touches.
+    pub fn add(&mut self, start: HostPhysAddr, end: HostPhysAddr) -> HyperResult<()> {
+        if start >= end || start % PAGE_SIZE_4K != 0 || end % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        // Ranges in `[first, last)` overlap or touch the new one.
+        let first = self.ranges.partition_point(|&(_, e)| e < start);
+        let last = self.ranges.partition_point(|&(s, _)| s <= end);
+        let merged = if first < last {
+            (
+                core::cmp::min(start, self.ranges[first].0),
+                core::cmp::max(end, self.ranges[last - 1].1),
+            )
+        } else {
+            (start, end)
+        };
+        self.ranges.splice(first..last, [merged]);
+        Ok(())
+    }
+
+    /// Removes `[start, end)`, splitting the ranges it lies within, e.g. when a page backing guest
+    /// RAM is given back to the host.
+    pub fn remove(&mut self, start: HostPhysAddr, end: HostPhysAddr) {
+        // Ranges in `[first, last)` overlap the removed one.
+        let first = self.ranges.partition_point(|&(_, e)| e <= start);
+        let last = self.ranges.partition_point(|&(s, _)| s < end);
+        if first >= last {
+            return;
+        }
+        let (head, tail) = (self.ranges[first].0, self.ranges[last - 1].1);
+        let rest = [(head, start), (end, tail)]
+            .into_iter()
+            .filter(|&(s, e)| s < e);
+        self.ranges.splice(first..last, rest);
+    }
+
+    /// The `[start, end)` ranges of the set, sorted.
+    pub fn iter(&self) -> impl Iterator<Item = (HostPhysAddr, HostPhysAddr)> + '_ {
+        self.ranges.iter().copied()
+    }
+
+    /// Whether `[start, end)` overlaps a range of the set.
+    pub fn overlaps(&self, start: HostPhysAddr, end: HostPhysAddr) -> bool {
+        let index = self.ranges.partition_point(|&(_, e)| e <= start);
+        index < self.ranges.len() && self.ranges[index].0 < end
+    }
+
+    /// Whether `[addr, addr + len)` lies within one range of the set.
+    pub fn contains(&self, addr: HostPhysAddr, len: usize) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        let index = self.ranges.partition_point(|&(s, _)| s <= addr);
+        index > 0 && end <= self.ranges[index - 1].1
+    }
+}
@@ -0,0 +1,189 @@
+//! Static VM configuration from a manifest.
+//!
+//! A manifest declares the VMs to create at boot, for static partitioning setups where the host
+//! is handed the VMs rather than constructing them. It's a header followed by a sequence of
+//! records, each prefixed with its tag and length and padded to 8 bytes. All integers are little
+//! endian.
+//!
+//! ```text
+//! +--------------------------------------------------+
+//! | magic "HCVMMANI" | version u16 | rsvd u16 | rsvd u32 |  header
+//! +--------------------------------------------------+
+//! | tag u16 | rsvd u16 | length u32                  |  record header
+//! | value (`length` bytes, padded to 8 bytes)        |
+//! +--------------------------------------------------+
+//! | ...                                              |
+//! +--------------------------------------------------+
+//! | tag = END | 0 | 0                                |  end marker
+//! +--------------------------------------------------+
+//! ```
+//!
+//! A `VM` record starts the declaration of a VM, which the records up to the next `VM` record
+//! describe, as with the `VmConfigBuilder` methods of the same name:
+//!
+//! - `NAME`: the UTF-8 name the VM is attached to the console multiplexer as.
+//! - `VCPUS`: count u32.
+//! - `ENTRY`: gpa u64.
+//! - `RAM`: gpa u64, size u64.
+//! - `PASSTHROUGH`: gpa u64, hpa u64, size u64, `MappingFlags` bits u64.
+//! - `DEVICE`: gpa u64, irq u32, kind u32, followed for a GPU by width u32 and height u32.
+//! - `KERNEL`, `DTB` and `INITRD`: gpa u64, followed by the image, which is loaded into the VM's
+//!   RAM.
+//!
+//! Devices with a host backend, like the virtio 9P filesystem, can't be declared.
+use alloc::vec::Vec;
+
+use page_table_entry::MappingFlags;
+
+use super::vm_config::{DeviceConfig, VmConfigBuilder};
+use super::VM;
+use crate::{GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+const MAGIC: &[u8; 8] = b"HCVMMANI";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+/// Records are padded to a multiple of this.
+const RECORD_ALIGN: usize = 8;
+
+const TAG_END: u16 = 0;
+const TAG_VM: u16 = 1;
+const TAG_NAME: u16 = 2;
+const TAG_VCPUS: u16 = 3;
+const TAG_ENTRY: u16 = 4;
+const TAG_RAM: u16 = 5;
+const TAG_PASSTHROUGH: u16 = 6;
+const TAG_DEVICE: u16 = 7;
+const TAG_KERNEL: u16 = 8;
+const TAG_DTB: u16 = 9;
+const TAG_INITRD: u16 = 10;
+
+const DEVICE_UART: u32 = 0;
+const DEVICE_RTC: u32 = 1;
+const DEVICE_VIRTIO_RNG: u32 = 2;
+const DEVICE_VIRTIO_GPU: u32 = 3;
+const DEVICE_VIRTIO_BALLOON: u32 = 4;
+
+/// Creates the VMs declared in `manifest`, each with a new guest page table, and loads their
+/// images. Fails with `InvalidParam` if the manifest is malformed and `NotSupported` if it has a
+/// different version or a record or device unknown to this parser, in which case no VM is
+/// returned. Each VM is validated as by `VmConfigBuilder::build`.
+pub fn instantiate_manifest<H: HyperCraftHal, G: GuestPageTableTrait>(
+    manifest: &[u8],
+) -> HyperResult<Vec<VM<H, G>>> {
+    let mut reader = Reader(manifest);
+    let header = reader.bytes(HEADER_SIZE)?;
+    if &header[..8] != MAGIC {
+        return Err(HyperError::InvalidParam);
+    }
+    if u16::from_le_bytes([header[8], header[9]]) != VERSION {
+        return Err(HyperError::NotSupported);
+    }
+
+    let mut vms = Vec::new();
+    // The VM being declared, and its images.
+    let mut current: Option<(VmConfigBuilder, Vec<(GuestPhysAddr, &[u8])>)> = None;
+    loop {
+        let tag = reader.u16()?;
+        reader.u16()?;
+        let len = reader.u32()? as usize;
+        let mut value = Reader(reader.bytes(len)?);
+        reader.bytes((RECORD_ALIGN - len % RECORD_ALIGN) % RECORD_ALIGN)?;
+
+        if matches!(tag, TAG_END | TAG_VM) {
+            if let Some((builder, images)) = current.take() {
+                vms.push(instantiate(builder, &images)?);
+            }
+            match tag {
+                TAG_END => return Ok(vms),
+                _ => current = Some((VmConfigBuilder::new(), Vec::new())),
+            }
+            continue;
+        }
+        let (builder, images) = current.as_mut().ok_or(HyperError::InvalidParam)?;
+        let config = core::mem::take(builder);
+        *builder = match tag {
+            TAG_NAME => {
+                let name = core::str::from_utf8(value.0).map_err(|_| HyperError::InvalidParam)?;
+                config.console(name)
+            }
+            TAG_VCPUS => config.vcpus(value.u32()? as usize),
+            TAG_ENTRY => config.entry(value.addr()?),
+            TAG_RAM => config.ram(value.addr()?, value.addr()?),
+            TAG_PASSTHROUGH => {
+                let (gpa, hpa, size) = (value.addr()?, value.addr()?, value.addr()?);
+                let attrs = MappingFlags::from_bits(value.u64()? as usize)
+                    .ok_or(HyperError::InvalidParam)?;
+                config.passthrough(gpa, hpa, size, attrs)
+            }
+            TAG_DEVICE => {
+                let (gpa, irq) = (value.addr()?, value.u32()?);
+                let device = match value.u32()? {
+                    DEVICE_UART => DeviceConfig::Uart,
+                    DEVICE_RTC => DeviceConfig::Rtc,
+                    DEVICE_VIRTIO_RNG => DeviceConfig::VirtioRng,
+                    DEVICE_VIRTIO_GPU => DeviceConfig::VirtioGpu {
+                        width: value.u32()?,
+                        height: value.u32()?,
+                    },
+                    DEVICE_VIRTIO_BALLOON => DeviceConfig::VirtioBalloon,
+                    _ => return Err(HyperError::NotSupported),
+                };
+                config.device(gpa, irq, device)
+            }
+            TAG_KERNEL | TAG_DTB | TAG_INITRD => {
+                let gpa = value.addr()?;
+                let image = value.0;
+                images.push((gpa, image));
+                match tag {
+                    TAG_KERNEL => config.kernel(gpa, image.len()),
+                    TAG_DTB => config.dtb(gpa, image.len()),
+                    _ => config.initrd(gpa, image.len()),
+                }
+            }
+            _ => return Err(HyperError::NotSupported),
+        };
+    }
+}
+
+/// Creates the VM `builder` describes and loads `images` into it.
+fn instantiate<H: HyperCraftHal, G: GuestPageTableTrait>(
+    builder: VmConfigBuilder,
+    images: &[(GuestPhysAddr, &[u8])],
+) -> HyperResult<VM<H, G>> {
+    let mut vm = builder.build(G::new()?)?;
+    for &(gpa, image) in images {
+        vm.load_image(gpa, image)?;
+    }
+    Ok(vm)
+}
+
+/// Reads the fields of a manifest. Reading past the end fails with `InvalidParam`.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> HyperResult<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> HyperResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> HyperResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads an address or size.
+    fn addr(&mut self) -> HyperResult<usize> {
+        usize::try_from(self.u64()?).map_err(|_| HyperError::InvalidParam)
+    }
+}
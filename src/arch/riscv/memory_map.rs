@@ -0,0 +1,150 @@
+//! Standard layout of a VM's guest physical address space.
+//!
+//! Guests find their RAM and devices where the device tree says, so the host generating the
+//! device tree and the code registering the devices must agree on every address and interrupt.
+//! `GuestMemoryMap` is the single description both work from: it places RAM, the vPLIC, the UART,
+//! the RTC and an array of virtio-mmio slots, and hands the virtio slots out in order as devices
+//! are registered, remembering which ones are populated for the device tree.
+//! `GuestMemoryMap::standard` follows the QEMU `virt` machine, which guest kernels and firmware
+//! are commonly configured for.
+use super::devices::rtc::RTC_SIZE;
+use super::devices::uart::UART_SIZE;
+use super::vm::{PLIC_GPA, PLIC_SIZE};
+use crate::memory::PAGE_SIZE_4K;
+use crate::virtio::VIRTIO_MMIO_SIZE;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Where the standard map places guest RAM.
+pub const STANDARD_RAM_BASE: GuestPhysAddr = 0x8000_0000;
+/// Where the standard map places the RTC, and its interrupt.
+pub const STANDARD_RTC: MmioSlot = MmioSlot {
+    gpa: 0x10_1000,
+    size: RTC_SIZE,
+    irq: 11,
+};
+/// Where the standard map places the UART, and its interrupt.
+pub const STANDARD_UART: MmioSlot = MmioSlot {
+    gpa: 0x1000_0000,
+    size: UART_SIZE,
+    irq: 10,
+};
+/// Where the standard map places the first virtio-mmio slot, whose interrupt is 1. The following
+/// slots are one page apart, with interrupts counting up.
+pub const STANDARD_VIRTIO_BASE: GuestPhysAddr = 0x1000_1000;
+/// Number of virtio-mmio slots of the standard map.
+pub const STANDARD_VIRTIO_SLOTS: usize = 8;
+
+/// Most virtio-mmio slots a map can have.
+const MAX_VIRTIO_SLOTS: usize = u64::BITS as usize;
+
+/// The registers of a device and the guest interrupt it raises.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioSlot {
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    pub irq: u32,
+}
+
+/// The layout of a VM's guest physical address space, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestMemoryMap {
+    ram_base: GuestPhysAddr,
+    ram_size: usize,
+    uart: MmioSlot,
+    rtc: MmioSlot,
+    virtio_base: GuestPhysAddr,
+    virtio_irq_base: u32,
+    virtio_slots: usize,
+    /// Bit `i` is set once virtio slot `i` is handed out.
+    virtio_used: u64,
+}
+
+impl GuestMemoryMap {
+    /// The standard map with `ram_size` bytes of RAM.
+    pub fn standard(ram_size: usize) -> Self {
+        Self {
+            ram_base: STANDARD_RAM_BASE,
+            ram_size,
+            uart: STANDARD_UART,
+            rtc: STANDARD_RTC,
+            virtio_base: STANDARD_VIRTIO_BASE,
+            virtio_irq_base: 1,
+            virtio_slots: STANDARD_VIRTIO_SLOTS,
+            virtio_used: 0,
+        }
+    }
+
+    /// Moves RAM to `[base, base + size)`. Fails with `InvalidParam` if it isn't page aligned.
+    pub fn with_ram(mut self, base: GuestPhysAddr, size: usize) -> HyperResult<Self> {
+        if base % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.ram_base = base;
+        self.ram_size = size;
+        Ok(self)
+    }
+
+    /// Places `count` virtio-mmio slots one page apart from `base`, raising the interrupts from
+    /// `first_irq` on. Fails with `InvalidParam` if `base` isn't page aligned or there are more
+    /// slots than a map can track, and `BadState` if slots were already handed out.
+    pub fn with_virtio_window(
+        mut self,
+        base: GuestPhysAddr,
+        first_irq: u32,
+        count: usize,
+    ) -> HyperResult<Self> {
+        if base % PAGE_SIZE_4K != 0 || count > MAX_VIRTIO_SLOTS {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.virtio_used != 0 {
+            return Err(HyperError::BadState);
+        }
+        self.virtio_base = base;
+        self.virtio_irq_base = first_irq;
+        self.virtio_slots = count;
+        Ok(self)
+    }
+
+    /// Guest RAM, as its base and size.
+    pub fn ram(&self) -> (GuestPhysAddr, usize) {
+        (self.ram_base, self.ram_size)
+    }
+
+    /// The vPLIC registers, which every VM emulates at the same place.
+    pub fn plic(&self) -> (GuestPhysAddr, usize) {
+        (PLIC_GPA, PLIC_SIZE)
+    }
+
+    pub fn uart(&self) -> MmioSlot {
+        self.uart
+    }
+
+    pub fn rtc(&self) -> MmioSlot {
+        self.rtc
+    }
+
+    /// The virtio-mmio slot `index`, if the map has it.
+    pub fn virtio_slot(&self, index: usize) -> Option<MmioSlot> {
+        (index < self.virtio_slots).then(|| MmioSlot {
+            gpa: self.virtio_base + index * PAGE_SIZE_4K,
+            size: VIRTIO_MMIO_SIZE,
+            irq: self.virtio_irq_base + index as u32,
+        })
+    }
+
+    /// Hands out the first virtio-mmio slot not handed out yet. Fails with `OutOfRange` if none
+    /// is left.
+    pub fn assign_virtio_slot(&mut self) -> HyperResult<MmioSlot> {
+        let index = (!self.virtio_used).trailing_zeros() as usize;
+        let slot = self.virtio_slot(index).ok_or(HyperError::OutOfRange)?;
+        self.virtio_used |= 1 << index;
+        Ok(slot)
+    }
+
+    /// The virtio-mmio slots handed out, which the device tree lists.
+    pub fn assigned_virtio_slots(&self) -> impl Iterator<Item = MmioSlot> + '_ {
+        (0..self.virtio_slots)
+            .filter(move |&index| self.virtio_used & (1 << index) != 0)
+            .filter_map(move |index| self.virtio_slot(index))
+    }
+}
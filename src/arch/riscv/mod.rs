@@ -1,22 +1,71 @@
+mod aia;
+mod audit;
+mod bandwidth;
+mod caps;
+mod csr_emu;
 mod csrs;
+mod debug;
 mod detect;
 mod devices;
 mod ept;
+mod exit_stats;
+mod fp;
+mod gdb;
+mod iommu;
+mod ipi;
+mod isa;
+mod isolation;
+mod manifest;
+mod memory_map;
+mod per_cpu;
+mod realtime;
 mod regs;
+mod resources;
 mod sbi;
 mod smp;
+#[cfg(feature = "test-guests")]
+pub mod test_guests;
+pub mod tlb;
 mod vcpu;
 mod vm;
+mod vm_config;
 mod vm_pages;
 mod vmexit;
+mod vmid;
 
-pub use ept::NestedPageTable;
+pub use crate::virtio::blk_driver::VirtioBlkDriver;
+pub use crate::virtio::fs::{FsAttr, FsBackend, FsDirEntry, FsFileType};
+pub use crate::virtio::input::{InputEvent, InputHandle};
+pub use aia::init_aia;
+pub use audit::{
+    audit_isolation, GuestMapping, GuestRegion, IsolationViolation, RegionKind, ViolationKind,
+};
+pub use bandwidth::{CpuBandwidth, CpuClass, CpuShare};
+pub use caps::{init, HostCapabilities};
+pub use debug::DebugEvent;
+pub use ept::{GuestPagingMode, NestedPageTable, NestedPageTableSv48, NestedPageTableSv57};
+pub use exit_stats::{ExitReason, ExitStats, ExitTraceEntry};
+pub use gdb::{GdbAction, GdbConnection, GdbStub};
+pub use iommu::init_iommu;
+pub use ipi::IpiChannel;
+pub use isa::IsaExtensions;
+pub use manifest::instantiate_manifest;
+pub use memory_map::{
+    GuestMemoryMap, MmioSlot, STANDARD_RAM_BASE, STANDARD_RTC, STANDARD_UART, STANDARD_VIRTIO_BASE,
+    STANDARD_VIRTIO_SLOTS,
+};
+pub use per_cpu::HypervisorPerCpu;
+pub use realtime::{verify_realtime, RealtimeViolation, RealtimeViolationKind};
 pub use regs::GprIndex;
+pub use resources::{ResourceLimits, ResourceUsage};
 pub use sbi::SbiMessage as HyperCallMsg;
+pub use sbi::{ResetReason, ResetType};
 pub use smp::PerCpu;
-pub use vcpu::VCpu;
-pub use vm::VM;
-pub use vmexit::VmExitInfo;
+pub use vcpu::{CounterAccess, HartState, IrqKind, PauseHandle, VCpu, VCpuState};
+pub use vm::{StopReason, VM};
+pub use vm_config::{DeviceConfig, VmConfigBuilder};
+pub use vm_pages::{RomWritePolicy, SharePermission};
+pub use vmexit::{PrivilegeLevel, VmExitInfo};
 
 use self::csrs::{traps, ReadWriteCsr, RiscvCsrTrait, CSR};
 use self::detect::detect_h_extension;
@@ -24,52 +73,14 @@ use self::devices::plic::PlicState;
 use self::vcpu::VmCpuRegisters;
 use sbi::BaseFunction;
 
-/// Initialize the hypervisor runtime.
+/// Initialize the hypervisor runtime on the current hart. Other harts are set up with
+/// `HypervisorPerCpu::init`. See `init` for a version reporting what the hart lacks.
 pub fn init_hv_runtime() {
     if !detect_h_extension() {
         panic!("H Extension not supported.")
     }
 
     unsafe {
-        setup_csrs();
+        per_cpu::setup_csrs();
     }
 }
-
-/// Initialize (H)S-level CSRs to a reasonable state.
-unsafe fn setup_csrs() {
-    // Delegate some synchronous exceptions.
-    CSR.hedeleg.write_value(
-        traps::exception::INST_ADDR_MISALIGN
-            | traps::exception::BREAKPOINT
-            | traps::exception::ENV_CALL_FROM_U_OR_VU
-            | traps::exception::INST_PAGE_FAULT
-            | traps::exception::LOAD_PAGE_FAULT
-            | traps::exception::STORE_PAGE_FAULT
-            | traps::exception::ILLEGAL_INST,
-    );
-
-    // Delegate all interupts.
-    CSR.hideleg.write_value(
-        traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
-            | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
-            | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
-    );
-
-    // Clear all interrupts.
-    CSR.hvip.read_and_clear_bits(
-        traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
-            | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
-            | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
-    );
-
-    // clear all interrupts.
-    CSR.hcounteren.write_value(0xffff_ffff);
-
-    // enable interrupt
-    CSR.sie.write_value(
-        traps::interrupt::SUPERVISOR_EXTERNAL
-            | traps::interrupt::SUPERVISOR_SOFT
-            | traps::interrupt::SUPERVISOR_TIMER,
-    );
-    debug!("sie: {:#x}", CSR.sie.get_value());
-}
@@ -0,0 +1,76 @@
+//! Per-hart configuration of the hypervisor CSRs.
+use super::{csrs::traps, detect::detect_h_extension, isa::IsaExtensions, RiscvCsrTrait, CSR};
+use crate::{logging::LogContext, HyperError, HyperResult};
+
+/// Sets up the hypervisor CSRs of each hart that hosts guests.
+pub struct HypervisorPerCpu;
+
+impl HypervisorPerCpu {
+    /// Configures the current hart `hart_id` for hosting guests: delegates the guest's own
+    /// exceptions and VS-level interrupts to it, exposes the counters, probes the ISA extensions
+    /// guests may use and enables the host interrupts that drive the vCPUs. Must be called on
+    /// each hart before running vCPUs on it. Fails with `NotSupported` if the hart doesn't
+    /// implement the hypervisor extension.
+    pub fn init(hart_id: usize) -> HyperResult<()> {
+        if !detect_h_extension() {
+            return Err(HyperError::NotSupported);
+        }
+        unsafe { setup_csrs() };
+        hv_log!(
+            Debug,
+            Hart,
+            LogContext::NONE,
+            "hart {}: hypervisor CSRs initialized",
+            hart_id
+        );
+        Ok(())
+    }
+}
+
+/// Initialize (H)S-level CSRs to a reasonable state.
+pub(super) unsafe fn setup_csrs() {
+    // Delegate some synchronous exceptions.
+    CSR.hedeleg.write_value(
+        traps::exception::INST_ADDR_MISALIGN
+            | traps::exception::BREAKPOINT
+            | traps::exception::ENV_CALL_FROM_U_OR_VU
+            | traps::exception::INST_PAGE_FAULT
+            | traps::exception::LOAD_PAGE_FAULT
+            | traps::exception::STORE_PAGE_FAULT
+            | traps::exception::ILLEGAL_INST,
+    );
+
+    // Delegate all interupts.
+    CSR.hideleg.write_value(
+        traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+            | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
+            | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+    );
+
+    // Clear all interrupts.
+    CSR.hvip.read_and_clear_bits(
+        traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+            | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
+            | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+    );
+
+    // clear all interrupts.
+    CSR.hcounteren.write_value(0xffff_ffff);
+
+    // Probe the extensions guests may use, which vCPUs enable in `henvcfg` when activated.
+    IsaExtensions::host();
+
+    // enable interrupt
+    CSR.sie.write_value(
+        traps::interrupt::SUPERVISOR_EXTERNAL
+            | traps::interrupt::SUPERVISOR_SOFT
+            | traps::interrupt::SUPERVISOR_TIMER,
+    );
+    hv_log!(
+        Debug,
+        Hart,
+        LogContext::NONE,
+        "sie: {:#x}",
+        CSR.sie.get_value()
+    );
+}
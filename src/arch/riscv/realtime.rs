@@ -0,0 +1,91 @@
+//! Real-time partitioning.
+//!
+//! Real-time guests need a bound on how often the hypervisor takes the hart from them. In
+//! real-time mode, see `VM::set_realtime`, each vCPU of a VM is bound to a hart of its own and run
+//! there with `VM::run_realtime`, without time slices or WFI exits. The rest of the VM's
+//! configuration must not make the guest exit either: its devices are to be passed through rather
+//! than emulated, its interrupts delivered by AIA into guest interrupt files, its timer programmed
+//! through Sstc, and so on. `verify_realtime` checks the VMs of a partitioning setup against these
+//! constraints and reports every one that's broken, so that only the hypercalls a real-time guest
+//! makes itself are left to exit.
+use alloc::vec::Vec;
+
+use super::VM;
+use crate::{GuestPageTableTrait, GuestPhysAddr, HyperCraftHal};
+
+/// How the configuration of a real-time VM lets its guest exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealtimeViolationKind {
+    /// The vCPU may run on more than one hart.
+    UnboundVcpu(usize),
+    /// The hart of vCPU `vcpu` may also run vCPU `other_vcpu` of the VM at index `other_vm`.
+    SharedHart {
+        vcpu: usize,
+        hart: usize,
+        other_vm: usize,
+        other_vcpu: usize,
+    },
+    /// A device is emulated with its registers at this guest physical address, so accesses to it
+    /// exit.
+    EmulatedDevice(GuestPhysAddr),
+    /// Interrupts go through the emulated vPLIC rather than AIA guest interrupt files.
+    EmulatedInterruptController,
+    /// Sstc isn't exposed to the vCPU, so its timer is emulated through SBI.
+    EmulatedTimer(usize),
+    /// WFI executed by the vCPU exits.
+    WfiExit(usize),
+    /// Reads of `cycle`, `time` or `instret`, as numbered in `VCpu::set_counter_access`, by the
+    /// vCPU exit.
+    TrappedCounter { vcpu: usize, counter: usize },
+    /// The RAM page at this guest physical address isn't populated, so the guest's first access
+    /// to it faults.
+    UnpopulatedRam(GuestPhysAddr),
+    /// The VM's CPU time is capped or accounted in a CPU bandwidth group, which takes the harts
+    /// from it.
+    CpuTimeLimited,
+    /// Dirty logging write-protects the VM's RAM.
+    DirtyLogging,
+    /// The vCPU is in debug mode.
+    Debugging(usize),
+}
+
+/// A constraint of real-time mode broken by the VM at index `vm`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RealtimeViolation {
+    pub vm: usize,
+    pub kind: RealtimeViolationKind,
+}
+
+/// Checks the VMs of `vms` in real-time mode against the constraints of the module documentation,
+/// including that no vCPU of another VM, in real-time mode or not, may run on their harts. Returns
+/// the constraints broken, none if the real-time VMs only exit on their own hypercalls.
+pub fn verify_realtime<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vms: &[&VM<H, G>],
+) -> Vec<RealtimeViolation> {
+    let mut violations = Vec::new();
+    for (index, vm) in vms.iter().enumerate().filter(|(_, vm)| vm.realtime()) {
+        vm.realtime_violations(|kind| violations.push(RealtimeViolation { vm: index, kind }));
+        for (vcpu, affinity) in vm.vcpu_affinities() {
+            if !affinity.is_power_of_two() {
+                continue;
+            }
+            let hart = affinity.trailing_zeros() as usize;
+            for (other_vm, other) in vms.iter().enumerate() {
+                for (other_vcpu, other_affinity) in other.vcpu_affinities() {
+                    if (other_vm, other_vcpu) != (index, vcpu) && other_affinity & affinity != 0 {
+                        violations.push(RealtimeViolation {
+                            vm: index,
+                            kind: RealtimeViolationKind::SharedHart {
+                                vcpu,
+                                hart,
+                                other_vm,
+                                other_vcpu,
+                            },
+                        });
+                    }
+                }
+            }
+        }
+    }
+    violations
+}
@@ -0,0 +1,36 @@
+//! Accounting and limits of the hypervisor resources a VM consumes.
+//!
+//! A host running several untrusted guests caps what each VM may take: the host pages the
+//! hypervisor allocates for it, its emulated devices and the CPU time of its vCPUs. Allocations
+//! past a limit fail, and a VM that used up its CPU time stops running.
+
+/// Caps on the resources of a VM, see `VM::set_resource_limits`. `None` leaves a resource
+/// unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Host pages allocated for the VM's RAM on first touch and for its ROMs.
+    pub memory_pages: Option<usize>,
+    /// Emulated UART, RTC and virtio devices.
+    pub devices: Option<usize>,
+    /// Time the VM's vCPUs spend running the guest, in ticks of the `time` CSR.
+    pub cpu_time: Option<u64>,
+}
+
+/// Resources a VM currently consumes, see `VM::resource_usage`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Host pages allocated for the VM, as limited by `ResourceLimits::memory_pages`.
+    pub memory_pages: usize,
+    /// Emulated devices of the VM.
+    pub devices: usize,
+    /// Time the VM's vCPUs have spent running the guest, in ticks of the `time` CSR.
+    pub cpu_time: u64,
+}
+
+impl ResourceLimits {
+    /// CPU time left to a VM that has used `used`, `u64::MAX` if unlimited.
+    pub(crate) fn cpu_time_left(&self, used: u64) -> u64 {
+        self.cpu_time
+            .map_or(u64::MAX, |limit| limit.saturating_sub(used))
+    }
+}
@@ -0,0 +1,58 @@
+use sbi_spec::hsm::{
+    HART_GET_STATUS, HART_START, HART_STOP, HART_SUSPEND, HART_SUSPEND_TYPE_NON_RETENTIVE,
+    HART_SUSPEND_TYPE_RETENTIVE,
+};
+
+use crate::{HyperError, HyperResult};
+
+/// Functions for the Hart State Management extension
+#[derive(Clone, Copy, Debug)]
+pub enum HsmFunction {
+    /// Starts the stopped hart `hartid` in supervisor mode at `start_addr`, with `a0` set to its
+    /// hart id and `a1` to `opaque`.
+    HartStart {
+        hartid: usize,
+        start_addr: usize,
+        opaque: usize,
+    },
+    /// Stops the calling hart. Doesn't return on success.
+    HartStop,
+    /// Returns the HSM state of the hart `hartid`.
+    HartGetStatus(usize),
+    /// Suspends the calling hart until an interrupt is pending for it.
+    HartSuspend {
+        /// Whether the hart resumes at `resume_addr` rather than returning from the call.
+        non_retentive: bool,
+        resume_addr: usize,
+        opaque: usize,
+    },
+}
+
+impl HsmFunction {
+    /// Attempts to parse `Self` from the passed in `a0-a7`.
+    pub(crate) fn from_regs(args: &[usize]) -> HyperResult<Self> {
+        match args[6] {
+            HART_START => Ok(Self::HartStart {
+                hartid: args[0],
+                start_addr: args[1],
+                opaque: args[2],
+            }),
+            HART_STOP => Ok(Self::HartStop),
+            HART_GET_STATUS => Ok(Self::HartGetStatus(args[0])),
+            HART_SUSPEND => {
+                let non_retentive = match args[0] as u32 {
+                    HART_SUSPEND_TYPE_RETENTIVE => false,
+                    HART_SUSPEND_TYPE_NON_RETENTIVE => true,
+                    // Platform specific suspend types.
+                    _ => return Err(HyperError::NotSupported),
+                };
+                Ok(Self::HartSuspend {
+                    non_retentive,
+                    resume_addr: args[1],
+                    opaque: args[2],
+                })
+            }
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+}
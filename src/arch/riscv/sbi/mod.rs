@@ -1,16 +1,18 @@
 mod base;
 mod dbcn;
+mod hsm;
 mod pmu;
 mod rfnc;
 mod srst;
 
-use crate::{HyperError, HyperResult};
+use crate::{logging::LogContext, HyperError, HyperResult};
 pub use base::BaseFunction;
 use dbcn::DebugConsoleFunction;
+pub use hsm::HsmFunction;
 pub use pmu::PmuFunction;
 pub use rfnc::RemoteFenceFunction;
 use sbi_spec;
-pub use srst::ResetFunction;
+pub use srst::{ResetFunction, ResetReason, ResetType};
 
 pub const SBI_SUCCESS: usize = 0;
 pub const SBI_ERR_FAILUER: isize = -1;
@@ -20,6 +22,10 @@ pub const SBI_ERR_DENIED: isize = -4;
 pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
 pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
 
+/// Firmware-specific extension through which guests query the ISA extensions exposed to them.
+/// Its function 0 returns them in `a1`, as laid out by `IsaExtensions::bits`.
+pub const EID_HYPERCRAFT_ISA: usize = 0x0A00_0000;
+
 /// The values returned from an SBI function call.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct SbiReturn {
@@ -58,6 +64,10 @@ pub enum SbiMessage {
     RemoteFence(RemoteFenceFunction),
     /// The PMU Extension
     PMU(PmuFunction),
+    /// The Hart State Management extension.
+    HSM(HsmFunction),
+    /// Queries the ISA extensions exposed to the guest.
+    GetIsaExtensions,
 }
 
 impl SbiMessage {
@@ -76,10 +86,16 @@ impl SbiMessage {
                 RemoteFenceFunction::from_args(args).map(SbiMessage::RemoteFence)
             }
             sbi_spec::pmu::EID_PMU => PmuFunction::from_regs(args).map(SbiMessage::PMU),
+            sbi_spec::hsm::EID_HSM => HsmFunction::from_regs(args).map(SbiMessage::HSM),
+            EID_HYPERCRAFT_ISA if args[6] == 0 => Ok(SbiMessage::GetIsaExtensions),
             _ => {
-                error!("args: {:?}", args);
-                error!("args[7]: {:#x}", args[7]);
-                error!("EID_RFENCE: {:#x}", sbi_spec::rfnc::EID_RFNC);
+                hv_log!(
+                    Debug,
+                    Sbi,
+                    LogContext::NONE,
+                    "Unsupported SBI extension {:#x}",
+                    args[7]
+                );
                 Err(HyperError::NotFound)
             }
         }
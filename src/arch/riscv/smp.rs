@@ -5,8 +5,8 @@ use alloc::{collections::VecDeque, vec::Vec};
 use spin::{Mutex, Once};
 
 use crate::{
-    memory::PAGE_SIZE_4K, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HostVirtAddr, HyperCraftHal, HyperError, HyperResult, VCpu,
+    logging::LogContext, memory::PAGE_SIZE_4K, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr,
+    HostPhysAddr, HostVirtAddr, HyperCraftHal, HyperError, HyperResult, VCpu,
 };
 
 use super::detect::detect_h_extension;
@@ -32,15 +32,27 @@ impl<H: HyperCraftHal> PerCpu<H> {
         // TODO: get cpu info by device tree
         let cpu_nums: usize = 1;
         let pcpu_size = core::mem::size_of::<PerCpu<H>>() * cpu_nums;
-        debug!("pcpu_size: {:#x}", pcpu_size);
+        hv_log!(Debug, Hart, LogContext::NONE, "pcpu_size: {:#x}", pcpu_size);
         let pcpu_pages = H::alloc_pages((pcpu_size + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K)
             .ok_or(HyperError::NoMemory)?;
-        debug!("pcpu_pages: {:#x}", pcpu_pages);
+        hv_log!(
+            Debug,
+            Hart,
+            LogContext::NONE,
+            "pcpu_pages: {:#x}",
+            pcpu_pages
+        );
         PER_CPU_BASE.call_once(|| pcpu_pages);
         for cpu_id in 0..cpu_nums {
             let stack_top_addr = if cpu_id == boot_hart_id {
                 let boot_stack_top = Self::boot_cpu_stack()?;
-                debug!("boot_stack_top: {:#x}", boot_stack_top);
+                hv_log!(
+                    Debug,
+                    Hart,
+                    LogContext::NONE,
+                    "boot_stack_top: {:#x}",
+                    boot_stack_top
+                );
                 boot_stack_top
             } else {
                 H::alloc_pages((stack_size + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K)
@@ -96,6 +108,11 @@ impl<H: HyperCraftHal> PerCpu<H> {
         pcpu
     }
 
+    /// Get the ID of this CPU.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
     /// Get stack top addr.
     pub fn stack_top_addr(&self) -> HostVirtAddr {
         self.stack_top_addr
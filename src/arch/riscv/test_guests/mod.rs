@@ -0,0 +1,43 @@
+//! Minimal riscv guests with known-good behavior, for testing the hypervisor and its integration.
+//!
+//! The guests are assembled together with the crate and are position independent: load an image
+//! anywhere in guest RAM, point `sepc` at its first byte and run it with `satp` set to bare. Each
+//! guest prints `<name>: PASS` or `<name>: FAIL` on the SBI console and then shuts down through
+//! the SRST extension, with reason `SystemFailure` if the test failed: running it with `VM::run`
+//! returns `StopReason::SystemReset` carrying that reason.
+
+use core::arch::global_asm;
+
+global_asm!(
+    include_str!("common.S"),
+    include_str!("mmio_poke.S"),
+    include_str!("timer.S"),
+);
+
+extern "C" {
+    static _test_guest_mmio_poke_start: u8;
+    static _test_guest_mmio_poke_end: u8;
+    static _test_guest_timer_start: u8;
+    static _test_guest_timer_end: u8;
+}
+
+macro_rules! guest_image {
+    ($start:ident, $end:ident) => {
+        // Safety: the symbols delimit a read-only blob emitted by `global_asm!` above.
+        unsafe {
+            let start = core::ptr::addr_of!($start);
+            let len = core::ptr::addr_of!($end) as usize - start as usize;
+            core::slice::from_raw_parts(start, len)
+        }
+    };
+}
+
+/// Stores to and loads from the emulated PLIC, exercising MMIO trap-and-emulate.
+pub fn mmio_poke() -> &'static [u8] {
+    guest_image!(_test_guest_mmio_poke_start, _test_guest_mmio_poke_end)
+}
+
+/// Arms a timer through SBI and waits for the virtual supervisor timer interrupt.
+pub fn timer() -> &'static [u8] {
+    guest_image!(_test_guest_timer_start, _test_guest_timer_end)
+}
@@ -0,0 +1,92 @@
+//! Maintenance of the TLB entries caching guest translations.
+//!
+//! Guest-physical (G-stage) and combined VS-stage translations are tagged with the VMID in
+//! `hgatp`, so fences are scoped to the VMID of the VM whose mappings changed, and to the changed
+//! pages if there are few of them. A fence only applies to the executing hart: the other harts are
+//! reached through `HyperCraftHal::flush_guest_tlb`, the host's remote fence path.
+use core::arch::riscv64;
+
+use crate::{memory::PAGE_SIZE_4K, GuestPhysAddr, GuestVirtAddr, HyperCraftHal};
+
+/// Ranges spanning more pages than this are fenced as a whole VMID or ASID.
+const MAX_FENCED_PAGES: usize = 64;
+
+/// The VMID field of `hgatp`.
+pub(crate) const HGATP_VMID_SHIFT: usize = 44;
+pub(crate) const HGATP_VMID_MASK: usize = 0x3fff;
+
+/// The VMID in the `hgatp` value `hgatp`.
+pub fn vmid_of(hgatp: usize) -> usize {
+    (hgatp >> HGATP_VMID_SHIFT) & HGATP_VMID_MASK
+}
+
+/// Invalidates the guest translations of all VMs on this hart.
+pub fn hfence_gvma_all() {
+    unsafe { riscv64::hfence_gvma_all() };
+}
+
+/// Invalidates the guest translations of the VM with VMID `vmid` on this hart.
+pub fn hfence_gvma_vmid(vmid: usize) {
+    unsafe { riscv64::hfence_gvma_vmid(vmid) };
+}
+
+/// Invalidates the guest-physical translations of `[gpa, gpa + size)` of the VM with VMID `vmid`
+/// on this hart.
+pub fn hfence_gvma_range(vmid: usize, gpa: GuestPhysAddr, size: usize) {
+    let pages = size.div_ceil(PAGE_SIZE_4K);
+    if pages > MAX_FENCED_PAGES {
+        return hfence_gvma_vmid(vmid);
+    }
+    let start = gpa & !(PAGE_SIZE_4K - 1);
+    for page in 0..pages {
+        // The guest physical address operand is shifted right by 2.
+        unsafe { riscv64::hfence_gvma((start + page * PAGE_SIZE_4K) >> 2, vmid) };
+    }
+}
+
+/// Invalidates the VS-stage translations of the VM loaded in `hgatp` on this hart.
+pub fn hfence_vvma_all() {
+    unsafe { riscv64::hfence_vvma_all() };
+}
+
+/// Invalidates the VS-stage translations of `[va, va + size)` in the guest address space `asid`
+/// of the VM loaded in `hgatp` on this hart.
+pub fn hfence_vvma_range(asid: usize, va: GuestVirtAddr, size: usize) {
+    let pages = size.div_ceil(PAGE_SIZE_4K);
+    if pages > MAX_FENCED_PAGES {
+        return unsafe { riscv64::hfence_vvma_asid(asid) };
+    }
+    let start = va & !(PAGE_SIZE_4K - 1);
+    for page in 0..pages {
+        unsafe { riscv64::hfence_vvma(start + page * PAGE_SIZE_4K, asid) };
+    }
+}
+
+/// Invalidates the guest-physical translations of `[gpa, gpa + size)` of the VM whose guest page
+/// table is `hgatp` on all harts, after its mappings there were removed or restricted.
+pub(crate) fn flush_guest_range<H: HyperCraftHal>(hgatp: usize, gpa: GuestPhysAddr, size: usize) {
+    let vmid = vmid_of(hgatp);
+    hfence_gvma_range(vmid, gpa, size);
+    // A vCPU of the VM may still run here with the VMID of an earlier generation, which is fenced
+    // as well. The VMID loaded for another VM is left alone.
+    let loaded: usize;
+    unsafe { core::arch::asm!("csrr {}, hgatp", out(reg) loaded) };
+    let vmid_bits = HGATP_VMID_MASK << HGATP_VMID_SHIFT;
+    if loaded & !vmid_bits == hgatp & !vmid_bits && vmid_of(loaded) != vmid {
+        hfence_gvma_range(vmid_of(loaded), gpa, size);
+    }
+    H::flush_guest_tlb(gpa, size);
+}
+
+/// Invalidates the stale translations a vCPU of the VM whose guest page table is `hgatp` may find
+/// on this hart when it's loaded here, `migrated` telling whether it last ran on another hart.
+/// VMID 0 is shared by VMs without a VMID of their own, so everything is flushed for it.
+pub(crate) fn flush_on_load(hgatp: usize, migrated: bool) {
+    match vmid_of(hgatp) {
+        0 => hfence_gvma_all(),
+        // Remote fences may only reach the harts the VM runs on, so its entries here can predate
+        // changes made while it ran elsewhere.
+        vmid if migrated => hfence_gvma_vmid(vmid),
+        _ => {}
+    }
+}
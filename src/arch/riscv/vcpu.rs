@@ -1,22 +1,37 @@
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::arch::global_asm;
 use core::marker::PhantomData;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use memoffset::offset_of;
 use tock_registers::LocalRegisterCopy;
 
 // use alloc::sync::Arc;
-use riscv::register::{htinst, htval, hvip, scause, sstatus, stval};
+use riscv::register::{htinst, htval, hvip, mcause, scause, sstatus, stval, time};
 
 use crate::arch::vmexit::PrivilegeLevel;
 use crate::arch::{traps, RiscvCsrTrait, CSR};
+use crate::snapshot::{SectionBuilder, SectionReader, SnapshotReader};
+use crate::vcpus::MAX_CPUS;
 use crate::{
-    arch::sbi::SbiMessage, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HyperCraftHal, VmExitInfo,
+    arch::sbi::SbiMessage, EmuContext, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr,
+    HostPhysAddr, HyperCraftHal, HyperError, HyperResult, VmExitInfo,
 };
 
-use super::csrs::defs::hstatus;
+use super::csrs::defs::{
+    hstatus, CSR_HENVCFG, CSR_HTIMEDELTA, CSR_VSATP, CSR_VSCAUSE, CSR_VSEPC, CSR_VSIE,
+    CSR_VSSCRATCH, CSR_VSSTATUS, CSR_VSTIMECMP, CSR_VSTVAL, CSR_VSTVEC,
+};
+use super::exit_stats::{ExitReason, ExitRecorder, ExitStats, ExitTraceEntry};
+use super::fp::FpContext;
+use super::ipi::IpiChannel;
+use super::isa::{IsaExtensions, LCOFI};
 use super::regs::{GeneralPurposeRegisters, GprIndex};
+use super::smp::PerCpu;
+use super::tlb;
+use super::vm_pages::VmPages;
 // use super::Guest;
 
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
@@ -44,7 +59,7 @@ struct GuestCpuState {
 
 /// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
 /// restored whenever we switch between VMs.
-#[derive(Default)]
+#[derive(Default, Clone)]
 #[repr(C)]
 pub struct GuestVsCsrs {
     htimedelta: usize,
@@ -57,6 +72,8 @@ pub struct GuestVsCsrs {
     vstval: usize,
     vsatp: usize,
     vstimecmp: usize,
+    // VS-level interrupts pending in hvip.
+    hvip: usize,
 }
 
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
@@ -90,8 +107,8 @@ pub struct VmCpuRegisters {
     hyp_regs: HypervisorCpuState,
     guest_regs: GuestCpuState,
 
-    // CPU state that only applies when V=1, e.g. the VS-level CSRs. Saved/restored on activation of
-    // the vCPU.
+    // CPU state that only applies when V=1, e.g. the VS-level CSRs. Saved on deactivation of the
+    // vCPU, and restored on activation unless the hart still holds them.
     vs_csrs: GuestVsCsrs,
 
     // Virtualized HS-level CPU state.
@@ -115,6 +132,147 @@ const fn guest_gpr_offset(index: GprIndex) -> usize {
         + (index as usize) * size_of::<u64>()
 }
 
+macro_rules! csr_read {
+    ($csr:expr) => {{
+        let val: usize;
+        unsafe { core::arch::asm!("csrr {val}, {csr}", val = out(reg) val, csr = const $csr) };
+        val
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:expr, $val:expr) => {
+        unsafe { core::arch::asm!("csrw {csr}, {val}", csr = const $csr, val = in(reg) $val) }
+    };
+}
+
+/// Bits of `hstatus.VGEIN`, which depend on the host's interrupt file assignment.
+const HSTATUS_VGEIN_MASK: usize = 0x3f << 12;
+
+/// Token of the next vCPU created, 0 standing for none.
+static NEXT_VCPU_TOKEN: AtomicUsize = AtomicUsize::new(1);
+/// Token of the vCPU whose VS-level CSRs each hart last held, see `VCpu::activate`.
+static HART_VS_OWNER: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// VS-level interrupt bits of `hvip`.
+const HVIP_VS_IRQS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+    | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+    | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+
+// `sstatus` and `vsstatus` fields.
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// An interrupt that can be injected into a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqKind {
+    /// VS-level software interrupt.
+    Software,
+    /// VS-level timer interrupt.
+    Timer,
+    /// VS-level external interrupt.
+    External,
+}
+
+impl IrqKind {
+    pub(crate) fn hvip_bit(self) -> usize {
+        match self {
+            Self::Software => traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+            Self::Timer => traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
+            Self::External => traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL,
+        }
+    }
+}
+
+/// How a vCPU may read a hardware counter: `cycle`, `time`, `instret` or one of the
+/// `hpmcounter`s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterAccess {
+    /// Reads return the hardware counter without trapping.
+    Direct,
+    /// Reads trap and return zero, hiding the counter without faulting guests that probe it.
+    Zero,
+    /// Reads raise an illegal instruction exception in the guest.
+    Denied,
+}
+
+/// Power state of a vCPU, as the guest manages it through the SBI Hart State Management
+/// extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HartState {
+    /// The vCPU runs the guest.
+    Started,
+    /// The vCPU is powered off. It waits in the hypervisor when run, until another vCPU starts it.
+    #[default]
+    Stopped,
+    /// The vCPU has been started, and enters the guest at its start address the next time it runs.
+    StartPending,
+    /// The vCPU waits for an interrupt in `hart_suspend`.
+    Suspended,
+}
+
+/// Guest register state of a vCPU, as returned by `VCpu::state`.
+#[derive(Clone, Debug)]
+pub struct VCpuState {
+    /// x0 to x31.
+    pub gprs: [usize; 32],
+    pub pc: usize,
+    /// Privilege level the guest runs at.
+    pub priv_level: PrivilegeLevel,
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+}
+
+/// Pause state of a vCPU, shared with the harts pausing and resuming it.
+#[derive(Default)]
+struct PauseState {
+    /// Whether the vCPU is asked to pause.
+    requested: AtomicBool,
+    /// Whether the vCPU is loaded on a hart.
+    running: AtomicBool,
+}
+
+/// Pauses and resumes a vCPU from any hart.
+#[derive(Clone)]
+pub struct PauseHandle {
+    state: Arc<PauseState>,
+    /// Kicks the vCPU out of the guest.
+    ipi: IpiChannel,
+}
+
+impl PauseHandle {
+    /// Asks the vCPU to pause. A running vCPU is kicked out of the guest and stops at its next
+    /// exit, `VM::run_scheduled` returning. Until resumed, the vCPU returns right away when run.
+    pub fn pause(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        if self.state.running.load(Ordering::SeqCst) {
+            self.ipi.kick();
+        }
+    }
+
+    /// Lets the vCPU run again.
+    pub fn resume(&self) {
+        self.state.requested.store(false, Ordering::SeqCst);
+    }
+
+    /// Whether the vCPU is paused and off its hart, so its state can be inspected.
+    pub fn is_paused(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst) && !self.state.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Number of counters, indexed like the bits of `hcounteren`.
+const NUM_COUNTERS: usize = 32;
+/// Number of the `cycle` CSR, the first counter CSR.
+const CSR_CYCLE: u32 = 0xc00;
+
 #[allow(unused_macros)]
 macro_rules! hyp_csr_offset {
     ($reg:tt) => {
@@ -211,11 +369,43 @@ pub enum VmCpuStatus {
     Running,
 }
 
-#[derive(Default)]
 /// A virtual CPU within a guest
 pub struct VCpu<H: HyperCraftHal> {
     vcpu_id: usize,
     regs: VmCpuRegisters,
+    // Harts the vCPU may run on.
+    affinity: usize,
+    // Hart whose guest interrupt file the vCPU is bound to, the only one it may run on.
+    guest_file_hart: Option<usize>,
+    // Hart the vCPU's guest state is loaded on.
+    loaded_on: Option<usize>,
+    // Hart the vCPU was last loaded on.
+    last_hart: Option<usize>,
+    // Identifies the vCPU as the owner of a hart's VS-level CSRs.
+    token: usize,
+    // Whether the saved VS-level CSRs are those the vCPU left on `last_hart`, i.e. they weren't
+    // changed while it was off the hart.
+    vs_synced: bool,
+    // Counters the guest reads directly, as loaded into `hcounteren`.
+    counters_direct: u32,
+    // Counters whose reads are emulated as returning zero.
+    counters_zero: u32,
+    // Statistics and trace of the vCPU's exits.
+    exits: ExitRecorder,
+    // Whether EBREAK traps to the hypervisor rather than to the guest.
+    debug: bool,
+    // Pause requests, shared with the harts pausing the vCPU.
+    pause: Arc<PauseState>,
+    // Requests posted by other harts, and the doorbell kicking the vCPU out of the guest.
+    ipi: IpiChannel,
+    // Power state of the vCPU, as the guest sees it.
+    hart_state: HartState,
+    // Where the vCPU enters the guest when started or resumed, and the opaque value it's given.
+    resume_at: Option<(GuestPhysAddr, usize)>,
+    // ISA extensions enabled for the guest while the vCPU is loaded.
+    isa: IsaExtensions,
+    // FP and vector registers of the guest, and of the host while the vCPU is loaded.
+    fp: FpContext,
     // gpt: G,
     // pub guest: Arc<Guest>,
     marker: PhantomData<H>,
@@ -245,9 +435,27 @@ impl<H: HyperCraftHal> VCpu<H> {
 
         // Set entry
         regs.guest_regs.sepc = entry;
+        // No timer interrupt until the guest programs `stimecmp`, if Sstc is enabled.
+        regs.vs_csrs.vstimecmp = usize::MAX;
         Self {
             vcpu_id,
             regs,
+            affinity: usize::MAX,
+            guest_file_hart: None,
+            loaded_on: None,
+            last_hart: None,
+            token: NEXT_VCPU_TOKEN.fetch_add(1, Ordering::Relaxed),
+            vs_synced: false,
+            counters_direct: u32::MAX,
+            counters_zero: 0,
+            exits: ExitRecorder::default(),
+            debug: false,
+            pause: Arc::default(),
+            ipi: IpiChannel::new(vcpu_id, H::send_ipi, H::vcpu_interrupt_pending),
+            hart_state: HartState::Started,
+            resume_at: None,
+            isa: IsaExtensions::host().intersection(&IsaExtensions::GUEST_DEFAULT),
+            fp: FpContext::default(),
             // gpt,
             marker: PhantomData,
         }
@@ -255,16 +463,15 @@ impl<H: HyperCraftHal> VCpu<H> {
 
     /// Initialize nested mmu.
     pub fn init_page_map(&mut self, token: usize) {
-        // Set hgatp
-        // TODO: Sv39 currently, but should be configurable
+        // Set hgatp, whose mode is the one of the guest page table.
         self.regs.virtual_hs_csrs.hgatp = token;
         unsafe {
             core::arch::asm!(
                 "csrw hgatp, {hgatp}",
                 hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
             );
-            core::arch::riscv64::hfence_gvma_all();
         }
+        tlb::hfence_gvma_all();
     }
 
     /// Restore vCPU registers from the guest's GPRs
@@ -290,33 +497,128 @@ impl<H: HyperCraftHal> VCpu<H> {
         }
     }
 
-    /// Runs this vCPU until traps.
+    /// Runs this vCPU until traps. Requests posted through its `IpiChannel` are serviced before
+    /// entering the guest, and the guest is re-entered right away after a kick that only posted
+    /// requests.
     pub fn run(&mut self) -> VmExitInfo {
+        let hart = self
+            .loaded_on
+            .unwrap_or_else(|| PerCpu::<H>::this_cpu().cpu_id());
+        loop {
+            while !self.ipi.enter(hart) {
+                self.service_posted();
+            }
+            self.exits.on_entry(time::read() as u64);
+            let exit = self.run_guest();
+            self.ipi.exit();
+            let reason = ExitReason::from(&exit);
+            let pc = self.regs.guest_regs.sepc;
+            self.exits.on_exit(reason, pc, time::read() as u64);
+            if let VmExitInfo::HostInterruot(mcause::Interrupt::SupervisorSoft) = exit {
+                // Requests posted from now on ring the doorbell again.
+                unsafe {
+                    core::arch::asm!("csrc sip, {}", in(reg) traps::interrupt::SUPERVISOR_SOFT);
+                }
+                if self.service_posted() && !self.pause_requested() {
+                    continue;
+                }
+            }
+            return exit;
+        }
+    }
+
+    /// Services the requests posted through the vCPU's `IpiChannel`, returning whether there were
+    /// any. The vCPU must be the one loaded on this hart.
+    fn service_posted(&mut self) -> bool {
+        let posted = self.ipi.take();
+        if posted.irqs != 0 {
+            CSR.hvip.read_and_set_bits(posted.irqs);
+        }
+        if posted.tlb_flush {
+            tlb::hfence_gvma_vmid(tlb::vmid_of(self.regs.virtual_hs_csrs.hgatp));
+            tlb::hfence_vvma_all();
+        }
+        !posted.is_empty()
+    }
+
+    /// Exit counts and handling times of this vCPU since it was created or they were reset.
+    pub fn stats(&self) -> &ExitStats {
+        self.exits.stats()
+    }
+
+    /// Resets the exit statistics of this vCPU.
+    pub fn reset_stats(&mut self) {
+        self.exits.reset_stats();
+    }
+
+    /// Starts recording the last `capacity` exits of this vCPU, discarding those recorded so far.
+    pub fn enable_trace(&mut self, capacity: usize) {
+        self.exits.set_trace_capacity(capacity);
+    }
+
+    /// Stops recording exits and discards the trace.
+    pub fn disable_trace(&mut self) {
+        self.exits.set_trace_capacity(0);
+    }
+
+    /// Returns the exits recorded in the trace, oldest first.
+    pub fn dump_trace(&self) -> Vec<ExitTraceEntry> {
+        self.exits.trace()
+    }
+
+    fn run_guest(&mut self) -> VmExitInfo {
         let regs = &mut self.regs;
+        if self.debug {
+            CSR.hedeleg
+                .read_and_clear_bits(traps::exception::BREAKPOINT);
+        }
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
             _run_guest(regs);
         }
-        // Save off the trap information
-        regs.trap_csrs.scause = scause::read().bits();
+        if self.debug {
+            CSR.hedeleg.read_and_set_bits(traps::exception::BREAKPOINT);
+        }
+        // Save off the trap information. `htval` and `htinst` only matter for guest page faults.
+        let scause = scause::read();
+        regs.trap_csrs.scause = scause.bits();
         regs.trap_csrs.stval = stval::read();
-        regs.trap_csrs.htval = htval::read();
-        regs.trap_csrs.htinst = htinst::read();
+        regs.trap_csrs.htval = 0;
+        regs.trap_csrs.htinst = 0;
 
-        let scause = scause::read();
         use scause::{Exception, Interrupt, Trap};
         match scause.cause() {
             Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
                 let sbi_msg = SbiMessage::from_regs(regs.guest_regs.gprs.a_regs()).ok();
                 VmExitInfo::Ecall(sbi_msg)
             }
+            Trap::Exception(Exception::Breakpoint) => VmExitInfo::DebugEvent {
+                pc: regs.guest_regs.sepc,
+            },
+            Trap::Interrupt(Interrupt::SupervisorSoft) => {
+                VmExitInfo::HostInterruot(mcause::Interrupt::SupervisorSoft)
+            }
             Trap::Interrupt(Interrupt::SupervisorTimer) => VmExitInfo::TimerInterruptEmulation,
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 VmExitInfo::ExternalInterruptEmulation
             }
-            Trap::Exception(Exception::LoadGuestPageFault)
+            Trap::Exception(Exception::VirtualInstruction) => VmExitInfo::VirtualInstruction {
+                fault_pc: regs.guest_regs.sepc,
+                // `stval` may not hold the instruction, which is then read from guest memory.
+                inst: match regs.trap_csrs.stval as u32 {
+                    0 => VmPages
+                        .fetch_guest_instruction(regs.guest_regs.sepc)
+                        .unwrap_or(0),
+                    inst => inst,
+                },
+                priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+            },
+            Trap::Exception(Exception::InstructionGuestPageFault)
+            | Trap::Exception(Exception::LoadGuestPageFault)
             | Trap::Exception(Exception::StoreGuestPageFault) => {
+                regs.trap_csrs.htval = htval::read();
+                regs.trap_csrs.htinst = htinst::read();
                 let fault_addr = regs.trap_csrs.htval << 2 | regs.trap_csrs.stval & 0x3;
                 // debug!(
                 //     "fault_addr: {:#x}, htval: {:#x}, stval: {:#x}, sepc: {:#x}, scause: {:?}",
@@ -339,14 +641,11 @@ impl<H: HyperCraftHal> VCpu<H> {
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
                 }
             }
-            _ => {
-                panic!(
-                    "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
-                    scause.cause(),
-                    regs.guest_regs.sepc,
-                    regs.trap_csrs.stval
-                );
-            }
+            _ => VmExitInfo::UnhandledTrap {
+                cause: scause.bits(),
+                fault_pc: regs.guest_regs.sepc,
+                tval: regs.trap_csrs.stval,
+            },
         }
     }
 
@@ -360,36 +659,587 @@ impl<H: HyperCraftHal> VCpu<H> {
         self.regs.guest_regs.gprs.set_reg(index, val);
     }
 
+    /// Gets the guest pc.
+    pub fn pc(&self) -> usize {
+        self.regs.guest_regs.sepc
+    }
+
+    /// Sets the guest pc.
+    pub fn set_pc(&mut self, pc: usize) {
+        self.regs.guest_regs.sepc = pc;
+    }
+
     /// Advance guest pc by `instr_len` bytes
     pub fn advance_pc(&mut self, instr_len: usize) {
         self.regs.guest_regs.sepc += instr_len
     }
 
+    /// Selects the guest interrupt file `vgein` of `hart` as the source of the vCPU's VS-level
+    /// external interrupts, binding the vCPU to `hart`. Fails with `BadState` unless the vCPU is
+    /// restricted to `hart` alone, and its affinity can't be changed until it's unbound.
+    pub fn bind_guest_file(&mut self, hart: usize, vgein: usize) -> HyperResult<()> {
+        if hart >= usize::BITS as usize || self.affinity != 1 << hart {
+            return Err(HyperError::BadState);
+        }
+        self.guest_file_hart = Some(hart);
+        self.set_vgein(vgein);
+        Ok(())
+    }
+
+    /// Stops taking VS-level external interrupts from the guest interrupt file the vCPU is bound
+    /// to with `bind_guest_file`, if any.
+    pub fn unbind_guest_file(&mut self) {
+        self.guest_file_hart = None;
+        self.set_vgein(0);
+    }
+
+    fn set_vgein(&mut self, vgein: usize) {
+        let mut hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vgein.val(vgein));
+        self.regs.guest_regs.hstatus = hstatus.get();
+    }
+
+    /// Delivers the exception `cause` with trap value `tval` to the guest, as if it had been taken
+    /// in VS-mode: the guest resumes at its trap vector with the trap recorded in its VS-level CSRs.
+    /// The vCPU must be the one loaded on this hart, e.g. while handling its exit.
+    pub fn inject_exception(&mut self, cause: usize, tval: usize) -> HyperResult<()> {
+        if cause >= usize::BITS as usize {
+            return Err(HyperError::InvalidParam);
+        }
+        let guest = &mut self.regs.guest_regs;
+        let mut vsstatus = csr_read!(CSR_VSSTATUS);
+        // Trap entry: save the interrupt enable and the privilege level the guest trapped from.
+        vsstatus &= !(SSTATUS_SPIE | SSTATUS_SPP);
+        if vsstatus & SSTATUS_SIE != 0 {
+            vsstatus |= SSTATUS_SPIE;
+        }
+        vsstatus &= !SSTATUS_SIE;
+        vsstatus |= guest.sstatus & SSTATUS_SPP;
+        csr_write!(CSR_VSSTATUS, vsstatus);
+        csr_write!(CSR_VSEPC, guest.sepc);
+        csr_write!(CSR_VSCAUSE, cause);
+        csr_write!(CSR_VSTVAL, tval);
+        // Exceptions always go to the vector base, whatever the vectoring mode.
+        guest.sepc = csr_read!(CSR_VSTVEC) & !0x3;
+        guest.sstatus |= SSTATUS_SPP;
+        Ok(())
+    }
+
+    /// Makes the interrupt `kind` pending for the guest. It's taken once the guest enables it, in
+    /// `vsie` and `vsstatus.SIE`. The vCPU must be the one loaded on this hart.
+    pub fn inject_irq(&mut self, kind: IrqKind) {
+        CSR.hvip.read_and_set_bits(kind.hvip_bit());
+    }
+
+    /// Clears the pending interrupt `kind` of the guest, e.g. once the device raising it has been
+    /// serviced. The vCPU must be the one loaded on this hart.
+    pub fn clear_irq(&mut self, kind: IrqKind) {
+        CSR.hvip.read_and_clear_bits(kind.hvip_bit());
+    }
+
+    /// Whether the interrupt `kind` is pending for the guest.
+    pub fn irq_pending(&self, kind: IrqKind) -> bool {
+        CSR.hvip.get_value() & kind.hvip_bit() != 0
+    }
+
+    /// The guest's `stimecmp`, if it programs its timer through Sstc. The vCPU must be the one
+    /// loaded on this hart.
+    pub(crate) fn stimecmp(&self) -> Option<u64> {
+        self.isa.sstc.then(|| csr_read!(CSR_VSTIMECMP) as u64)
+    }
+
+    /// Whether any interrupt is pending for the guest, which wakes it up from WFI.
+    pub(crate) fn any_irq_pending(&self) -> bool {
+        CSR.hvip.get_value() & HVIP_VS_IRQS != 0 || self.ipi.irqs_posted()
+    }
+
+    /// Completes the emulated MMIO access `emu_ctx`: a load writes `val`, as read from the device,
+    /// into its destination register with the access' width and sign extension applied. The guest
+    /// then resumes after the trapping instruction.
+    pub fn complete_mmio(&mut self, emu_ctx: &EmuContext, val: usize) {
+        if !emu_ctx.write && emu_ctx.reg != 0 {
+            let reg = GprIndex::from_raw(emu_ctx.reg as u32).unwrap();
+            self.set_gpr(reg, emu_ctx.read_value(val));
+        }
+        self.advance_pc(emu_ctx.inst_len);
+    }
+
+    /// Restricts the vCPU to the harts set in `hart_mask`. Fails with `BadState` if the vCPU is
+    /// bound to a guest interrupt file and `hart_mask` isn't the file's hart alone.
+    pub fn set_affinity(&mut self, hart_mask: usize) -> HyperResult<()> {
+        if hart_mask == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self
+            .guest_file_hart
+            .is_some_and(|hart| hart_mask != 1 << hart)
+        {
+            return Err(HyperError::BadState);
+        }
+        self.affinity = hart_mask;
+        Ok(())
+    }
+
+    /// Sets the `hgatp` value the vCPU runs with, the VM's guest page table tagged with its VMID.
+    /// Takes effect the next time the vCPU is activated on a hart.
+    pub(crate) fn set_hgatp(&mut self, hgatp: usize) {
+        self.regs.virtual_hs_csrs.hgatp = hgatp;
+    }
+
+    /// The harts the vCPU may run on, as a mask.
+    pub fn affinity(&self) -> usize {
+        self.affinity
+    }
+
+    /// Loads the vCPU's guest state into the current hart `hart_id` so it can run there: its
+    /// VS-level CSRs, its pending VS-level interrupts, its FP and vector registers and its guest
+    /// page table, whose stale translations on this hart are flushed. Does nothing if the vCPU is
+    /// already loaded on this hart. Fails if `hart_id` isn't in the vCPU's affinity.
+    pub fn activate(&mut self, hart_id: usize) -> HyperResult<()> {
+        if hart_id >= usize::BITS as usize || self.affinity & (1 << hart_id) == 0 {
+            return Err(HyperError::BadState);
+        }
+        match self.loaded_on {
+            Some(hart) if hart == hart_id => return Ok(()),
+            // The state on the other hart would be lost.
+            Some(_) => return Err(HyperError::BadState),
+            None => {}
+        }
+        // A vCPU running again on the hart it left, with no other vCPU having run there since,
+        // finds its VS-level CSRs still loaded, except for its interrupts.
+        let owner = HART_VS_OWNER.get(hart_id);
+        if self.vs_synced
+            && self.last_hart == Some(hart_id)
+            && owner.is_some_and(|owner| owner.load(Ordering::Relaxed) == self.token)
+        {
+            CSR.hvip.read_and_set_bits(self.regs.vs_csrs.hvip);
+        } else {
+            self.restore_vs_csrs();
+            if let Some(owner) = owner {
+                owner.store(self.token, Ordering::Relaxed);
+            }
+        }
+        // The guest's FP and vector registers stay loaded across exits: the hypervisor doesn't
+        // use them, and they're only saved back when deactivating if the guest dirtied them.
+        self.fp
+            .configure(&mut self.regs.guest_regs.sstatus, &self.isa);
+        self.fp.load(&mut self.regs.guest_regs.sstatus);
+        let hgatp = self.regs.virtual_hs_csrs.hgatp;
+        unsafe { core::arch::asm!("csrw hgatp, {}", in(reg) hgatp) };
+        tlb::flush_on_load(hgatp, self.last_hart.is_some_and(|hart| hart != hart_id));
+        CSR.hcounteren.write_value(self.counters_direct as usize);
+        csr_write!(CSR_HENVCFG, self.isa.henvcfg());
+        if self.isa.sscofpmf {
+            CSR.hideleg.read_and_set_bits(LCOFI);
+        } else {
+            CSR.hideleg.read_and_clear_bits(LCOFI);
+        }
+        self.loaded_on = Some(hart_id);
+        self.last_hart = Some(hart_id);
+        self.pause.running.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Saves the vCPU's guest state from the hart it's loaded on, which must be the current one,
+    /// so it can be activated on another hart or another vCPU can run on this one.
+    pub fn deactivate(&mut self) {
+        if self.loaded_on.take().is_some() {
+            self.save_vs_csrs();
+            self.fp.put(&mut self.regs.guest_regs.sstatus);
+            CSR.hvip.read_and_clear_bits(HVIP_VS_IRQS);
+            self.pause.running.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Sets how the guest may read counter `counter`, 0 being `cycle`, 1 `time`, 2 `instret` and
+    /// 3 to 31 `hpmcounter3` to `hpmcounter31`. All counters are read directly by default. Takes
+    /// effect the next time the vCPU is activated on a hart.
+    pub fn set_counter_access(&mut self, counter: usize, access: CounterAccess) -> HyperResult<()> {
+        if counter >= NUM_COUNTERS {
+            return Err(HyperError::InvalidParam);
+        }
+        let bit = 1 << counter;
+        self.counters_direct &= !bit;
+        self.counters_zero &= !bit;
+        match access {
+            CounterAccess::Direct => self.counters_direct |= bit,
+            CounterAccess::Zero => self.counters_zero |= bit,
+            CounterAccess::Denied => {}
+        }
+        Ok(())
+    }
+
+    /// How the guest may read counter `counter`.
+    pub fn counter_access(&self, counter: usize) -> CounterAccess {
+        let bit = 1u32.checked_shl(counter as u32).unwrap_or(0);
+        if self.counters_direct & bit != 0 {
+            CounterAccess::Direct
+        } else if self.counters_zero & bit != 0 {
+            CounterAccess::Zero
+        } else {
+            CounterAccess::Denied
+        }
+    }
+
+    /// The ISA extensions enabled for the guest.
+    pub fn isa_extensions(&self) -> IsaExtensions {
+        self.isa
+    }
+
+    /// Enables the ISA extensions `isa` for the guest, which the host must implement. Takes
+    /// effect the next time the vCPU is activated on a hart.
+    pub(crate) fn set_isa_extensions(&mut self, isa: IsaExtensions) {
+        self.isa = isa;
+    }
+
+    /// If the virtual instruction `inst` is a read of a counter whose reads return zero, returns
+    /// the register the value goes to. Reads from VU-mode are only emulated if the guest kernel
+    /// allows them in `scounteren`.
+    pub(crate) fn zeroed_counter_read(&self, inst: u32) -> Option<GprIndex> {
+        const OPCODE_SYSTEM: u32 = 0x73;
+        // CSRRS, CSRRC, CSRRSI and CSRRCI, which only read the CSR with a zero rs1 or uimm.
+        let funct3 = (inst >> 12) & 0x7;
+        if inst & 0x7f != OPCODE_SYSTEM
+            || !matches!(funct3, 0b010 | 0b011 | 0b110 | 0b111)
+            || (inst >> 15) & 0x1f != 0
+        {
+            return None;
+        }
+        let counter = (inst >> 20).checked_sub(CSR_CYCLE)? as usize;
+        if counter >= NUM_COUNTERS || self.counters_zero & (1 << counter) == 0 {
+            return None;
+        }
+        let guest = &self.regs.guest_regs;
+        if guest.sstatus & SSTATUS_SPP == 0 && guest.scounteren & (1 << counter) == 0 {
+            return None;
+        }
+        GprIndex::from_raw((inst >> 7) & 0x1f)
+    }
+
+    /// Whether the guest kernel lets VU-mode read counter `counter` through `scounteren`.
+    pub(crate) fn user_counter_enabled(&self, counter: usize) -> bool {
+        counter < NUM_COUNTERS && self.regs.guest_regs.scounteren & (1 << counter) != 0
+    }
+
+    /// The guest's `time`, offset from the hart's by `htimedelta`. The vCPU must be the one loaded
+    /// on this hart.
+    pub(crate) fn guest_time(&self) -> u64 {
+        (time::read() as u64).wrapping_add(csr_read!(CSR_HTIMEDELTA) as u64)
+    }
+
+    /// Makes WFI executed by the guest trap as a virtual instruction, so the host can schedule
+    /// another vCPU instead of stalling the hart.
+    pub fn set_wfi_exit(&mut self, enabled: bool) {
+        let mut hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vtw.val(enabled as usize));
+        self.regs.guest_regs.hstatus = hstatus.get();
+    }
+
+    /// Whether WFI executed by the guest traps, see `set_wfi_exit`.
+    pub fn wfi_exit(&self) -> bool {
+        let hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.is_set(hstatus::vtw)
+    }
+
     /// Gets the vCPU's id.
     pub fn vcpu_id(&self) -> usize {
         self.vcpu_id
     }
 
+    /// Puts the vCPU in debug mode, where EBREAK executed by the guest traps to the hypervisor as
+    /// `VmExitInfo::DebugEvent` rather than to the guest, so it can hit breakpoints placed in
+    /// guest memory.
+    pub fn set_debug(&mut self, enabled: bool) {
+        self.debug = enabled;
+    }
+
+    /// Whether the vCPU is in debug mode.
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    /// The guest's `vsatp`. A vCPU loaded on a hart must be loaded on the current one.
+    pub(crate) fn vsatp(&self) -> usize {
+        self.vs_csrs().vsatp
+    }
+
+    /// The guest's `vsepc`. A vCPU loaded on a hart must be loaded on the current one.
+    pub(crate) fn vsepc(&self) -> usize {
+        self.vs_csrs().vsepc
+    }
+
+    /// Returns a handle through which any hart can pause and resume the vCPU.
+    pub fn pause_handle(&self) -> PauseHandle {
+        PauseHandle {
+            state: self.pause.clone(),
+            ipi: self.ipi.clone(),
+        }
+    }
+
+    /// Returns the channel through which any hart can post interrupts and TLB flushes to the vCPU,
+    /// kicking it out of the guest.
+    pub fn ipi_channel(&self) -> IpiChannel {
+        self.ipi.clone()
+    }
+
+    /// Asks the vCPU to pause, see `PauseHandle::pause`.
+    pub fn pause(&self) {
+        self.pause_handle().pause();
+    }
+
+    /// Lets the paused vCPU run again.
+    pub fn resume(&self) {
+        self.pause_handle().resume();
+    }
+
+    /// Whether the vCPU is paused and off its hart.
+    pub fn is_paused(&self) -> bool {
+        self.pause_handle().is_paused()
+    }
+
+    /// The vCPU's power state.
+    pub fn hart_state(&self) -> HartState {
+        self.hart_state
+    }
+
+    /// Powers off the vCPU, e.g. a secondary vCPU the guest brings up through SBI HSM. When run,
+    /// the vCPU waits in the hypervisor until another vCPU starts it with `hart_start`.
+    pub fn power_off(&mut self) {
+        self.hart_state = HartState::Stopped;
+        self.resume_at = None;
+    }
+
+    /// Starts the stopped vCPU at `start_addr` with `opaque` in `a1`. It enters the guest there the
+    /// next time it runs, see `wake`. Fails with `BadState` if the vCPU isn't stopped.
+    pub(crate) fn start(&mut self, start_addr: GuestPhysAddr, opaque: usize) -> HyperResult<()> {
+        if self.hart_state != HartState::Stopped {
+            return Err(HyperError::BadState);
+        }
+        self.hart_state = HartState::StartPending;
+        self.resume_at = Some((start_addr, opaque));
+        Ok(())
+    }
+
+    /// Suspends the vCPU until it's woken, resuming at `resume_at` with the given opaque value in
+    /// `a1` if set, otherwise where it left the guest.
+    pub(crate) fn suspend(&mut self, resume_at: Option<(GuestPhysAddr, usize)>) {
+        self.hart_state = HartState::Suspended;
+        self.resume_at = resume_at;
+    }
+
+    /// Moves a vCPU that's start pending or suspended to the started state. If it has a start or
+    /// resume address, the guest enters it in VS-mode with translation and interrupts disabled, its
+    /// hart id in `a0` and the opaque value in `a1`. The vCPU must be the one loaded on this hart.
+    pub(crate) fn wake(&mut self) {
+        if !matches!(
+            self.hart_state,
+            HartState::StartPending | HartState::Suspended
+        ) {
+            return;
+        }
+        self.hart_state = HartState::Started;
+        if let Some((addr, opaque)) = self.resume_at.take() {
+            let guest = &mut self.regs.guest_regs;
+            guest.sepc = addr;
+            guest.sstatus |= SSTATUS_SPP;
+            guest.gprs.set_reg(GprIndex::A0, self.vcpu_id);
+            guest.gprs.set_reg(GprIndex::A1, opaque);
+            let vsstatus = csr_read!(CSR_VSSTATUS);
+            csr_write!(CSR_VSSTATUS, vsstatus & !SSTATUS_SIE);
+            csr_write!(CSR_VSATP, 0);
+        }
+    }
+
+    /// The `stval` of the vCPU's last exit, e.g. the guest virtual address of a faulting access.
+    pub(crate) fn trap_value(&self) -> usize {
+        self.regs.trap_csrs.stval
+    }
+
+    /// Whether the vCPU is asked to pause.
+    pub(crate) fn pause_requested(&self) -> bool {
+        self.pause.requested.load(Ordering::SeqCst)
+    }
+
+    /// Captures the guest register state of the vCPU, e.g. while it's paused. A vCPU loaded on a
+    /// hart must be loaded on the current one.
+    pub fn state(&self) -> VCpuState {
+        let guest = &self.regs.guest_regs;
+        let vs = self.vs_csrs();
+        let mut gprs = [0; 32];
+        for (index, gpr) in gprs.iter_mut().enumerate() {
+            *gpr = guest.gprs.reg(GprIndex::from_raw(index as u32).unwrap());
+        }
+        VCpuState {
+            gprs,
+            pc: guest.sepc,
+            priv_level: PrivilegeLevel::from_hstatus(guest.hstatus),
+            vsstatus: vs.vsstatus,
+            vsie: vs.vsie,
+            vstvec: vs.vstvec,
+            vsscratch: vs.vsscratch,
+            vsepc: vs.vsepc,
+            vscause: vs.vscause,
+            vstval: vs.vstval,
+            vsatp: vs.vsatp,
+        }
+    }
+
     /// Gets the vCPU's registers.
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Appends the vCPU's architectural state, starting with its id, to a snapshot section. The
+    /// vCPU must be paused, and if it's still loaded on a hart, its VS-level CSRs and FP and vector
+    /// registers are read from the current hart, which must be that one.
+    pub(crate) fn save_state(&mut self, payload: &mut SectionBuilder) {
+        if self.loaded_on.is_some() {
+            self.save_vs_csrs();
+            self.fp.sync(&mut self.regs.guest_regs.sstatus);
+        }
+        payload.put_u64(self.vcpu_id as u64);
+        for index in 0..32 {
+            let reg = GprIndex::from_raw(index).unwrap();
+            payload.put_u64(self.regs.guest_regs.gprs.reg(reg) as u64);
+        }
+        let guest = &self.regs.guest_regs;
+        let vs = &self.regs.vs_csrs;
+        let hs = &self.regs.virtual_hs_csrs;
+        for val in [
+            guest.sepc,
+            guest.sstatus,
+            guest.hstatus & !HSTATUS_VGEIN_MASK,
+            guest.scounteren,
+            vs.htimedelta,
+            vs.vsstatus,
+            vs.vsie,
+            vs.vstvec,
+            vs.vsscratch,
+            vs.vsepc,
+            vs.vscause,
+            vs.vstval,
+            vs.vsatp,
+            vs.hvip,
+            hs.hie,
+            hs.hgeie,
+            vs.vstimecmp,
+        ] {
+            payload.put_u64(val as u64);
+        }
+        self.fp.save_state(payload);
+    }
+
+    /// Restores the state saved by `save_state`, whose vCPU id has already been consumed. Sections
+    /// of version 1 have neither `vstimecmp` nor the FP and vector registers, which are left as they
+    /// are. If the vCPU is loaded on a hart, its VS-level CSRs and FP and vector registers are
+    /// loaded into the current hart, which must be that one.
+    pub(crate) fn restore_state<R: SnapshotReader>(
+        &mut self,
+        section: &mut SectionReader<'_, '_, R>,
+    ) -> HyperResult<()> {
+        let mut next = || section.read_u64().map(|val| val as usize);
+        let guest = &mut self.regs.guest_regs;
+        for index in 0..32 {
+            guest
+                .gprs
+                .set_reg(GprIndex::from_raw(index).unwrap(), next()?);
+        }
+        guest.sepc = next()?;
+        guest.sstatus = next()?;
+        guest.hstatus = next()? & !HSTATUS_VGEIN_MASK | guest.hstatus & HSTATUS_VGEIN_MASK;
+        guest.scounteren = next()?;
+        let vs = &mut self.regs.vs_csrs;
+        vs.htimedelta = next()?;
+        vs.vsstatus = next()?;
+        vs.vsie = next()?;
+        vs.vstvec = next()?;
+        vs.vsscratch = next()?;
+        vs.vsepc = next()?;
+        vs.vscause = next()?;
+        vs.vstval = next()?;
+        vs.vsatp = next()?;
+        vs.hvip = next()? & HVIP_VS_IRQS;
+        self.regs.virtual_hs_csrs.hie = next()?;
+        self.regs.virtual_hs_csrs.hgeie = next()?;
+        if section.header().version >= 2 {
+            self.regs.vs_csrs.vstimecmp = section.read_u64()? as usize;
+            self.fp.restore_state(section, &self.isa)?;
+        }
+        if self.loaded_on.is_some() {
+            self.restore_vs_csrs();
+            let sstatus = &mut self.regs.guest_regs.sstatus;
+            self.fp.configure(sstatus, &self.isa);
+            self.fp.reload(sstatus);
+        } else {
+            self.vs_synced = false;
+        }
+        Ok(())
+    }
 }
 
 // Private methods implements
 impl<H: HyperCraftHal> VCpu<H> {
-    /// Delivers the given exception to the vCPU, setting its register state
-    /// to handle the trap the next time it is run.
-    fn inject_exception(&mut self) {
-        unsafe {
-            core::arch::asm!(
-                "csrw vsepc, {hyp_sepc}",
-                "csrw vscause, {hyp_scause}",
-                "csrr {guest_sepc}, vstvec",
-                hyp_sepc = in(reg) self.regs.guest_regs.sepc,
-                hyp_scause = in(reg) self.regs.trap_csrs.scause,
-                guest_sepc = out(reg) self.regs.guest_regs.sepc,
-            );
+    /// Saves the hart's VS-level CSRs into the vCPU's state.
+    fn save_vs_csrs(&mut self) {
+        self.vs_synced = true;
+        let vs = &mut self.regs.vs_csrs;
+        vs.htimedelta = csr_read!(CSR_HTIMEDELTA);
+        vs.vsstatus = csr_read!(CSR_VSSTATUS);
+        vs.vsie = csr_read!(CSR_VSIE);
+        vs.vstvec = csr_read!(CSR_VSTVEC);
+        vs.vsscratch = csr_read!(CSR_VSSCRATCH);
+        vs.vsepc = csr_read!(CSR_VSEPC);
+        vs.vscause = csr_read!(CSR_VSCAUSE);
+        vs.vstval = csr_read!(CSR_VSTVAL);
+        vs.vsatp = csr_read!(CSR_VSATP);
+        if self.isa.sstc {
+            vs.vstimecmp = csr_read!(CSR_VSTIMECMP);
+        }
+        vs.hvip = CSR.hvip.get_value() & HVIP_VS_IRQS;
+    }
+
+    /// The vCPU's VS-level CSRs, read from the hart if the vCPU is loaded on it.
+    fn vs_csrs(&self) -> GuestVsCsrs {
+        if self.loaded_on.is_none() {
+            return self.regs.vs_csrs.clone();
+        }
+        GuestVsCsrs {
+            htimedelta: csr_read!(CSR_HTIMEDELTA),
+            vsstatus: csr_read!(CSR_VSSTATUS),
+            vsie: csr_read!(CSR_VSIE),
+            vstvec: csr_read!(CSR_VSTVEC),
+            vsscratch: csr_read!(CSR_VSSCRATCH),
+            vsepc: csr_read!(CSR_VSEPC),
+            vscause: csr_read!(CSR_VSCAUSE),
+            vstval: csr_read!(CSR_VSTVAL),
+            vsatp: csr_read!(CSR_VSATP),
+            vstimecmp: match self.isa.sstc {
+                true => csr_read!(CSR_VSTIMECMP),
+                false => self.regs.vs_csrs.vstimecmp,
+            },
+            hvip: CSR.hvip.get_value() & HVIP_VS_IRQS,
+        }
+    }
+
+    /// Loads the vCPU's VS-level CSRs into the hart.
+    fn restore_vs_csrs(&self) {
+        let vs = &self.regs.vs_csrs;
+        csr_write!(CSR_HTIMEDELTA, vs.htimedelta);
+        csr_write!(CSR_VSSTATUS, vs.vsstatus);
+        csr_write!(CSR_VSIE, vs.vsie);
+        csr_write!(CSR_VSTVEC, vs.vstvec);
+        csr_write!(CSR_VSSCRATCH, vs.vsscratch);
+        csr_write!(CSR_VSEPC, vs.vsepc);
+        csr_write!(CSR_VSCAUSE, vs.vscause);
+        csr_write!(CSR_VSTVAL, vs.vstval);
+        csr_write!(CSR_VSATP, vs.vsatp);
+        if self.isa.sstc {
+            csr_write!(CSR_VSTIMECMP, vs.vstimecmp);
         }
+        CSR.hvip.read_and_clear_bits(HVIP_VS_IRQS & !vs.hvip);
+        CSR.hvip.read_and_set_bits(vs.hvip);
     }
 }
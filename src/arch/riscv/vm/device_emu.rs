@@ -0,0 +1,559 @@
+//! Emulated devices of a VM: the host API adding the UART, RTC and virtio devices, and the
+//! dispatch of their MMIO accesses and interrupts to the vPLIC or the APLIC.
+use super::{current_time, GuestRam, PLIC_SIZE, VM};
+use crate::arch::{
+    aia::send_msi,
+    devices::rtc::{EmuRtc, RTC_SIZE},
+    devices::uart::{EmuUart, UART_SIZE},
+    regs::GeneralPurposeRegisters,
+    vcpu::IrqKind,
+    vm_pages::VmRegionType,
+};
+use crate::{
+    console::{self, ConsoleId},
+    device::EmuDevice,
+    logging::LogContext,
+    memory::PAGE_SIZE_4K,
+    virtio::{
+        balloon::VirtioBalloon,
+        fs::{FsBackend, Virtio9p},
+        gpu::VirtioGpu,
+        input::{InputHandle, VirtioInput},
+        rng::VirtioRng,
+        EmuVirtioMmio, VirtioDevice, VirtioMmio, VIRTIO_MMIO_SIZE,
+    },
+    EmuContext, GprIndex, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HyperCraftHal,
+    HyperError, HyperResult,
+};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use riscv_decode::Instruction;
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Attaches the VM to the console multiplexer, with its output prefixed by `name`. The SBI
+    /// console of the guest is then served by the multiplexer rather than the physical console.
+    pub fn attach_console(&mut self, name: &str) -> HyperResult<ConsoleId> {
+        if self.console.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let id = console::attach(name)?;
+        self.console = Some(id);
+        Ok(id)
+    }
+
+    /// Sends the host input `bytes` to the VM's console, whether it has the focus or not, e.g. from
+    /// a terminal connected to the VM. If the UART then requests its received data interrupt, the
+    /// vCPU it's routed to is kicked so the guest takes it on its next exit rather than at the end
+    /// of its time slice. Returns how many of the first bytes were taken: the others don't fit in the console's
+    /// input ring until the guest reads some, and the host is to send them again later, see
+    /// `console_input_space`. Fails with `BadState` if the VM isn't attached to the console
+    /// multiplexer.
+    pub fn push_console_input(&mut self, bytes: &[u8]) -> HyperResult<usize> {
+        let console = self.console.ok_or(HyperError::BadState)?;
+        let count = console::push_input_to(console, bytes);
+        if count > 0 {
+            self.kick_rising_irqs();
+        }
+        Ok(count)
+    }
+
+    /// How many bytes of input the VM's console can still take, none if it isn't attached to the
+    /// console multiplexer.
+    pub fn console_input_space(&self) -> usize {
+        self.console.map_or(0, console::input_space)
+    }
+
+    /// Emulates a 16550 UART at `gpa` on the VM's console, raising the guest interrupt `irq` on
+    /// the vPLIC, or on the APLIC once AIA is enabled. Received data interrupts are raised on the
+    /// next exit of a vCPU of the VM after input arrives, see `push_console_input`. Requires the VM
+    /// to be attached to the console multiplexer.
+    pub fn add_uart(&mut self, gpa: GuestPhysAddr, irq: u32) -> HyperResult<()> {
+        let console = self.console.ok_or(HyperError::BadState)?;
+        if self.uart.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let uart = EmuUart::new(gpa, console, irq);
+        self.add_device(Arc::new(uart), gpa, UART_SIZE, irq)?;
+        self.uart = Some(gpa);
+        Ok(())
+    }
+
+    /// Emulates a Goldfish RTC at `gpa` reporting `HyperCraftHal::wall_clock`, raising the guest
+    /// interrupt `irq` like the UART does. Alarms fire on the next exit of a vCPU of the VM after
+    /// they expire, so their precision depends on how often the vCPUs exit, e.g. their time slices.
+    pub fn add_rtc(&mut self, gpa: GuestPhysAddr, irq: u32) -> HyperResult<()> {
+        if self.rtc.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let rtc = EmuRtc::new(gpa, irq, H::wall_clock);
+        self.add_device(Arc::new(rtc), gpa, RTC_SIZE, irq)?;
+        self.rtc = Some(gpa);
+        Ok(())
+    }
+
+    /// Emulates a virtio-mmio entropy device at `gpa` taking entropy from
+    /// `HyperCraftHal::fill_entropy`, raising the guest interrupt `irq` like the UART does.
+    pub fn add_virtio_rng(&mut self, gpa: GuestPhysAddr, irq: u32) -> HyperResult<()> {
+        self.add_virtio_device(gpa, irq, Box::new(VirtioRng::new(H::fill_entropy)))
+    }
+
+    /// Emulates a virtio-mmio GPU at `gpa` with a single `width` x `height` display, shown
+    /// through `HyperCraftHal::display_update`, raising the guest interrupt `irq` like the UART
+    /// does.
+    pub fn add_virtio_gpu(
+        &mut self,
+        gpa: GuestPhysAddr,
+        irq: u32,
+        width: u32,
+        height: u32,
+    ) -> HyperResult<()> {
+        if width == 0 || height == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let device = VirtioGpu::new(width, height, H::display_update);
+        self.add_virtio_device(gpa, irq, Box::new(device))
+    }
+
+    /// Emulates a virtio-mmio keyboard and mouse at `gpa`, raising the guest interrupt `irq` like
+    /// the UART does. Returns the handle through which the host injects input events.
+    pub fn add_virtio_input(&mut self, gpa: GuestPhysAddr, irq: u32) -> HyperResult<InputHandle> {
+        let (device, handle) = VirtioInput::new();
+        self.add_virtio_device(gpa, irq, Box::new(device))?;
+        Ok(handle)
+    }
+
+    /// Emulates a virtio-mmio memory balloon at `gpa`, raising the guest interrupt `irq` like the
+    /// UART does. RAM pages the guest puts into the balloon are unmapped and, if they were
+    /// allocated on first touch, given back through `HyperCraftHal::dealloc_page`.
+    pub fn add_virtio_balloon(&mut self, gpa: GuestPhysAddr, irq: u32) -> HyperResult<()> {
+        if self.balloon.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let (device, control) = VirtioBalloon::new();
+        self.add_virtio_device(gpa, irq, Box::new(device))?;
+        self.balloon = Some((control, irq));
+        Ok(())
+    }
+
+    /// Asks the guest to inflate or deflate its balloon to `num_pages` 4K pages. The vCPU the
+    /// balloon's interrupt is routed to is kicked, and the guest told on its exit.
+    pub fn set_balloon_target(&mut self, num_pages: u32) -> HyperResult<()> {
+        let (balloon, irq) = self.balloon.as_ref().ok_or(HyperError::NotFound)?;
+        balloon.set_target(num_pages);
+        self.kick_irq_target(*irq);
+        Ok(())
+    }
+
+    /// Number of pages the guest reports its balloon holds.
+    pub fn balloon_pages(&self) -> HyperResult<u32> {
+        let (balloon, _) = self.balloon.as_ref().ok_or(HyperError::NotFound)?;
+        Ok(balloon.actual())
+    }
+
+    /// Puts the virtio device at `gpa` in polling mode, where the guest doesn't notify its queues
+    /// and they're processed on every exit of a vCPU of the VM and by `poll_virtio_devices`
+    /// instead, or back to being notified. Fails with `NotFound` if there's no virtio device at `gpa`.
+    pub fn set_virtio_polling(&mut self, gpa: GuestPhysAddr, enabled: bool) -> HyperResult<()> {
+        let dev = self.virtio_dev(gpa)?;
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        dev.mmio().set_polling(enabled, &mem)
+    }
+
+    /// Coalesces the used buffer interrupts of the virtio device at `gpa`, deferring them until
+    /// `max_completions` requests completed or `max_delay` ticks of the `time` CSR passed, so
+    /// bursts of small requests don't interrupt the guest for each one. A `max_completions` of 1
+    /// disables moderation, as by default. Fails with `NotFound` if there's no virtio device at
+    /// `gpa`.
+    pub fn set_virtio_irq_moderation(
+        &mut self,
+        gpa: GuestPhysAddr,
+        max_completions: u32,
+        max_delay: u64,
+    ) -> HyperResult<()> {
+        let dev = self.virtio_dev(gpa)?;
+        dev.mmio().set_irq_moderation(max_completions, max_delay);
+        Ok(())
+    }
+
+    /// Changes the configuration space of the virtio device at `gpa` at `offset` to `data` on
+    /// behalf of the host, e.g. a balloon's target size, and raises the device's configuration
+    /// change interrupt. The vCPU the interrupt is routed to is kicked, and the interrupt delivered
+    /// on its exit. Fails with
+    /// `NotFound` if there's no virtio device at `gpa`, and as `VirtioDevice::update_config` does
+    /// if the change isn't allowed.
+    pub fn update_virtio_config(
+        &mut self,
+        gpa: GuestPhysAddr,
+        offset: usize,
+        data: &[u8],
+    ) -> HyperResult<()> {
+        self.virtio_dev(gpa)?.mmio().update_config(offset, data)?;
+        self.kick_rising_irqs();
+        Ok(())
+    }
+
+    /// Processes the queues of the virtio devices in polling mode. The VM is borrowed by
+    /// `run_scheduled` while its vCPUs run, and their exits poll the devices then, so this is for
+    /// the host to call between runs, e.g. at the end of a time slice, to catch up on requests
+    /// submitted while no vCPU exited. The vCPU a device's interrupt is routed to is kicked if the
+    /// device raises it, and the interrupt delivered on its exit.
+    pub fn poll_virtio_devices(&mut self) {
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        for dev in &self.virtio_devs {
+            let mmio = dev.mmio();
+            if mmio.polling() {
+                mmio.poll(&mem, current_time());
+            }
+        }
+        self.kick_rising_irqs();
+    }
+
+    /// Emulates a virtio-mmio 9P filesystem device at `gpa` sharing the directory tree of
+    /// `backend` under the mount tag `tag`, raising the guest interrupt `irq` like the UART does.
+    pub fn add_virtio_fs(
+        &mut self,
+        gpa: GuestPhysAddr,
+        irq: u32,
+        tag: &str,
+        backend: Box<dyn FsBackend>,
+    ) -> HyperResult<()> {
+        let device = Virtio9p::new(tag, backend)?;
+        self.add_virtio_device(gpa, irq, Box::new(device))
+    }
+
+    /// When the earliest deferred virtio interrupt is due.
+    pub(super) fn virtio_irq_deadline(&self) -> Option<u64> {
+        self.virtio_devs
+            .iter()
+            .filter_map(|dev| dev.mmio().irq_deadline())
+            .min()
+    }
+
+    /// The virtio device at `gpa`. Fails with `NotFound` if there's none.
+    fn virtio_dev(&self, gpa: GuestPhysAddr) -> HyperResult<&EmuVirtioMmio> {
+        self.virtio_devs
+            .iter()
+            .find(|dev| dev.contains(gpa))
+            .map(|dev| &**dev)
+            .ok_or(HyperError::NotFound)
+    }
+
+    /// Puts the emulated devices and the APLIC back into their power-on state, e.g. before the
+    /// host boots the guest again after the VM stopped with `StopReason::SystemReset`.
+    pub fn reset_devices(&mut self) {
+        self.devices.reset();
+    }
+
+    /// Emulates the MMIO access that trapped at `inst_addr`. Returns the access and, for loads,
+    /// the value read.
+    pub(super) fn handle_page_fault(
+        &mut self,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+        fault_addr: GuestPhysAddr,
+        gprs: &GeneralPurposeRegisters,
+    ) -> HyperResult<(EmuContext, usize)> {
+        //  plic
+        if fault_addr >= self.plic.base() && fault_addr < self.plic.base() + PLIC_SIZE {
+            let emu_ctx = self.decode_mmio_inst(inst_addr, inst, fault_addr)?;
+            let val = self.handle_plic(&emu_ctx, gprs)?;
+            return Ok((emu_ctx, val));
+        }
+        let Some(dev) = self.devices.find(fault_addr) else {
+            hv_log!(
+                Error,
+                Mmu,
+                LogContext::vm(self.id),
+                "inst_addr: {:#x}, fault_addr: {:#x}",
+                inst_addr,
+                fault_addr
+            );
+            return Err(HyperError::PageFault);
+        };
+        let emu_ctx = self.decode_mmio_inst(inst_addr, inst, fault_addr)?;
+        if !emu_ctx.write {
+            let val = dev.handle_read(emu_ctx.address, emu_ctx.width)?;
+            return Ok((emu_ctx, val as usize));
+        }
+        let val = emu_ctx.write_value(gprs.reg(GprIndex::from_raw(emu_ctx.reg as u32).unwrap()));
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        dev.handle_write(emu_ctx.address, emu_ctx.width, val as u64, &mem)?;
+        // Enabling APLIC interrupts may make pending ones deliverable, and the guest may have
+        // put pages into its balloon.
+        self.deliver_msis();
+        self.reclaim_ballooned_pages();
+        Ok((emu_ctx, 0))
+    }
+
+    fn handle_plic(
+        &mut self,
+        emu_ctx: &EmuContext,
+        gprs: &GeneralPurposeRegisters,
+    ) -> HyperResult<usize> {
+        if emu_ctx.width != 4 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        if emu_ctx.write {
+            let val =
+                emu_ctx.write_value(gprs.reg(GprIndex::from_raw(emu_ctx.reg as u32).unwrap()));
+            self.plic.write_u32(emu_ctx.address, val as u32);
+            Ok(0)
+        } else {
+            Ok(self.plic.read_u32(emu_ctx.address) as usize)
+        }
+    }
+
+    /// Unmaps the RAM pages the guest put into its balloon, giving back those allocated on first
+    /// touch. Pages the caller mapped stay mapped, as their memory isn't the hypervisor's to free.
+    /// Nothing is reclaimed while devices are passed through, as their DMA may reach any page.
+    fn reclaim_ballooned_pages(&mut self) {
+        let Some((balloon, _)) = &self.balloon else {
+            return;
+        };
+        if !self.passthrough_devices.is_empty() {
+            return;
+        }
+        let mut reclaimed = false;
+        for pfn in balloon.take_inflated() {
+            let gpa = (pfn as usize) << 12;
+            match self.regions.find(gpa) {
+                Some(region) if region.region_type() == VmRegionType::Confidential => {}
+                _ => continue,
+            }
+            let Ok(hpa) = self.gpt.translate(gpa) else {
+                continue;
+            };
+            let page = H::phys_to_virt(hpa);
+            if !self.lazy_pages.contains(&page) {
+                continue;
+            }
+            if let Err(err) = self.gpt.unmap(gpa) {
+                hv_log!(
+                    Warn,
+                    Mmu,
+                    LogContext::vm(self.id),
+                    "failed to unmap ballooned page {:#x}: {:?}",
+                    gpa,
+                    err
+                );
+                continue;
+            }
+            self.flush_guest_tlb(gpa, PAGE_SIZE_4K);
+            self.lazy_pages.remove(&page);
+            self.host_memory.remove(hpa, hpa + PAGE_SIZE_4K);
+            H::dealloc_page(page);
+            reclaimed = true;
+            // The page now reads as zeros.
+            if let Some(dirty_log) = &mut self.dirty_log {
+                dirty_log.get_mut().set(gpa);
+            }
+        }
+        // A virtqueue's rings may have been on one of the pages.
+        if reclaimed {
+            self.invalidate_virtio_rings();
+        }
+    }
+
+    /// Makes the virtio devices translate the host addresses of their virtqueue rings again.
+    pub(super) fn invalidate_virtio_rings(&mut self) {
+        for dev in &self.virtio_devs {
+            dev.mmio().invalidate_rings();
+        }
+    }
+
+    fn add_virtio_device(
+        &mut self,
+        gpa: GuestPhysAddr,
+        irq: u32,
+        device: Box<dyn VirtioDevice>,
+    ) -> HyperResult<()> {
+        let mut mmio = VirtioMmio::new(gpa, device);
+        mmio.set_log_context(LogContext::vm(self.id));
+        let dev = Arc::new(EmuVirtioMmio::new(mmio, irq, current_time));
+        self.add_device(dev.clone(), gpa, VIRTIO_MMIO_SIZE, irq)?;
+        self.virtio_devs.push(dev);
+        Ok(())
+    }
+
+    /// Adds `device`, whose `size` bytes of registers are at `gpa` and which raises the guest
+    /// interrupt `irq`, to the devices MMIO accesses are dispatched to.
+    fn add_device(
+        &mut self,
+        device: Arc<dyn EmuDevice>,
+        gpa: GuestPhysAddr,
+        size: usize,
+        irq: u32,
+    ) -> HyperResult<()> {
+        self.check_device_limit()?;
+        self.add_device_window(gpa, size)?;
+        self.plic.add_virtual_irq(irq)?;
+        self.devices.add(device);
+        Ok(())
+    }
+
+    /// Registers the `size` bytes of registers of an emulated device at `gpa`, rounded up to whole
+    /// pages, as MMIO.
+    pub(super) fn add_device_window(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        let size = (size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        self.add_mmio_region(gpa, size)
+    }
+
+    /// Brings the emulated devices up to date and raises their interrupts on the interrupt
+    /// controller while they request them.
+    pub(super) fn update_device_irqs(&mut self, vcpu_id: usize) {
+        for index in 0..self.devices.len() {
+            let mem = GuestRam::<H, G>::new(
+                &self.gpt,
+                &self.regions,
+                &self.host_memory,
+                self.dirty_log.as_ref(),
+            );
+            let Some((irq, level, rising)) = self.devices.update_irq_line(index, &mem) else {
+                continue;
+            };
+            self.set_device_irq(vcpu_id, irq, level, rising);
+        }
+    }
+
+    /// Kicks the vCPUs the device interrupts requested since they were last checked are routed
+    /// to, e.g. after the host gave a device work, so they're raised on their next exit.
+    fn kick_rising_irqs(&self) {
+        self.devices
+            .for_each_rising_irq(|irq| self.kick_irq_target(irq));
+    }
+
+    /// Kicks the vCPU the device interrupt `irq` is routed to, so it's raised on its next exit
+    /// rather than at the end of its time slice: the target hart of `irq` at the APLIC, or vCPU 0,
+    /// whose context the vPLIC serves.
+    fn kick_irq_target(&self, irq: u32) {
+        let vcpu_id = self
+            .aplic
+            .as_ref()
+            .and_then(|aplic| aplic.lock().target_hart(irq as usize))
+            .unwrap_or(0);
+        H::vcpu_interrupt_pending(vcpu_id);
+    }
+
+    /// Raises the level-triggered device interrupt `irq`, whose line is at `level` and was just
+    /// raised if `rising`.
+    fn set_device_irq(&mut self, vcpu_id: usize, irq: u32, level: bool, rising: bool) {
+        if let Some(aplic) = &self.aplic {
+            // MSIs are edge-triggered.
+            if rising {
+                aplic.lock().set_pending(irq as usize);
+                self.deliver_msis();
+            }
+        } else if level && self.plic.claim_complete[HOST_CONTEXT_ID] == 0 {
+            self.plic.claim_complete[HOST_CONTEXT_ID] = irq;
+            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+            vcpu.inject_irq(IrqKind::External);
+        }
+    }
+
+    /// Decodes the load or store at `inst_addr` that trapped on an MMIO access to `fault_addr`.
+    pub(super) fn decode_mmio_inst(
+        &mut self,
+        inst_addr: GuestVirtAddr,
+        mut inst: u32,
+        fault_addr: GuestPhysAddr,
+    ) -> HyperResult<EmuContext> {
+        if inst == 0 {
+            // If hinst does not provide information about trap,
+            // we must read the instruction from guest's memory maunally.
+            inst = self.vm_pages.fetch_guest_instruction(inst_addr)?;
+        }
+        let i1 = inst as u16;
+        let len = riscv_decode::instruction_length(i1);
+        let inst = match len {
+            2 => i1 as u32,
+            4 => inst,
+            _ => unreachable!(),
+        };
+        // assert!(len == 4);
+        let decode_inst = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
+        // (access width, store, sign extension, register accessed)
+        let (width, write, sign_ext, reg) = match decode_inst {
+            Instruction::Lb(i) => (1, false, true, i.rd()),
+            Instruction::Lh(i) => (2, false, true, i.rd()),
+            Instruction::Lw(i) => (4, false, true, i.rd()),
+            Instruction::Ld(i) => (8, false, true, i.rd()),
+            Instruction::Lbu(i) => (1, false, false, i.rd()),
+            Instruction::Lhu(i) => (2, false, false, i.rd()),
+            Instruction::Lwu(i) => (4, false, false, i.rd()),
+            Instruction::Sb(i) => (1, true, false, i.rs2()),
+            Instruction::Sh(i) => (2, true, false, i.rs2()),
+            Instruction::Sw(i) => (4, true, false, i.rs2()),
+            Instruction::Sd(i) => (8, true, false, i.rs2()),
+            _ => return Err(HyperError::InvalidInstruction),
+        };
+        Ok(EmuContext {
+            address: fault_addr,
+            width,
+            write,
+            sign_ext,
+            reg: reg as usize,
+            reg_width: core::mem::size_of::<usize>(),
+            inst_len: len,
+        })
+    }
+
+    /// Sends the MSIs the APLIC has become ready to deliver.
+    fn deliver_msis(&self) {
+        let Some(aplic) = &self.aplic else {
+            return;
+        };
+        let mut aplic = aplic.lock();
+        while let Some(msi) = aplic.next_msi() {
+            match self.imsic_files.get(msi.hart).copied().flatten() {
+                Some(file) => send_msi::<H>(file.addr, msi.eiid),
+                None => hv_log!(
+                    Warn,
+                    Irq,
+                    LogContext::vm(self.id),
+                    "APLIC target hart {} has no interrupt file",
+                    msi.hart
+                ),
+            }
+        }
+    }
+
+    pub(super) fn handle_irq(&mut self, vcpu_id: usize) {
+        let context_id = HOST_CONTEXT_ID;
+        let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * context_id;
+        let irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
+        // Spurious: the interrupt was claimed elsewhere or withdrawn meanwhile.
+        if irq == 0 {
+            return;
+        }
+        let guest_irq = self.plic.guest_irq(irq);
+        if let Some(aplic) = &self.aplic {
+            // The interrupt is forwarded as an MSI, so it's completed at the host PLIC right away.
+            aplic.lock().set_pending(guest_irq as usize);
+            unsafe { core::ptr::write_volatile(claim_and_complete_addr as *mut u32, irq) };
+            self.deliver_msis();
+            return;
+        }
+        self.plic.claim_complete[context_id] = guest_irq;
+
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+        vcpu.inject_irq(IrqKind::External);
+    }
+}
+
+/// The PLIC context guest external interrupts are claimed from.
+const HOST_CONTEXT_ID: usize = 1;
@@ -0,0 +1,252 @@
+//! Emulation of the SBI calls and CSRs the vCPUs of a VM trap on.
+use super::VM;
+use crate::arch::{
+    csr_emu::CsrInstruction,
+    csrs::defs::{CSR_SENVCFG, CSR_STIMECMP},
+    regs::GeneralPurposeRegisters,
+    sbi::PmuFunction,
+    sbi::{BaseFunction, HsmFunction, RemoteFenceFunction},
+    vcpu::{CounterAccess, HartState, IrqKind},
+    vm_pages::{VmRegion, VmRegionType},
+    vmexit::PrivilegeLevel,
+};
+use crate::{
+    arch::sbi::{
+        EID_HYPERCRAFT_ISA, SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_INAVLID_PARAM,
+        SBI_ERR_INVALID_ADDRESS, SBI_SUCCESS,
+    },
+    logging::LogContext,
+    GprIndex, GuestPageTableTrait, HyperCraftHal, HyperResult,
+};
+use sbi_rt::{pmu_counter_get_info, pmu_counter_stop};
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    pub(super) fn handle_base_function(
+        &self,
+        base: BaseFunction,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<()> {
+        match base {
+            BaseFunction::GetSepcificationVersion => {
+                let version = sbi_rt::get_spec_version();
+                gprs.set_reg(GprIndex::A1, version.major() << 24 | version.minor());
+                hv_log!(
+                    Debug,
+                    Sbi,
+                    LogContext::vm(self.id),
+                    "GetSepcificationVersion: {}",
+                    version.major() << 24 | version.minor()
+                );
+            }
+            BaseFunction::GetImplementationID => {
+                let id = sbi_rt::get_sbi_impl_id();
+                gprs.set_reg(GprIndex::A1, id);
+            }
+            BaseFunction::GetImplementationVersion => {
+                let impl_version = sbi_rt::get_sbi_impl_version();
+                gprs.set_reg(GprIndex::A1, impl_version);
+            }
+            BaseFunction::ProbeSbiExtension(extension) => {
+                let extension = match extension as usize {
+                    EID_HYPERCRAFT_ISA => 1,
+                    extension => sbi_rt::probe_extension(extension).raw,
+                };
+                gprs.set_reg(GprIndex::A1, extension);
+            }
+            BaseFunction::GetMachineVendorID => {
+                let mvendorid = sbi_rt::get_mvendorid();
+                gprs.set_reg(GprIndex::A1, mvendorid);
+            }
+            BaseFunction::GetMachineArchitectureID => {
+                let marchid = sbi_rt::get_marchid();
+                gprs.set_reg(GprIndex::A1, marchid);
+            }
+            BaseFunction::GetMachineImplementationID => {
+                let mimpid = sbi_rt::get_mimpid();
+                gprs.set_reg(GprIndex::A1, mimpid);
+            }
+        }
+        gprs.set_reg(GprIndex::A0, 0);
+        Ok(())
+    }
+
+    pub(super) fn handle_pmu_function(
+        &self,
+        pmu: PmuFunction,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<()> {
+        gprs.set_reg(GprIndex::A0, 0);
+        match pmu {
+            PmuFunction::GetNumCounters => gprs.set_reg(GprIndex::A1, sbi_rt::pmu_num_counters()),
+            PmuFunction::GetCounterInfo(counter_index) => {
+                let sbi_ret = pmu_counter_get_info(counter_index as usize);
+                gprs.set_reg(GprIndex::A0, sbi_ret.error);
+                gprs.set_reg(GprIndex::A1, sbi_ret.value);
+            }
+            PmuFunction::StopCounter {
+                counter_index,
+                counter_mask,
+                stop_flags,
+            } => {
+                let sbi_ret = pmu_counter_stop(
+                    counter_index as usize,
+                    counter_mask as usize,
+                    stop_flags as usize,
+                );
+                gprs.set_reg(GprIndex::A0, sbi_ret.error);
+                gprs.set_reg(GprIndex::A1, sbi_ret.value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Emulates the CSR instruction `inst` of the vCPU `vcpu_id`, which trapped as a virtual
+    /// instruction from `priv_level`, for the CSRs the guest can't access directly:
+    ///
+    /// - `stimecmp`, when Sstc isn't enabled for the guest, is backed by the vCPU's timer as set
+    ///   through the SBI `set_timer`. Reads return the pending deadline, all ones once it's passed.
+    /// - `time` is read from the hart, offset by `htimedelta`, if the vCPU reads counters directly.
+    /// - `senvcfg` reads as zero and ignores writes, as if the hart implemented none of its fields.
+    ///
+    /// Returns whether the access has been emulated, writing the old value of the CSR to the
+    /// destination register. Other CSRs, writes to read-only CSRs and accesses the guest's
+    /// privilege level doesn't allow are left for the guest to take as illegal instructions.
+    pub(super) fn emulate_csr(
+        &mut self,
+        vcpu_id: usize,
+        inst: &CsrInstruction,
+        priv_level: PrivilegeLevel,
+        gprs: &mut GeneralPurposeRegisters,
+        slice_end: u64,
+    ) -> bool {
+        let from_user = matches!(priv_level, PrivilegeLevel::User);
+        if inst.writes() && inst.read_only() || from_user && !inst.user_level() {
+            return false;
+        }
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+        let old = match inst.csr {
+            CSR_STIMECMP => self.timer_deadlines[vcpu_id] as usize,
+            CSR_SENVCFG => 0,
+            csr if csr == CSR_TIME
+                && vcpu.counter_access(TIME_COUNTER) == CounterAccess::Direct
+                && (!from_user || vcpu.user_counter_enabled(TIME_COUNTER)) =>
+            {
+                vcpu.guest_time() as usize
+            }
+            _ => return false,
+        };
+        if inst.writes() && inst.csr == CSR_STIMECMP {
+            self.timer_deadlines[vcpu_id] = inst.new_value(old, gprs) as u64;
+            vcpu.clear_irq(IrqKind::Timer);
+            self.program_timer(vcpu_id, slice_end);
+        }
+        gprs.set_reg(inst.rd, old);
+        true
+    }
+
+    /// Handles the SBI HSM call `hsm` of the vCPU `vcpu_id`, whose hart id is its vCPU id. Returns
+    /// whether the vCPU suspended itself with no interrupt pending, so it waits as on WFI. A vCPU
+    /// stopping itself waits in `run_scheduled` until started.
+    pub(super) fn handle_hsm_function(
+        &mut self,
+        vcpu_id: usize,
+        hsm: HsmFunction,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> bool {
+        let mut ret = SBI_SUCCESS as isize;
+        let mut blocked = false;
+        match hsm {
+            HsmFunction::HartStart {
+                hartid,
+                start_addr,
+                opaque,
+            } => {
+                let executable = matches!(
+                    self.regions.find(start_addr).map(VmRegion::region_type),
+                    Some(
+                        VmRegionType::Confidential
+                            | VmRegionType::Shared(_)
+                            | VmRegionType::Rom(_)
+                            | VmRegionType::ConfidentialRemovable
+                            | VmRegionType::SharedRemovable
+                    )
+                );
+                ret = match self.vcpus.get_vcpu(hartid) {
+                    Err(_) => SBI_ERR_INAVLID_PARAM,
+                    Ok(_) if !executable => SBI_ERR_INVALID_ADDRESS,
+                    Ok(vcpu) => match vcpu.start(start_addr, opaque) {
+                        Ok(()) => {
+                            // Wakes the target up if it's waiting on its hart.
+                            H::vcpu_interrupt_pending(hartid);
+                            SBI_SUCCESS as isize
+                        }
+                        Err(_) => SBI_ERR_ALREADY_AVAILABLE,
+                    },
+                };
+            }
+            HsmFunction::HartStop => self.vcpus.get_vcpu(vcpu_id).unwrap().power_off(),
+            HsmFunction::HartGetStatus(hartid) => match self.vcpus.get_vcpu(hartid) {
+                Ok(vcpu) => {
+                    let status = match vcpu.hart_state() {
+                        HartState::Started => sbi_spec::hsm::HART_STATE_STARTED,
+                        HartState::Stopped => sbi_spec::hsm::HART_STATE_STOPPED,
+                        HartState::StartPending => sbi_spec::hsm::HART_STATE_START_PENDING,
+                        HartState::Suspended => sbi_spec::hsm::HART_STATE_SUSPENDED,
+                    };
+                    gprs.set_reg(GprIndex::A1, status);
+                }
+                Err(_) => ret = SBI_ERR_INAVLID_PARAM,
+            },
+            HsmFunction::HartSuspend {
+                non_retentive,
+                resume_addr,
+                opaque,
+            } => {
+                let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                vcpu.suspend(non_retentive.then_some((resume_addr, opaque)));
+                blocked = !vcpu.any_irq_pending();
+            }
+        }
+        gprs.set_reg(GprIndex::A0, ret as usize);
+        blocked
+    }
+
+    pub(super) fn handle_rfnc_function(
+        &self,
+        rfnc: RemoteFenceFunction,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult<()> {
+        gprs.set_reg(GprIndex::A0, 0);
+        match rfnc {
+            RemoteFenceFunction::FenceI {
+                hart_mask,
+                hart_mask_base,
+            } => {
+                let sbi_ret = sbi_rt::remote_fence_i(hart_mask as usize, hart_mask_base as usize);
+                gprs.set_reg(GprIndex::A0, sbi_ret.error);
+                gprs.set_reg(GprIndex::A1, sbi_ret.value);
+            }
+            RemoteFenceFunction::RemoteSFenceVMA {
+                hart_mask,
+                hart_mask_base,
+                start_addr,
+                size,
+            } => {
+                let sbi_ret = sbi_rt::remote_sfence_vma(
+                    hart_mask as usize,
+                    hart_mask_base as usize,
+                    start_addr as usize,
+                    size as usize,
+                );
+                gprs.set_reg(GprIndex::A0, sbi_ret.error);
+                gprs.set_reg(GprIndex::A1, sbi_ret.value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of the `time` CSR.
+const CSR_TIME: u16 = 0xc01;
+/// Index of `time` among the counters, see `VCpu::set_counter_access`.
+const TIME_COUNTER: usize = 1;
@@ -0,0 +1,1946 @@
+mod device_emu;
+mod hart_emu;
+mod snapshot;
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{
+    aia::{send_msi, Aia, AIA},
+    audit::{GuestMapping, GuestRegion, RegionKind},
+    bandwidth::CpuBandwidth,
+    csr_emu::CsrInstruction,
+    debug::{self, GuestDebugger},
+    devices::aplic::{EmuAplic, APLIC_SIZE},
+    devices::plic::{PlicState, MAX_CONTEXTS},
+    ept::GuestPagingMode,
+    iommu::{gscid_of, IOMMU},
+    isa::IsaExtensions,
+    isolation::HostRangeSet,
+    memory_map::GuestMemoryMap,
+    realtime::RealtimeViolationKind,
+    regs::GeneralPurposeRegisters,
+    resources::{ResourceLimits, ResourceUsage},
+    sbi::{ResetFunction, ResetReason, ResetType},
+    tlb::{self, HGATP_VMID_SHIFT},
+    traps,
+    vcpu::{self, CounterAccess, HartState, IrqKind, VmCpuRegisters},
+    vm_pages::{RomWritePolicy, SharePermission, VmPages, VmRegion, VmRegionList, VmRegionType},
+    vmexit::PrivilegeLevel,
+    vmid::{self, Vmid},
+    HyperCallMsg, RiscvCsrTrait, CSR,
+};
+use crate::{
+    arch::sbi::{SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS},
+    console::{self, ConsoleId},
+    device::{DeviceRegistry, EmuDevice},
+    logging::LogContext,
+    memory::PAGE_SIZE_4K,
+    snapshot::DirtyBitmap,
+    utils::{Sha256, SHA256_DIGEST_SIZE},
+    vcpus::VM_CPUS_MAX,
+    virtio::{balloon::BalloonControl, EmuVirtioMmio, GuestMemory},
+    GprIndex, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr,
+    HyperCraftHal, HyperError, HyperResult, PerCpu, VCpu, VcpuScheduler, VmCpus, VmExitInfo,
+};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+
+/// Mapping flags of guest RAM pages.
+const RAM_FLAGS: MappingFlags = MappingFlags::READ
+    .union(MappingFlags::WRITE)
+    .union(MappingFlags::EXECUTE)
+    .union(MappingFlags::USER);
+/// Mapping flags of guest RAM pages write-protected for dirty logging.
+const RAM_WP_FLAGS: MappingFlags = RAM_FLAGS.difference(MappingFlags::WRITE);
+/// Mapping flags of memory shared read-only into a VM.
+const SHARED_RO_FLAGS: MappingFlags = RAM_WP_FLAGS;
+/// Mapping flags of guest ROM pages.
+const ROM_FLAGS: MappingFlags = RAM_WP_FLAGS;
+
+/// Where the vPLIC is emulated in the guest physical address space.
+pub(crate) const PLIC_GPA: GuestPhysAddr = 0xC00_0000;
+pub(crate) const PLIC_SIZE: usize = 0x400_0000;
+
+/// Memory of another VM shared into a VM with `VM::share_region`.
+struct VmShare {
+    gpa: GuestPhysAddr,
+    size: usize,
+    /// Id of the VM the memory belongs to.
+    src: usize,
+    /// Host memory backing the share.
+    memory: HostRangeSet,
+}
+
+/// A guest interrupt file of hart `i` assigned to vCPU `i` by `VM::enable_aia`.
+#[derive(Clone, Copy)]
+struct ImsicFile {
+    /// Where the file is mapped in the guest physical address space.
+    gpa: GuestPhysAddr,
+    /// Guest external interrupt number of the file on its hart.
+    vgein: usize,
+    /// Host physical address of the file.
+    addr: HostPhysAddr,
+}
+
+/// Why `VM::run_scheduled` returned without failing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The vCPU stopped as its scheduler asked, was paused or ran out of CPU budget, and can be
+    /// run again.
+    Stopped,
+    /// The guest asked for a system reset of type `reset_type` for `reason` through the SBI SRST
+    /// extension. The VM is stopped for good: its vCPUs return this right away when run again, and
+    /// it's up to the host to tear the VM down or build it anew.
+    SystemReset {
+        reset_type: ResetType,
+        reason: ResetReason,
+    },
+}
+
+/// Id of the next VM created.
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A VM that is being run.
+pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
+    /// Id of the VM, unique among those created, tagging its log messages.
+    id: usize,
+    vcpus: VmCpus<H>,
+    gpt: G,
+    vm_pages: VmPages,
+    /// Layout of the guest physical address space.
+    regions: VmRegionList,
+    /// Host physical memory assigned to the VM, which its RAM may be mapped to.
+    host_memory: HostRangeSet,
+    plic: PlicState,
+    /// The emulated APLIC, replacing the vPLIC once AIA is enabled.
+    aplic: Option<Arc<EmuAplic>>,
+    /// Guest interrupt file assigned to each vCPU when AIA is enabled.
+    imsic_files: [Option<ImsicFile>; VM_CPUS_MAX],
+    /// Guest RAM pages allocated on first touch and ROM pages, freed with the VM unless the guest
+    /// balloons them earlier.
+    lazy_pages: BTreeSet<HostVirtAddr>,
+    /// Pages written since dirty logging was enabled or the bitmap was last taken, by the guest or
+    /// through `GuestRam`.
+    dirty_log: Option<RefCell<DirtyBitmap>>,
+    /// Timer deadline each vCPU set through SBI, `u64::MAX` if none is pending.
+    timer_deadlines: [u64; VM_CPUS_MAX],
+    /// The VM's console of the console multiplexer, if it's attached to it.
+    console: Option<ConsoleId>,
+    /// Emulated devices MMIO accesses outside the vPLIC are dispatched to.
+    devices: DeviceRegistry,
+    /// Where the emulated UART is, if the VM has one.
+    uart: Option<GuestPhysAddr>,
+    /// Where the emulated RTC is, if the VM has one.
+    rtc: Option<GuestPhysAddr>,
+    /// The emulated virtio devices, which are also in `devices`.
+    virtio_devs: Vec<Arc<EmuVirtioMmio>>,
+    /// Control of the balloon device, if the VM has one, and its guest interrupt.
+    balloon: Option<(BalloonControl, u32)>,
+    /// Breakpoints and single steps placed in guest memory.
+    debugger: GuestDebugger,
+    /// Caps on the resources the VM consumes.
+    limits: ResourceLimits,
+    /// Time the vCPUs have spent running the guest, in ticks of the `time` CSR.
+    cpu_time: u64,
+    /// The CPU bandwidth group the VM's CPU time is accounted in, and its slot there.
+    bandwidth: Option<(Arc<CpuBandwidth>, usize)>,
+    /// Whether each vCPU is bound to a hart of its own and runs there without time slices.
+    realtime: bool,
+    /// Measurement of the images loaded with `load_and_measure`.
+    measurement: [u8; SHA256_DIGEST_SIZE],
+    /// Hardware VMID tagging the VM's guest translations.
+    vmid: Vmid,
+    /// Devices passed through with `attach_passthrough_device`.
+    passthrough_devices: Vec<u32>,
+    /// The `hgatp` the IOMMU translates the passthrough devices' DMA through, whose VMID tags
+    /// their cached translations.
+    iommu_hgatp: usize,
+    /// Memory of other VMs shared into the VM.
+    shares: Vec<VmShare>,
+    /// The system reset the guest asked for, which stopped the VM.
+    system_reset: Option<StopReason>,
+    /// Layout of the guest physical address space the device tree is generated from, if known.
+    memory_map: Option<GuestMemoryMap>,
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Create a new VM with `vcpus` vCPUs and `gpt` as the guest page table. The second-stage
+    /// translation mode of `gpt`, see `GuestPagingMode`, bounds the guest physical address space:
+    /// regions beyond it fail to be added with `OutOfRange`.
+    pub fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self> {
+        let mut vm = Self {
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            vcpus,
+            gpt,
+            vm_pages: VmPages::default(),
+            regions: VmRegionList::default(),
+            host_memory: HostRangeSet::default(),
+            plic: PlicState::new(PLIC_GPA),
+            aplic: None,
+            imsic_files: [None; VM_CPUS_MAX],
+            lazy_pages: BTreeSet::new(),
+            dirty_log: None,
+            timer_deadlines: [u64::MAX; VM_CPUS_MAX],
+            console: None,
+            devices: DeviceRegistry::default(),
+            uart: None,
+            rtc: None,
+            virtio_devs: Vec::new(),
+            balloon: None,
+            debugger: GuestDebugger::default(),
+            limits: ResourceLimits::default(),
+            cpu_time: 0,
+            bandwidth: None,
+            realtime: false,
+            measurement: [0; SHA256_DIGEST_SIZE],
+            vmid: Vmid::default(),
+            passthrough_devices: Vec::new(),
+            iommu_hgatp: 0,
+            shares: Vec::new(),
+            system_reset: None,
+            memory_map: None,
+        };
+        // Regions must be translatable by the second-stage mode of the guest page table.
+        if let Some(mode) = GuestPagingMode::from_hgatp(vm.gpt.token()) {
+            vm.regions.set_limit(1 << mode.gpa_bits());
+        }
+        vm.regions
+            .add(PLIC_GPA, PLIC_GPA + PLIC_SIZE, VmRegionType::Mmio)?;
+        Ok(vm)
+    }
+
+    /// Exposes the ISA extensions in `isa` that the host implements to the guest, on all its vCPUs
+    /// from the next time they're activated, and returns those exposed. The others are disabled in
+    /// `henvcfg` and not reported to the guest, so it doesn't use what the host can't virtualize.
+    /// `IsaExtensions::GUEST_DEFAULT` is exposed by default.
+    pub fn set_guest_extensions(&mut self, isa: IsaExtensions) -> IsaExtensions {
+        let isa = isa.intersection(&IsaExtensions::host());
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.set_isa_extensions(isa);
+            }
+        }
+        isa
+    }
+
+    /// Caps the resources the VM consumes from now on. Allocations that would exceed a limit fail
+    /// with `NoMemory`, be it adding a device or a page the guest touches first, which is fatal to
+    /// the vCPU touching it. Resources already consumed past a lowered limit aren't taken back.
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    /// Accounts the VM's CPU time in `slot` of `bandwidth`, see `CpuBandwidth::add`. Time slices
+    /// of its vCPUs then end with its budget for the period, and `run_scheduled` returns once it's
+    /// used up. The slot is removed when the VM is dropped.
+    pub fn set_cpu_bandwidth(&mut self, bandwidth: Arc<CpuBandwidth>, slot: usize) {
+        if let Some((old, old_slot)) = self.bandwidth.replace((bandwidth, slot)) {
+            old.remove(old_slot);
+        }
+    }
+
+    /// The caps on the resources the VM consumes.
+    pub fn resource_limits(&self) -> ResourceLimits {
+        self.limits
+    }
+
+    /// The resources the VM currently consumes.
+    pub fn resource_usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            memory_pages: self.lazy_pages.len(),
+            // The APLIC is the interrupt controller rather than a device.
+            devices: self.devices.len() - self.aplic.is_some() as usize,
+            cpu_time: self.cpu_time,
+        }
+    }
+
+    /// The id of the VM, unique among those created, which its log messages are tagged with.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Records `map` as the layout of the VM's guest physical address space, for the host to
+    /// generate the device tree from and to place devices registered later.
+    pub fn set_memory_map(&mut self, map: GuestMemoryMap) {
+        self.memory_map = Some(map);
+    }
+
+    /// The layout of the VM's guest physical address space, if set.
+    pub fn memory_map(&self) -> Option<&GuestMemoryMap> {
+        self.memory_map.as_ref()
+    }
+
+    /// The layout of the VM's guest physical address space, e.g. to hand out a virtio-mmio slot
+    /// to a device added with `add_virtio_*`.
+    pub fn memory_map_mut(&mut self) -> Option<&mut GuestMemoryMap> {
+        self.memory_map.as_mut()
+    }
+
+    /// Initialize `VCpu` by `vcpu_id`.
+    pub fn init_vcpu(&mut self, vcpu_id: usize) {
+        let hgatp = self.hgatp();
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+        vcpu.init_page_map(hgatp);
+    }
+
+    /// The `hgatp` value the VM's vCPUs run with: its guest page table, tagged with its VMID.
+    fn hgatp(&self) -> usize {
+        self.gpt.token() | self.vmid.id() << HGATP_VMID_SHIFT
+    }
+
+    /// Registers `[gpa, gpa + size)` as guest RAM. The caller may map (part of) it in the guest page
+    /// table upfront, to host memory assigned with `assign_host_memory`; pages left unmapped are
+    /// allocated through `HyperCraftHal::alloc_page` and mapped when the guest first touches them.
+    ///
+    /// While devices are passed through, the region is populated at once instead.
+    pub fn add_ram_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        self.regions
+            .add(gpa, gpa + size, VmRegionType::Confidential)?;
+        if !self.passthrough_devices.is_empty() {
+            self.populate_ram()?;
+        }
+        Ok(())
+    }
+
+    /// Adds a ROM at `gpa` holding `data`, padded with zeros to whole pages. The guest reads and
+    /// executes it like RAM, while its writes fault or are dropped as per `policy`.
+    pub fn add_rom_region(
+        &mut self,
+        gpa: GuestPhysAddr,
+        data: &[u8],
+        policy: RomWritePolicy,
+    ) -> HyperResult<()> {
+        let size = (data.len() + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        self.regions
+            .add(gpa, gpa + size, VmRegionType::Rom(policy))?;
+        for (index, chunk) in data.chunks(PAGE_SIZE_4K).enumerate() {
+            self.check_page_limit()?;
+            let page = H::alloc_page().ok_or(HyperError::NoMemory)?;
+            unsafe {
+                core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE_4K);
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), page as *mut u8, chunk.len());
+            }
+            let page_gpa = gpa + index * PAGE_SIZE_4K;
+            if let Err(err) = self.gpt.map(page_gpa, H::virt_to_phys(page), ROM_FLAGS) {
+                H::dealloc_page(page);
+                return Err(err);
+            }
+            self.lazy_pages.insert(page);
+        }
+        Ok(())
+    }
+
+    /// Copies `data` into guest RAM at `gpa`, e.g. a kernel image before the VM first runs, as
+    /// `copy_to_guest` does.
+    pub fn load_image(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> HyperResult<()> {
+        self.copy_to_guest(gpa, data)
+    }
+
+    /// Copies the guest RAM at `gpa` into `buf`. The range is translated page by page, so it may
+    /// span pages mapped to scattered host memory, and copied in one go wherever consecutive pages
+    /// are backed by contiguous host memory. Pages not populated yet read as zeros. Fails with
+    /// `OutOfRange` if the range isn't guest RAM or memory shared into the VM, or is mapped to host
+    /// memory not assigned to the VM.
+    pub fn copy_from_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        let mem = self.guest_ram();
+        mem.for_each_run(gpa, buf.len(), false, |src, offset, len| {
+            let dst = &mut buf[offset..offset + len];
+            match src {
+                // Safety: the range is guest RAM backed by host memory assigned to the VM.
+                Some(src) => unsafe {
+                    core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), len)
+                },
+                None => dst.fill(0),
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies `data` into guest RAM at `gpa`, translated and copied as by `copy_from_guest`. Pages
+    /// not populated yet are allocated as on the guest's first touch; populated ones must be backed
+    /// by host memory assigned to the VM. Memory shared into the VM read-only can't be written.
+    pub fn copy_to_guest(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> HyperResult<()> {
+        let end = gpa.checked_add(data.len()).ok_or(HyperError::OutOfRange)?;
+        for page in (gpa & !(PAGE_SIZE_4K - 1)..end).step_by(PAGE_SIZE_4K) {
+            match self.regions.find(page).map(VmRegion::region_type) {
+                Some(VmRegionType::Confidential) if self.gpt.translate(page).is_err() => {
+                    self.populate_ram_page(page)?;
+                }
+                Some(region_type) if is_guest_ram(region_type, true) => {}
+                _ => return Err(HyperError::OutOfRange),
+            }
+        }
+        let mem = self.guest_ram();
+        mem.write(gpa, data)
+    }
+
+    /// Loads `data` into guest RAM at `gpa` as `load_image` does and extends the VM's measurement
+    /// with it, e.g. for the host to attest the images the guest was booted from. The measurement
+    /// becomes the SHA-256 of the previous measurement followed by the SHA-256 of `gpa` and the
+    /// length of `data`, both as little endian u64, and `data`. It starts as zeros.
+    pub fn load_and_measure(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> HyperResult<()> {
+        self.load_image(gpa, data)?;
+        let mut image = Sha256::new();
+        image.update(&(gpa as u64).to_le_bytes());
+        image.update(&(data.len() as u64).to_le_bytes());
+        image.update(data);
+        let mut measurement = Sha256::new();
+        measurement.update(&self.measurement);
+        measurement.update(&image.finish());
+        self.measurement = measurement.finish();
+        Ok(())
+    }
+
+    /// The measurement of the images loaded with `load_and_measure`.
+    pub fn measurement(&self) -> [u8; SHA256_DIGEST_SIZE] {
+        self.measurement
+    }
+
+    /// Registers `[gpa, gpa + size)` as MMIO of emulated devices, so no RAM or ROM can be placed
+    /// there. The registers of the devices the VM emulates itself, e.g. the vPLIC at `0xC00_0000`,
+    /// are registered the same way when they're added, rounded up to whole pages, so they must be
+    /// page aligned and fail with `BadState` over another region. Guest accesses outside of any
+    /// region are emulated as well.
+    pub fn add_mmio_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        self.regions.add(gpa, gpa + size, VmRegionType::Mmio)
+    }
+
+    /// Maps the host device MMIO `[hpa, hpa + size)` at `gpa`, giving the guest direct access to
+    /// it without trapping. `attrs` are the accesses allowed, `READ` and optionally `WRITE`, and
+    /// may ask for the `UNCACHED` memory type, e.g. for a framebuffer, rather than `DEVICE`. Other
+    /// accesses are fatal faults.
+    ///
+    /// The memory type is applied by page tables supporting Svpbmt. Otherwise the device's host
+    /// physical memory attributes apply, which make MMIO non-cacheable.
+    pub fn map_passthrough(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        size: usize,
+        attrs: MappingFlags,
+    ) -> HyperResult<()> {
+        if hpa % PAGE_SIZE_4K != 0
+            || !attrs.contains(MappingFlags::READ)
+            || attrs.contains(MappingFlags::EXECUTE)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut flags = attrs | MappingFlags::USER;
+        if !attrs.intersects(MappingFlags::DEVICE | MappingFlags::UNCACHED) {
+            flags |= MappingFlags::DEVICE;
+        }
+        self.regions
+            .add(gpa, gpa + size, VmRegionType::Passthrough)?;
+        self.gpt.map_region(gpa, hpa, size, flags)?;
+        self.flush_guest_tlb(gpa, size);
+        Ok(())
+    }
+
+    /// Assigns the host physical memory `[hpa, hpa + size)` to the VM, allowing its RAM to be
+    /// mapped there. The hypervisor only accesses guest RAM on the guest's behalf, e.g. for
+    /// snapshots, if it's backed by memory assigned to the VM.
+    pub fn assign_host_memory(&mut self, hpa: HostPhysAddr, size: usize) -> HyperResult<()> {
+        self.host_memory.add(hpa, hpa + size)
+    }
+
+    /// Shares `[src_gpa, src_gpa + size)` of the RAM of the VM `src` into this VM at `gpa`, for
+    /// producer/consumer designs like shared-memory devices, with the guest allowed the accesses
+    /// in `perm`. Its other accesses to the share are fatal faults. The share lasts until revoked
+    /// with `revoke_share`, and `audit_isolation` accepts the VMs overlapping there.
+    ///
+    /// Only RAM the caller mapped to host memory assigned to `src` can be shared, as the pages
+    /// allocated on first touch are freed with their VM. Fails with `InvalidParam` if the range
+    /// isn't page-aligned RAM of `src`, and `BadState` if a page isn't mapped to memory assigned
+    /// by the caller or is already shared into this VM.
+    pub fn share_region(
+        &mut self,
+        src: &Self,
+        src_gpa: GuestPhysAddr,
+        gpa: GuestPhysAddr,
+        size: usize,
+        perm: SharePermission,
+    ) -> HyperResult<()> {
+        if src_gpa % PAGE_SIZE_4K != 0 || gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        match src.regions.find(src_gpa) {
+            Some(region)
+                if region.region_type() == VmRegionType::Confidential
+                    && src_gpa + size <= region.start() + region.size() => {}
+            _ => return Err(HyperError::InvalidParam),
+        }
+        let mut memory = HostRangeSet::default();
+        let mut pages = Vec::new();
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            let hpa = src
+                .gpt
+                .translate(src_gpa + offset)
+                .map_err(|_| HyperError::BadState)?;
+            if src.lazy_pages.contains(&H::phys_to_virt(hpa))
+                || !src.host_memory.contains(hpa, PAGE_SIZE_4K)
+                || self.host_memory.overlaps(hpa, hpa + PAGE_SIZE_4K)
+            {
+                return Err(HyperError::BadState);
+            }
+            memory.add(hpa, hpa + PAGE_SIZE_4K)?;
+            pages.push(hpa);
+        }
+        let flags = match perm {
+            SharePermission::ReadOnly => SHARED_RO_FLAGS,
+            SharePermission::ReadWrite => RAM_FLAGS,
+        };
+        self.regions
+            .add(gpa, gpa + size, VmRegionType::Shared(perm))?;
+        for (index, &hpa) in pages.iter().enumerate() {
+            if let Err(err) = self.gpt.map(gpa + index * PAGE_SIZE_4K, hpa, flags) {
+                for mapped in 0..index {
+                    let _ = self.gpt.unmap(gpa + mapped * PAGE_SIZE_4K);
+                }
+                self.regions.remove(gpa);
+                return Err(err);
+            }
+        }
+        // The hypervisor accesses the share on the guest's behalf like its own RAM.
+        for (start, end) in memory.iter() {
+            self.host_memory.add(start, end)?;
+        }
+        self.shares.push(VmShare {
+            gpa,
+            size,
+            src: src.id,
+            memory,
+        });
+        Ok(())
+    }
+
+    /// Revokes the share mapped at `gpa` with `share_region`, unmapping it from the guest. Fails
+    /// with `NotFound` if there's no share at `gpa`.
+    pub fn revoke_share(&mut self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let index = self
+            .shares
+            .iter()
+            .position(|share| share.gpa == gpa)
+            .ok_or(HyperError::NotFound)?;
+        let share = self.shares.remove(index);
+        for offset in (0..share.size).step_by(PAGE_SIZE_4K) {
+            let _ = self.gpt.unmap(gpa + offset);
+        }
+        self.flush_guest_tlb(gpa, share.size);
+        self.regions.remove(gpa);
+        for (start, end) in share.memory.iter() {
+            self.host_memory.remove(start, end);
+        }
+        // A virtqueue's rings may have been in the share.
+        self.invalidate_virtio_rings();
+        Ok(())
+    }
+
+    /// The host memory the VM `src` shares into this VM.
+    pub(crate) fn shared_from(&self, src: usize) -> HostRangeSet {
+        let mut memory = HostRangeSet::default();
+        for share in self.shares.iter().filter(|share| share.src == src) {
+            for (start, end) in share.memory.iter() {
+                let _ = memory.add(start, end);
+            }
+        }
+        memory
+    }
+
+    /// The host memory assigned to the VM, less the memory other VMs share into it.
+    pub(crate) fn private_memory(&self) -> HostRangeSet {
+        let mut memory = self.host_memory.clone();
+        for share in &self.shares {
+            for (start, end) in share.memory.iter() {
+                memory.remove(start, end);
+            }
+        }
+        memory
+    }
+
+    /// Checks that all mapped guest RAM pages are backed by host memory assigned to the VM. Fails
+    /// with `OutOfRange` if one isn't, e.g. because the caller mapped RAM to host memory it didn't
+    /// assign.
+    pub fn verify_mappings(&self) -> HyperResult<()> {
+        for gpa in self.mapped_ram_pages() {
+            let hpa = self.gpt.translate(gpa)?;
+            if !self.host_memory.contains(hpa, PAGE_SIZE_4K) {
+                hv_log!(
+                    Warn,
+                    Mmu,
+                    LogContext::vm(self.id),
+                    "RAM page {:#x} is mapped to foreign host memory {:#x}",
+                    gpa,
+                    hpa
+                );
+                return Err(HyperError::OutOfRange);
+            }
+        }
+        Ok(())
+    }
+
+    /// The regions registered in the VM's guest physical address space, in the order they were
+    /// added.
+    pub fn guest_regions(&self) -> Vec<GuestRegion> {
+        self.regions
+            .iter()
+            .map(|r| GuestRegion {
+                start: r.start(),
+                end: r.end(),
+                kind: r.region_type().into(),
+            })
+            .collect()
+    }
+
+    /// The mappings of the VM's guest page table within its regions, merged into runs of pages
+    /// contiguous in both guest and host memory. Pages mapped outside the regions aren't found, as
+    /// the guest page table is probed page by page rather than walked.
+    pub fn guest_mappings(&self) -> Vec<GuestMapping> {
+        let mut mappings: Vec<GuestMapping> = Vec::new();
+        for region in self.regions.iter() {
+            let kind = RegionKind::from(region.region_type());
+            let start = region.start() & !(PAGE_SIZE_4K - 1);
+            for gpa in (start..region.end()).step_by(PAGE_SIZE_4K) {
+                let Ok(hpa) = self.gpt.translate(gpa) else {
+                    continue;
+                };
+                match mappings.last_mut() {
+                    Some(last)
+                        if last.kind == kind
+                            && last.gpa + last.size == gpa
+                            && last.hpa + last.size == hpa =>
+                    {
+                        last.size += PAGE_SIZE_4K;
+                    }
+                    _ => mappings.push(GuestMapping {
+                        gpa,
+                        hpa,
+                        size: PAGE_SIZE_4K,
+                        kind,
+                    }),
+                }
+            }
+        }
+        mappings
+    }
+
+    /// The host memory private to the VM: assigned to it or allocated for its RAM.
+    pub(crate) fn host_memory(&self) -> &HostRangeSet {
+        &self.host_memory
+    }
+
+    /// Translates the guest RAM range `[gpa, gpa + len)`, which must not cross a page boundary, to
+    /// the host physical address it's mapped to, e.g. for a device backend to access a buffer the
+    /// guest handed it, and to write it if `write`. Fails with `OutOfRange` if the range isn't
+    /// guest RAM or memory shared into the VM, backed by host memory assigned to the VM, or if
+    /// `write` and the memory is shared read-only.
+    pub fn translate_guest_range(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        write: bool,
+    ) -> HyperResult<HostPhysAddr> {
+        translate_guest_range(&self.gpt, &self.regions, &self.host_memory, gpa, len, write)
+    }
+
+    /// Starts tracking the guest RAM pages written by the guest, by its emulated devices and through
+    /// `copy_to_guest`. Populated RAM pages are write-protected, the first write of the guest to each
+    /// marking it dirty and making it writable again. RAM mapped by the caller must be mapped with 4K
+    /// pages.
+    ///
+    /// Fails with `BadState` while devices are passed through, as their DMA writes can't fault.
+    pub fn enable_dirty_log(&mut self) -> HyperResult<()> {
+        if self.dirty_log.is_some() || !self.passthrough_devices.is_empty() {
+            return Err(HyperError::BadState);
+        }
+        let ram = self.ram_regions();
+        let start = ram
+            .clone()
+            .map(|r| r.start())
+            .min()
+            .ok_or(HyperError::NotFound)?;
+        let end = ram.map(|r| r.start() + r.size()).max().unwrap();
+        self.dirty_log = Some(RefCell::new(DirtyBitmap::new(start, end - start)));
+        // Device writes through the cached host addresses of virtqueue rings wouldn't be tracked.
+        self.invalidate_virtio_rings();
+        let pages = self.mapped_ram_pages().collect::<Vec<_>>();
+        self.protect_ram_pages(pages.into_iter(), RAM_WP_FLAGS)
+    }
+
+    /// Stops dirty logging and makes all populated RAM pages writable again.
+    pub fn disable_dirty_log(&mut self) -> HyperResult<()> {
+        if self.dirty_log.take().is_none() {
+            return Err(HyperError::BadState);
+        }
+        self.invalidate_virtio_rings();
+        let pages = self.mapped_ram_pages().collect::<Vec<_>>();
+        self.protect_ram_pages(pages.into_iter(), RAM_FLAGS)
+    }
+
+    /// Returns the pages written since dirty logging was enabled or this was last called, and
+    /// write-protects them again to track further writes.
+    pub fn take_dirty_bitmap(&mut self) -> HyperResult<DirtyBitmap> {
+        let dirty_log = self
+            .dirty_log
+            .as_mut()
+            .ok_or(HyperError::BadState)?
+            .get_mut();
+        let fresh = DirtyBitmap::new(dirty_log.base(), dirty_log.size());
+        let dirty = core::mem::replace(dirty_log, fresh);
+        self.protect_ram_pages(dirty.iter(), RAM_WP_FLAGS)?;
+        Ok(dirty)
+    }
+
+    /// Passes the DMA-capable device `device_id` through to this VM. The device's DMA is
+    /// translated by the IOMMU using this VM's guest page table, tagged with its VMID.
+    ///
+    /// DMA doesn't fault the way the guest does, so all guest RAM is populated first and stays so
+    /// while a device is attached: the balloon isn't reclaimed and dirty logging can't be enabled
+    /// meanwhile. Fails with `BadState` if dirty logging is enabled, and `NoMemory` if populating
+    /// RAM would exceed the VM's limit. The device is detached when the VM is dropped.
+    pub fn attach_passthrough_device(&mut self, device_id: u32) -> HyperResult<()> {
+        let iommu = IOMMU.get().ok_or(HyperError::NotSupported)?;
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        self.populate_ram()?;
+        self.vmid.assign();
+        self.rebind_passthrough_devices();
+        let hgatp = self.hgatp();
+        iommu.lock().attach_device::<H>(device_id, hgatp)?;
+        self.iommu_hgatp = hgatp;
+        self.passthrough_devices.push(device_id);
+        Ok(())
+    }
+
+    /// Detaches the passthrough device `device_id` from this VM. Fails with `NotFound` if it isn't
+    /// attached to this VM.
+    pub fn detach_passthrough_device(&mut self, device_id: u32) -> HyperResult<()> {
+        let iommu = IOMMU.get().ok_or(HyperError::NotSupported)?;
+        let index = self
+            .passthrough_devices
+            .iter()
+            .position(|&id| id == device_id)
+            .ok_or(HyperError::NotFound)?;
+        iommu.lock().detach_device::<H>(device_id)?;
+        self.passthrough_devices.remove(index);
+        Ok(())
+    }
+
+    /// Routes the physical interrupt `host_irq` to this VM, where it's raised as `guest_irq` on the
+    /// virtual PLIC. Completions of `guest_irq` by the guest are forwarded to the host PLIC.
+    ///
+    /// Fails with `NotSupported` once AIA is enabled: the host interrupt is wired to the host
+    /// PLIC, so it can't be delivered to a guest interrupt file without trapping.
+    pub fn passthrough_irq(&mut self, host_irq: usize, guest_irq: usize) -> HyperResult<()> {
+        if self.aplic.is_some() {
+            return Err(HyperError::NotSupported);
+        }
+        let host_irq = u32::try_from(host_irq).map_err(|_| HyperError::InvalidParam)?;
+        let guest_irq = u32::try_from(guest_irq).map_err(|_| HyperError::InvalidParam)?;
+        self.plic.route_irq(host_irq, guest_irq)
+    }
+
+    /// Switches the VM's interrupt controller from the vPLIC to AIA. vCPU `i` is given a guest
+    /// interrupt file of hart `i`, which is mapped into the guest as its IMSIC S-level file at
+    /// `imsic_gpa + i * 0x1000`, and an APLIC domain in MSI delivery mode is emulated at
+    /// `aplic_gpa`. The files are given back when the VM is dropped.
+    ///
+    /// A guest interrupt file only interrupts the hart it belongs to, so vCPU `i` must be
+    /// restricted to hart `i` alone with `VCpu::set_affinity`, and stays so while AIA is enabled.
+    /// Fails with `BadState` otherwise, and with `NotSupported` if the host has no AIA or host
+    /// interrupts are already routed to the VM with `passthrough_irq`. The vPLIC then stays in
+    /// use, with no file left assigned.
+    pub fn enable_aia(
+        &mut self,
+        imsic_gpa: GuestPhysAddr,
+        aplic_gpa: GuestPhysAddr,
+    ) -> HyperResult<()> {
+        let aia = AIA.get().ok_or(HyperError::NotSupported)?;
+        if self.aplic.is_some() {
+            return Err(HyperError::BadState);
+        }
+        if self.plic.has_irq_routes() {
+            return Err(HyperError::NotSupported);
+        }
+        self.add_device_window(aplic_gpa, APLIC_SIZE)?;
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if self.vcpus.get_vcpu(vcpu_id).is_err() {
+                continue;
+            }
+            let file_gpa = imsic_gpa + vcpu_id * PAGE_SIZE_4K;
+            if let Err(err) = self.assign_guest_file(aia, vcpu_id, file_gpa) {
+                self.release_guest_files();
+                self.regions.remove(aplic_gpa);
+                return Err(err);
+            }
+        }
+        let aplic = Arc::new(EmuAplic::new(aplic_gpa));
+        self.devices.add(aplic.clone());
+        self.aplic = Some(aplic);
+        Ok(())
+    }
+
+    /// Sends an MSI with identity `eiid` to the interrupt file of vCPU `vcpu_id`, e.g. to signal
+    /// the completion of an emulated device's request. Requires AIA to be enabled.
+    pub fn inject_msi(&mut self, vcpu_id: usize, eiid: u32) -> HyperResult<()> {
+        let file = self
+            .imsic_files
+            .get(vcpu_id)
+            .copied()
+            .flatten()
+            .ok_or(HyperError::NotSupported)?;
+        send_msi::<H>(file.addr, eiid);
+        H::vcpu_interrupt_pending(vcpu_id);
+        Ok(())
+    }
+
+    /// Returns the vCPU `vcpu_id`, e.g. to access its registers while the VM isn't running.
+    pub fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        self.vcpus.get_vcpu(vcpu_id)
+    }
+
+    /// Reads the guest memory at the guest virtual address `gva` in the address space of the vCPU
+    /// `vcpu_id` into `buf`. The memory must be guest RAM.
+    pub fn read_guest_virt(
+        &mut self,
+        vcpu_id: usize,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+    ) -> HyperResult<()> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        debug::read_virt(vcpu, gva, buf, &mem)
+    }
+
+    /// Writes `buf` to the guest memory at the guest virtual address `gva` in the address space of
+    /// the vCPU `vcpu_id`, e.g. to patch guest code. The memory must be guest RAM.
+    pub fn write_guest_virt(
+        &mut self,
+        vcpu_id: usize,
+        gva: GuestVirtAddr,
+        buf: &[u8],
+    ) -> HyperResult<()> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        debug::write_virt(vcpu, gva, buf, &mem)
+    }
+
+    /// Puts all vCPUs in debug mode, where they stop at breakpoints and single steps as reported
+    /// to `VcpuScheduler::on_debug_event`, or takes them out of it, removing all breakpoints.
+    pub fn set_debug(&mut self, enabled: bool) -> HyperResult<()> {
+        if !enabled {
+            let mem = GuestRam::<H, G>::new(
+                &self.gpt,
+                &self.regions,
+                &self.host_memory,
+                self.dirty_log.as_ref(),
+            );
+            self.debugger.clear(&mem)?;
+        }
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.set_debug(enabled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts a breakpoint at the guest virtual address `gva` in the address space of the vCPU
+    /// `vcpu_id`, replacing the instruction there in guest RAM until it's removed. The VM must be
+    /// in debug mode and not running.
+    pub fn insert_breakpoint(&mut self, vcpu_id: usize, gva: GuestVirtAddr) -> HyperResult<()> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        if !vcpu.debug() {
+            return Err(HyperError::BadState);
+        }
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        self.debugger.insert_breakpoint(vcpu, gva, &mem)
+    }
+
+    /// Removes the breakpoint at `gva`, restoring the original instruction.
+    pub fn remove_breakpoint(&mut self, gva: GuestVirtAddr) -> HyperResult<()> {
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        self.debugger.remove_breakpoint(gva, &mem)
+    }
+
+    /// Makes the vCPU `vcpu_id` stop after its next instruction, reporting `DebugEvent::Step`
+    /// when it's run again. Steps over a breakpoint the vCPU stopped at. Other vCPUs must not run
+    /// until the step completes, and no breakpoints can be changed meanwhile. The VM must be in
+    /// debug mode and not running.
+    pub fn single_step(&mut self, vcpu_id: usize) -> HyperResult<()> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        if !vcpu.debug() {
+            return Err(HyperError::BadState);
+        }
+        let mem = GuestRam::<H, G>::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        );
+        self.debugger.single_step(vcpu, &mem)
+    }
+
+    /// Puts the VM in real-time mode, binding vCPU `i` to the hart `harts[i]` alone. Its vCPUs then
+    /// run without WFI exits and are to be run with `run_realtime`, each on its hart, which the
+    /// host keeps for it. Check the rest of the configuration with `verify_realtime`. Fails with
+    /// `InvalidParam` if there isn't one distinct hart per vCPU.
+    pub fn set_realtime(&mut self, harts: &[usize]) -> HyperResult<()> {
+        let distinct = harts
+            .iter()
+            .enumerate()
+            .all(|(index, hart)| !harts[..index].contains(hart));
+        if harts.len() != self.vcpus.iter().count()
+            || !distinct
+            || harts.iter().any(|&hart| hart >= usize::BITS as usize)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        for (vcpu_id, &hart) in harts.iter().enumerate() {
+            let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+            vcpu.set_affinity(1 << hart)?;
+            vcpu.set_wfi_exit(false);
+        }
+        self.realtime = true;
+        Ok(())
+    }
+
+    /// Runs the vCPU `vcpu_id` of a VM in real-time mode on its hart, without time slices, until
+    /// it's paused, the guest asks for a system reset or faults fatally as with `run_scheduled`.
+    /// WFI executed by the guest waits on the hart. Fails with `BadState` if the VM isn't in
+    /// real-time mode.
+    pub fn run_realtime(&mut self, vcpu_id: usize) -> HyperResult<StopReason> {
+        if !self.realtime {
+            return Err(HyperError::BadState);
+        }
+        self.run_scheduled(vcpu_id, &mut Unscheduled::<H>(PhantomData))
+    }
+
+    /// Runs the vCPU `vcpu_id` on this hart until it's paused, the guest asks for a system reset
+    /// or faults fatally, as with `run_scheduled`. WFI executed by the guest parks the hart.
+    pub fn run(&mut self, vcpu_id: usize) -> HyperResult<StopReason> {
+        self.run_scheduled(vcpu_id, &mut Unscheduled::<H>(PhantomData))
+    }
+
+    #[allow(unused_variables, deprecated)]
+    /// Runs the vCPU `vcpu_id` in time slices given by `sched`, until it tells the vCPU to stop
+    /// running. A host timer interrupt ends each slice, and WFI executed by the guest is reported
+    /// to `sched` rather than stalling the hart. Calling this again resumes the vCPU, possibly on
+    /// another hart of its affinity.
+    ///
+    /// Returns as well when the vCPU is paused through `VCpu::pause`, once it's off the hart, and
+    /// when the VM has used up its CPU budget of the period, see `set_cpu_bandwidth`.
+    ///
+    /// The guest manages the power state of its vCPUs through the SBI HSM extension, its hart ids
+    /// being the vCPU ids. A stopped vCPU is reported to `sched` as blocked until another vCPU
+    /// starts it, and a vCPU suspending itself waits for an interrupt as on WFI.
+    ///
+    /// A system reset the guest asks for through the SBI SRST extension stops the VM rather than
+    /// the host, and is returned as `StopReason::SystemReset`.
+    ///
+    /// Fails with `OutOfRange` once the VM has used up its CPU time, see `set_resource_limits`.
+    ///
+    /// Fails if the guest faults fatally, e.g. on an access to memory that's neither RAM nor an
+    /// emulated device or on a trap the hypervisor has no handler for, leaving the vCPU stopped at
+    /// the faulting instruction so the VM can be inspected, e.g. with `dump_core`. The caller
+    /// decides whether to kill the VM; the hypervisor itself keeps running.
+    pub fn run_scheduled<S: VcpuScheduler>(
+        &mut self,
+        vcpu_id: usize,
+        sched: &mut S,
+    ) -> HyperResult<StopReason> {
+        if let Some(reset) = self.system_reset {
+            return Ok(reset);
+        }
+        let mut vm_exit_info: VmExitInfo;
+        let mut gprs = GeneralPurposeRegisters::default();
+        {
+            let hart_id = PerCpu::<H>::this_cpu().cpu_id();
+            self.vmid.assign();
+            vmid::sync_hart(hart_id);
+            self.rebind_passthrough_devices();
+            let hgatp = self.hgatp();
+            let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+            vcpu.set_hgatp(hgatp);
+            vcpu.activate(hart_id)?;
+            vcpu.set_wfi_exit(!self.realtime);
+        }
+        let mut slice_end = self.slice_end(current_time(), sched.timeslice(vcpu_id));
+        self.program_timer(vcpu_id, slice_end);
+        loop {
+            let len = 4;
+            let mut advance_pc = false;
+            let mut stop = false;
+            let mut fatal = None;
+            {
+                let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                // A step over an instruction jumping to itself completes without running.
+                let mem = GuestRam::<H, G>::new(
+                    &self.gpt,
+                    &self.regions,
+                    &self.host_memory,
+                    self.dirty_log.as_ref(),
+                );
+                match self.debugger.take_complete_step(vcpu, &mem) {
+                    Ok(Some(event)) if !sched.on_debug_event(vcpu_id, event) => {
+                        vcpu.deactivate();
+                        return Ok(StopReason::Stopped);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        vcpu.deactivate();
+                        return Err(err);
+                    }
+                }
+                if vcpu.pause_requested() {
+                    vcpu.deactivate();
+                    return Ok(StopReason::Stopped);
+                }
+                if self.limits.cpu_time_left(self.cpu_time) == 0 {
+                    vcpu.deactivate();
+                    return Err(HyperError::OutOfRange);
+                }
+                if bandwidth_left(&self.bandwidth, current_time()) == 0 {
+                    vcpu.deactivate();
+                    return Ok(StopReason::Stopped);
+                }
+                // A stopped vCPU waits to be started by another one, which kicks it.
+                if vcpu.hart_state() == HartState::Stopped {
+                    if !sched.on_vcpu_blocked(vcpu_id) {
+                        vcpu.deactivate();
+                        return Ok(StopReason::Stopped);
+                    }
+                    unsafe {
+                        core::arch::asm!("csrc sip, {}", in(reg) traps::interrupt::SUPERVISOR_SOFT);
+                    }
+                    continue;
+                }
+                vcpu.wake();
+                let entered = current_time();
+                vm_exit_info = vcpu.run();
+                let now = current_time();
+                self.cpu_time += now.saturating_sub(entered);
+                if let Some((bandwidth, slot)) = &self.bandwidth {
+                    bandwidth.charge(*slot, now.saturating_sub(entered), now);
+                }
+                vcpu.save_gprs(&mut gprs);
+            }
+
+            match vm_exit_info {
+                VmExitInfo::Ecall(sbi_msg) => {
+                    match sbi_msg {
+                        Some(HyperCallMsg::Base(base)) => {
+                            fatal = self.handle_base_function(base, &mut gprs).err();
+                        }
+                        Some(HyperCallMsg::GetChar) => {
+                            let c = match self.console {
+                                Some(id) => console::read_input(id).map_or(usize::MAX, usize::from),
+                                None => sbi_rt::legacy::console_getchar(),
+                            };
+                            gprs.set_reg(GprIndex::A1, c);
+                        }
+                        Some(HyperCallMsg::PutChar(c)) => match self.console {
+                            Some(id) => console::write_output(id, c as u8),
+                            None => {
+                                sbi_rt::legacy::console_putchar(c);
+                            }
+                        },
+                        Some(HyperCallMsg::SetTimer(timer)) => {
+                            self.timer_deadlines[vcpu_id] = timer as u64;
+                            // Clear guest timer interrupt
+                            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                            vcpu.clear_irq(IrqKind::Timer);
+                            self.program_timer(vcpu_id, slice_end);
+                        }
+                        Some(HyperCallMsg::Reset(ResetFunction::Reset { reset_type, reason })) => {
+                            hv_log!(
+                                Info,
+                                Sbi,
+                                LogContext::vcpu(self.id, vcpu_id),
+                                "system reset {:?} for {:?}",
+                                reset_type,
+                                reason
+                            );
+                            self.system_reset =
+                                Some(StopReason::SystemReset { reset_type, reason });
+                            stop = true;
+                        }
+                        Some(HyperCallMsg::RemoteFence(rfnc)) => {
+                            fatal = self.handle_rfnc_function(rfnc, &mut gprs).err();
+                        }
+                        Some(HyperCallMsg::PMU(pmu)) => {
+                            fatal = self.handle_pmu_function(pmu, &mut gprs).err();
+                        }
+                        Some(HyperCallMsg::GetIsaExtensions) => {
+                            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                            gprs.set_reg(GprIndex::A0, SBI_SUCCESS);
+                            gprs.set_reg(GprIndex::A1, vcpu.isa_extensions().bits());
+                        }
+                        Some(HyperCallMsg::HSM(hsm)) => {
+                            if self.handle_hsm_function(vcpu_id, hsm, &mut gprs) {
+                                stop = !self.idle_vcpu(vcpu_id, sched, slice_end);
+                            }
+                        }
+                        // Extensions and functions that aren't implemented, e.g. probed by the
+                        // guest.
+                        _ => gprs.set_reg(GprIndex::A0, SBI_ERR_NOT_SUPPORTED as usize),
+                    }
+                    advance_pc = fatal.is_none();
+                }
+                VmExitInfo::PageFault {
+                    fault_addr,
+                    falut_pc,
+                    inst,
+                    priv_level,
+                } => match self.regions.find(fault_addr).map(VmRegion::region_type) {
+                    Some(VmRegionType::Rom(policy)) => {
+                        if let Err(err) =
+                            self.handle_rom_write(vcpu_id, falut_pc, inst, fault_addr, policy)
+                        {
+                            hv_log!(
+                                Error,
+                                Mmu,
+                                LogContext::vcpu(self.id, vcpu_id),
+                                "Write to ROM at {:#x} addr@{:#x} with error {:?}",
+                                falut_pc,
+                                fault_addr,
+                                err
+                            );
+                            fatal = Some(err);
+                        }
+                    }
+                    // Passthrough MMIO is mapped, so the access itself isn't allowed.
+                    Some(VmRegionType::Passthrough) => {
+                        hv_log!(
+                            Error,
+                            Mmu,
+                            LogContext::vcpu(self.id, vcpu_id),
+                            "Passthrough MMIO fault at {:#x} addr@{:#x}",
+                            falut_pc,
+                            fault_addr
+                        );
+                        fatal = Some(HyperError::PageFault);
+                    }
+                    _ => match self.handle_ram_fault(fault_addr) {
+                        // The page has been populated, retry the access.
+                        Ok(true) => {}
+                        Ok(false) => match priv_level {
+                            super::vmexit::PrivilegeLevel::Supervisor => {
+                                match self.handle_page_fault(falut_pc, inst, fault_addr, &gprs) {
+                                    Ok((emu_ctx, val)) => {
+                                        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                                        vcpu.complete_mmio(&emu_ctx, val);
+                                        // Pick up the register loaded by the access.
+                                        vcpu.save_gprs(&mut gprs);
+                                    }
+                                    Err(err) => {
+                                        hv_log!(
+                                            Error,
+                                            Mmu,
+                                            LogContext::vcpu(self.id, vcpu_id),
+                                            "Page fault at {:#x} addr@{:#x} with error {:?}",
+                                            falut_pc,
+                                            fault_addr,
+                                            err
+                                        );
+                                        fatal = Some(err);
+                                    }
+                                }
+                            }
+                            super::vmexit::PrivilegeLevel::User => {
+                                hv_log!(
+                                    Error,
+                                    Mmu,
+                                    LogContext::vcpu(self.id, vcpu_id),
+                                    "User page fault at {:#x} addr@{:#x}",
+                                    falut_pc,
+                                    fault_addr
+                                );
+                                fatal = Some(HyperError::PageFault);
+                            }
+                        },
+                        Err(err) => {
+                            hv_log!(
+                                Error,
+                                Mmu,
+                                LogContext::vcpu(self.id, vcpu_id),
+                                "Failed to populate guest RAM at {:#x} with error {:?}",
+                                fault_addr,
+                                err
+                            );
+                            fatal = Some(err);
+                        }
+                    },
+                },
+                VmExitInfo::TimerInterruptEmulation => {
+                    // debug!("timer irq emulation");
+                    let now = current_time();
+                    if now >= self.timer_deadlines[vcpu_id] {
+                        // Enable guest timer interrupt
+                        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                        vcpu.inject_irq(IrqKind::Timer);
+                        self.timer_deadlines[vcpu_id] = u64::MAX;
+                    }
+                    if self.limits.cpu_time_left(self.cpu_time) == 0 {
+                        fatal = Some(HyperError::OutOfRange);
+                    } else if bandwidth_left(&self.bandwidth, now) == 0 {
+                        // Throttled until the next period.
+                        stop = true;
+                    } else if now >= slice_end {
+                        if sched.on_timeslice_expired(vcpu_id) {
+                            slice_end = self.slice_end(now, sched.timeslice(vcpu_id));
+                        } else {
+                            stop = true;
+                        }
+                    }
+                    self.program_timer(vcpu_id, slice_end);
+                }
+                VmExitInfo::VirtualInstruction { inst, .. } if inst == WFI_INST => {
+                    // An interrupt already pending for the guest wakes it up right away.
+                    if !self.vcpus.get_vcpu(vcpu_id).unwrap().any_irq_pending() {
+                        stop = !self.idle_vcpu(vcpu_id, sched, slice_end);
+                    }
+                    advance_pc = true;
+                }
+                VmExitInfo::VirtualInstruction {
+                    inst, priv_level, ..
+                } => {
+                    let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                    if let Some(rd) = vcpu.zeroed_counter_read(inst) {
+                        gprs.set_reg(rd, 0);
+                        advance_pc = true;
+                    } else if CsrInstruction::decode(inst).is_some_and(|csr_inst| {
+                        self.emulate_csr(vcpu_id, &csr_inst, priv_level, &mut gprs, slice_end)
+                    }) {
+                        advance_pc = true;
+                    } else {
+                        // Instructions the guest isn't allowed to execute, as if it had no
+                        // hypervisor underneath.
+                        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                        fatal = vcpu
+                            .inject_exception(ILLEGAL_INST_CAUSE, inst as usize)
+                            .err();
+                    }
+                }
+                VmExitInfo::ExternalInterruptEmulation => self.handle_irq(vcpu_id),
+                // The vCPU was kicked out of the guest, e.g. to pause it.
+                VmExitInfo::HostInterruot(_) => unsafe {
+                    core::arch::asm!("csrc sip, {}", in(reg) traps::interrupt::SUPERVISOR_SOFT);
+                },
+                VmExitInfo::DebugEvent { pc } => {
+                    let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                    let mem = GuestRam::<H, G>::new(
+                        &self.gpt,
+                        &self.regions,
+                        &self.host_memory,
+                        self.dirty_log.as_ref(),
+                    );
+                    match self.debugger.on_ebreak(vcpu, pc, &mem) {
+                        Ok(Some(event)) => stop = !sched.on_debug_event(vcpu_id, event),
+                        // The guest's own EBREAK.
+                        Ok(None) => fatal = vcpu.inject_exception(BREAKPOINT_CAUSE, pc).err(),
+                        Err(err) => fatal = Some(err),
+                    }
+                }
+                VmExitInfo::UnhandledTrap {
+                    cause,
+                    fault_pc,
+                    tval,
+                } => {
+                    hv_log!(
+                        Error,
+                        Vcpu,
+                        LogContext::vcpu(self.id, vcpu_id),
+                        "Unhandled trap {:#x} at {:#x} stval@{:#x}",
+                        cause,
+                        fault_pc,
+                        tval
+                    );
+                    fatal = Some(HyperError::NotSupported);
+                }
+            }
+            let irq_deadline = self.virtio_irq_deadline();
+            self.update_device_irqs(vcpu_id);
+            if self.virtio_irq_deadline() != irq_deadline {
+                self.program_timer(vcpu_id, slice_end);
+            }
+
+            {
+                let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                vcpu.restore_gprs(&gprs);
+                if advance_pc {
+                    vcpu.advance_pc(len);
+                }
+            }
+            if stop || fatal.is_some() {
+                // Save the guest state so the vCPU can be resumed on any hart.
+                self.vcpus.get_vcpu(vcpu_id).unwrap().deactivate();
+                let reason = self.system_reset.unwrap_or(StopReason::Stopped);
+                return fatal.map_or(Ok(reason), Err);
+            }
+        }
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> Drop for VM<H, G> {
+    fn drop(&mut self) {
+        // The devices' DMA must stop reaching guest RAM before it's freed.
+        if let Some(iommu) = IOMMU.get() {
+            let mut iommu = iommu.lock();
+            for &device_id in &self.passthrough_devices {
+                if let Err(err) = iommu.detach_device::<H>(device_id) {
+                    hv_log!(
+                        Error,
+                        Iommu,
+                        LogContext::vm(self.id),
+                        "failed to detach device {:#x}: {:?}",
+                        device_id,
+                        err
+                    );
+                }
+            }
+        }
+        for &page in &self.lazy_pages {
+            H::dealloc_page(page);
+        }
+        self.release_guest_files();
+        if let Some(id) = self.console {
+            console::detach(id);
+        }
+        if let Some((bandwidth, slot)) = &self.bandwidth {
+            bandwidth.remove(*slot);
+        }
+    }
+}
+
+// Privaie methods implementation
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Backs the guest RAM page containing `fault_addr` with a newly allocated host page. Returns
+    /// false if `fault_addr` isn't guest RAM, i.e. the fault is an MMIO access to be emulated.
+    fn handle_ram_fault(&mut self, fault_addr: GuestPhysAddr) -> HyperResult<bool> {
+        match self.regions.find(fault_addr).map(VmRegion::region_type) {
+            Some(VmRegionType::Confidential) => {}
+            // Shares are mapped in full, so the access itself isn't allowed, e.g. a write to a
+            // read-only one.
+            Some(VmRegionType::Shared(_)) => return Err(HyperError::PageFault),
+            _ => return Ok(false),
+        }
+        let gpa = fault_addr & !(PAGE_SIZE_4K - 1);
+        if self.gpt.translate(gpa).is_err() {
+            self.populate_ram_page(gpa)?;
+            return Ok(true);
+        }
+        match &mut self.dirty_log {
+            // First write of the guest to a write-protected page since the dirty bitmap was taken.
+            // The page may be dirty already, written through `GuestRam` meanwhile.
+            Some(dirty_log) => {
+                dirty_log.get_mut().set(gpa);
+                self.gpt.protect(gpa, RAM_FLAGS)?;
+                self.flush_guest_tlb(gpa, PAGE_SIZE_4K);
+                Ok(true)
+            }
+            // Already populated and writable, so the access itself isn't allowed.
+            _ => Err(HyperError::PageFault),
+        }
+    }
+
+    /// Handles a store of the guest at `inst_addr` to the ROM at `fault_addr`, which traps as ROM
+    /// is mapped read-only, as per the ROM's `policy`.
+    fn handle_rom_write(
+        &mut self,
+        vcpu_id: usize,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+        fault_addr: GuestPhysAddr,
+        policy: RomWritePolicy,
+    ) -> HyperResult<()> {
+        match policy {
+            RomWritePolicy::Fault => {
+                let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+                let gva = vcpu.trap_value();
+                vcpu.inject_exception(STORE_ACCESS_FAULT_CAUSE, gva)
+            }
+            RomWritePolicy::Ignore => {
+                let emu_ctx = self.decode_mmio_inst(inst_addr, inst, fault_addr)?;
+                self.vcpus.get_vcpu(vcpu_id)?.advance_pc(emu_ctx.inst_len);
+                Ok(())
+            }
+        }
+    }
+
+    /// Programs the host timer for the earliest of the guest's timer deadline, `slice_end`, the
+    /// end of the running time slice, and a deferred virtio interrupt, and disables it if none is
+    /// pending.
+    fn program_timer(&self, vcpu_id: usize, slice_end: u64) {
+        let deadline = core::cmp::min(self.timer_deadlines[vcpu_id], slice_end);
+        let deadline = self
+            .virtio_irq_deadline()
+            .map_or(deadline, |d| d.min(deadline));
+        if deadline == u64::MAX {
+            // Clear host timer interrupt
+            CSR.sie
+                .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
+        } else {
+            sbi_rt::set_timer(deadline);
+            //  Enable host timer interrupt
+            CSR.sie
+                .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+        }
+    }
+
+    /// Lets `sched` idle the vCPU `vcpu_id` until an interrupt, with the host timer armed for the
+    /// vCPU's next timer interrupt. Returns whether the vCPU keeps running.
+    fn idle_vcpu<S: VcpuScheduler>(
+        &mut self,
+        vcpu_id: usize,
+        sched: &mut S,
+        slice_end: u64,
+    ) -> bool {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+        let wake_at = vcpu
+            .stimecmp()
+            .map_or(self.timer_deadlines[vcpu_id], |cmp| {
+                cmp.min(self.timer_deadlines[vcpu_id])
+            });
+        // A timer the guest programs through Sstc fires in VS-mode, which doesn't end the wait.
+        self.program_timer(vcpu_id, slice_end.min(wake_at));
+        let keep = sched.on_vcpu_idle(vcpu_id, (wake_at != u64::MAX).then_some(wake_at));
+        self.program_timer(vcpu_id, slice_end);
+        keep
+    }
+
+    /// Whether the VM is in real-time mode, see `set_realtime`.
+    pub(crate) fn realtime(&self) -> bool {
+        self.realtime
+    }
+
+    /// The id of each vCPU with the harts it may run on, as a mask.
+    pub(crate) fn vcpu_affinities(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.vcpus
+            .iter()
+            .map(|vcpu| (vcpu.vcpu_id(), vcpu.affinity()))
+    }
+
+    /// Reports each way the VM's own configuration lets its guest exit in real-time mode, see
+    /// `verify_realtime`, which checks the harts shared with other VMs itself.
+    pub(crate) fn realtime_violations(&self, mut report: impl FnMut(RealtimeViolationKind)) {
+        for vcpu in self.vcpus.iter() {
+            let vcpu_id = vcpu.vcpu_id();
+            if !vcpu.affinity().is_power_of_two() {
+                report(RealtimeViolationKind::UnboundVcpu(vcpu_id));
+            }
+            if !vcpu.isa_extensions().sstc {
+                report(RealtimeViolationKind::EmulatedTimer(vcpu_id));
+            }
+            if vcpu.wfi_exit() {
+                report(RealtimeViolationKind::WfiExit(vcpu_id));
+            }
+            // `cycle`, `time` and `instret`.
+            for counter in 0..3 {
+                if vcpu.counter_access(counter) != CounterAccess::Direct {
+                    report(RealtimeViolationKind::TrappedCounter {
+                        vcpu: vcpu_id,
+                        counter,
+                    });
+                }
+            }
+            if vcpu.debug() {
+                report(RealtimeViolationKind::Debugging(vcpu_id));
+            }
+        }
+        if self.aplic.is_none() {
+            report(RealtimeViolationKind::EmulatedInterruptController);
+        }
+        for region in self.regions.iter() {
+            let start = region.start();
+            let interrupt_controller = start == PLIC_GPA
+                || self
+                    .aplic
+                    .as_ref()
+                    .is_some_and(|aplic| aplic.contains(start));
+            if region.region_type() == VmRegionType::Mmio && !interrupt_controller {
+                report(RealtimeViolationKind::EmulatedDevice(start));
+            }
+        }
+        for region in self.ram_regions() {
+            let unpopulated = (region.start()..region.start() + region.size())
+                .step_by(PAGE_SIZE_4K)
+                .find(|&gpa| self.gpt.translate(gpa).is_err());
+            if let Some(gpa) = unpopulated {
+                report(RealtimeViolationKind::UnpopulatedRam(gpa));
+            }
+        }
+        if self.limits.cpu_time.is_some() || self.bandwidth.is_some() {
+            report(RealtimeViolationKind::CpuTimeLimited);
+        }
+        if self.dirty_log.is_some() {
+            report(RealtimeViolationKind::DirtyLogging);
+        }
+    }
+
+    /// Guest RAM regions.
+    fn ram_regions(&self) -> impl Iterator<Item = &VmRegion> + Clone {
+        self.regions
+            .iter()
+            .filter(|r| r.region_type() == VmRegionType::Confidential)
+    }
+
+    /// Populated guest RAM pages.
+    fn mapped_ram_pages(&self) -> impl Iterator<Item = GuestPhysAddr> + Clone + '_ {
+        self.ram_regions()
+            .flat_map(|r| (r.start()..r.start() + r.size()).step_by(PAGE_SIZE_4K))
+            .filter(|&gpa| self.gpt.translate(gpa).is_ok())
+    }
+
+    /// Changes the mapping flags of the RAM pages at `pages` to `flags`.
+    fn protect_ram_pages(
+        &mut self,
+        pages: impl Iterator<Item = GuestPhysAddr>,
+        flags: MappingFlags,
+    ) -> HyperResult<()> {
+        for gpa in pages {
+            self.gpt.protect(gpa, flags)?;
+        }
+        for region in self.ram_regions() {
+            self.flush_guest_tlb(region.start(), region.size());
+        }
+        Ok(())
+    }
+
+    /// Invalidates the translations of `[gpa, gpa + size)` cached on all harts, and by the IOMMU
+    /// for passthrough devices, after its mappings in the guest page table were removed or
+    /// restricted.
+    fn flush_guest_tlb(&self, gpa: GuestPhysAddr, size: usize) {
+        tlb::flush_guest_range::<H>(self.hgatp(), gpa, size);
+        if self.passthrough_devices.is_empty() {
+            return;
+        }
+        let Some(iommu) = IOMMU.get() else {
+            return;
+        };
+        if let Err(err) = iommu.lock().invalidate_gscid(gscid_of(self.iommu_hgatp)) {
+            hv_log!(
+                Error,
+                Iommu,
+                LogContext::vm(self.id),
+                "failed to invalidate IOMMU translations: {:?}",
+                err
+            );
+        }
+    }
+
+    /// Assigns a guest interrupt file of hart `vcpu_id` to the vCPU `vcpu_id`, mapped into the
+    /// guest at `gpa`. What was assigned before failing is recorded in `imsic_files`, for
+    /// `release_guest_files` to give back.
+    fn assign_guest_file(
+        &mut self,
+        aia: &Aia,
+        vcpu_id: usize,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<()> {
+        if self.vcpus.get_vcpu(vcpu_id)?.affinity() != 1 << vcpu_id {
+            return Err(HyperError::BadState);
+        }
+        let vgein = aia.alloc_guest_file(vcpu_id)?;
+        if let Err(err) = self
+            .regions
+            .add(gpa, gpa + PAGE_SIZE_4K, VmRegionType::Imsic)
+        {
+            aia.free_guest_file(vcpu_id, vgein);
+            return Err(err);
+        }
+        let addr = aia.guest_file_addr(vcpu_id, vgein);
+        self.imsic_files[vcpu_id] = Some(ImsicFile { gpa, vgein, addr });
+        self.gpt.map(
+            gpa,
+            addr,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER,
+        )?;
+        self.vcpus
+            .get_vcpu(vcpu_id)?
+            .bind_guest_file(vcpu_id, vgein)
+    }
+
+    /// Gives back the guest interrupt files assigned to the vCPUs, unmapping them from the guest.
+    fn release_guest_files(&mut self) {
+        let Some(aia) = AIA.get() else {
+            return;
+        };
+        for vcpu_id in 0..VM_CPUS_MAX {
+            let Some(file) = self.imsic_files[vcpu_id].take() else {
+                continue;
+            };
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.unbind_guest_file();
+            }
+            if self.gpt.unmap(file.gpa).is_ok() {
+                self.flush_guest_tlb(file.gpa, PAGE_SIZE_4K);
+            }
+            self.regions.remove(file.gpa);
+            aia.free_guest_file(vcpu_id, file.vgein);
+        }
+    }
+
+    /// Points the passthrough devices at the VM's current `hgatp` if its VMID changed since they
+    /// were attached, e.g. as a new VMID generation started.
+    fn rebind_passthrough_devices(&mut self) {
+        let hgatp = self.hgatp();
+        if self.passthrough_devices.is_empty() || hgatp == self.iommu_hgatp {
+            return;
+        }
+        let Some(iommu) = IOMMU.get() else {
+            return;
+        };
+        let mut iommu = iommu.lock();
+        for &device_id in &self.passthrough_devices {
+            if let Err(err) = iommu.rebind_device::<H>(device_id, hgatp) {
+                hv_log!(
+                    Error,
+                    Iommu,
+                    LogContext::vm(self.id),
+                    "failed to rebind device {:#x}: {:?}",
+                    device_id,
+                    err
+                );
+            }
+        }
+        self.iommu_hgatp = hgatp;
+    }
+
+    /// Backs every unpopulated guest RAM page with a zeroed host page.
+    fn populate_ram(&mut self) -> HyperResult<()> {
+        let pages = self
+            .ram_regions()
+            .flat_map(|r| (r.start()..r.start() + r.size()).step_by(PAGE_SIZE_4K))
+            .filter(|&gpa| self.gpt.translate(gpa).is_err())
+            .collect::<Vec<_>>();
+        for gpa in pages {
+            self.populate_ram_page(gpa)?;
+        }
+        Ok(())
+    }
+
+    /// Fails with `NoMemory` if allocating another page would exceed the VM's limit.
+    fn check_page_limit(&self) -> HyperResult<()> {
+        let usage = self.resource_usage();
+        if self
+            .limits
+            .memory_pages
+            .is_some_and(|limit| usage.memory_pages >= limit)
+        {
+            return Err(HyperError::NoMemory);
+        }
+        Ok(())
+    }
+
+    /// Fails with `NoMemory` if adding another emulated device would exceed the VM's limit.
+    fn check_device_limit(&self) -> HyperResult<()> {
+        let usage = self.resource_usage();
+        if self
+            .limits
+            .devices
+            .is_some_and(|limit| usage.devices >= limit)
+        {
+            return Err(HyperError::NoMemory);
+        }
+        Ok(())
+    }
+
+    /// End of a time slice of `timeslice` ticks starting at `now`, cut short when the VM's CPU
+    /// time or its CPU budget of the period runs out.
+    fn slice_end(&self, now: u64, timeslice: u64) -> u64 {
+        let left = self
+            .limits
+            .cpu_time_left(self.cpu_time)
+            .min(bandwidth_left(&self.bandwidth, now));
+        now.saturating_add(timeslice.min(left))
+    }
+
+    /// The VM's RAM as emulated devices and the debugger access it. Where another field of the VM
+    /// is borrowed mutably meanwhile, e.g. a device, it's made with `GuestRam::new` instead.
+    fn guest_ram(&self) -> GuestRam<'_, H, G> {
+        GuestRam::new(
+            &self.gpt,
+            &self.regions,
+            &self.host_memory,
+            self.dirty_log.as_ref(),
+        )
+    }
+
+    /// Backs the unmapped guest RAM page at `gpa` with a zeroed host page.
+    fn populate_ram_page(&mut self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+        self.check_page_limit()?;
+        let page = H::alloc_page().ok_or(HyperError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE_4K) };
+        let hpa = H::virt_to_phys(page);
+        if let Err(err) = self.gpt.map(gpa, hpa, RAM_FLAGS) {
+            H::dealloc_page(page);
+            return Err(err);
+        }
+        self.flush_guest_tlb(gpa, PAGE_SIZE_4K);
+        self.lazy_pages.insert(page);
+        self.host_memory.add(hpa, hpa + PAGE_SIZE_4K)?;
+        // The page is mapped writable, so it has to be considered written.
+        if let Some(dirty_log) = &mut self.dirty_log {
+            dirty_log.get_mut().set(gpa);
+        }
+        Ok(hpa)
+    }
+}
+
+/// Encoding of the WFI instruction.
+const WFI_INST: u32 = 0x1050_0073;
+/// `scause` of an illegal instruction exception.
+const ILLEGAL_INST_CAUSE: usize = 2;
+/// `scause` of a breakpoint exception.
+const BREAKPOINT_CAUSE: usize = 3;
+/// `scause` of a store access fault.
+const STORE_ACCESS_FAULT_CAUSE: usize = 7;
+
+/// Current value of the `time` CSR.
+fn current_time() -> u64 {
+    riscv::register::time::read() as u64
+}
+
+/// CPU time a VM accounted in `bandwidth` has left in the period containing `now`, `u64::MAX` if
+/// it isn't in a bandwidth group.
+fn bandwidth_left(bandwidth: &Option<(Arc<CpuBandwidth>, usize)>, now: u64) -> u64 {
+    bandwidth.as_ref().map_or(u64::MAX, |(bandwidth, slot)| {
+        bandwidth.remaining(*slot, now)
+    })
+}
+
+/// Scheduler of `VM::run`, which keeps running the vCPU and parks the hart when it's blocked.
+struct Unscheduled<H>(PhantomData<H>);
+
+impl<H: HyperCraftHal> VcpuScheduler for Unscheduled<H> {
+    fn timeslice(&mut self, _vcpu_id: usize) -> u64 {
+        u64::MAX
+    }
+
+    fn on_timeslice_expired(&mut self, _vcpu_id: usize) -> bool {
+        true
+    }
+
+    fn on_vcpu_blocked(&mut self, _vcpu_id: usize) -> bool {
+        H::park_hart(None);
+        true
+    }
+
+    fn on_vcpu_idle(&mut self, _vcpu_id: usize, wake_at: Option<u64>) -> bool {
+        H::park_hart(wake_at);
+        true
+    }
+}
+
+/// Whether memory of `region_type` is accessed as guest RAM on the guest's behalf, for a write if
+/// `write`: the VM's RAM, and memory shared into it unless it's read-only and written.
+fn is_guest_ram(region_type: VmRegionType, write: bool) -> bool {
+    match region_type {
+        VmRegionType::Confidential | VmRegionType::Shared(SharePermission::ReadWrite) => true,
+        VmRegionType::Shared(SharePermission::ReadOnly) => !write,
+        _ => false,
+    }
+}
+
+/// Translates the guest RAM range `[gpa, gpa + len)`, which must not cross a page boundary, to be
+/// written if `write`, checking that it's backed by host memory in `host_memory`.
+fn translate_guest_range<G: GuestPageTableTrait>(
+    gpt: &G,
+    regions: &VmRegionList,
+    host_memory: &HostRangeSet,
+    gpa: GuestPhysAddr,
+    len: usize,
+    write: bool,
+) -> HyperResult<HostPhysAddr> {
+    let page = gpa & !(PAGE_SIZE_4K - 1);
+    if len > PAGE_SIZE_4K - (gpa - page) {
+        return Err(HyperError::InvalidParam);
+    }
+    match regions.find(gpa) {
+        Some(region) if is_guest_ram(region.region_type(), write) => {}
+        _ => return Err(HyperError::OutOfRange),
+    }
+    let hpa = gpt.translate(page)? + gpa - page;
+    if !host_memory.contains(hpa, len) {
+        return Err(HyperError::OutOfRange);
+    }
+    Ok(hpa)
+}
+
+/// The RAM of a VM, accessed by its emulated devices, and the memory shared into it, which they
+/// can't write if it's shared read-only. Writes mark the pages they touch in the VM's dirty log, if
+/// it's enabled, and direct access through `host_addr` is refused meanwhile.
+struct GuestRam<'a, H: HyperCraftHal, G: GuestPageTableTrait> {
+    gpt: &'a G,
+    regions: &'a VmRegionList,
+    host_memory: &'a HostRangeSet,
+    dirty_log: Option<&'a RefCell<DirtyBitmap>>,
+    marker: PhantomData<H>,
+}
+
+impl<'a, H: HyperCraftHal, G: GuestPageTableTrait> GuestRam<'a, H, G> {
+    fn new(
+        gpt: &'a G,
+        regions: &'a VmRegionList,
+        host_memory: &'a HostRangeSet,
+        dirty_log: Option<&'a RefCell<DirtyBitmap>>,
+    ) -> Self {
+        Self {
+            gpt,
+            regions,
+            host_memory,
+            dirty_log,
+            marker: PhantomData,
+        }
+    }
+
+    /// Marks the pages of `[gpa, gpa + len)` dirty, after they were written.
+    fn mark_dirty(&self, gpa: GuestPhysAddr, len: usize) {
+        let Some(dirty_log) = self.dirty_log else {
+            return;
+        };
+        let mut dirty_log = dirty_log.borrow_mut();
+        for page in (gpa & !(PAGE_SIZE_4K - 1)..gpa + len).step_by(PAGE_SIZE_4K) {
+            dirty_log.set(page);
+        }
+    }
+
+    /// Calls `f` with each run of `[gpa, gpa + len)` backed by contiguous host memory, as its host
+    /// virtual address, its offset in the range and its length, so it's copied at once. Runs of RAM
+    /// pages not populated yet have no address. The range is checked to be writable if `write`.
+    fn for_each_run(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(Option<HostVirtAddr>, usize, usize) -> HyperResult<()>,
+    ) -> HyperResult<()> {
+        // Start, offset and length of the run being extended.
+        let mut run: Option<(Option<HostVirtAddr>, usize, usize)> = None;
+        let mut offset = 0;
+        while offset < len {
+            let addr = gpa.checked_add(offset).ok_or(HyperError::OutOfRange)?;
+            let chunk = core::cmp::min(PAGE_SIZE_4K - addr % PAGE_SIZE_4K, len - offset);
+            let is_ram = matches!(
+                self.regions.find(addr),
+                Some(region) if region.region_type() == VmRegionType::Confidential
+            );
+            let hva = match self.gpt.translate(addr & !(PAGE_SIZE_4K - 1)) {
+                Err(_) if is_ram => None,
+                _ => Some(H::phys_to_virt(translate_guest_range(
+                    self.gpt,
+                    self.regions,
+                    self.host_memory,
+                    addr,
+                    chunk,
+                    write,
+                )?)),
+            };
+            let extends = |start: Option<HostVirtAddr>, run_len: usize| match (start, hva) {
+                (Some(start), Some(hva)) => start + run_len == hva,
+                (None, None) => true,
+                _ => false,
+            };
+            match &mut run {
+                Some((start, _, run_len)) if extends(*start, *run_len) => *run_len += chunk,
+                _ => {
+                    if let Some((start, run_offset, run_len)) = run.take() {
+                        f(start, run_offset, run_len)?;
+                    }
+                    run = Some((hva, offset, chunk));
+                }
+            }
+            offset += chunk;
+        }
+        match run {
+            Some((start, run_offset, run_len)) => f(start, run_offset, run_len),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `f` with the host virtual address and length of each piece of `[gpa, gpa + len)`
+    /// within a page, which the caller may write.
+    fn for_each_page(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        mut f: impl FnMut(HostVirtAddr, usize, usize),
+    ) -> HyperResult<()> {
+        let mut offset = 0;
+        while offset < len {
+            let addr = gpa.checked_add(offset).ok_or(HyperError::OutOfRange)?;
+            let chunk = core::cmp::min(PAGE_SIZE_4K - addr % PAGE_SIZE_4K, len - offset);
+            let hpa =
+                translate_guest_range(self.gpt, self.regions, self.host_memory, addr, chunk, true)?;
+            f(H::phys_to_virt(hpa), offset, chunk);
+            offset += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> GuestMemory for GuestRam<'_, H, G> {
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        self.for_each_run(gpa, buf.len(), false, |src, offset, len| {
+            let src = src.ok_or(HyperError::OutOfRange)?;
+            // Safety: the range is guest RAM backed by host memory assigned to the VM.
+            unsafe {
+                core::ptr::copy_nonoverlapping(src as *const u8, buf[offset..].as_mut_ptr(), len)
+            };
+            Ok(())
+        })
+    }
+
+    fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+        self.for_each_run(gpa, buf.len(), true, |dst, offset, len| {
+            let dst = dst.ok_or(HyperError::OutOfRange)?;
+            // Safety: the range is guest RAM backed by host memory assigned to the VM.
+            unsafe { core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), dst as *mut u8, len) };
+            Ok(())
+        })?;
+        self.mark_dirty(gpa, buf.len());
+        Ok(())
+    }
+
+    fn host_addr(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<HostVirtAddr> {
+        // Writes through the address couldn't be tracked.
+        if self.dirty_log.is_some() {
+            return Err(HyperError::NotSupported);
+        }
+        let mut start = None;
+        let mut contiguous = true;
+        self.for_each_page(gpa, len, |hva, offset, _| match start {
+            None => start = Some(hva),
+            Some(start) => contiguous &= hva == start + offset,
+        })?;
+        match start {
+            Some(start) if contiguous => Ok(start),
+            _ => Err(HyperError::OutOfRange),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Core dumps and snapshots of a VM, and its restoration from snapshots.
+use super::{translate_guest_range, VM};
+use crate::arch::{
+    isolation::HostRangeSet,
+    vm_pages::{VmRegionList, VmRegionType},
+};
+use crate::{
+    coredump::{write_elf_core, CoreNote, CoreSegment, EM_RISCV, NT_PRSTATUS},
+    memory::PAGE_SIZE_4K,
+    snapshot::{
+        read_memory_pages, restore_chain, write_memory_pages, ChainInfo, DirtyBitmap,
+        SectionBuilder, SectionKind, SectionReader, SnapshotEncoder, SnapshotReader,
+        SnapshotWriter,
+    },
+    vcpus::VM_CPUS_MAX,
+    virtio::GuestMemory,
+    GprIndex, GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult, VCpu,
+};
+use alloc::vec::Vec;
+
+/// Snapshot section holding the state of one vCPU, led by its id.
+const SECTION_VCPU: SectionKind = SectionKind(0x100);
+/// Snapshot section holding the vPLIC state.
+const SECTION_PLIC: SectionKind = SectionKind(0x101);
+/// Snapshot section holding the emulated APLIC state, present when AIA is enabled.
+const SECTION_APLIC: SectionKind = SectionKind(0x102);
+const SECTION_VERSION: u16 = 1;
+/// Version of `SECTION_VCPU`. Version 2 added `vstimecmp` and the FP and vector registers.
+const SECTION_VCPU_VERSION: u16 = 2;
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Writes an ELF core dump of the VM to `writer`, with the register state of every vCPU and
+    /// the contents of its RAM regions. Unmapped RAM pages are dumped as zeros. The VM must not be
+    /// running.
+    pub fn dump_core<W: SnapshotWriter>(&mut self, writer: &mut W) -> HyperResult<()> {
+        let mut prstatus = Vec::new();
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                prstatus.push(elf_prstatus(vcpu));
+            }
+        }
+        let notes: Vec<CoreNote> = prstatus
+            .iter()
+            .map(|desc| CoreNote {
+                name: "CORE",
+                note_type: NT_PRSTATUS,
+                desc,
+            })
+            .collect();
+        let segments: Vec<CoreSegment> = self
+            .ram_regions()
+            .map(|r| CoreSegment {
+                gpa: r.start(),
+                size: r.size(),
+            })
+            .collect();
+        let (gpt, regions, host_memory) = (&self.gpt, &self.regions, &self.host_memory);
+        write_elf_core(writer, EM_RISCV, &notes, &segments, |gpa, buf| {
+            read_guest_memory::<H, G>(gpt, regions, host_memory, gpa, buf)
+        })
+    }
+
+    /// Writes a snapshot of the paused VM to `writer`: the vCPU and interrupt controller state, and
+    /// guest RAM. With `dirty`, only the pages it marks are saved, making the snapshot a delta on
+    /// top of the snapshot `chain.base`; otherwise all populated RAM pages are.
+    ///
+    /// The VS-level CSRs of vCPUs still loaded on a hart are read from the current hart, so such
+    /// vCPUs must have last run on it. Host interrupt routes aren't part of the snapshot.
+    pub fn save_snapshot<W: SnapshotWriter>(
+        &mut self,
+        writer: &mut W,
+        chain: ChainInfo,
+        dirty: Option<&DirtyBitmap>,
+    ) -> HyperResult<()> {
+        let mut encoder = SnapshotEncoder::new(writer)?;
+        chain.write(&mut encoder)?;
+
+        let (gpt, regions, host_memory) = (&self.gpt, &self.regions, &self.host_memory);
+        let read_page =
+            |gpa, buf: &mut [u8]| read_guest_memory::<H, G>(gpt, regions, host_memory, gpa, buf);
+        match dirty {
+            Some(dirty) => write_memory_pages(&mut encoder, dirty.iter(), read_page)?,
+            None => write_memory_pages(&mut encoder, self.mapped_ram_pages(), read_page)?,
+        }
+
+        for vcpu_id in 0..VM_CPUS_MAX {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                let mut payload = SectionBuilder::new();
+                vcpu.save_state(&mut payload);
+                encoder.write_section(
+                    SECTION_VCPU,
+                    SECTION_VCPU_VERSION,
+                    false,
+                    payload.as_bytes(),
+                )?;
+            }
+        }
+        let mut payload = SectionBuilder::new();
+        self.plic.save(&mut payload);
+        encoder.write_section(SECTION_PLIC, SECTION_VERSION, false, payload.as_bytes())?;
+        if let Some(aplic) = &self.aplic {
+            let mut payload = SectionBuilder::new();
+            aplic.lock().save(&mut payload);
+            encoder.write_section(SECTION_APLIC, SECTION_VERSION, false, payload.as_bytes())?;
+        }
+        encoder.finish()
+    }
+
+    /// Restores the VM from a chain of snapshots written by `save_snapshot`, a full snapshot
+    /// followed by its deltas. The VM must be paused and have the same vCPUs, RAM regions and, if
+    /// the snapshot was taken with AIA enabled, AIA configuration as the one the snapshots were
+    /// taken of. The VS-level CSRs of vCPUs loaded on a hart are loaded into the current hart,
+    /// which must be that one.
+    pub fn restore_snapshot<R: SnapshotReader>(&mut self, snapshots: &mut [R]) -> HyperResult<()> {
+        let supported = |kind| match kind {
+            SECTION_VCPU => Some(SECTION_VCPU_VERSION),
+            SECTION_PLIC | SECTION_APLIC => Some(SECTION_VERSION),
+            _ => None,
+        };
+        restore_chain(snapshots, supported, |section| {
+            self.restore_section(section)
+        })
+    }
+
+    fn restore_section<R: SnapshotReader>(
+        &mut self,
+        section: &mut SectionReader<'_, '_, R>,
+    ) -> HyperResult<()> {
+        let kind = section.header().kind;
+        match kind {
+            SectionKind::MEMORY_PAGES => read_memory_pages(section, |gpa, buf| {
+                match self.regions.find(gpa) {
+                    Some(region) if region.region_type() == VmRegionType::Confidential => {}
+                    _ => return Err(HyperError::InvalidParam),
+                }
+                if self.gpt.translate(gpa).is_err() {
+                    self.populate_ram_page(gpa)?;
+                }
+                self.guest_ram().write(gpa, buf)
+            }),
+            SECTION_VCPU => {
+                let vcpu_id = section.read_u64()? as usize;
+                self.vcpus.get_vcpu(vcpu_id)?.restore_state(section)
+            }
+            SECTION_PLIC => self.plic.restore(section),
+            SECTION_APLIC => self
+                .aplic
+                .as_ref()
+                .ok_or(HyperError::BadState)?
+                .lock()
+                .restore(section),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copies guest RAM at `gpa` into `buf`, which must not cross a page boundary. Unmapped memory
+/// reads as zeros.
+fn read_guest_memory<H: HyperCraftHal, G: GuestPageTableTrait>(
+    gpt: &G,
+    regions: &VmRegionList,
+    host_memory: &HostRangeSet,
+    gpa: GuestPhysAddr,
+    buf: &mut [u8],
+) -> HyperResult<()> {
+    if gpt.translate(gpa & !(PAGE_SIZE_4K - 1)).is_err() {
+        buf.fill(0);
+        return Ok(());
+    }
+    let hpa = translate_guest_range(gpt, regions, host_memory, gpa, buf.len(), false)?;
+    let src = H::phys_to_virt(hpa) as *const u8;
+    // Safety: the range is guest RAM backed by host memory assigned to the VM.
+    unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+    Ok(())
+}
+
+/// Size of the riscv64 `elf_prstatus` structure.
+const ELF_PRSTATUS_SIZE: usize = 376;
+
+/// Builds the `NT_PRSTATUS` note of `vcpu` for core dumps.
+fn elf_prstatus<H: HyperCraftHal>(vcpu: &VCpu<H>) -> [u8; ELF_PRSTATUS_SIZE] {
+    let mut prstatus = [0u8; ELF_PRSTATUS_SIZE];
+    // pr_pid, which debuggers use to tell threads apart.
+    prstatus[32..36].copy_from_slice(&(vcpu.vcpu_id() as u32 + 1).to_le_bytes());
+    // pr_reg: pc followed by x1-x31.
+    let pr_reg = &mut prstatus[112..368];
+    pr_reg[0..8].copy_from_slice(&(vcpu.pc() as u64).to_le_bytes());
+    for index in 1..32 {
+        let val = vcpu.get_gpr(GprIndex::from_raw(index).unwrap()) as u64;
+        let offset = index as usize * 8;
+        pr_reg[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+    }
+    prstatus
+}
@@ -0,0 +1,338 @@
+//! Declarative construction of VMs.
+//!
+//! `VmConfigBuilder` collects the memory layout, vCPUs, emulated devices and boot image addresses of
+//! a VM, and checks them against each other before the VM is created: RAM, passthrough MMIO and
+//! device registers must not overlap each other or the vPLIC, device interrupts must be distinct,
+//! and the entry point and images must lie in RAM. The VM is then created and populated with the
+//! same `VM` methods a caller would use, failing as a whole if one of them does.
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use page_table_entry::MappingFlags;
+
+use super::devices::rtc::RTC_SIZE;
+use super::devices::uart::UART_SIZE;
+use super::memory_map::GuestMemoryMap;
+use super::vm::{PLIC_GPA, PLIC_SIZE};
+use super::vm_pages::{VmRegionList, VmRegionType};
+use super::VM;
+use crate::memory::PAGE_SIZE_4K;
+use crate::vcpus::VM_CPUS_MAX;
+use crate::virtio::fs::FsBackend;
+use crate::virtio::VIRTIO_MMIO_SIZE;
+use crate::{
+    GprIndex, GuestPageTableTrait, GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError,
+    HyperResult, VCpu, VmCpus,
+};
+
+/// An emulated device of a VM built by `VmConfigBuilder`.
+pub enum DeviceConfig {
+    /// A 16550 UART on the VM's console, see `VM::add_uart`.
+    Uart,
+    /// A Goldfish RTC, see `VM::add_rtc`.
+    Rtc,
+    /// A virtio entropy device, see `VM::add_virtio_rng`.
+    VirtioRng,
+    /// A virtio GPU with a single `width` x `height` display, see `VM::add_virtio_gpu`.
+    VirtioGpu { width: u32, height: u32 },
+    /// A virtio memory balloon, see `VM::add_virtio_balloon`.
+    VirtioBalloon,
+    /// A virtio 9P filesystem sharing `backend` under the mount tag `tag`, see
+    /// `VM::add_virtio_fs`.
+    VirtioFs {
+        tag: String,
+        backend: Box<dyn FsBackend>,
+    },
+}
+
+impl DeviceConfig {
+    /// Size of the device's registers in the guest physical address space, in whole pages.
+    fn window_size(&self) -> usize {
+        let size = match self {
+            Self::Uart => UART_SIZE,
+            Self::Rtc => RTC_SIZE,
+            _ => VIRTIO_MMIO_SIZE,
+        };
+        (size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1)
+    }
+}
+
+/// An emulated device and where the guest finds it.
+struct DeviceEntry {
+    gpa: GuestPhysAddr,
+    irq: u32,
+    config: DeviceConfig,
+}
+
+/// A host device MMIO window mapped into the guest.
+struct PassthroughEntry {
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    size: usize,
+    attrs: MappingFlags,
+}
+
+/// Builds a VM from its configuration, see the module documentation.
+///
+/// Devices handing the host a handle, like the virtio input device, are added to the built VM.
+#[derive(Default)]
+pub struct VmConfigBuilder {
+    ram: Vec<(GuestPhysAddr, usize)>,
+    passthrough: Vec<PassthroughEntry>,
+    num_vcpus: usize,
+    entry: Option<GuestPhysAddr>,
+    console: Option<String>,
+    devices: Vec<DeviceEntry>,
+    kernel: Option<(GuestPhysAddr, usize)>,
+    dtb: Option<(GuestPhysAddr, usize)>,
+    initrd: Option<(GuestPhysAddr, usize)>,
+    memory_map: Option<GuestMemoryMap>,
+    /// Whether a device added with `mapped_device` found no place in the memory map.
+    unplaced: bool,
+}
+
+impl VmConfigBuilder {
+    /// Creates an empty configuration, with a single vCPU.
+    pub fn new() -> Self {
+        Self {
+            num_vcpus: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the guest RAM region `[gpa, gpa + size)`, see `VM::add_ram_region`.
+    pub fn ram(mut self, gpa: GuestPhysAddr, size: usize) -> Self {
+        self.ram.push((gpa, size));
+        self
+    }
+
+    /// Maps the host device MMIO `[hpa, hpa + size)` at `gpa`, see `VM::map_passthrough`.
+    pub fn passthrough(
+        mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        size: usize,
+        attrs: MappingFlags,
+    ) -> Self {
+        self.passthrough.push(PassthroughEntry {
+            gpa,
+            hpa,
+            size,
+            attrs,
+        });
+        self
+    }
+
+    /// Gives the VM `num_vcpus` vCPUs, numbered from 0. Only vCPU 0 starts at the entry point, the
+    /// others are powered off until the guest starts them through SBI HSM.
+    pub fn vcpus(mut self, num_vcpus: usize) -> Self {
+        self.num_vcpus = num_vcpus;
+        self
+    }
+
+    /// Starts the vCPUs at `entry` rather than at the kernel.
+    pub fn entry(mut self, entry: GuestPhysAddr) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    /// Attaches the VM to the console multiplexer as `name`, see `VM::attach_console`.
+    pub fn console(mut self, name: &str) -> Self {
+        self.console = Some(String::from(name));
+        self
+    }
+
+    /// Adds the emulated device `config` with its registers at `gpa`, raising the guest interrupt
+    /// `irq`.
+    pub fn device(mut self, gpa: GuestPhysAddr, irq: u32, config: DeviceConfig) -> Self {
+        self.devices.push(DeviceEntry { gpa, irq, config });
+        self
+    }
+
+    /// Lays the VM out as `map` and adds its RAM. The built VM keeps the map, see
+    /// `VM::memory_map`.
+    pub fn memory_map(mut self, map: GuestMemoryMap) -> Self {
+        self.ram.push(map.ram());
+        self.memory_map = Some(map);
+        self
+    }
+
+    /// Adds the emulated device `config` where the memory map set with `memory_map` places it:
+    /// the UART and the RTC at their slots, virtio devices in the next free virtio-mmio slot.
+    pub fn mapped_device(mut self, config: DeviceConfig) -> Self {
+        let slot = match (&mut self.memory_map, &config) {
+            (None, _) => None,
+            (Some(map), DeviceConfig::Uart) => Some(map.uart()),
+            (Some(map), DeviceConfig::Rtc) => Some(map.rtc()),
+            (Some(map), _) => map.assign_virtio_slot().ok(),
+        };
+        match slot {
+            Some(slot) => self.devices.push(DeviceEntry {
+                gpa: slot.gpa,
+                irq: slot.irq,
+                config,
+            }),
+            None => self.unplaced = true,
+        }
+        self
+    }
+
+    /// Places the `size`-byte kernel image at `gpa`, where the vCPUs start unless `entry` says
+    /// otherwise.
+    pub fn kernel(mut self, gpa: GuestPhysAddr, size: usize) -> Self {
+        self.kernel = Some((gpa, size));
+        self
+    }
+
+    /// Places the `size`-byte device tree blob at `gpa`, whose address the vCPUs find in `a1`.
+    pub fn dtb(mut self, gpa: GuestPhysAddr, size: usize) -> Self {
+        self.dtb = Some((gpa, size));
+        self
+    }
+
+    /// Places the `size`-byte initial ramdisk at `gpa`, which the device tree is expected to
+    /// point the guest to.
+    pub fn initrd(mut self, gpa: GuestPhysAddr, size: usize) -> Self {
+        self.initrd = Some((gpa, size));
+        self
+    }
+
+    /// Checks the configuration. Fails with `InvalidParam` if it's incomplete or a region is
+    /// malformed, `BadState` if regions, device registers or images overlap or interrupts or
+    /// single-instance devices are repeated, and `OutOfRange` if the entry point or an image isn't
+    /// in RAM. A device added with `mapped_device` fails it with `InvalidParam` if there was no
+    /// memory map and `OutOfRange` if the map's virtio-mmio slots ran out.
+    pub fn validate(&self) -> HyperResult<()> {
+        if !(1..=VM_CPUS_MAX).contains(&self.num_vcpus) {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.unplaced {
+            return Err(match self.memory_map {
+                Some(_) => HyperError::OutOfRange,
+                None => HyperError::InvalidParam,
+            });
+        }
+
+        // Lays out the address space as the VM will, which rejects overlapping regions.
+        let mut layout = VmRegionList::default();
+        layout.add(PLIC_GPA, PLIC_GPA + PLIC_SIZE, VmRegionType::Mmio)?;
+        for &(gpa, size) in &self.ram {
+            layout.add(gpa, checked_end(gpa, size)?, VmRegionType::Confidential)?;
+        }
+        for entry in &self.passthrough {
+            let end = checked_end(entry.gpa, entry.size)?;
+            layout.add(entry.gpa, end, VmRegionType::Passthrough)?;
+        }
+        for (index, device) in self.devices.iter().enumerate() {
+            let end = checked_end(device.gpa, device.config.window_size())?;
+            layout.add(device.gpa, end, VmRegionType::Mmio)?;
+            let earlier = &self.devices[..index];
+            let single = matches!(
+                device.config,
+                DeviceConfig::Uart | DeviceConfig::Rtc | DeviceConfig::VirtioBalloon
+            );
+            let same_kind = |other: &DeviceEntry| {
+                core::mem::discriminant(&other.config) == core::mem::discriminant(&device.config)
+            };
+            if earlier
+                .iter()
+                .any(|other| other.irq == device.irq || single && same_kind(other))
+            {
+                return Err(HyperError::BadState);
+            }
+            if matches!(device.config, DeviceConfig::Uart) && self.console.is_none() {
+                return Err(HyperError::BadState);
+            }
+        }
+
+        let entry = self
+            .entry
+            .or(self.kernel.map(|(gpa, _)| gpa))
+            .ok_or(HyperError::InvalidParam)?;
+        let images = [self.kernel, self.dtb, self.initrd];
+        let images = images.iter().flatten();
+        for (index, &(gpa, size)) in images.clone().enumerate() {
+            if size == 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            let end = checked_end(gpa, size)?;
+            if !self.in_ram(gpa, end) {
+                return Err(HyperError::OutOfRange);
+            }
+            if images
+                .clone()
+                .take(index)
+                .any(|&(other, other_size)| gpa < other + other_size && other < end)
+            {
+                return Err(HyperError::BadState);
+            }
+        }
+        if !self.in_ram(entry, checked_end(entry, 1)?) {
+            return Err(HyperError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration and creates the VM with `gpt` as its guest page table. The
+    /// images aren't loaded: the caller maps or copies them into guest RAM before running the VM.
+    pub fn build<H: HyperCraftHal, G: GuestPageTableTrait>(self, gpt: G) -> HyperResult<VM<H, G>> {
+        self.validate()?;
+        let entry = self.entry.or(self.kernel.map(|(gpa, _)| gpa)).unwrap();
+        let mut vcpus = VmCpus::new();
+        for vcpu_id in 0..self.num_vcpus {
+            let mut vcpu = VCpu::new(vcpu_id, entry);
+            if let Some((dtb, _)) = self.dtb {
+                vcpu.set_gpr(GprIndex::A1, dtb);
+            }
+            if vcpu_id != 0 {
+                vcpu.power_off();
+            }
+            vcpus.add_vcpu(vcpu)?;
+        }
+
+        let mut vm = VM::new(vcpus, gpt)?;
+        for &(gpa, size) in &self.ram {
+            vm.add_ram_region(gpa, size)?;
+        }
+        for entry in &self.passthrough {
+            vm.map_passthrough(entry.gpa, entry.hpa, entry.size, entry.attrs)?;
+        }
+        if let Some(name) = &self.console {
+            vm.attach_console(name)?;
+        }
+        if let Some(map) = self.memory_map {
+            vm.set_memory_map(map);
+        }
+        for DeviceEntry { gpa, irq, config } in self.devices {
+            match config {
+                DeviceConfig::Uart => vm.add_uart(gpa, irq)?,
+                DeviceConfig::Rtc => vm.add_rtc(gpa, irq)?,
+                DeviceConfig::VirtioRng => vm.add_virtio_rng(gpa, irq)?,
+                DeviceConfig::VirtioGpu { width, height } => {
+                    vm.add_virtio_gpu(gpa, irq, width, height)?
+                }
+                DeviceConfig::VirtioBalloon => vm.add_virtio_balloon(gpa, irq)?,
+                DeviceConfig::VirtioFs { tag, backend } => {
+                    vm.add_virtio_fs(gpa, irq, &tag, backend)?
+                }
+            }
+        }
+        for vcpu_id in 0..self.num_vcpus {
+            vm.init_vcpu(vcpu_id);
+        }
+        Ok(vm)
+    }
+
+    /// Whether `[start, end)` lies within one RAM region.
+    fn in_ram(&self, start: GuestPhysAddr, end: GuestPhysAddr) -> bool {
+        self.ram
+            .iter()
+            .any(|&(gpa, size)| gpa <= start && end <= gpa + size)
+    }
+}
+
+/// End of the `size` bytes at `gpa`, failing if it overflows.
+fn checked_end(gpa: GuestPhysAddr, size: usize) -> HyperResult<GuestPhysAddr> {
+    gpa.checked_add(size).ok_or(HyperError::InvalidParam)
+}
@@ -3,7 +3,7 @@ use core::arch::global_asm;
 use arrayvec::ArrayVec;
 use riscv_decode::Instruction;
 
-use crate::{GuestPhysAddr, HyperError, HyperResult};
+use crate::{memory::PAGE_SIZE_4K, GuestPhysAddr, HyperError, HyperResult};
 global_asm!(include_str!("mem_extable.S"));
 
 extern "C" {
@@ -14,13 +14,17 @@ extern "C" {
 
 // Types of regions in a VM's guest physical address space.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-enum VmRegionType {
+pub enum VmRegionType {
     // Memory that is private to this VM.
     Confidential,
-    // Memory that is shared with the parent
-    Shared,
+    // Memory that is shared with the parent, or from another VM with the given permission.
+    Shared(SharePermission),
     // Emulated MMIO region; accesses always cause a fault that is forwarded to the VM's host.
     Mmio,
+    // Read-only memory; writes fault and are handled as per the policy.
+    Rom(RomWritePolicy),
+    // Host device MMIO mapped for the guest to access directly.
+    Passthrough,
     // IMSIC interrupt file pages.
     Imsic,
     // PCI BAR pages.
@@ -31,6 +35,22 @@ enum VmRegionType {
     SharedRemovable,
 }
 
+/// What happens to guest writes to a ROM region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RomWritePolicy {
+    /// Writes raise a store access fault in the guest.
+    Fault,
+    /// Writes are dropped.
+    Ignore,
+}
+
+/// How a VM may access memory shared into it from another VM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SharePermission {
+    ReadOnly,
+    ReadWrite,
+}
+
 /// A contiguous region of guest physical address space.
 #[derive(Clone, Debug)]
 pub struct VmRegion {
@@ -39,14 +59,89 @@ pub struct VmRegion {
     region_type: VmRegionType,
 }
 
+impl VmRegion {
+    /// Start of the region.
+    pub fn start(&self) -> GuestPhysAddr {
+        self.start
+    }
+
+    /// End (exclusive) of the region.
+    pub fn end(&self) -> GuestPhysAddr {
+        self.end
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// What the region is used for.
+    pub fn region_type(&self) -> VmRegionType {
+        self.region_type
+    }
+}
+
 /// The maximum number of distinct memory regions we support in `VmRegionList`.
 const MAX_MEM_REGIONS: usize = 128;
 
 /// The regions of guest physical address space for a VM. Used to track which parts of the address
 /// space are designated for a particular purpose. Pages may only be inserted into a VM's address
 /// space if the mapping falls within a region of the proper type.
+#[derive(Default)]
 pub struct VmRegionList {
     regions: ArrayVec<VmRegion, MAX_MEM_REGIONS>,
+    /// End of the guest physical address space regions must fit in, if bounded.
+    limit: Option<GuestPhysAddr>,
+}
+
+impl VmRegionList {
+    /// Bounds the guest physical address space to `[0, limit)`.
+    pub fn set_limit(&mut self, limit: GuestPhysAddr) {
+        self.limit = Some(limit);
+    }
+
+    /// Adds the region `[start, end)`, which must not overlap any existing region. Fails with
+    /// `OutOfRange` if it ends beyond the limit of the address space.
+    pub fn add(
+        &mut self,
+        start: GuestPhysAddr,
+        end: GuestPhysAddr,
+        region_type: VmRegionType,
+    ) -> HyperResult<()> {
+        if start >= end || start % PAGE_SIZE_4K != 0 || end % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.limit.is_some_and(|limit| end > limit) {
+            return Err(HyperError::OutOfRange);
+        }
+        if self.regions.iter().any(|r| start < r.end && r.start < end) {
+            return Err(HyperError::BadState);
+        }
+        self.regions
+            .try_push(VmRegion {
+                start,
+                end,
+                region_type,
+            })
+            .map_err(|_| HyperError::NoMemory)
+    }
+
+    /// Removes the region starting at `start`.
+    pub fn remove(&mut self, start: GuestPhysAddr) {
+        self.regions.retain(|r| r.start != start);
+    }
+
+    /// Returns the region containing `addr`.
+    pub fn find(&self, addr: GuestPhysAddr) -> Option<&VmRegion> {
+        self.regions
+            .iter()
+            .find(|r| (r.start..r.end).contains(&addr))
+    }
+
+    /// Iterates over the regions in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &VmRegion> + Clone {
+        self.regions.iter()
+    }
 }
 
 /// Represents the activate VM address space. Used to directly access a guest's memory.
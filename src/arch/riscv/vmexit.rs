@@ -44,6 +44,8 @@ pub enum VmExitInfo {
     VirtualInstruction {
         /// Virtual instruction addr.
         fault_pc: GuestVirtAddr,
+        /// Virtual instruction, or 0 if the hart doesn't report it.
+        inst: u32,
         /// Virtual instruction privilege level.
         priv_level: PrivilegeLevel,
     },
@@ -54,4 +56,18 @@ pub enum VmExitInfo {
     TimerInterruptEmulation,
     /// An external interrupt for the running vCPU that can't be delegated and must be injected.
     ExternalInterruptEmulation,
+    /// EBREAK executed by a vCPU in debug mode, at a debugger breakpoint or one of the guest's own.
+    DebugEvent {
+        /// Address of the EBREAK.
+        pc: GuestVirtAddr,
+    },
+    /// A trap the hypervisor has no handler for.
+    UnhandledTrap {
+        /// Value of scause.
+        cause: usize,
+        /// Trapping inst addr.
+        fault_pc: GuestVirtAddr,
+        /// Value of stval.
+        tval: usize,
+    },
 }
@@ -0,0 +1,95 @@
+//! Allocation of hardware VMIDs to VMs.
+//!
+//! Guest translations cached in TLBs are tagged with the VMID in `hgatp`, so VMs with distinct
+//! VMIDs can share a hart without flushing each other's entries when their vCPUs are switched.
+//! VMIDs are handed out in generations: once a generation runs out, a new one starts, every hart
+//! flushes all guest translations before loading a vCPU again, and VMs get a new VMID the next
+//! time one of their vCPUs is loaded. VMID 0 is never handed out, and is used by all VMs if the
+//! hart implements no VMID bits, in which case loading a vCPU always flushes.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::{Mutex, Once};
+
+use super::csrs::defs::CSR_HGATP;
+use super::tlb::{self, HGATP_VMID_MASK, HGATP_VMID_SHIFT};
+use crate::vcpus::MAX_CPUS;
+
+/// The current generation, starting at 1 so that VMs start without a VMID.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+/// The next VMID to hand out in the current generation.
+static NEXT_VMID: Mutex<usize> = Mutex::new(1);
+/// The generation each hart has flushed its guest translations for.
+static HART_GENERATION: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(1) }; MAX_CPUS];
+static VMID_BITS: Once<u32> = Once::new();
+
+/// The number of VMID bits the hart implements, probed on first use.
+pub fn vmid_bits() -> u32 {
+    *VMID_BITS.call_once(|| {
+        let probed: usize;
+        // `hgatp.VMID` is WARL, so unimplemented bits read back clear. Bare mode keeps the
+        // probe from translating anything.
+        unsafe {
+            core::arch::asm!(
+                "csrrw {old}, {csr}, {val}",
+                "csrr {probed}, {csr}",
+                "csrw {csr}, {old}",
+                csr = const CSR_HGATP,
+                val = in(reg) HGATP_VMID_MASK << HGATP_VMID_SHIFT,
+                old = out(reg) _,
+                probed = out(reg) probed,
+            );
+        }
+        ((probed >> HGATP_VMID_SHIFT) & HGATP_VMID_MASK).count_ones()
+    })
+}
+
+/// The VMID of a VM, valid for one generation.
+#[derive(Debug, Default)]
+pub(crate) struct Vmid {
+    generation: u64,
+    id: usize,
+}
+
+impl Vmid {
+    /// The VMID, 0 until one has been assigned.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Makes sure the VMID is valid in the current generation, allocating one if not, and returns
+    /// it. Starts a new generation if the current one has run out.
+    pub fn assign(&mut self) -> usize {
+        if self.generation == GENERATION.load(Ordering::Acquire) {
+            return self.id;
+        }
+        let max = (1usize << vmid_bits()) - 1;
+        if max == 0 {
+            return 0;
+        }
+        let mut next = NEXT_VMID.lock();
+        // Checked again, another VM may have started a new generation meanwhile.
+        let mut generation = GENERATION.load(Ordering::Acquire);
+        if *next > max {
+            generation += 1;
+            GENERATION.store(generation, Ordering::Release);
+            *next = 1;
+        }
+        self.generation = generation;
+        self.id = *next;
+        *next += 1;
+        self.id
+    }
+}
+
+/// Flushes the guest translations of hart `hart_id`, the current one, if a generation started
+/// since it last did, so VMIDs handed out again don't hit entries of their previous VMs. Called
+/// before loading a vCPU on the hart.
+pub(crate) fn sync_hart(hart_id: usize) {
+    let generation = GENERATION.load(Ordering::Acquire);
+    let Some(hart_generation) = HART_GENERATION.get(hart_id) else {
+        return tlb::hfence_gvma_all();
+    };
+    if hart_generation.swap(generation, Ordering::AcqRel) != generation {
+        tlb::hfence_gvma_all();
+    }
+}
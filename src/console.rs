@@ -0,0 +1,187 @@
+//! Console multiplexer sharing the physical console between VMs.
+//!
+//! Each VM attached to the multiplexer gets a console. Its output is buffered per line and written
+//! to the physical console prefixed with the VM's name, so lines of different VMs don't interleave.
+//! Input from the host, e.g. keyboard input read from the physical UART, goes to the console that
+//! has the focus, which the host switches with `set_focus`, or to a given console with
+//! `push_input_to`. Input waits in the console's ring until the guest reads it: once the ring is
+//! full, bytes are left to the host, which pushes them again once the guest has caught up.
+//!
+//! Guests reach their console on every console exit, so the set of attached consoles is an
+//! `RcuCell` read without locking, and each console has its own lock: VMs only contend for the
+//! physical console, while one of them writes out a line.
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+use crate::utils::RcuCell;
+use crate::{HyperError, HyperResult};
+
+/// Identifies a VM console.
+pub type ConsoleId = usize;
+
+/// Maximum number of consoles attached at the same time.
+pub const MAX_CONSOLES: usize = 8;
+
+/// Input bytes buffered per console. Further input is left to the host until the guest reads
+/// some, see `push_input_to`.
+const INPUT_BUF_SIZE: usize = 256;
+/// Output is flushed once a line gets this long, even without a newline.
+const LINE_BUF_SIZE: usize = 256;
+
+struct VmConsole {
+    prefix: String,
+    line: Vec<u8>,
+    input: VecDeque<u8>,
+}
+
+type ConsoleSlots = [Option<Arc<Mutex<VmConsole>>>; MAX_CONSOLES];
+
+struct ConsoleMux {
+    /// Writes to the physical console, locked so lines of different VMs don't interleave.
+    output: Mutex<fn(&[u8])>,
+    consoles: RcuCell<ConsoleSlots>,
+    focus: Mutex<Option<ConsoleId>>,
+}
+
+static CONSOLE_MUX: Once<ConsoleMux> = Once::new();
+
+fn mux() -> HyperResult<&'static ConsoleMux> {
+    CONSOLE_MUX.get().ok_or(HyperError::NotSupported)
+}
+
+/// Runs `f` on the console `id`, if it's attached.
+fn with_console<R>(id: ConsoleId, f: impl FnOnce(&ConsoleMux, &mut VmConsole) -> R) -> Option<R> {
+    let mux = mux().ok()?;
+    let consoles = mux.consoles.read();
+    let mut console = consoles.get(id)?.as_ref()?.lock();
+    Some(f(mux, &mut console))
+}
+
+/// Initializes the console multiplexer, which writes to the physical console through `output`.
+pub fn init_console(output: fn(&[u8])) {
+    CONSOLE_MUX.call_once(|| ConsoleMux {
+        output: Mutex::new(output),
+        consoles: RcuCell::default(),
+        focus: Mutex::new(None),
+    });
+}
+
+/// Attaches a console whose output lines are prefixed with `[name] `. The first console attached
+/// gets the focus.
+pub fn attach(name: &str) -> HyperResult<ConsoleId> {
+    let mux = mux()?;
+    let console = Arc::new(Mutex::new(VmConsole {
+        prefix: format!("[{}] ", name),
+        line: Vec::new(),
+        input: VecDeque::new(),
+    }));
+    let mut id = None;
+    mux.consoles.update(|consoles| {
+        let mut consoles = consoles.clone();
+        id = consoles.iter().position(Option::is_none);
+        if let Some(id) = id {
+            consoles[id] = Some(console);
+        }
+        consoles
+    });
+    let id = id.ok_or(HyperError::NoMemory)?;
+    mux.focus.lock().get_or_insert(id);
+    Ok(id)
+}
+
+/// Detaches the console `id`, flushing its pending output.
+pub fn detach(id: ConsoleId) {
+    let Ok(mux) = mux() else {
+        return;
+    };
+    let mut detached = None;
+    mux.consoles.update(|consoles| {
+        let mut consoles = consoles.clone();
+        detached = consoles.get_mut(id).and_then(Option::take);
+        consoles
+    });
+    if let Some(console) = detached {
+        flush(mux, &mut console.lock());
+    }
+    let mut focus = mux.focus.lock();
+    if *focus == Some(id) {
+        *focus = mux.consoles.read().iter().position(Option::is_some);
+    }
+}
+
+/// Gives the focus to the console `id`, which receives the host input from now on.
+pub fn set_focus(id: ConsoleId) -> HyperResult<()> {
+    let mux = mux()?;
+    if !matches!(mux.consoles.read().get(id), Some(Some(_))) {
+        return Err(HyperError::NotFound);
+    }
+    *mux.focus.lock() = Some(id);
+    Ok(())
+}
+
+/// The console that has the focus.
+pub fn focus() -> Option<ConsoleId> {
+    *mux().ok()?.focus.lock()
+}
+
+/// Routes a byte of host input to the console that has the focus. Returns false if it's dropped,
+/// because no console is attached or the input buffer is full.
+pub fn push_input(byte: u8) -> bool {
+    match focus() {
+        Some(id) => push_input_to(id, &[byte]) == 1,
+        None => false,
+    }
+}
+
+/// Queues the host input `bytes` for the console `id`, whether it has the focus or not. Returns
+/// how many of the first bytes were queued, fewer than all once its input ring is full, and none
+/// if it isn't attached.
+pub fn push_input_to(id: ConsoleId, bytes: &[u8]) -> usize {
+    with_console(id, |_, console| {
+        let count = bytes.len().min(INPUT_BUF_SIZE - console.input.len());
+        console.input.extend(&bytes[..count]);
+        count
+    })
+    .unwrap_or(0)
+}
+
+/// How many bytes of input the console `id` can still queue.
+pub fn input_space(id: ConsoleId) -> usize {
+    with_console(id, |_, console| INPUT_BUF_SIZE - console.input.len()).unwrap_or(0)
+}
+
+/// Whether input is waiting to be read from the console `id`.
+pub fn has_input(id: ConsoleId) -> bool {
+    with_console(id, |_, console| !console.input.is_empty()).unwrap_or(false)
+}
+
+/// Reads a byte of input from the console `id`.
+pub fn read_input(id: ConsoleId) -> Option<u8> {
+    with_console(id, |_, console| console.input.pop_front())?
+}
+
+/// Writes a byte of output to the console `id`. Output is written out line by line.
+pub fn write_output(id: ConsoleId, byte: u8) {
+    with_console(id, |mux, console| {
+        console.line.push(byte);
+        if byte == b'\n' || console.line.len() >= LINE_BUF_SIZE {
+            flush(mux, console);
+        }
+    });
+}
+
+fn flush(mux: &ConsoleMux, console: &mut VmConsole) {
+    if console.line.is_empty() {
+        return;
+    }
+    let output = mux.output.lock();
+    (*output)(console.prefix.as_bytes());
+    (*output)(&console.line);
+    if console.line.last() != Some(&b'\n') {
+        (*output)(b"\n");
+    }
+    console.line.clear();
+}
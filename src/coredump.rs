@@ -0,0 +1,169 @@
+//! Guest core dumps in ELF format.
+//!
+//! A dump is a standard ELF core file: a `PT_NOTE` segment carrying per-vCPU register state
+//! followed by one `PT_LOAD` segment per guest RAM region, whose virtual and physical addresses
+//! are the region's guest physical address. This is the layout QEMU's `dump-guest-memory`
+//! produces, so gdb and crash can open the dumps of guest kernels directly.
+//!
+//! The file is streamed through a `SnapshotWriter`, guest memory being read page by page.
+use crate::{
+    memory::PAGE_SIZE_4K, snapshot::SnapshotWriter, GuestPhysAddr, HyperError, HyperResult,
+};
+
+/// `e_machine` value for RISC-V.
+pub const EM_RISCV: u16 = 243;
+/// `e_machine` value for AArch64.
+pub const EM_AARCH64: u16 = 183;
+/// `e_machine` value for x86-64.
+pub const EM_X86_64: u16 = 62;
+
+/// Note type of the per-thread `elf_prstatus` register state.
+pub const NT_PRSTATUS: u32 = 1;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 0x7;
+
+/// A note of the `PT_NOTE` segment.
+pub struct CoreNote<'a> {
+    /// Owner of the note, e.g. "CORE" for the standard notes.
+    pub name: &'a str,
+    /// Type of the note, e.g. `NT_PRSTATUS`.
+    pub note_type: u32,
+    /// Contents of the note.
+    pub desc: &'a [u8],
+}
+
+impl CoreNote<'_> {
+    fn size(&self) -> usize {
+        12 + align4(self.name.len() + 1) + align4(self.desc.len())
+    }
+}
+
+/// A guest RAM region dumped as a `PT_LOAD` segment.
+#[derive(Clone, Copy, Debug)]
+pub struct CoreSegment {
+    /// Guest physical address of the region.
+    pub gpa: GuestPhysAddr,
+    /// Size of the region in bytes.
+    pub size: usize,
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Writes an ELF core file for machine `machine` containing `notes` and the guest memory of
+/// `segments`, which is read through `read_memory` one page at most at a time.
+pub fn write_elf_core<W: SnapshotWriter>(
+    writer: &mut W,
+    machine: u16,
+    notes: &[CoreNote<'_>],
+    segments: &[CoreSegment],
+    mut read_memory: impl FnMut(GuestPhysAddr, &mut [u8]) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let phnum = 1 + segments.len();
+    if phnum > u16::MAX as usize {
+        return Err(HyperError::InvalidParam);
+    }
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let notes_size: usize = notes.iter().map(CoreNote::size).sum();
+    // Page-align the memory contents so the dump can be mmapped by tools.
+    let data_offset = (notes_offset + notes_size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+
+    // ELF header.
+    let mut header = [0u8; ELF_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"\x7fELF");
+    header[4] = 2; // ELFCLASS64
+    header[5] = 1; // ELFDATA2LSB
+    header[6] = 1; // EV_CURRENT
+    header[16..18].copy_from_slice(&ET_CORE.to_le_bytes());
+    header[18..20].copy_from_slice(&machine.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[32..40].copy_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    header[52..54].copy_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header[56..58].copy_from_slice(&(phnum as u16).to_le_bytes());
+    writer.write(&header)?;
+
+    // Program headers.
+    write_program_header(writer, PT_NOTE, 0, notes_offset, 0, notes_size, 4)?;
+    let mut offset = data_offset;
+    for segment in segments {
+        write_program_header(
+            writer,
+            PT_LOAD,
+            PF_RWX,
+            offset,
+            segment.gpa,
+            segment.size,
+            PAGE_SIZE_4K,
+        )?;
+        offset += segment.size;
+    }
+
+    // Notes.
+    for note in notes {
+        let namesz = note.name.len() + 1;
+        writer.write(&(namesz as u32).to_le_bytes())?;
+        writer.write(&(note.desc.len() as u32).to_le_bytes())?;
+        writer.write(&note.note_type.to_le_bytes())?;
+        writer.write(note.name.as_bytes())?;
+        write_zeros(writer, align4(namesz) - note.name.len())?;
+        writer.write(note.desc)?;
+        write_zeros(writer, align4(note.desc.len()) - note.desc.len())?;
+    }
+    write_zeros(writer, data_offset - notes_offset - notes_size)?;
+
+    // Memory.
+    let mut buf = vec![0u8; PAGE_SIZE_4K];
+    for segment in segments {
+        let mut gpa = segment.gpa;
+        let end = segment.gpa + segment.size;
+        while gpa < end {
+            // Never cross a page boundary within one read.
+            let len = core::cmp::min(PAGE_SIZE_4K - gpa % PAGE_SIZE_4K, end - gpa);
+            read_memory(gpa, &mut buf[..len])?;
+            writer.write(&buf[..len])?;
+            gpa += len;
+        }
+    }
+    Ok(())
+}
+
+fn write_program_header<W: SnapshotWriter>(
+    writer: &mut W,
+    p_type: u32,
+    flags: u32,
+    offset: usize,
+    addr: GuestPhysAddr,
+    size: usize,
+    align: usize,
+) -> HyperResult<()> {
+    let mut phdr = [0u8; PROGRAM_HEADER_SIZE];
+    phdr[0..4].copy_from_slice(&p_type.to_le_bytes());
+    phdr[4..8].copy_from_slice(&flags.to_le_bytes());
+    phdr[8..16].copy_from_slice(&(offset as u64).to_le_bytes());
+    // p_vaddr and p_paddr.
+    phdr[16..24].copy_from_slice(&(addr as u64).to_le_bytes());
+    phdr[24..32].copy_from_slice(&(addr as u64).to_le_bytes());
+    // p_filesz and p_memsz.
+    phdr[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+    phdr[40..48].copy_from_slice(&(size as u64).to_le_bytes());
+    phdr[48..56].copy_from_slice(&(align as u64).to_le_bytes());
+    writer.write(&phdr)
+}
+
+fn write_zeros<W: SnapshotWriter>(writer: &mut W, mut len: usize) -> HyperResult<()> {
+    let zeros = [0u8; 64];
+    while len > 0 {
+        let chunk = core::cmp::min(len, zeros.len());
+        writer.write(&zeros[..chunk])?;
+        len -= chunk;
+    }
+    Ok(())
+}
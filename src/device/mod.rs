@@ -1,9 +1,166 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{utils::RcuCell, virtio::GuestMemory, GuestPhysAddr, HyperResult};
+
+/// Describes a trapped MMIO access to be emulated.
 #[repr(C)]
 pub struct EmuContext {
+    /// Guest physical address accessed.
     pub address: usize,
+    /// Access width in bytes.
     pub width: usize,
+    /// Whether the access is a store.
     pub write: bool,
+    /// Whether a load sign-extends the value read.
     pub sign_ext: bool,
+    /// Index of the source register of a store or the destination register of a load.
     pub reg: usize,
+    /// Width of the register in bytes.
     pub reg_width: usize,
+    /// Length of the trapping instruction in bytes.
+    pub inst_len: usize,
+}
+
+impl EmuContext {
+    fn mask(width: usize) -> usize {
+        if width >= core::mem::size_of::<usize>() {
+            usize::MAX
+        } else {
+            (1 << (width * 8)) - 1
+        }
+    }
+
+    /// The value a store writes to the device, given the value of its source register.
+    pub fn write_value(&self, reg_val: usize) -> usize {
+        reg_val & Self::mask(self.width)
+    }
+
+    /// The value a load puts into its destination register, given the value `val` read from the
+    /// device. Bits beyond the access width are dropped, then the value is sign- or zero-extended
+    /// to the register width.
+    pub fn read_value(&self, val: usize) -> usize {
+        let mask = Self::mask(self.width);
+        let mut val = val & mask;
+        if self.sign_ext && val & (1 << (self.width * 8 - 1)) != 0 {
+            val |= !mask;
+        }
+        val & Self::mask(self.reg_width)
+    }
+}
+
+/// An emulated device the VM dispatches the MMIO accesses trapping in its register window to.
+/// Devices keep their state behind their own locks, so the VM holds them as `Arc<dyn EmuDevice>`
+/// in a `DeviceRegistry` and may keep a handle of the concrete type for its own use.
+pub trait EmuDevice: Send + Sync {
+    /// Whether `addr` is in the device's register window.
+    fn contains(&self, addr: GuestPhysAddr) -> bool;
+
+    /// Emulates a load of `width` bytes at `addr`, returning the value read. Fails with
+    /// `InvalidInstruction` if the device's registers can't be accessed with that width.
+    fn handle_read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64>;
+
+    /// Emulates a store of the `width` bytes of `val` at `addr`. `mem` is the guest RAM, for
+    /// devices doing DMA. Fails like `handle_read`.
+    fn handle_write(
+        &self,
+        addr: GuestPhysAddr,
+        width: usize,
+        val: u64,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()>;
+
+    /// Puts the device back into its power-on state.
+    fn reset(&self);
+
+    /// The guest interrupt the device raises and whether it currently requests it, none if it
+    /// raises no interrupt.
+    fn irq_line(&self) -> Option<(u32, bool)>;
+
+    /// Catches up on work that became due without an access, e.g. an expired alarm or buffers to
+    /// process in polling mode. Called on every exit of a vCPU of the VM.
+    fn update(&self, _mem: &dyn GuestMemory) {}
+}
+
+/// A device of a `DeviceRegistry`.
+struct Registered {
+    device: Arc<dyn EmuDevice>,
+    /// Whether the device requested its interrupt when last checked.
+    raised: AtomicBool,
+}
+
+/// The emulated devices of a VM. Every trapping MMIO access and every exit looks the devices up,
+/// while they're only added when the VM is set up, so the list is an `RcuCell`: lookups don't
+/// take a lock, and adding a device publishes a new copy of the list.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: RcuCell<Vec<Arc<Registered>>>,
+}
+
+impl DeviceRegistry {
+    /// Adds `device`, which doesn't request its interrupt yet.
+    pub fn add(&self, device: Arc<dyn EmuDevice>) {
+        let registered = Arc::new(Registered {
+            device,
+            raised: AtomicBool::new(false),
+        });
+        self.devices.update(|devices| {
+            let mut devices = devices.clone();
+            devices.push(registered.clone());
+            devices
+        });
+    }
+
+    /// Number of devices.
+    pub fn len(&self) -> usize {
+        self.devices.read().len()
+    }
+
+    /// Whether there are no devices.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The device whose register window contains `addr`, if any.
+    pub fn find(&self, addr: GuestPhysAddr) -> Option<Arc<dyn EmuDevice>> {
+        let devices = self.devices.read();
+        let found = devices.iter().find(|dev| dev.device.contains(addr));
+        found.map(|dev| dev.device.clone())
+    }
+
+    /// Brings the device `index` up to date, see `EmuDevice::update`, and returns its interrupt
+    /// line: the guest interrupt, whether the device requests it, and whether it didn't when last
+    /// checked. None if there's no such device or it raises no interrupt.
+    pub fn update_irq_line(
+        &self,
+        index: usize,
+        mem: &dyn GuestMemory,
+    ) -> Option<(u32, bool, bool)> {
+        let dev = self.devices.read().get(index)?.clone();
+        dev.device.update(mem);
+        let (irq, level) = dev.device.irq_line()?;
+        let raised = dev.raised.swap(level, Ordering::AcqRel);
+        Some((irq, level, level && !raised))
+    }
+
+    /// Calls `f` with the guest interrupt of each device that requested it since it was last
+    /// checked.
+    pub fn for_each_rising_irq(&self, mut f: impl FnMut(u32)) {
+        for dev in self.devices.read().iter() {
+            if let Some((irq, true)) = dev.device.irq_line() {
+                if !dev.raised.load(Ordering::Acquire) {
+                    f(irq);
+                }
+            }
+        }
+    }
+
+    /// Puts the devices back into their power-on state, see `EmuDevice::reset`.
+    pub fn reset(&self) {
+        for dev in self.devices.read().iter() {
+            dev.device.reset();
+            dev.raised.store(false, Ordering::Release);
+        }
+    }
 }
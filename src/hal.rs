@@ -1,7 +1,8 @@
-use crate::{GuestPageTableTrait, HostPageNum, HostPhysAddr, HostVirtAddr, HyperResult, memory::PAGE_SIZE_4K};
+use crate::{GuestPageTableTrait, GuestPhysAddr, HostPageNum, HostPhysAddr, HostVirtAddr, HyperResult, memory::PAGE_SIZE_4K};
 
 /// The interfaces which the underlginh software(kernel or hypervisor) must implement.
-pub trait HyperCraftHal: Sized {
+/// It's a type without data, which per-CPU state refers to for the lifetime of the hypervisor.
+pub trait HyperCraftHal: Sized + 'static {
     /// Page size.
     const PAGE_SIZE: usize = PAGE_SIZE_4K;
 
@@ -31,10 +32,10 @@ pub trait HyperCraftHal: Sized {
     // fn vmexit_handler(vcpu: &mut crate::VCpu<Self>, vm_exit_info: VmExitInfo);
 
     /// Convert a host physical address to host virtual address.
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
     fn phys_to_virt(pa: HostPhysAddr) -> HostVirtAddr;
     /// Convert a host virtual address to host physical address.
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
     fn virt_to_phys(va: HostVirtAddr) -> HostPhysAddr;
     /// VM-Exit handler.
     #[cfg(target_arch = "x86_64")]
@@ -42,4 +43,52 @@ pub trait HyperCraftHal: Sized {
     /// Current time in nanoseconds.
     #[cfg(target_arch = "x86_64")]
     fn current_time_nanos() -> u64;
+    /// Wall-clock time in nanoseconds since the Unix epoch, as reported to guests by emulated
+    /// RTCs. Defaults to 0, the epoch, for hosts without a wall clock.
+    #[cfg(target_arch = "riscv64")]
+    fn wall_clock() -> u64 {
+        0
+    }
+    /// Fills `buf` with random bytes from the host's entropy source, as handed to guests by
+    /// emulated entropy devices. Defaults to failing with `NotSupported`.
+    #[cfg(target_arch = "riscv64")]
+    fn fill_entropy(_buf: &mut [u8]) -> HyperResult<()> {
+        Err(crate::HyperError::NotSupported)
+    }
+    /// Shows the display of an emulated GPU: `pixels` are `0x00RRGGBB`, `stride` per row, of
+    /// which the guest has just updated the rectangle `rect`, given as `(x, y, width, height)`.
+    /// Defaults to showing nothing.
+    #[cfg(target_arch = "riscv64")]
+    fn display_update(_pixels: &[u32], _stride: usize, _rect: (u32, u32, u32, u32)) {}
+    /// Invalidates the cached guest-physical translations of `[gpa, gpa + size)` on the other
+    /// CPUs, called after mappings of a guest page table have changed and the calling CPU has
+    /// fenced its own. Defaults to a `HFENCE.GVMA` on every hart through the SBI RFENCE
+    /// extension.
+    #[cfg(target_arch = "riscv64")]
+    fn flush_guest_tlb(gpa: GuestPhysAddr, size: usize) {
+        let _ = sbi_rt::remote_hfence_gvma(0, usize::MAX, gpa, size);
+    }
+    /// Called when an interrupt has been made pending for the vCPU `vcpu_id` outside of its own
+    /// exit handling, or it's asked to pause, so the host can wake the vCPU up if it's blocked, or
+    /// kick it out of the guest if it runs on another CPU. On riscv a vCPU is kicked by a
+    /// supervisor software interrupt to its hart, which the hypervisor clears. Requests posted
+    /// through a vCPU's `IpiChannel` only call this while the vCPU is out of the guest. Defaults
+    /// to nothing, for hosts running each vCPU on its own hart without blocking it: the interrupt
+    /// is then seen on the vCPU's next exit.
+    #[cfg(target_arch = "riscv64")]
+    fn vcpu_interrupt_pending(_vcpu_id: usize) {}
+    /// Sends a supervisor software interrupt to the hart `hart_id`, kicking the vCPU running there
+    /// out of the guest. Defaults to the SBI IPI extension.
+    #[cfg(target_arch = "riscv64")]
+    fn send_ipi(hart_id: usize) {
+        sbi_rt::send_ipi(1, hart_id);
+    }
+    /// Parks the calling hart while the vCPU it runs through `VM::run` waits, until an interrupt
+    /// enabled in `sie` is pending or, if given, the `time` CSR reaches `wake_at`, for which the
+    /// host timer interrupt is armed. The host may yield the hart to other work meanwhile, as
+    /// long as it returns once the vCPU is kicked. Defaults to WFI.
+    #[cfg(target_arch = "riscv64")]
+    fn park_hart(_wake_at: Option<u64>) {
+        unsafe { core::arch::asm!("wfi") };
+    }
 }
@@ -28,6 +28,9 @@ extern crate log;
 #[macro_use]
 extern crate alloc;
 
+#[macro_use]
+pub mod logging;
+
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64/mod.rs"]
 mod arch;
@@ -38,13 +41,16 @@ mod arch;
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
 
-#[cfg(target_arch = "aarch64")]
+pub mod console;
+pub mod coredump;
 mod device;
 mod hal;
 mod memory;
+pub mod snapshot;
 mod traits;
+pub mod utils;
 mod vcpus;
-#[cfg(target_arch = "aarch64")]
+mod virtio;
 pub use device::EmuContext;
 
 /// HyperCraft Result Define.
@@ -53,6 +59,34 @@ pub type HyperResult<T = ()> = Result<T, HyperError>;
 #[cfg(not(target_arch = "aarch64"))]
 pub use arch::{init_hv_runtime, GprIndex, HyperCallMsg, VmExitInfo};
 
+#[cfg(target_arch = "riscv64")]
+pub use arch::{
+    audit_isolation, init, init_aia, init_iommu, instantiate_manifest, verify_realtime,
+    CounterAccess, CpuBandwidth, CpuClass, CpuShare, DebugEvent, DeviceConfig, ExitReason,
+    ExitStats, ExitTraceEntry, FsAttr, FsBackend, FsDirEntry, FsFileType, GdbAction, GdbConnection,
+    GdbStub, GuestMapping, GuestMemoryMap, GuestPagingMode, GuestRegion, HartState,
+    HostCapabilities, HypervisorPerCpu, InputEvent, InputHandle, IpiChannel, IrqKind,
+    IsaExtensions, IsolationViolation, MmioSlot, NestedPageTableSv48, NestedPageTableSv57,
+    PauseHandle, PrivilegeLevel, RealtimeViolation, RealtimeViolationKind, RegionKind, ResetReason,
+    ResetType, ResourceLimits, ResourceUsage, RomWritePolicy, SharePermission, StopReason,
+    VCpuState, ViolationKind, VirtioBlkDriver, VmConfigBuilder, STANDARD_RAM_BASE, STANDARD_RTC,
+    STANDARD_UART, STANDARD_VIRTIO_BASE, STANDARD_VIRTIO_SLOTS,
+};
+#[cfg(target_arch = "riscv64")]
+pub use traits::VcpuScheduler;
+
+/// Minimal guest images for testing, see the module documentation.
+#[cfg(all(target_arch = "riscv64", feature = "test-guests"))]
+pub use arch::test_guests;
+
+/// Fuzzing entry points for virtio parsing, see the module documentation.
+#[cfg(feature = "fuzzing")]
+pub use virtio::fuzz;
+
+/// Guest TLB maintenance, see the module documentation.
+#[cfg(target_arch = "riscv64")]
+pub use arch::tlb;
+
 pub use arch::{NestedPageTable, PerCpu, VCpu, VM};
 
 pub use hal::HyperCraftHal;
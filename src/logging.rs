@@ -0,0 +1,145 @@
+//! Logging tagged with the subsystem and the VM and vCPU a message is about.
+//!
+//! Messages go through the `log` crate with their subsystem as target, e.g. `hypercraft::virtio`,
+//! and are prefixed with the ids of the VM and vCPU they're about when known, so the output of
+//! several VMs can be told apart. Each subsystem has its own level, adjustable at runtime, which
+//! filters its messages before they're formatted: hot paths like virtio I/O can be silenced
+//! without reconfiguring the host's logger.
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub use log::LevelFilter;
+
+/// A part of the hypervisor messages are logged for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Per-hart setup of the hypervisor.
+    Hart,
+    /// vCPU execution and exit handling.
+    Vcpu,
+    /// Guest memory and second-stage translation.
+    Mmu,
+    /// Interrupt controllers and interrupt injection.
+    Irq,
+    /// Emulated virtio devices.
+    Virtio,
+    /// SBI calls of guests.
+    Sbi,
+    /// The IOMMU.
+    Iommu,
+    /// VM snapshots.
+    Snapshot,
+}
+
+impl Subsystem {
+    /// All subsystems.
+    pub const ALL: [Self; 8] = [
+        Self::Hart,
+        Self::Vcpu,
+        Self::Mmu,
+        Self::Irq,
+        Self::Virtio,
+        Self::Sbi,
+        Self::Iommu,
+        Self::Snapshot,
+    ];
+
+    /// The `log` target of the subsystem's messages.
+    pub fn target(self) -> &'static str {
+        match self {
+            Self::Hart => "hypercraft::hart",
+            Self::Vcpu => "hypercraft::vcpu",
+            Self::Mmu => "hypercraft::mmu",
+            Self::Irq => "hypercraft::irq",
+            Self::Virtio => "hypercraft::virtio",
+            Self::Sbi => "hypercraft::sbi",
+            Self::Iommu => "hypercraft::iommu",
+            Self::Snapshot => "hypercraft::snapshot",
+        }
+    }
+}
+
+/// Level of each subsystem, as a `LevelFilter` discriminant. All are logged by default.
+static LEVELS: [AtomicUsize; Subsystem::ALL.len()] =
+    [const { AtomicUsize::new(LevelFilter::Trace as usize) }; Subsystem::ALL.len()];
+
+/// Sets the most verbose level logged for `subsystem`. Messages must also pass the `log` crate's
+/// global maximum level.
+pub fn set_level(subsystem: Subsystem, level: LevelFilter) {
+    LEVELS[subsystem as usize].store(level as usize, Ordering::Relaxed);
+}
+
+/// The most verbose level logged for `subsystem`.
+pub fn level(subsystem: Subsystem) -> LevelFilter {
+    match LEVELS[subsystem as usize].load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Whether messages of `subsystem` at `level` are logged.
+pub fn enabled(subsystem: Subsystem, level: log::Level) -> bool {
+    level <= self::level(subsystem) && level <= log::max_level()
+}
+
+/// The VM and vCPU a message is about, printed as its prefix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogContext {
+    pub vm: Option<usize>,
+    pub vcpu: Option<usize>,
+}
+
+impl LogContext {
+    /// No VM in particular.
+    pub const NONE: Self = Self {
+        vm: None,
+        vcpu: None,
+    };
+
+    /// The VM `vm` as a whole.
+    pub fn vm(vm: usize) -> Self {
+        Self {
+            vm: Some(vm),
+            vcpu: None,
+        }
+    }
+
+    /// The vCPU `vcpu` of the VM `vm`.
+    pub fn vcpu(vm: usize, vcpu: usize) -> Self {
+        Self {
+            vm: Some(vm),
+            vcpu: Some(vcpu),
+        }
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.vm, self.vcpu) {
+            (Some(vm), Some(vcpu)) => write!(f, "vm{}/vcpu{}: ", vm, vcpu),
+            (Some(vm), None) => write!(f, "vm{}: ", vm),
+            (None, Some(vcpu)) => write!(f, "vcpu{}: ", vcpu),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Logs a message of the subsystem `$subsystem` at `$level` about the `LogContext` `$ctx`, e.g.
+/// `hv_log!(Warn, Virtio, ctx, "queue {} failed", index)`.
+macro_rules! hv_log {
+    ($level:ident, $subsystem:ident, $ctx:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($crate::logging::Subsystem::$subsystem, log::Level::$level) {
+            log::log!(
+                target: $crate::logging::Subsystem::$subsystem.target(),
+                log::Level::$level,
+                "{}{}",
+                $ctx,
+                format_args!($($arg)+)
+            );
+        }
+    };
+}
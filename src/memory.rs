@@ -44,6 +44,14 @@ pub trait GuestPageTableTrait {
     /// Unmap the guest physical frame `hpa`
     fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult<()>;
 
+    /// Change the flags of the mapped guest physical frame `gpa` to `flags`. The default
+    /// implementation remaps the frame, page tables can override it to update the entry in place.
+    fn protect(&mut self, gpa: GuestPhysAddr, flags: MappingFlags) -> HyperResult<()> {
+        let hpa = self.translate(gpa)?;
+        self.unmap(gpa)?;
+        self.map(gpa, hpa, flags)
+    }
+
     /// Translate the host physical address which the guest physical frame of
     /// `gpa` maps to.
     fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr>;
@@ -0,0 +1,374 @@
+//! Differential snapshots for periodic checkpointing.
+//!
+//! A delta snapshot carries only the guest pages written since the snapshot it's based on, plus
+//! the complete device state, which is small. Every snapshot of a chain starts with a `CHAIN`
+//! section naming itself and its base. A chain is restored by applying the full snapshot and then
+//! each delta in order, so that sections of later snapshots override those of earlier ones.
+use alloc::vec::Vec;
+
+use super::{SectionKind, SectionReader, SnapshotDecoder, SnapshotEncoder};
+use super::{SnapshotReader, SnapshotWriter};
+use crate::{logging::LogContext, GuestPhysAddr, HyperError, HyperResult};
+
+/// Granularity of guest memory in snapshots and dirty bitmaps.
+pub const SNAPSHOT_PAGE_SIZE: usize = 0x1000;
+
+const CHAIN_VERSION: u16 = 1;
+const MEMORY_PAGES_VERSION: u16 = 1;
+
+impl SectionKind {
+    /// Identity of a snapshot and of the snapshot it's a delta against.
+    pub const CHAIN: Self = Self(1);
+    /// Guest memory pages, each stored as its guest physical address followed by its contents.
+    pub const MEMORY_PAGES: Self = Self(2);
+}
+
+/// Position of a snapshot in a delta chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainInfo {
+    /// Identifier of this snapshot, chosen by the host.
+    pub id: u64,
+    /// Identifier of the snapshot this one is a delta against, or `None` for a full snapshot.
+    pub base: Option<u64>,
+}
+
+impl ChainInfo {
+    /// Writes the `CHAIN` section. Must be the first section of a snapshot.
+    pub fn write<W: SnapshotWriter>(
+        &self,
+        encoder: &mut SnapshotEncoder<'_, W>,
+    ) -> HyperResult<()> {
+        let mut payload = [0u8; 17];
+        payload[0..8].copy_from_slice(&self.id.to_le_bytes());
+        payload[8] = self.base.is_some() as u8;
+        payload[9..17].copy_from_slice(&self.base.unwrap_or(0).to_le_bytes());
+        encoder.write_section(SectionKind::CHAIN, CHAIN_VERSION, false, &payload)
+    }
+
+    /// Parses a `CHAIN` section.
+    pub fn read<R: SnapshotReader>(section: &mut SectionReader<'_, '_, R>) -> HyperResult<Self> {
+        let id = section.read_u64()?;
+        let mut has_base = [0u8; 1];
+        section.read(&mut has_base)?;
+        let base = section.read_u64()?;
+        Ok(Self {
+            id,
+            base: (has_base[0] != 0).then_some(base),
+        })
+    }
+}
+
+/// Set of guest pages written since some point in time, one bit per page of a guest physical
+/// range.
+#[derive(Clone, Debug)]
+pub struct DirtyBitmap {
+    base: GuestPhysAddr,
+    pages: usize,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Creates a clean bitmap covering `size` bytes of guest memory starting at `base`.
+    pub fn new(base: GuestPhysAddr, size: usize) -> Self {
+        let pages = (size + SNAPSHOT_PAGE_SIZE - 1) / SNAPSHOT_PAGE_SIZE;
+        Self {
+            base,
+            pages,
+            bits: vec![0; (pages + 63) / 64],
+        }
+    }
+
+    /// Start of the covered guest physical range.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Size of the covered guest physical range in bytes.
+    pub fn size(&self) -> usize {
+        self.pages * SNAPSHOT_PAGE_SIZE
+    }
+
+    fn page_index(&self, gpa: GuestPhysAddr) -> Option<usize> {
+        let index = gpa.checked_sub(self.base)? / SNAPSHOT_PAGE_SIZE;
+        (index < self.pages).then_some(index)
+    }
+
+    /// Marks the page containing `gpa` dirty. Returns false if `gpa` isn't covered.
+    pub fn set(&mut self, gpa: GuestPhysAddr) -> bool {
+        match self.page_index(gpa) {
+            Some(index) => {
+                self.bits[index / 64] |= 1 << (index % 64);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the page containing `gpa` is dirty.
+    pub fn is_dirty(&self, gpa: GuestPhysAddr) -> bool {
+        self.page_index(gpa)
+            .is_some_and(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    /// Number of dirty pages.
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Marks every page clean.
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    /// Guest physical addresses of the dirty pages, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = GuestPhysAddr> + Clone + '_ {
+        self.bits.iter().enumerate().flat_map(move |(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| self.base + (i * 64 + bit) * SNAPSHOT_PAGE_SIZE)
+        })
+    }
+}
+
+/// Writes the pages at `pages` as a `MEMORY_PAGES` section, reading their contents through
+/// `read_page`.
+pub fn write_memory_pages<W: SnapshotWriter>(
+    encoder: &mut SnapshotEncoder<'_, W>,
+    pages: impl Iterator<Item = GuestPhysAddr> + Clone,
+    mut read_page: impl FnMut(GuestPhysAddr, &mut [u8]) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let count = pages.clone().count() as u64;
+    let entry_size = (8 + SNAPSHOT_PAGE_SIZE) as u64;
+    encoder.begin_section(
+        SectionKind::MEMORY_PAGES,
+        MEMORY_PAGES_VERSION,
+        false,
+        count * entry_size,
+    )?;
+    let mut buf = vec![0u8; SNAPSHOT_PAGE_SIZE];
+    for gpa in pages {
+        read_page(gpa, &mut buf)?;
+        encoder.write_payload(&(gpa as u64).to_le_bytes())?;
+        encoder.write_payload(&buf)?;
+    }
+    Ok(())
+}
+
+/// Reads a `MEMORY_PAGES` section, handing each page to `write_page`.
+pub fn read_memory_pages<R: SnapshotReader>(
+    section: &mut SectionReader<'_, '_, R>,
+    mut write_page: impl FnMut(GuestPhysAddr, &[u8]) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let mut buf = vec![0u8; SNAPSHOT_PAGE_SIZE];
+    while section.remaining() >= (8 + SNAPSHOT_PAGE_SIZE) as u64 {
+        let gpa = section.read_u64()? as GuestPhysAddr;
+        section.read(&mut buf)?;
+        write_page(gpa, &buf)?;
+    }
+    Ok(())
+}
+
+/// Restores a chain of snapshots: a full snapshot followed by deltas, each based on the one before
+/// it. `apply` is called for every section after the `CHAIN` section, in order, and `max_version`
+/// tells which section kinds it understands, as for `SnapshotDecoder::next_section`. `MEMORY_PAGES`
+/// sections are always passed to `apply`, which reads them with `read_memory_pages`.
+pub fn restore_chain<R: SnapshotReader>(
+    snapshots: &mut [R],
+    max_version: impl Fn(SectionKind) -> Option<u16>,
+    mut apply: impl FnMut(&mut SectionReader<'_, '_, R>) -> HyperResult<()>,
+) -> HyperResult<()> {
+    let supported = |kind| match kind {
+        SectionKind::CHAIN => Some(CHAIN_VERSION),
+        SectionKind::MEMORY_PAGES => Some(MEMORY_PAGES_VERSION),
+        _ => max_version(kind),
+    };
+    let mut last = None;
+    for reader in snapshots.iter_mut() {
+        let mut decoder = SnapshotDecoder::new(reader)?;
+        let info = match decoder.next_section(supported)? {
+            Some(mut section) if section.header().kind == SectionKind::CHAIN => {
+                ChainInfo::read(&mut section)?
+            }
+            _ => return Err(HyperError::InvalidParam),
+        };
+        if info.base != last {
+            hv_log!(
+                Warn,
+                Snapshot,
+                LogContext::NONE,
+                "snapshot {} is based on {:?}, expected {:?}",
+                info.id,
+                info.base,
+                last
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        last = Some(info.id);
+        while let Some(mut section) = decoder.next_section(supported)? {
+            apply(&mut section)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: SectionKind = SectionKind(0x100);
+
+    fn supported(kind: SectionKind) -> Option<u16> {
+        match kind {
+            SectionKind::MEMORY_PAGES => Some(MEMORY_PAGES_VERSION),
+            DEVICE => Some(1),
+            _ => None,
+        }
+    }
+
+    fn page(gpa: GuestPhysAddr) -> Vec<u8> {
+        vec![(gpa / SNAPSHOT_PAGE_SIZE) as u8; SNAPSHOT_PAGE_SIZE]
+    }
+
+    /// Encodes a snapshot of the chain made of `pages` and a device section holding `device`.
+    fn snapshot(info: ChainInfo, pages: &[GuestPhysAddr], device: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = SnapshotEncoder::new(&mut buf).unwrap();
+        info.write(&mut encoder).unwrap();
+        write_memory_pages(&mut encoder, pages.iter().copied(), |gpa, buf| {
+            buf.copy_from_slice(&page(gpa));
+            Ok(())
+        })
+        .unwrap();
+        encoder.write_section(DEVICE, 1, false, &[device]).unwrap();
+        encoder.finish().unwrap();
+        buf
+    }
+
+    #[test]
+    fn dirty_bitmap() {
+        let base = 0x8000_0000;
+        let mut bitmap = DirtyBitmap::new(base, 100 * SNAPSHOT_PAGE_SIZE + 1);
+        assert_eq!(bitmap.base(), base);
+        assert_eq!(bitmap.size(), 101 * SNAPSHOT_PAGE_SIZE);
+        assert_eq!(bitmap.count(), 0);
+
+        assert!(!bitmap.set(base - 1));
+        assert!(!bitmap.set(base + 101 * SNAPSHOT_PAGE_SIZE));
+        for page in [100, 3, 64, 3] {
+            assert!(bitmap.set(base + page * SNAPSHOT_PAGE_SIZE + 0x123));
+        }
+        assert!(bitmap.is_dirty(base + 64 * SNAPSHOT_PAGE_SIZE));
+        assert!(!bitmap.is_dirty(base + 63 * SNAPSHOT_PAGE_SIZE));
+        assert!(!bitmap.is_dirty(base - 1));
+        assert_eq!(bitmap.count(), 3);
+        let dirty: Vec<_> = bitmap.iter().collect();
+        assert_eq!(
+            dirty,
+            [3, 64, 100].map(|page| base + page * SNAPSHOT_PAGE_SIZE)
+        );
+
+        bitmap.clear();
+        assert_eq!(bitmap.count(), 0);
+        assert_eq!(bitmap.iter().next(), None);
+    }
+
+    #[test]
+    fn memory_pages_round_trip() {
+        let pages = [0x1000, 0x5000, 0x2000];
+        let buf = snapshot(ChainInfo { id: 1, base: None }, &pages, 0);
+        let mut reader = buf.as_slice();
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        let supported = |kind| match kind {
+            SectionKind::CHAIN => Some(CHAIN_VERSION),
+            SectionKind::MEMORY_PAGES => Some(MEMORY_PAGES_VERSION),
+            _ => None,
+        };
+
+        let mut section = decoder.next_section(supported).unwrap().unwrap();
+        let info = ChainInfo::read(&mut section).unwrap();
+        assert_eq!(info, ChainInfo { id: 1, base: None });
+
+        let mut section = decoder.next_section(supported).unwrap().unwrap();
+        assert_eq!(section.header().kind, SectionKind::MEMORY_PAGES);
+        let mut restored = Vec::new();
+        read_memory_pages(&mut section, |gpa, data| {
+            assert_eq!(data, page(gpa));
+            restored.push(gpa);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(restored, pages);
+    }
+
+    #[test]
+    fn restores_chain_in_order() {
+        let mut snapshots = [
+            snapshot(ChainInfo { id: 7, base: None }, &[0x1000, 0x2000], 1),
+            snapshot(
+                ChainInfo {
+                    id: 9,
+                    base: Some(7),
+                },
+                &[0x2000],
+                2,
+            ),
+        ];
+        let mut readers = snapshots.each_mut().map(|buf| buf.as_slice());
+        let mut applied = Vec::new();
+        restore_chain(&mut readers, supported, |section| {
+            match section.header().kind {
+                SectionKind::MEMORY_PAGES => read_memory_pages(section, |gpa, _| {
+                    applied.push(gpa);
+                    Ok(())
+                }),
+                DEVICE => {
+                    let mut state = [0u8; 1];
+                    section.read(&mut state)?;
+                    applied.push(state[0] as GuestPhysAddr);
+                    Ok(())
+                }
+                _ => unreachable!(),
+            }
+        })
+        .unwrap();
+        assert_eq!(applied, [0x1000, 0x2000, 1, 0x2000, 2]);
+    }
+
+    #[test]
+    fn rejects_broken_chain() {
+        let full = snapshot(ChainInfo { id: 7, base: None }, &[], 0);
+        let delta = snapshot(
+            ChainInfo {
+                id: 9,
+                base: Some(8),
+            },
+            &[],
+            0,
+        );
+        let mut readers = [full.as_slice(), delta.as_slice()];
+        assert_eq!(
+            restore_chain(&mut readers, supported, |_| Ok(())),
+            Err(HyperError::InvalidParam)
+        );
+
+        // A delta can't be restored on its own.
+        let mut readers = [delta.as_slice()];
+        assert_eq!(
+            restore_chain(&mut readers, supported, |_| Ok(())),
+            Err(HyperError::InvalidParam)
+        );
+
+        let mut buf = Vec::new();
+        let mut encoder = SnapshotEncoder::new(&mut buf).unwrap();
+        encoder.write_section(DEVICE, 1, false, &[0]).unwrap();
+        encoder.finish().unwrap();
+        let mut readers = [buf.as_slice()];
+        assert_eq!(
+            restore_chain(&mut readers, supported, |_| Ok(())),
+            Err(HyperError::InvalidParam)
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! Self-describing snapshot container format.
+//!
+//! A snapshot is a file header followed by a sequence of sections, each prefixed with its own
+//! header. All integers are little endian.
+//!
+//! ```text
+//! +--------------------------------------------------+
+//! | magic "HCSNAPSH" | major u16 | minor u16 | rsvd u32 |  file header
+//! +--------------------------------------------------+
+//! | kind u32 | version u16 | flags u16 | length u64  |  section header
+//! | payload (`length` bytes)                         |
+//! +--------------------------------------------------+
+//! | ...                                              |
+//! +--------------------------------------------------+
+//! | kind = END | 0 | 0 | 0                           |  end marker
+//! +--------------------------------------------------+
+//! ```
+//!
+//! Compatibility rules:
+//! - Snapshots with a different major format version are rejected, newer minor versions are
+//!   accepted.
+//! - A section the reader doesn't know, or whose version is newer than the reader supports, is
+//!   skipped if it's flagged optional and rejects the snapshot otherwise.
+//! - Fields may be appended to a section's payload without bumping its version. Readers ignore
+//!   trailing bytes they don't understand and treat trailing fields missing from snapshots of
+//!   older producers as absent.
+use alloc::vec::Vec;
+
+use super::{SnapshotReader, SnapshotWriter};
+use crate::{logging::LogContext, HyperError, HyperResult};
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"HCSNAPSH";
+/// Major version of the container format. Bumped on incompatible changes.
+pub const SNAPSHOT_FORMAT_MAJOR: u16 = 1;
+/// Minor version of the container format. Bumped on backward compatible changes.
+pub const SNAPSHOT_FORMAT_MINOR: u16 = 0;
+
+const FILE_HEADER_SIZE: usize = 16;
+const SECTION_HEADER_SIZE: usize = 16;
+
+/// The section may be skipped by readers that don't understand it.
+const SECTION_FLAG_OPTIONAL: u16 = 1 << 0;
+
+/// Identifies the contents of a section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionKind(pub u32);
+
+impl SectionKind {
+    /// Marks the end of the snapshot.
+    pub const END: Self = Self(0);
+}
+
+/// Header preceding every section of a snapshot.
+#[derive(Clone, Copy, Debug)]
+pub struct SectionHeader {
+    /// What the section contains.
+    pub kind: SectionKind,
+    /// Layout version of the section payload.
+    pub version: u16,
+    /// Section flags.
+    pub flags: u16,
+    /// Length of the payload in bytes.
+    pub length: u64,
+}
+
+impl SectionHeader {
+    /// Whether readers that don't understand this section may skip it.
+    pub fn is_optional(&self) -> bool {
+        self.flags & SECTION_FLAG_OPTIONAL != 0
+    }
+
+    fn to_bytes(self) -> [u8; SECTION_HEADER_SIZE] {
+        let mut buf = [0u8; SECTION_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&self.kind.0.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.length.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; SECTION_HEADER_SIZE]) -> Self {
+        Self {
+            kind: SectionKind(u32::from_le_bytes(buf[0..4].try_into().unwrap())),
+            version: u16::from_le_bytes(buf[4..6].try_into().unwrap()),
+            flags: u16::from_le_bytes(buf[6..8].try_into().unwrap()),
+            length: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// Streams a snapshot into a `SnapshotWriter`.
+pub struct SnapshotEncoder<'a, W: SnapshotWriter> {
+    writer: &'a mut W,
+    /// Payload bytes still owed to the current section.
+    pending: u64,
+}
+
+impl<'a, W: SnapshotWriter> SnapshotEncoder<'a, W> {
+    /// Starts a new snapshot by writing the file header.
+    pub fn new(writer: &'a mut W) -> HyperResult<Self> {
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        header[0..8].copy_from_slice(&SNAPSHOT_MAGIC);
+        header[8..10].copy_from_slice(&SNAPSHOT_FORMAT_MAJOR.to_le_bytes());
+        header[10..12].copy_from_slice(&SNAPSHOT_FORMAT_MINOR.to_le_bytes());
+        writer.write(&header)?;
+        Ok(Self { writer, pending: 0 })
+    }
+
+    /// Writes a whole section whose payload is `payload`.
+    pub fn write_section(
+        &mut self,
+        kind: SectionKind,
+        version: u16,
+        optional: bool,
+        payload: &[u8],
+    ) -> HyperResult<()> {
+        self.begin_section(kind, version, optional, payload.len() as u64)?;
+        self.write_payload(payload)
+    }
+
+    /// Starts a section of `length` payload bytes, which must then be supplied through
+    /// `write_payload`. Used to stream large payloads such as guest memory.
+    pub fn begin_section(
+        &mut self,
+        kind: SectionKind,
+        version: u16,
+        optional: bool,
+        length: u64,
+    ) -> HyperResult<()> {
+        if self.pending != 0 || kind == SectionKind::END {
+            return Err(HyperError::BadState);
+        }
+        let header = SectionHeader {
+            kind,
+            version,
+            flags: if optional { SECTION_FLAG_OPTIONAL } else { 0 },
+            length,
+        };
+        self.writer.write(&header.to_bytes())?;
+        self.pending = length;
+        Ok(())
+    }
+
+    /// Writes (part of) the payload of the current section.
+    pub fn write_payload(&mut self, buf: &[u8]) -> HyperResult<()> {
+        if buf.len() as u64 > self.pending {
+            return Err(HyperError::OutOfRange);
+        }
+        self.writer.write(buf)?;
+        self.pending -= buf.len() as u64;
+        Ok(())
+    }
+
+    /// Terminates the snapshot.
+    pub fn finish(self) -> HyperResult<()> {
+        if self.pending != 0 {
+            return Err(HyperError::BadState);
+        }
+        let end = SectionHeader {
+            kind: SectionKind::END,
+            version: 0,
+            flags: 0,
+            length: 0,
+        };
+        self.writer.write(&end.to_bytes())
+    }
+}
+
+/// Parses a snapshot from a `SnapshotReader`.
+pub struct SnapshotDecoder<'a, R: SnapshotReader> {
+    reader: &'a mut R,
+    minor: u16,
+    /// Unread payload bytes of the current section.
+    remaining: u64,
+    finished: bool,
+}
+
+impl<'a, R: SnapshotReader> SnapshotDecoder<'a, R> {
+    /// Reads and validates the file header.
+    pub fn new(reader: &'a mut R) -> HyperResult<Self> {
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        reader.read(&mut header)?;
+        if header[0..8] != SNAPSHOT_MAGIC {
+            return Err(HyperError::InvalidParam);
+        }
+        let major = u16::from_le_bytes([header[8], header[9]]);
+        let minor = u16::from_le_bytes([header[10], header[11]]);
+        if major != SNAPSHOT_FORMAT_MAJOR {
+            hv_log!(
+                Warn,
+                Snapshot,
+                LogContext::NONE,
+                "snapshot format {}.{} is not supported (expected {}.x)",
+                major,
+                minor,
+                SNAPSHOT_FORMAT_MAJOR
+            );
+            return Err(HyperError::NotSupported);
+        }
+        Ok(Self {
+            reader,
+            minor,
+            remaining: 0,
+            finished: false,
+        })
+    }
+
+    /// Minor format version of the snapshot being read.
+    pub fn format_minor(&self) -> u16 {
+        self.minor
+    }
+
+    /// Advances to the next section the caller understands. `max_version` returns the newest
+    /// version of a section kind the caller can parse, or `None` if the kind is unknown.
+    /// Unsupported optional sections are skipped; unsupported mandatory sections fail with
+    /// `HyperError::NotSupported`. Returns `None` once the end of the snapshot is reached.
+    ///
+    /// Any unread payload of the previous section is skipped.
+    pub fn next_section(
+        &mut self,
+        max_version: impl Fn(SectionKind) -> Option<u16>,
+    ) -> HyperResult<Option<SectionReader<'_, 'a, R>>> {
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+            self.reader.skip(self.remaining)?;
+            self.remaining = 0;
+
+            let mut buf = [0u8; SECTION_HEADER_SIZE];
+            self.reader.read(&mut buf)?;
+            let header = SectionHeader::from_bytes(&buf);
+            if header.kind == SectionKind::END {
+                self.finished = true;
+                return Ok(None);
+            }
+            self.remaining = header.length;
+
+            let supported = matches!(max_version(header.kind), Some(v) if header.version <= v);
+            if supported {
+                return Ok(Some(SectionReader {
+                    decoder: self,
+                    header,
+                }));
+            }
+            if !header.is_optional() {
+                hv_log!(
+                    Warn,
+                    Snapshot,
+                    LogContext::NONE,
+                    "unsupported mandatory snapshot section {:?} version {}",
+                    header.kind,
+                    header.version
+                );
+                return Err(HyperError::NotSupported);
+            }
+            hv_log!(
+                Debug,
+                Snapshot,
+                LogContext::NONE,
+                "skipping optional snapshot section {:?} version {}",
+                header.kind,
+                header.version
+            );
+        }
+    }
+}
+
+/// Reads the payload of one section. Reads never go past the end of the section.
+pub struct SectionReader<'d, 'a, R: SnapshotReader> {
+    decoder: &'d mut SnapshotDecoder<'a, R>,
+    header: SectionHeader,
+}
+
+impl<R: SnapshotReader> SectionReader<'_, '_, R> {
+    /// Header of this section.
+    pub fn header(&self) -> &SectionHeader {
+        &self.header
+    }
+
+    /// Number of payload bytes not read yet.
+    pub fn remaining(&self) -> u64 {
+        self.decoder.remaining
+    }
+
+    /// Fills `buf` from the payload.
+    pub fn read(&mut self, buf: &mut [u8]) -> HyperResult<()> {
+        if buf.len() as u64 > self.decoder.remaining {
+            return Err(HyperError::OutOfRange);
+        }
+        self.decoder.reader.read(buf)?;
+        self.decoder.remaining -= buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reads a little endian `u16`.
+    pub fn read_u16(&mut self) -> HyperResult<u16> {
+        let mut buf = [0u8; 2];
+        self.read(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Reads a little endian `u32`.
+    pub fn read_u32(&mut self) -> HyperResult<u32> {
+        let mut buf = [0u8; 4];
+        self.read(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads a little endian `u64`.
+    pub fn read_u64(&mut self) -> HyperResult<u64> {
+        let mut buf = [0u8; 8];
+        self.read(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads a `u64` field that older producers may not have written, returning `default` if the
+    /// payload ends before it.
+    pub fn read_u64_or(&mut self, default: u64) -> HyperResult<u64> {
+        if self.remaining() < 8 {
+            return Ok(default);
+        }
+        self.read_u64()
+    }
+}
+
+/// Builds the payload of a small section in memory.
+#[derive(Default)]
+pub struct SectionBuilder {
+    buf: Vec<u8>,
+}
+
+impl SectionBuilder {
+    /// Creates an empty payload.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends raw bytes.
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Appends a little endian `u16`.
+    pub fn put_u16(&mut self, val: u16) -> &mut Self {
+        self.put_bytes(&val.to_le_bytes())
+    }
+
+    /// Appends a little endian `u32`.
+    pub fn put_u32(&mut self, val: u32) -> &mut Self {
+        self.put_bytes(&val.to_le_bytes())
+    }
+
+    /// Appends a little endian `u64`.
+    pub fn put_u64(&mut self, val: u64) -> &mut Self {
+        self.put_bytes(&val.to_le_bytes())
+    }
+
+    /// The payload built so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU: SectionKind = SectionKind(0x100);
+    const DEVICE: SectionKind = SectionKind(0x101);
+
+    fn known(kind: SectionKind) -> Option<u16> {
+        match kind {
+            CPU => Some(2),
+            _ => None,
+        }
+    }
+
+    fn encode(sections: &[(SectionKind, u16, bool, &[u8])]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = SnapshotEncoder::new(&mut buf).unwrap();
+        for &(kind, version, optional, payload) in sections {
+            encoder
+                .write_section(kind, version, optional, payload)
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut payload = SectionBuilder::new();
+        payload
+            .put_u16(0x1234)
+            .put_u32(0x5678_9abc)
+            .put_u64(u64::MAX);
+        let mut buf = Vec::new();
+        let mut encoder = SnapshotEncoder::new(&mut buf).unwrap();
+        encoder
+            .write_section(CPU, 1, false, payload.as_bytes())
+            .unwrap();
+        encoder.begin_section(CPU, 2, false, 6).unwrap();
+        encoder.write_payload(b"str").unwrap();
+        encoder.write_payload(b"eam").unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = buf.as_slice();
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        assert_eq!(decoder.format_minor(), SNAPSHOT_FORMAT_MINOR);
+        let mut section = decoder.next_section(known).unwrap().unwrap();
+        assert_eq!(section.header().kind, CPU);
+        assert_eq!(section.header().version, 1);
+        assert_eq!(section.remaining(), 14);
+        assert_eq!(section.read_u16().unwrap(), 0x1234);
+        assert_eq!(section.read_u32().unwrap(), 0x5678_9abc);
+        assert_eq!(section.read_u64().unwrap(), u64::MAX);
+        assert_eq!(section.read_u64_or(7).unwrap(), 7);
+
+        let mut section = decoder.next_section(known).unwrap().unwrap();
+        let mut data = [0u8; 6];
+        section.read(&mut data).unwrap();
+        assert_eq!(&data, b"stream");
+        assert_eq!(section.read(&mut [0u8; 1]), Err(HyperError::OutOfRange));
+        assert!(decoder.next_section(known).unwrap().is_none());
+        assert!(decoder.next_section(known).unwrap().is_none());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn skips_unread_payload() {
+        let buf = encode(&[(CPU, 1, false, b"ignored"), (CPU, 1, false, b"\x2a\0")]);
+        let mut reader = buf.as_slice();
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        let mut section = decoder.next_section(known).unwrap().unwrap();
+        assert_eq!(section.read_u16().unwrap(), u16::from_le_bytes(*b"ig"));
+        let mut section = decoder.next_section(known).unwrap().unwrap();
+        assert_eq!(section.read_u16().unwrap(), 42);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut buf = encode(&[]);
+        buf[0] = b'X';
+        assert!(matches!(
+            SnapshotDecoder::new(&mut buf.as_slice()),
+            Err(HyperError::InvalidParam)
+        ));
+
+        let mut buf = encode(&[]);
+        buf[8..10].copy_from_slice(&(SNAPSHOT_FORMAT_MAJOR + 1).to_le_bytes());
+        assert!(matches!(
+            SnapshotDecoder::new(&mut buf.as_slice()),
+            Err(HyperError::NotSupported)
+        ));
+
+        let buf = encode(&[]);
+        assert!(matches!(
+            SnapshotDecoder::new(&mut &buf[..FILE_HEADER_SIZE - 1]),
+            Err(HyperError::OutOfRange)
+        ));
+    }
+
+    #[test]
+    fn accepts_newer_minor() {
+        let mut buf = encode(&[(CPU, 1, false, b"")]);
+        buf[10..12].copy_from_slice(&(SNAPSHOT_FORMAT_MINOR + 1).to_le_bytes());
+        let mut reader = buf.as_slice();
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        assert_eq!(decoder.format_minor(), SNAPSHOT_FORMAT_MINOR + 1);
+        assert!(decoder.next_section(known).unwrap().is_some());
+    }
+
+    #[test]
+    fn unsupported_sections() {
+        let buf = encode(&[
+            (DEVICE, 1, true, b"unknown"),
+            (CPU, 3, true, b"too new"),
+            (CPU, 2, false, b""),
+        ]);
+        let mut reader = buf.as_slice();
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        let section = decoder.next_section(known).unwrap().unwrap();
+        assert_eq!(section.header().version, 2);
+
+        for (kind, version) in [(DEVICE, 1), (CPU, 3)] {
+            let buf = encode(&[(kind, version, false, b"")]);
+            let mut reader = buf.as_slice();
+            let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+            assert!(matches!(
+                decoder.next_section(known),
+                Err(HyperError::NotSupported)
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_snapshot() {
+        let buf = encode(&[(CPU, 1, false, b"payload")]);
+        let mut reader = &buf[..buf.len() - SECTION_HEADER_SIZE - 1];
+        let mut decoder = SnapshotDecoder::new(&mut reader).unwrap();
+        assert!(decoder.next_section(known).unwrap().is_some());
+        assert!(matches!(
+            decoder.next_section(known),
+            Err(HyperError::OutOfRange)
+        ));
+    }
+
+    #[test]
+    fn encoder_misuse() {
+        let mut buf = Vec::new();
+        let mut encoder = SnapshotEncoder::new(&mut buf).unwrap();
+        assert_eq!(
+            encoder.begin_section(SectionKind::END, 0, false, 0),
+            Err(HyperError::BadState)
+        );
+        encoder.begin_section(CPU, 1, false, 2).unwrap();
+        assert_eq!(encoder.write_payload(b"abc"), Err(HyperError::OutOfRange));
+        assert_eq!(
+            encoder.begin_section(CPU, 1, false, 0),
+            Err(HyperError::BadState)
+        );
+        encoder.write_payload(b"a").unwrap();
+        assert_eq!(encoder.finish(), Err(HyperError::BadState));
+    }
+}
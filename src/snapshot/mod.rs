@@ -0,0 +1,64 @@
+//! VM snapshot support.
+//!
+//! Snapshots are written to and read from host-provided byte streams using a self-describing
+//! container format described in the `format` module. Periodic checkpoints can be taken as
+//! deltas against a previous snapshot, see the `delta` module.
+
+mod delta;
+mod format;
+
+pub use delta::{
+    read_memory_pages, restore_chain, write_memory_pages, ChainInfo, DirtyBitmap,
+    SNAPSHOT_PAGE_SIZE,
+};
+pub use format::{
+    SectionBuilder, SectionHeader, SectionKind, SectionReader, SnapshotDecoder, SnapshotEncoder,
+    SNAPSHOT_FORMAT_MAJOR, SNAPSHOT_FORMAT_MINOR,
+};
+
+use crate::HyperResult;
+
+/// A sink the snapshot is streamed into, implemented by the host (e.g. a file or a network
+/// connection).
+pub trait SnapshotWriter {
+    /// Writes all bytes of `buf`.
+    fn write(&mut self, buf: &[u8]) -> HyperResult<()>;
+}
+
+/// A source a snapshot is streamed from, implemented by the host.
+pub trait SnapshotReader {
+    /// Fills `buf` completely, or fails with `HyperError::OutOfRange` if the stream ends early.
+    fn read(&mut self, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Skips the next `len` bytes of the stream.
+    fn skip(&mut self, mut len: u64) -> HyperResult<()> {
+        let mut scratch = [0u8; 64];
+        while len > 0 {
+            let chunk = core::cmp::min(len, scratch.len() as u64) as usize;
+            self.read(&mut scratch[..chunk])?;
+            len -= chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SnapshotWriter for alloc::vec::Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> HyperResult<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SnapshotReader for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> HyperResult<()> {
+        if buf.len() > self.len() {
+            return Err(crate::HyperError::OutOfRange);
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
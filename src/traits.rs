@@ -1,5 +1,5 @@
 #[cfg(target_arch = "riscv64")]
-use crate::{GprIndex, VmExitInfo,};
+use crate::{DebugEvent, GprIndex, VmExitInfo,};
 
 use crate::arch::VCpu;
 use crate::{
@@ -25,6 +25,41 @@ pub trait VCpuTrait {
     fn vcpu_id(&self) -> usize;
 }
 
+#[cfg(target_arch = "riscv64")]
+/// Scheduling hooks through which an embedding OS time-shares vCPUs on a hart, see
+/// `VM::run_scheduled`.
+pub trait VcpuScheduler {
+    /// Length of the time slice the vCPU `vcpu_id` runs for before `on_timeslice_expired` is
+    /// called, in ticks of the `time` CSR.
+    fn timeslice(&mut self, vcpu_id: usize) -> u64;
+
+    /// Called when the vCPU `vcpu_id` has used up its time slice. Returns whether it keeps
+    /// running for another slice, otherwise `VM::run_scheduled` returns to let the caller switch
+    /// to another vCPU.
+    fn on_timeslice_expired(&mut self, vcpu_id: usize) -> bool;
+
+    /// Called when the vCPU `vcpu_id` waits for an interrupt. The scheduler may wait on the hart,
+    /// and returns whether the vCPU keeps running, as for `on_timeslice_expired`.
+    fn on_vcpu_blocked(&mut self, vcpu_id: usize) -> bool;
+
+    /// Called when the vCPU `vcpu_id` idles, waiting for an interrupt after WFI or an SBI HSM
+    /// suspend, with the `time` CSR value its next timer interrupt is due at, if any. The host
+    /// timer interrupt is armed for it, so the scheduler can park the hart, e.g. through
+    /// `HyperCraftHal::park_hart`, or run other work meanwhile. Returns whether the vCPU keeps
+    /// running, as for `on_timeslice_expired`. Defaults to `on_vcpu_blocked`.
+    fn on_vcpu_idle(&mut self, vcpu_id: usize, _wake_at: Option<u64>) -> bool {
+        self.on_vcpu_blocked(vcpu_id)
+    }
+
+    /// Called when the vCPU `vcpu_id`, in debug mode, hits a breakpoint or completes a single
+    /// step. Returns whether the vCPU keeps running, as for `on_timeslice_expired`. A vCPU resumed
+    /// at a breakpoint hits it again, so a debugger stops it and steps over the breakpoint with
+    /// `VM::single_step`. Stops the vCPU by default.
+    fn on_debug_event(&mut self, _vcpu_id: usize, _event: DebugEvent) -> bool {
+        false
+    }
+}
+
 /// Trait for PerCpu struct.
 pub trait PerCpuTrait<H: HyperCraftHal> {
     /// Initializes the `PerCpu` structures for each CPU. This (the boot CPU's) per-CPU
@@ -0,0 +1,7 @@
+//! Utilities shared by the hypervisor subsystems.
+
+mod rcu;
+mod sha256;
+
+pub use rcu::{RcuCell, RcuReadGuard};
+pub use sha256::{Sha256, SHA256_DIGEST_SIZE};
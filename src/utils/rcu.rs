@@ -0,0 +1,150 @@
+//! An RCU-like cell for read-mostly data.
+//!
+//! Readers never block and never write shared cache lines other than a per-epoch reader counter.
+//! Updates publish a new copy of the value and free the old one once every reader that might
+//! still see it has left its read-side critical section.
+use alloc::boxed::Box;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+/// A read-mostly value with wait-free reads and copy-on-write updates.
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+    /// Current epoch; only its parity is meaningful.
+    epoch: AtomicUsize,
+    /// Number of readers inside a critical section, per epoch parity.
+    readers: [AtomicUsize; 2],
+    /// Serializes updaters.
+    update_lock: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+unsafe impl<T: Send> Send for RcuCell<T> {}
+
+impl<T> RcuCell<T> {
+    /// Creates a cell holding `value`.
+    pub fn new(value: T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            update_lock: Mutex::new(()),
+        }
+    }
+
+    /// Enters a read-side critical section, which lasts as long as the returned guard. Updates
+    /// made meanwhile aren't visible through the guard. Guards should be short-lived as they
+    /// delay updaters.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        let parity = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let parity = epoch & 1;
+            self.readers[parity].fetch_add(1, Ordering::SeqCst);
+            // An updater may have flipped the epoch before seeing our count, retry in the new one.
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break parity;
+            }
+            self.readers[parity].fetch_sub(1, Ordering::SeqCst);
+        };
+        let value = unsafe { &*self.ptr.load(Ordering::SeqCst) };
+        RcuReadGuard {
+            cell: self,
+            parity,
+            value,
+        }
+    }
+
+    /// Replaces the value with `f(current value)`. Returns once no reader can access the old
+    /// value anymore, which is then dropped. Must not be called inside a read-side critical
+    /// section of the same cell.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) {
+        let _guard = self.update_lock.lock();
+        // Updaters are serialized, so the current value can't be freed under us.
+        let new = f(unsafe { &*self.ptr.load(Ordering::SeqCst) });
+        let old = self
+            .ptr
+            .swap(Box::into_raw(Box::new(new)), Ordering::SeqCst);
+
+        // New readers count in the other epoch and see the new value; wait for the ones that may
+        // have seen the old value.
+        let old_parity = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+        while self.readers[old_parity].load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+        drop(unsafe { Box::from_raw(old) });
+    }
+
+    /// Replaces the value with `value`, see `update`.
+    pub fn replace(&self, value: T) {
+        self.update(|_| value);
+    }
+}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+/// A read-side critical section of an `RcuCell`, giving access to its value.
+pub struct RcuReadGuard<'a, T> {
+    cell: &'a RcuCell<T>,
+    parity: usize,
+    value: &'a T,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.cell.readers[self.parity].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn update_and_replace() {
+        let cell = RcuCell::new(1);
+        cell.update(|val| val + 1);
+        assert_eq!(*cell.read(), 2);
+        cell.replace(5);
+        assert_eq!(*cell.read(), 5);
+    }
+
+    #[test]
+    fn update_waits_for_readers() {
+        let cell = Arc::new(RcuCell::new(vec![1]));
+        let guard = cell.read();
+        let updater = {
+            let cell = cell.clone();
+            thread::spawn(move || cell.replace(vec![2]))
+        };
+        thread::sleep(Duration::from_millis(50));
+        // The old value stays alive and unchanged while the guard exists.
+        assert!(!updater.is_finished());
+        assert_eq!(*guard, [1]);
+        drop(guard);
+        updater.join().unwrap();
+        assert_eq!(*cell.read(), [2]);
+    }
+}
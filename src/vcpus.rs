@@ -44,6 +44,11 @@ impl<H: HyperCraftHal> VmCpus<H> {
             .ok_or(HyperError::NotFound)?;
         Ok(vcpu)
     }
+
+    /// The vCPUs in the set, by increasing id.
+    pub fn iter(&self) -> impl Iterator<Item = &VCpu<H>> {
+        self.inner.iter().filter_map(Once::get)
+    }
 }
 
 // Safety: Each VCpu is wrapped with a Mutex to provide safe concurrent access to VCpu.
@@ -0,0 +1,134 @@
+//! virtio memory balloon device.
+//!
+//! The host sets a target number of pages through the `BalloonControl` of the device, and the
+//! driver inflates or deflates the balloon towards it. Page frames the guest puts into the balloon
+//! are collected for the VM, which unmaps them and gives them back to the host. Deflated pages
+//! need no handling, as they're backed again when the guest first touches them.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{read_chain, GuestMemory, VirtioDevice, Virtq};
+use crate::{HyperError, HyperResult};
+
+const VIRTIO_ID_BALLOON: u32 = 5;
+
+const INFLATEQ: usize = 0;
+
+/// Page frame numbers accepted per buffer.
+const MAX_PFNS: usize = 256;
+
+#[derive(Default)]
+struct BalloonState {
+    /// Pages the host wants the balloon to hold.
+    num_pages: u32,
+    /// Pages the driver reports the balloon holds.
+    actual: u32,
+    /// Whether `num_pages` changed since the driver was last interrupted.
+    config_changed: bool,
+    /// Page frames put into the balloon and not yet given back to the host.
+    inflated: Vec<u32>,
+}
+
+/// Host side of a balloon device.
+#[derive(Clone)]
+pub struct BalloonControl(Arc<Mutex<BalloonState>>);
+
+impl BalloonControl {
+    /// Asks the guest to make the balloon hold `num_pages` pages.
+    pub fn set_target(&self, num_pages: u32) {
+        let mut state = self.0.lock();
+        state.num_pages = num_pages;
+        state.config_changed = true;
+    }
+
+    /// Pages the guest reports the balloon holds.
+    pub fn actual(&self) -> u32 {
+        self.0.lock().actual
+    }
+
+    /// Takes the page frames put into the balloon since this was last called.
+    pub fn take_inflated(&self) -> Vec<u32> {
+        core::mem::take(&mut self.0.lock().inflated)
+    }
+}
+
+/// A memory balloon.
+pub struct VirtioBalloon {
+    state: Arc<Mutex<BalloonState>>,
+}
+
+impl VirtioBalloon {
+    /// Creates a device, with the control through which the host drives it.
+    pub fn new() -> (Self, BalloonControl) {
+        let state = Arc::new(Mutex::new(BalloonState::default()));
+        let device = Self {
+            state: state.clone(),
+        };
+        (device, BalloonControl(state))
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        let state = self.state.lock();
+        let config = [state.num_pages, state.actual];
+        config
+            .get(offset / 4)
+            .map_or(0, |val| val.to_le_bytes()[offset % 4])
+    }
+
+    fn write_config(&self, offset: usize, val: u8) {
+        // Only `actual` is writable.
+        if (4..8).contains(&offset) {
+            let mut state = self.state.lock();
+            let mut bytes = state.actual.to_le_bytes();
+            bytes[offset - 4] = val;
+            state.actual = u32::from_le_bytes(bytes);
+        }
+    }
+
+    fn update_config(&self, offset: usize, data: &[u8]) -> HyperResult<()> {
+        // Only `num_pages` is set by the host.
+        if offset + data.len() > 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut state = self.state.lock();
+        let mut bytes = state.num_pages.to_le_bytes();
+        bytes[offset..offset + data.len()].copy_from_slice(data);
+        state.num_pages = u32::from_le_bytes(bytes);
+        Ok(())
+    }
+
+    fn config_changed(&self) -> bool {
+        core::mem::take(&mut self.state.lock().config_changed)
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        while let Some((head, chain)) = queue.pop_avail(mem)? {
+            if index == INFLATEQ {
+                let pfns = read_chain(mem, &chain, MAX_PFNS * 4)?;
+                let pfns = pfns
+                    .chunks_exact(4)
+                    .map(|pfn| u32::from_le_bytes(pfn.try_into().unwrap()));
+                self.state.lock().inflated.extend(pfns);
+            }
+            queue.push_used(mem, head, 0)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! Driver for virtio-mmio block devices, for the host itself to use.
+//!
+//! When hypercraft runs nested under another hypervisor, e.g. in test setups chaining it with
+//! QEMU, the disks of the host are virtio devices of the outer hypervisor. `VirtioBlkDriver`
+//! drives such a device through the same transport registers and split virtqueue layout that
+//! `VirtioMmio` and `Virtq` emulate. It keeps a single small queue in host memory and makes one
+//! request at a time, polling for its completion, which is enough to load guest images.
+//! Data goes through a bounce page, so callers' buffers needn't be physically contiguous.
+use core::marker::PhantomData;
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+
+use super::queue::{DESC_SIZE, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use super::{
+    MAGIC_VALUE, VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_F_VERSION_1, VIRTIO_MMIO_CONFIG,
+    VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL, VIRTIO_MMIO_DEVICE_ID,
+    VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_INTERRUPT_ACK,
+    VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_DESC_HIGH,
+    VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_DEVICE_HIGH, VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+    VIRTIO_MMIO_QUEUE_DRIVER_HIGH, VIRTIO_MMIO_QUEUE_DRIVER_LOW, VIRTIO_MMIO_QUEUE_NOTIFY,
+    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
+    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VERSION,
+};
+use crate::memory::PAGE_SIZE_4K;
+use crate::{HostVirtAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// Device ID of block devices.
+const VIRTIO_ID_BLOCK: u32 = 2;
+/// Feature bit of devices that refuse writes.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Size of a sector, the unit of block addresses.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Entries of the queue: a request takes a header, a data and a status descriptor.
+const QUEUE_SIZE: u16 = 4;
+// Layout of the queue page. The used ring must be 4-byte aligned.
+const AVAIL_OFFSET: usize = QUEUE_SIZE as usize * DESC_SIZE;
+const USED_OFFSET: usize = 0x400;
+/// The request header, followed by the status byte.
+const HEADER_OFFSET: usize = 0x800;
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_OFFSET + HEADER_SIZE;
+
+/// A virtio-mmio block device driven by the host, see the module documentation.
+pub struct VirtioBlkDriver<H: HyperCraftHal> {
+    /// Where the device's registers are mapped for the host.
+    base: HostVirtAddr,
+    /// Page holding the queue, the request header and the status.
+    queue_page: HostVirtAddr,
+    /// Page the data is transferred through.
+    bounce_page: HostVirtAddr,
+    avail_idx: u16,
+    used_idx: u16,
+    /// Capacity in sectors.
+    capacity: u64,
+    read_only: bool,
+    marker: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> VirtioBlkDriver<H> {
+    /// Initializes the block device whose registers the host mapped at `base`. Fails with
+    /// `NotFound` if there's no modern virtio block device there, `NotSupported` if its queue is
+    /// too small, `NoMemory` if the queue can't be allocated, and `BadState` if the device
+    /// rejects the driver.
+    pub fn new(base: HostVirtAddr) -> HyperResult<Self> {
+        if read_reg(base, VIRTIO_MMIO_MAGIC_VALUE) != MAGIC_VALUE
+            || read_reg(base, VIRTIO_MMIO_VERSION) != 2
+            || read_reg(base, VIRTIO_MMIO_DEVICE_ID) != VIRTIO_ID_BLOCK
+        {
+            return Err(HyperError::NotFound);
+        }
+        // Dropping the driver from here on resets the device and frees the pages allocated.
+        let mut driver = Self {
+            base,
+            queue_page: 0,
+            bounce_page: 0,
+            avail_idx: 0,
+            used_idx: 0,
+            capacity: 0,
+            read_only: false,
+            marker: PhantomData,
+        };
+        driver.write_reg(VIRTIO_MMIO_STATUS, 0);
+        driver.write_reg(VIRTIO_MMIO_STATUS, VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        driver.write_reg(
+            VIRTIO_MMIO_STATUS,
+            VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER,
+        );
+
+        let mut device_features = 0;
+        for sel in 0..2 {
+            driver.write_reg(VIRTIO_MMIO_DEVICE_FEATURES_SEL, sel);
+            let bits = driver.read_reg(VIRTIO_MMIO_DEVICE_FEATURES) as u64;
+            device_features |= bits << (sel * 32);
+        }
+        if device_features & VIRTIO_F_VERSION_1 == 0 {
+            return Err(HyperError::NotSupported);
+        }
+        let features = device_features & (VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO);
+        for sel in 0..2 {
+            driver.write_reg(VIRTIO_MMIO_DRIVER_FEATURES_SEL, sel);
+            driver.write_reg(VIRTIO_MMIO_DRIVER_FEATURES, (features >> (sel * 32)) as u32);
+        }
+        let status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        driver.write_reg(VIRTIO_MMIO_STATUS, status | VIRTIO_CONFIG_S_FEATURES_OK);
+        if driver.read_reg(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+            return Err(HyperError::BadState);
+        }
+        driver.read_only = features & VIRTIO_BLK_F_RO != 0;
+        driver.capacity = driver.read_reg(VIRTIO_MMIO_CONFIG) as u64
+            | (driver.read_reg(VIRTIO_MMIO_CONFIG + 4) as u64) << 32;
+
+        driver.write_reg(VIRTIO_MMIO_QUEUE_SEL, 0);
+        if driver.read_reg(VIRTIO_MMIO_QUEUE_READY) != 0
+            || driver.read_reg(VIRTIO_MMIO_QUEUE_NUM_MAX) < QUEUE_SIZE as u32
+        {
+            return Err(HyperError::NotSupported);
+        }
+        driver.queue_page = H::alloc_page().ok_or(HyperError::NoMemory)?;
+        driver.bounce_page = H::alloc_page().ok_or(HyperError::NoMemory)?;
+        // Safety: the page was just allocated for the driver.
+        unsafe { core::ptr::write_bytes(driver.queue_page as *mut u8, 0, PAGE_SIZE_4K) };
+        let queue = H::virt_to_phys(driver.queue_page) as u64;
+        driver.write_reg(VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE as u32);
+        for (low, high, addr) in [
+            (
+                VIRTIO_MMIO_QUEUE_DESC_LOW,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                queue,
+            ),
+            (
+                VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+                VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+                queue + AVAIL_OFFSET as u64,
+            ),
+            (
+                VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+                VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+                queue + USED_OFFSET as u64,
+            ),
+        ] {
+            driver.write_reg(low, addr as u32);
+            driver.write_reg(high, (addr >> 32) as u32);
+        }
+        driver.write_reg(VIRTIO_MMIO_QUEUE_READY, 1);
+        driver.write_reg(
+            VIRTIO_MMIO_STATUS,
+            status | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK,
+        );
+        Ok(driver)
+    }
+
+    /// Capacity of the device in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Whether the device refuses writes.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Reads the sectors from `sector` on into `buf`, whose length must be a multiple of
+    /// `SECTOR_SIZE`. Fails with `InvalidParam` if it isn't, `OutOfRange` if the sectors are beyond
+    /// the device's capacity, and `Internal` if the device fails the request.
+    pub fn read_blocks(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult<()> {
+        self.check_range(sector, buf.len())?;
+        for (index, chunk) in buf.chunks_mut(PAGE_SIZE_4K).enumerate() {
+            let sector = sector + (index * PAGE_SIZE_4K / SECTOR_SIZE) as u64;
+            self.request(VIRTIO_BLK_T_IN, sector, chunk.len())?;
+            // Safety: the bounce page belongs to the driver and the device is done with it.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.bounce_page as *const u8,
+                    chunk.as_mut_ptr(),
+                    chunk.len(),
+                )
+            };
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the sectors from `sector` on, failing as `read_blocks` does, and with
+    /// `NotSupported` if the device is read-only.
+    pub fn write_blocks(&mut self, sector: u64, buf: &[u8]) -> HyperResult<()> {
+        if self.read_only {
+            return Err(HyperError::NotSupported);
+        }
+        self.check_range(sector, buf.len())?;
+        for (index, chunk) in buf.chunks(PAGE_SIZE_4K).enumerate() {
+            let sector = sector + (index * PAGE_SIZE_4K / SECTOR_SIZE) as u64;
+            // Safety: as in `read_blocks`.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    chunk.as_ptr(),
+                    self.bounce_page as *mut u8,
+                    chunk.len(),
+                )
+            };
+            self.request(VIRTIO_BLK_T_OUT, sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `len` bytes from `sector` on are whole sectors within the device.
+    fn check_range(&self, sector: u64, len: usize) -> HyperResult<()> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(HyperError::OutOfRange),
+        }
+    }
+
+    /// Makes a request of type `kind` for `len` bytes of the bounce page at `sector`, and waits
+    /// for the device to complete it.
+    fn request(&mut self, kind: u32, sector: u64, len: usize) -> HyperResult<()> {
+        let queue = self.queue_page;
+        let queue_pa = H::virt_to_phys(queue) as u64;
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        let data_flags = match kind {
+            VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            _ => VIRTQ_DESC_F_NEXT,
+        };
+        let descs = [
+            (
+                queue_pa + HEADER_OFFSET as u64,
+                HEADER_SIZE,
+                VIRTQ_DESC_F_NEXT,
+            ),
+            (H::virt_to_phys(self.bounce_page) as u64, len, data_flags),
+            (queue_pa + STATUS_OFFSET as u64, 1, VIRTQ_DESC_F_WRITE),
+        ];
+        // Safety: the queue page belongs to the driver, and the device only accesses it while a
+        // request is in flight.
+        unsafe {
+            let page = queue as *mut u8;
+            page.add(HEADER_OFFSET)
+                .copy_from_nonoverlapping(header.as_ptr(), HEADER_SIZE);
+            page.add(STATUS_OFFSET).write_volatile(0xff);
+            for (index, &(addr, len, flags)) in descs.iter().enumerate() {
+                let mut desc = [0u8; DESC_SIZE];
+                desc[0..8].copy_from_slice(&addr.to_le_bytes());
+                desc[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+                desc[12..14].copy_from_slice(&flags.to_le_bytes());
+                desc[14..16].copy_from_slice(&(index as u16 + 1).to_le_bytes());
+                page.add(index * DESC_SIZE)
+                    .copy_from_nonoverlapping(desc.as_ptr(), DESC_SIZE);
+            }
+            let slot = AVAIL_OFFSET + 4 + (self.avail_idx % QUEUE_SIZE) as usize * 2;
+            page.add(slot).cast::<u16>().write_volatile(0u16.to_le());
+        }
+        // Make the chain visible before the index that makes it available.
+        fence(Ordering::Release);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.ring_u16(AVAIL_OFFSET + 2)
+            .store(self.avail_idx.to_le(), Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        self.write_reg(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+        while u16::from_le(self.ring_u16(USED_OFFSET + 2).load(Ordering::Relaxed)) == self.used_idx
+        {
+            core::hint::spin_loop();
+        }
+        // Read the status only after seeing the index that covers it.
+        fence(Ordering::Acquire);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let interrupts = self.read_reg(VIRTIO_MMIO_INTERRUPT_STATUS);
+        self.write_reg(VIRTIO_MMIO_INTERRUPT_ACK, interrupts);
+        // Safety: as above.
+        let status = unsafe { ((queue + STATUS_OFFSET) as *const u8).read_volatile() };
+        match status {
+            VIRTIO_BLK_S_OK => Ok(()),
+            _ => Err(HyperError::Internal),
+        }
+    }
+
+    /// The 16-bit ring field at `offset` in the queue page.
+    fn ring_u16(&self, offset: usize) -> &AtomicU16 {
+        // Safety: the field is aligned and within the queue page, which the driver owns.
+        unsafe { &*((self.queue_page + offset) as *const AtomicU16) }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        read_reg(self.base, offset)
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        // Safety: the caller of `new` mapped the device's registers at `base`.
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+}
+
+/// Reads the register at `offset` of the device whose registers are mapped at `base`.
+fn read_reg(base: HostVirtAddr, offset: usize) -> u32 {
+    // Safety: the caller of `VirtioBlkDriver::new` mapped the device's registers at `base`.
+    unsafe { ((base + offset) as *const u32).read_volatile() }
+}
+
+impl<H: HyperCraftHal> Drop for VirtioBlkDriver<H> {
+    fn drop(&mut self) {
+        // Stop the device from accessing the pages before freeing them.
+        self.write_reg(VIRTIO_MMIO_STATUS, 0);
+        for page in [self.queue_page, self.bounce_page] {
+            if page != 0 {
+                H::dealloc_page(page);
+            }
+        }
+    }
+}
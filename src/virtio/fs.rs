@@ -0,0 +1,437 @@
+//! virtio 9P shared filesystem device.
+//!
+//! Serves a host-provided directory tree to the guest over 9P2000.L, which Linux mounts with
+//! `mount -t 9p -o trans=virtio <tag> <dir>`. Files are looked up, read and written, and
+//! directories listed, through the host's `FsBackend`. Creating, removing and renaming files isn't
+//! supported.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{read_chain, write_chain, GuestMemory, VirtioDevice, Virtq};
+use crate::{HyperError, HyperResult};
+
+const VIRTIO_ID_9P: u32 = 9;
+
+/// The configuration space holds the mount tag.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// Largest message exchanged with the guest.
+const MAX_MSIZE: u32 = 64 * 1024;
+/// Size of the header of a message: size, type and tag.
+const HEADER_SIZE: usize = 7;
+/// Size of the header of an `Rread`, which precedes the data.
+const RREAD_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+
+const P9_RLERROR: u8 = 7;
+const P9_TLOPEN: u8 = 12;
+const P9_TGETATTR: u8 = 24;
+const P9_TREADDIR: u8 = 40;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_TCLUNK: u8 = 120;
+
+/// Size of a qid: type, version and path.
+const QID_SIZE: usize = 13;
+const P9_QTDIR: u8 = 0x80;
+const P9_QTSYMLINK: u8 = 0x02;
+const P9_QTFILE: u8 = 0x00;
+
+/// `Rgetattr` valid mask for the basic fields: mode, nlink, uid, gid, rdev, times, ino, size and
+/// blocks.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EINVAL: u32 = 22;
+const ENOTDIR: u32 = 20;
+const EOPNOTSUPP: u32 = 95;
+
+/// Type of a file served by an `FsBackend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsFileType {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// A symbolic link.
+    Symlink,
+}
+
+/// Attributes of a file served by an `FsBackend`.
+#[derive(Clone, Copy, Debug)]
+pub struct FsAttr {
+    /// Type of the file.
+    pub file_type: FsFileType,
+    /// Permission bits, e.g. 0o644.
+    pub perm: u32,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Number of hard links to the file.
+    pub nlink: u64,
+    /// Last modification time in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// An entry of a directory served by an `FsBackend`.
+#[derive(Clone, Debug)]
+pub struct FsDirEntry {
+    /// Inode number of the file.
+    pub ino: u64,
+    /// Type of the file.
+    pub file_type: FsFileType,
+    /// Name of the file within the directory.
+    pub name: String,
+}
+
+/// A directory tree shared with guests, implemented by the host. Files are identified by inode
+/// numbers, which must be unique and stable.
+pub trait FsBackend: Send {
+    /// Inode number of the root directory.
+    fn root(&self) -> u64;
+
+    /// Looks up `name` in the directory `dir`. Fails with `NotFound` if there's no such entry.
+    fn lookup(&mut self, dir: u64, name: &str) -> HyperResult<u64>;
+
+    /// Returns the attributes of the file `ino`.
+    fn getattr(&mut self, ino: u64) -> HyperResult<FsAttr>;
+
+    /// Reads the file `ino` at `offset` into `buf`. Returns the number of bytes read, 0 at the end
+    /// of the file.
+    fn read(&mut self, ino: u64, offset: u64, buf: &mut [u8]) -> HyperResult<usize>;
+
+    /// Writes `buf` to the file `ino` at `offset`. Returns the number of bytes written. Files are
+    /// read-only by default.
+    fn write(&mut self, _ino: u64, _offset: u64, _buf: &[u8]) -> HyperResult<usize> {
+        Err(HyperError::NotSupported)
+    }
+
+    /// Returns entry `index` of the directory `dir`, or `None` past its last entry.
+    fn readdir(&mut self, dir: u64, index: u64) -> HyperResult<Option<FsDirEntry>>;
+}
+
+/// A 9P filesystem device exporting an `FsBackend` under a mount tag.
+pub struct Virtio9p {
+    tag: String,
+    session: Mutex<Session>,
+}
+
+/// State of a `Virtio9p`, locked while it serves requests.
+struct Session {
+    backend: Box<dyn FsBackend>,
+    msize: u32,
+    /// Files the guest refers to, by fid.
+    fids: BTreeMap<u32, u64>,
+}
+
+impl Virtio9p {
+    pub fn new(tag: &str, backend: Box<dyn FsBackend>) -> HyperResult<Self> {
+        if tag.is_empty() || tag.len() > u16::MAX as usize {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            tag: String::from(tag),
+            session: Mutex::new(Session {
+                backend,
+                msize: MAX_MSIZE,
+                fids: BTreeMap::new(),
+            }),
+        })
+    }
+}
+
+impl Session {
+    /// Handles the request `req`, writing the response to `resp`.
+    fn handle(&mut self, req: &[u8], resp: &mut Vec<u8>) {
+        let mut msg = Reader(req);
+        let (Some(_size), Some(msg_type), Some(tag)) = (msg.u32(), msg.u8(), msg.u16()) else {
+            return;
+        };
+        resp.extend_from_slice(&[0; 4]);
+        resp.push(msg_type.wrapping_add(1));
+        resp.extend_from_slice(&tag.to_le_bytes());
+        if let Err(errno) = self.dispatch(msg_type, &mut msg, resp) {
+            resp.truncate(HEADER_SIZE);
+            resp[4] = P9_RLERROR;
+            resp.extend_from_slice(&errno.to_le_bytes());
+        }
+        let size = resp.len() as u32;
+        resp[0..4].copy_from_slice(&size.to_le_bytes());
+    }
+
+    fn dispatch(&mut self, msg_type: u8, msg: &mut Reader, resp: &mut Vec<u8>) -> Result<(), u32> {
+        match msg_type {
+            P9_TVERSION => {
+                let msize = msg.u32().ok_or(EINVAL)?;
+                let version = msg.str().ok_or(EINVAL)?;
+                self.msize = msize.clamp(RREAD_HEADER_SIZE + 1, MAX_MSIZE);
+                // A new session starts, dropping all fids.
+                self.fids.clear();
+                resp.extend_from_slice(&self.msize.to_le_bytes());
+                let version = if version == "9P2000.L" {
+                    version
+                } else {
+                    "unknown"
+                };
+                put_str(resp, version);
+            }
+            P9_TATTACH => {
+                let fid = msg.u32().ok_or(EINVAL)?;
+                let root = self.backend.root();
+                let attr = self.backend.getattr(root).map_err(errno)?;
+                self.fids.insert(fid, root);
+                put_qid(resp, root, &attr);
+            }
+            P9_TWALK => {
+                let fid = msg.u32().ok_or(EINVAL)?;
+                let newfid = msg.u32().ok_or(EINVAL)?;
+                let nwname = msg.u16().ok_or(EINVAL)?;
+                let mut ino = self.fid(fid)?;
+                let mut qids = Vec::new();
+                for i in 0..nwname {
+                    let name = msg.str().ok_or(EINVAL)?;
+                    let next = match self.backend.lookup(ino, name) {
+                        Ok(next) => next,
+                        // Only the first element failing to resolve is an error.
+                        Err(err) if i == 0 => return Err(errno(err)),
+                        Err(_) => break,
+                    };
+                    let attr = self.backend.getattr(next).map_err(errno)?;
+                    put_qid(&mut qids, next, &attr);
+                    ino = next;
+                }
+                let nwqid = qids.len() / QID_SIZE;
+                if nwqid == nwname as usize {
+                    self.fids.insert(newfid, ino);
+                }
+                resp.extend_from_slice(&(nwqid as u16).to_le_bytes());
+                resp.extend_from_slice(&qids);
+            }
+            P9_TGETATTR => {
+                let ino = self.fid(msg.u32().ok_or(EINVAL)?)?;
+                let attr = self.backend.getattr(ino).map_err(errno)?;
+                let mode = attr.perm & 0o7777
+                    | match attr.file_type {
+                        FsFileType::File => S_IFREG,
+                        FsFileType::Dir => S_IFDIR,
+                        FsFileType::Symlink => S_IFLNK,
+                    };
+                let (sec, nsec) = (attr.mtime / 1_000_000_000, attr.mtime % 1_000_000_000);
+                resp.extend_from_slice(&P9_GETATTR_BASIC.to_le_bytes());
+                put_qid(resp, ino, &attr);
+                resp.extend_from_slice(&mode.to_le_bytes());
+                // uid and gid.
+                resp.extend_from_slice(&[0; 8]);
+                for val in [
+                    attr.nlink,
+                    0, // rdev
+                    attr.size,
+                    4096, // blksize
+                    (attr.size + 511) / 512,
+                    sec, // atime
+                    nsec,
+                    sec, // mtime
+                    nsec,
+                    sec, // ctime
+                    nsec,
+                    0, // btime
+                    0,
+                    0, // gen
+                    0, // data_version
+                ] {
+                    resp.extend_from_slice(&val.to_le_bytes());
+                }
+            }
+            P9_TLOPEN => {
+                let ino = self.fid(msg.u32().ok_or(EINVAL)?)?;
+                let attr = self.backend.getattr(ino).map_err(errno)?;
+                put_qid(resp, ino, &attr);
+                resp.extend_from_slice(&(self.msize - RREAD_HEADER_SIZE).to_le_bytes());
+            }
+            P9_TREAD => {
+                let ino = self.fid(msg.u32().ok_or(EINVAL)?)?;
+                let offset = msg.u64().ok_or(EINVAL)?;
+                let count =
+                    core::cmp::min(msg.u32().ok_or(EINVAL)?, self.msize - RREAD_HEADER_SIZE);
+                let start = resp.len() + 4;
+                resp.resize(start + count as usize, 0);
+                let len = self
+                    .backend
+                    .read(ino, offset, &mut resp[start..])
+                    .map_err(errno)?;
+                let len = core::cmp::min(len, count as usize);
+                resp.truncate(start + len);
+                resp[start - 4..start].copy_from_slice(&(len as u32).to_le_bytes());
+            }
+            P9_TWRITE => {
+                let ino = self.fid(msg.u32().ok_or(EINVAL)?)?;
+                let offset = msg.u64().ok_or(EINVAL)?;
+                let count = msg.u32().ok_or(EINVAL)?;
+                let data = msg.bytes(count as usize).ok_or(EINVAL)?;
+                let len = self.backend.write(ino, offset, data).map_err(errno)?;
+                resp.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+            P9_TREADDIR => {
+                let ino = self.fid(msg.u32().ok_or(EINVAL)?)?;
+                let offset = msg.u64().ok_or(EINVAL)?;
+                let count =
+                    core::cmp::min(msg.u32().ok_or(EINVAL)?, self.msize - RREAD_HEADER_SIZE);
+                if self.backend.getattr(ino).map_err(errno)?.file_type != FsFileType::Dir {
+                    return Err(ENOTDIR);
+                }
+                let mut entries = Vec::new();
+                let mut index = offset;
+                while let Some(entry) = self.backend.readdir(ino, index).map_err(errno)? {
+                    // qid, offset, type and name.
+                    if entries.len() + QID_SIZE + 8 + 1 + 2 + entry.name.len() > count as usize {
+                        break;
+                    }
+                    let qid_type = qid_type(entry.file_type);
+                    entries.push(qid_type);
+                    entries.extend_from_slice(&0u32.to_le_bytes());
+                    entries.extend_from_slice(&entry.ino.to_le_bytes());
+                    index += 1;
+                    // The offset of the next entry.
+                    entries.extend_from_slice(&index.to_le_bytes());
+                    entries.push(dirent_type(entry.file_type));
+                    put_str(&mut entries, &entry.name);
+                }
+                resp.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                resp.extend_from_slice(&entries);
+            }
+            P9_TCLUNK => {
+                let fid = msg.u32().ok_or(EINVAL)?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(())
+    }
+
+    fn fid(&self, fid: u32) -> Result<u64, u32> {
+        self.fids.get(&fid).copied().ok_or(EBADF)
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        let tag_len = (self.tag.len() as u16).to_le_bytes();
+        match offset {
+            0 | 1 => tag_len[offset],
+            _ => self.tag.as_bytes().get(offset - 2).copied().unwrap_or(0),
+        }
+    }
+
+    fn process_queue(
+        &self,
+        _index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        let mut session = self.session.lock();
+        while let Some((head, chain)) = queue.pop_avail(mem)? {
+            let req = read_chain(mem, &chain, session.msize as usize)?;
+            let mut resp = Vec::new();
+            session.handle(&req, &mut resp);
+            let written = write_chain(mem, &chain, &resp)?;
+            queue.push_used(mem, head, written)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the fields of a 9P message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).ok()
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_qid(buf: &mut Vec<u8>, ino: u64, attr: &FsAttr) {
+    buf.push(qid_type(attr.file_type));
+    // The version changes with the file's contents.
+    buf.extend_from_slice(&(attr.mtime as u32).to_le_bytes());
+    buf.extend_from_slice(&ino.to_le_bytes());
+}
+
+fn qid_type(file_type: FsFileType) -> u8 {
+    match file_type {
+        FsFileType::File => P9_QTFILE,
+        FsFileType::Dir => P9_QTDIR,
+        FsFileType::Symlink => P9_QTSYMLINK,
+    }
+}
+
+/// The `d_type` of a directory entry.
+fn dirent_type(file_type: FsFileType) -> u8 {
+    match file_type {
+        FsFileType::File => 8,
+        FsFileType::Dir => 4,
+        FsFileType::Symlink => 10,
+    }
+}
+
+fn errno(err: HyperError) -> u32 {
+    match err {
+        HyperError::NotFound => ENOENT,
+        HyperError::NotSupported => EOPNOTSUPP,
+        HyperError::InvalidParam => EINVAL,
+        _ => EIO,
+    }
+}
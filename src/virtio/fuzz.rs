@@ -0,0 +1,179 @@
+//! Entry points driving the guest-controlled parsing of virtio devices from arbitrary input, for
+//! fuzzing: the rings and descriptor chains of a virtqueue, and the register state machine of the
+//! virtio-mmio transport.
+//!
+//! Runs are deterministic and bounded: guest RAM is a fixed-size buffer the input is loaded into,
+//! and only so many chains are taken or registers accessed per input. A panic, or an access
+//! outside of the buffer, is a bug in the parsing rather than in the harness.
+//!
+//! The virtio emulation doesn't depend on the architecture, so the entry points build for the host
+//! too and can be driven by a host fuzzer such as cargo-fuzz.
+use alloc::boxed::Box;
+use alloc::vec;
+
+use super::balloon::VirtioBalloon;
+use super::QUEUE_SIZE_MAX;
+use super::{read_chain, write_chain, GuestMemory, VirtioMmio, Virtq, VIRTIO_MMIO_SIZE};
+use crate::{GuestPhysAddr, HostVirtAddr, HyperError, HyperResult};
+
+/// Size of the guest RAM the input is loaded into, at guest physical address 0.
+pub const FUZZ_RAM_SIZE: usize = 0x10000;
+/// Most chains taken from the queue, or registers accessed, per input.
+const MAX_STEPS: usize = 256;
+/// Most bytes read from a chain.
+const MAX_CHAIN_DATA: usize = 0x1000;
+
+/// Guest RAM backed by a host buffer, which the device may access directly like RAM mapped in
+/// the VM if `mapped`.
+struct FuzzMemory {
+    ram: *mut u8,
+    mapped: bool,
+}
+
+impl FuzzMemory {
+    /// RAM holding `data` from its start, zeros past it.
+    fn new(data: &[u8], mapped: bool) -> Self {
+        let mut ram = vec![0u8; FUZZ_RAM_SIZE].into_boxed_slice();
+        let len = data.len().min(FUZZ_RAM_SIZE);
+        ram[..len].copy_from_slice(&data[..len]);
+        Self {
+            ram: Box::into_raw(ram) as *mut u8,
+            mapped,
+        }
+    }
+
+    /// The host address of `[gpa, gpa + len)`, if it's in the buffer.
+    fn translate(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<*mut u8> {
+        match gpa.checked_add(len) {
+            Some(end) if end <= FUZZ_RAM_SIZE => Ok(self.ram.wrapping_add(gpa)),
+            _ => Err(HyperError::PageFault),
+        }
+    }
+}
+
+impl GuestMemory for FuzzMemory {
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        let src = self.translate(gpa, buf.len())?;
+        unsafe { core::ptr::copy(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+        let dst = self.translate(gpa, buf.len())?;
+        unsafe { core::ptr::copy(buf.as_ptr(), dst, buf.len()) };
+        Ok(())
+    }
+
+    fn host_addr(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<HostVirtAddr> {
+        if !self.mapped {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(self.translate(gpa, len)? as HostVirtAddr)
+    }
+}
+
+impl Drop for FuzzMemory {
+    fn drop(&mut self) {
+        let ram = core::ptr::slice_from_raw_parts_mut(self.ram, FUZZ_RAM_SIZE);
+        drop(unsafe { Box::from_raw(ram) });
+    }
+}
+
+/// The input, read front to back as little-endian values, and as zeros once exhausted.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        let len = N.min(self.0.len());
+        bytes[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+}
+
+/// Serves the virtqueue described by `input` like a device would, taking each chain the driver
+/// made available, reading its buffers, writing them back and returning it, until the driver has
+/// none left or breaks the protocol.
+///
+/// The input starts with a byte whose low 4 bits are the log2 of the queue size, bit 4 negotiates
+/// `VIRTIO_F_EVENT_IDX`, bit 5 polls the queue and bit 6 lets the device access the rings
+/// directly. Then come the guest physical addresses of the descriptor table, the avail ring and
+/// the used ring, 16 bits each. The rest is loaded into guest RAM.
+pub fn fuzz_process_queue(input: &[u8]) {
+    let mut input = Input(input);
+    let flags = input.u8();
+    let size = 1u16 << (flags & 0xf);
+    let desc_addr = input.u16() as GuestPhysAddr;
+    let avail_addr = input.u16() as GuestPhysAddr;
+    let used_addr = input.u16() as GuestPhysAddr;
+    let mem = FuzzMemory::new(input.0, flags & 1 << 6 != 0);
+
+    let mut queue = Virtq::new(QUEUE_SIZE_MAX);
+    if queue.set_size(size).is_err() {
+        return;
+    }
+    queue.desc_addr = desc_addr;
+    queue.avail_addr = avail_addr;
+    queue.used_addr = used_addr;
+    queue.event_idx = flags & 1 << 4 != 0;
+    if queue.set_polled(flags & 1 << 5 != 0, &mem).is_err() {
+        return;
+    }
+    queue.set_ready(true, &mem);
+    for _ in 0..MAX_STEPS {
+        let Ok(Some((head, chain))) = queue.pop_avail(&mem) else {
+            return;
+        };
+        let data = read_chain(&mem, &chain, MAX_CHAIN_DATA).unwrap_or_default();
+        let len = write_chain(&mem, &chain, &data).unwrap_or(0);
+        if queue.push_used(&mem, head, len).is_err() || queue.publish_used(&mem).is_err() {
+            return;
+        }
+    }
+}
+
+/// Drives a virtio-mmio transport, with a balloon device behind it, through the register accesses
+/// encoded in `input`, as a driver would.
+///
+/// The input starts with a byte giving the number of accesses, each 12 bytes: a byte whose bit 0
+/// makes it a write and bit 1 polls the device after it, a 16-bit register offset, a byte whose
+/// low 2 bits are the log2 of the width, and the 64-bit value written. The rest is loaded into
+/// guest RAM, which the device accesses directly.
+pub fn fuzz_mmio_access(input: &[u8]) {
+    let mut input = Input(input);
+    let count = (input.u8() as usize).min(MAX_STEPS);
+    let accesses = &input.0[..(count * 12).min(input.0.len())];
+    let mem = FuzzMemory::new(&input.0[accesses.len()..], true);
+    let mut accesses = Input(accesses);
+
+    let (balloon, _control) = VirtioBalloon::new();
+    let dev = VirtioMmio::new(0, Box::new(balloon));
+    dev.set_irq_moderation(4, 8);
+    for now in 0..count as u64 {
+        let op = accesses.u8();
+        let offset = accesses.u16() as usize % VIRTIO_MMIO_SIZE;
+        let width = 1 << (accesses.u8() & 0x3);
+        let val = accesses.u64();
+        if op & 1 != 0 {
+            dev.write(offset, width, val, &mem);
+        } else {
+            dev.read(offset, width);
+        }
+        if op & 2 != 0 {
+            dev.poll(&mem, now);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! virtio-gpu 2D device.
+//!
+//! Emulates a single scanout of a fixed size without 3D acceleration. The guest renders into 2D
+//! resources backed by its own memory, transfers them to host-side copies and flushes the one set
+//! as the scanout, which is converted to `0x00RRGGBB` pixels and handed to the host's display
+//! callback. Cursor commands are accepted but ignored.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{read_chain, write_chain, GuestMemory, VirtioDevice, Virtq};
+use crate::{GuestPhysAddr, HyperResult};
+
+const VIRTIO_ID_GPU: u32 = 16;
+
+const CONTROLQ: usize = 0;
+const CURSORQ: usize = 1;
+
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+/// Size of the header of every command and response.
+const CTRL_HDR_SIZE: usize = 24;
+/// Largest command accepted, bounding the backing pages attached at once.
+const MAX_CMD_SIZE: usize = 64 * 1024;
+/// Largest resource the guest may create.
+const MAX_RESOURCE_SIZE: usize = 64 * 1024 * 1024;
+/// Bytes per pixel of all supported formats.
+const BPP: usize = 4;
+
+/// Byte order of the pixels of a resource.
+#[derive(Clone, Copy)]
+enum PixelOrder {
+    Bgrx,
+    Xrgb,
+    Rgbx,
+    Xbgr,
+}
+
+impl PixelOrder {
+    fn from_format(format: u32) -> Option<Self> {
+        match format {
+            // B8G8R8A8 and B8G8R8X8.
+            1 | 2 => Some(Self::Bgrx),
+            // A8R8G8B8 and X8R8G8B8.
+            3 | 4 => Some(Self::Xrgb),
+            // R8G8B8A8 and R8G8B8X8.
+            67 | 134 => Some(Self::Rgbx),
+            // X8B8G8R8 and A8B8G8R8.
+            68 | 121 => Some(Self::Xbgr),
+            _ => None,
+        }
+    }
+
+    fn to_rgb(self, p: &[u8]) -> u32 {
+        let (r, g, b) = match self {
+            Self::Bgrx => (p[2], p[1], p[0]),
+            Self::Xrgb => (p[1], p[2], p[3]),
+            Self::Rgbx => (p[0], p[1], p[2]),
+            Self::Xbgr => (p[3], p[2], p[1]),
+        };
+        (r as u32) << 16 | (g as u32) << 8 | b as u32
+    }
+}
+
+/// A rectangle of a resource or scanout.
+#[derive(Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    /// Whether the rectangle lies within a `width` x `height` area.
+    fn within(&self, width: u32, height: u32) -> bool {
+        self.x
+            .checked_add(self.width)
+            .is_some_and(|end| end <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|end| end <= height)
+    }
+}
+
+/// A 2D resource: its host-side copy and the guest memory backing it.
+struct Resource {
+    order: PixelOrder,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+    backing: Vec<(GuestPhysAddr, usize)>,
+}
+
+/// Called with the pixels of the scanout, `stride` per row, after the guest updated `rect`:
+/// `(x, y, width, height)`.
+pub type DisplayUpdate = fn(pixels: &[u32], stride: usize, rect: (u32, u32, u32, u32));
+
+/// A 2D GPU with one scanout.
+pub struct VirtioGpu(Mutex<Gpu>);
+
+/// State of a `VirtioGpu`, locked while it executes a control command.
+struct Gpu {
+    width: u32,
+    height: u32,
+    display_update: DisplayUpdate,
+    resources: BTreeMap<u32, Resource>,
+    /// Resource shown on the scanout, 0 if it's disabled.
+    scanout: u32,
+    framebuffer: Vec<u32>,
+}
+
+impl VirtioGpu {
+    /// Creates a GPU with a `width` x `height` scanout, whose updates go to `display_update`.
+    pub fn new(width: u32, height: u32, display_update: DisplayUpdate) -> Self {
+        Self(Mutex::new(Gpu {
+            width,
+            height,
+            display_update,
+            resources: BTreeMap::new(),
+            scanout: 0,
+            framebuffer: vec![0; width as usize * height as usize],
+        }))
+    }
+}
+
+impl Gpu {
+    /// Executes the control command `cmd`, writing the response to `resp`.
+    fn handle(&mut self, cmd: &[u8], mem: &dyn GuestMemory, resp: &mut Vec<u8>) {
+        if cmd.len() < CTRL_HDR_SIZE {
+            return;
+        }
+        let flags = le32(cmd, 4);
+        resp.extend_from_slice(&[0; CTRL_HDR_SIZE]);
+        // Commands complete synchronously, so fences are signalled right away.
+        if flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+            resp[4..24].copy_from_slice(&cmd[4..24]);
+        }
+        let resp_type = match self.execute(le32(cmd, 0), &cmd[CTRL_HDR_SIZE..], mem, resp) {
+            Ok(resp_type) => resp_type,
+            Err(resp_type) => {
+                resp.truncate(CTRL_HDR_SIZE);
+                resp_type
+            }
+        };
+        resp[0..4].copy_from_slice(&resp_type.to_le_bytes());
+    }
+
+    fn execute(
+        &mut self,
+        cmd_type: u32,
+        body: &[u8],
+        mem: &dyn GuestMemory,
+        resp: &mut Vec<u8>,
+    ) -> Result<u32, u32> {
+        let arg = |index: usize| -> Result<u32, u32> {
+            body.get(index * 4..index * 4 + 4)
+                .map(|_| le32(body, index * 4))
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)
+        };
+        let rect = || -> Result<Rect, u32> {
+            Ok(Rect {
+                x: arg(0)?,
+                y: arg(1)?,
+                width: arg(2)?,
+                height: arg(3)?,
+            })
+        };
+        match cmd_type {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                for scanout in 0..VIRTIO_GPU_MAX_SCANOUTS {
+                    let mode = if scanout == 0 {
+                        [0, 0, self.width, self.height, 1, 0]
+                    } else {
+                        [0; 6]
+                    };
+                    mode.iter()
+                        .for_each(|val| resp.extend_from_slice(&val.to_le_bytes()));
+                }
+                return Ok(VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                let (id, format, width, height) = (arg(0)?, arg(1)?, arg(2)?, arg(3)?);
+                let order =
+                    PixelOrder::from_format(format).ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+                if id == 0 || self.resources.contains_key(&id) {
+                    return Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+                }
+                let size = (width as usize)
+                    .checked_mul(height as usize)
+                    .and_then(|pixels| pixels.checked_mul(BPP))
+                    .filter(|&size| size <= MAX_RESOURCE_SIZE)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY)?;
+                let resource = Resource {
+                    order,
+                    width,
+                    height,
+                    data: vec![0; size],
+                    backing: Vec::new(),
+                };
+                self.resources.insert(id, resource);
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                let id = arg(0)?;
+                self.resources
+                    .remove(&id)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+                if self.scanout == id {
+                    self.scanout = 0;
+                }
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => {
+                let (scanout, id) = (arg(4)?, arg(5)?);
+                if scanout != 0 {
+                    return Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+                }
+                if id != 0 && !self.resources.contains_key(&id) {
+                    return Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+                }
+                self.scanout = id;
+            }
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                let (rect, id) = (rect()?, arg(4)?);
+                if !self.resources.contains_key(&id) {
+                    return Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+                }
+                if id == self.scanout {
+                    self.flush(id, rect);
+                }
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                let rect = rect()?;
+                let offset = (arg(4)? as u64 | (arg(5)? as u64) << 32) as usize;
+                let resource = self
+                    .resources
+                    .get_mut(&arg(6)?)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+                if !rect.within(resource.width, resource.height) {
+                    return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+                }
+                let stride = resource.width as usize * BPP;
+                let row_len = rect.width as usize * BPP;
+                for row in 0..rect.height as usize {
+                    let src = offset
+                        .checked_add(stride * row)
+                        .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+                    let dst = (rect.y as usize + row) * stride + rect.x as usize * BPP;
+                    let Resource { data, backing, .. } = resource;
+                    read_backing(mem, backing, src, &mut data[dst..dst + row_len])?;
+                }
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                let (id, nr_entries) = (arg(0)?, arg(1)? as usize);
+                let entries = body
+                    .get(8..)
+                    .filter(|entries| entries.len() / 16 >= nr_entries)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+                let resource = self
+                    .resources
+                    .get_mut(&id)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+                resource.backing = entries
+                    .chunks_exact(16)
+                    .take(nr_entries)
+                    .map(|entry| {
+                        let addr = le32(entry, 0) as u64 | (le32(entry, 4) as u64) << 32;
+                        (addr as GuestPhysAddr, le32(entry, 8) as usize)
+                    })
+                    .collect();
+            }
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                let resource = self
+                    .resources
+                    .get_mut(&arg(0)?)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+                resource.backing.clear();
+            }
+            _ => return Err(VIRTIO_GPU_RESP_ERR_UNSPEC),
+        }
+        Ok(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Converts `rect` of the resource `id` shown on the scanout, clipped to the scanout, and
+    /// passes it to the display.
+    fn flush(&mut self, id: u32, rect: Rect) {
+        let resource = &self.resources[&id];
+        let width = self.width.min(resource.width);
+        let height = self.height.min(resource.height);
+        let x_end = rect.x.saturating_add(rect.width).min(width);
+        let y_end = rect.y.saturating_add(rect.height).min(height);
+        if rect.x >= x_end || rect.y >= y_end {
+            return;
+        }
+        for y in rect.y..y_end {
+            for x in rect.x..x_end {
+                let src = (y as usize * resource.width as usize + x as usize) * BPP;
+                let pixel = resource.order.to_rgb(&resource.data[src..src + BPP]);
+                self.framebuffer[y as usize * self.width as usize + x as usize] = pixel;
+            }
+        }
+        (self.display_update)(
+            &self.framebuffer,
+            self.width as usize,
+            (rect.x, rect.y, x_end - rect.x, y_end - rect.y),
+        );
+    }
+}
+
+impl VirtioDevice for VirtioGpu {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_GPU
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        // events_read, events_clear, num_scanouts and num_capsets.
+        let config = [0u32, 0, 1, 0];
+        config
+            .get(offset / 4)
+            .map_or(0, |val| val.to_le_bytes()[offset % 4])
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        while let Some((head, chain)) = queue.pop_avail(mem)? {
+            let mut written = 0;
+            if index == CONTROLQ {
+                let cmd = read_chain(mem, &chain, MAX_CMD_SIZE)?;
+                let mut resp = Vec::new();
+                self.0.lock().handle(&cmd, mem, &mut resp);
+                written = write_chain(mem, &chain, &resp)?;
+            } else {
+                debug_assert_eq!(index, CURSORQ);
+            }
+            queue.push_used(mem, head, written)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the bytes at `offset` of the guest memory `backing` into `buf`.
+fn read_backing(
+    mem: &dyn GuestMemory,
+    backing: &[(GuestPhysAddr, usize)],
+    mut offset: usize,
+    buf: &mut [u8],
+) -> Result<(), u32> {
+    let mut done = 0;
+    for &(addr, len) in backing {
+        if done == buf.len() {
+            break;
+        }
+        if offset >= len {
+            offset -= len;
+            continue;
+        }
+        let chunk = core::cmp::min(len - offset, buf.len() - done);
+        mem.read(addr + offset, &mut buf[done..done + chunk])
+            .map_err(|_| VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+        done += chunk;
+        offset = 0;
+    }
+    if done < buf.len() {
+        return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+    }
+    Ok(())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
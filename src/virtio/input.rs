@@ -0,0 +1,225 @@
+//! virtio input device.
+//!
+//! Emulates a combined keyboard and mouse. The host injects Linux evdev events through the
+//! `InputHandle` of the device, which are delivered to the guest as it makes event buffers
+//! available. LED updates the driver sends on the status queue are ignored.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+use super::{write_chain, GuestMemory, VirtioDevice, Virtq};
+use crate::HyperResult;
+
+const VIRTIO_ID_INPUT: u32 = 18;
+
+const EVENTQ: usize = 0;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+/// Offset of the selected item in the configuration space.
+const CONFIG_DATA: usize = 8;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_LED: u16 = 0x11;
+
+const BTN_LEFT: u16 = 0x110;
+const BTN_MIDDLE: u16 = 0x112;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_WHEEL: u16 = 0x08;
+const LED_SCROLLL: u16 = 0x02;
+const BUS_VIRTUAL: u16 = 0x06;
+
+const NAME: &[u8] = b"hypercraft virtio input";
+
+/// Events buffered per device. Further events are dropped until the guest takes some.
+const EVENT_BUF_SIZE: usize = 256;
+
+/// A Linux evdev input event.
+#[derive(Clone, Copy, Debug)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    /// The key or button `code` is pressed or released.
+    pub fn key(code: u16, pressed: bool) -> Self {
+        Self {
+            event_type: EV_KEY,
+            code,
+            value: pressed as u32,
+        }
+    }
+
+    /// The pointer moves by `dx` and `dy`.
+    pub fn motion(dx: i32, dy: i32) -> [Self; 2] {
+        [(REL_X, dx), (REL_Y, dy)].map(|(code, delta)| Self {
+            event_type: EV_REL,
+            code,
+            value: delta as u32,
+        })
+    }
+
+    /// The wheel scrolls by `delta`.
+    pub fn wheel(delta: i32) -> Self {
+        Self {
+            event_type: EV_REL,
+            code: REL_WHEEL,
+            value: delta as u32,
+        }
+    }
+
+    /// Ends the events forming one input report.
+    pub fn sync() -> Self {
+        Self {
+            event_type: EV_SYN,
+            code: 0,
+            value: 0,
+        }
+    }
+}
+
+/// Host side of a virtio input device, through which events are injected.
+#[derive(Clone)]
+pub struct InputHandle(Arc<Mutex<VecDeque<InputEvent>>>);
+
+impl InputHandle {
+    /// Queues `events` for the guest, which receives them on its next exit. Returns false if some
+    /// are dropped, because the guest doesn't take its events.
+    pub fn push(&self, events: &[InputEvent]) -> bool {
+        let mut queue = self.0.lock();
+        let room = EVENT_BUF_SIZE - queue.len();
+        queue.extend(events.iter().take(room));
+        events.len() <= room
+    }
+}
+
+/// A combined keyboard and mouse.
+pub struct VirtioInput {
+    events: Arc<Mutex<VecDeque<InputEvent>>>,
+    select: AtomicU8,
+    subsel: AtomicU8,
+}
+
+impl VirtioInput {
+    /// Creates a device, with the handle the host injects its events through.
+    pub fn new() -> (Self, InputHandle) {
+        let events = Arc::new(Mutex::new(VecDeque::new()));
+        let device = Self {
+            events: events.clone(),
+            select: AtomicU8::new(0),
+            subsel: AtomicU8::new(0),
+        };
+        (device, InputHandle(events))
+    }
+
+    /// Whether the item selected in the configuration space contains the byte `index`, and its
+    /// value.
+    fn config_byte(&self, index: usize) -> Option<u8> {
+        let subsel = self.subsel.load(Ordering::Relaxed);
+        match (self.select.load(Ordering::Relaxed), subsel) {
+            (VIRTIO_INPUT_CFG_ID_NAME, 0) => NAME.get(index).copied(),
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => {
+                // Bus type, vendor, product and version.
+                let ids = [BUS_VIRTUAL, 0, 0, 1];
+                ids.get(index / 2).map(|id| id.to_le_bytes()[index % 2])
+            }
+            (VIRTIO_INPUT_CFG_EV_BITS, subsel) => {
+                // The last code of the bitmap, and whether a code is supported.
+                let (last, supported): (u16, fn(u16) -> bool) = match subsel as u16 {
+                    // All keyboard keys and the mouse buttons.
+                    EV_KEY => (BTN_MIDDLE, |code| {
+                        (1..0x100).contains(&code) || code >= BTN_LEFT
+                    }),
+                    EV_REL => (REL_WHEEL, |code| matches!(code, REL_X | REL_Y | REL_WHEEL)),
+                    EV_LED => (LED_SCROLLL, |_| true),
+                    _ => return None,
+                };
+                if index > last as usize / 8 {
+                    return None;
+                }
+                let bits = (0..8)
+                    .map(|bit| index * 8 + bit)
+                    .filter(|&code| code <= last as usize && supported(code as u16))
+                    .fold(0, |byte, code| byte | 1 << (code % 8));
+                Some(bits)
+            }
+            _ => None,
+        }
+    }
+
+    /// Size of the item selected in the configuration space, 0 if there's none.
+    fn config_size(&self) -> u8 {
+        (0..128)
+            .take_while(|&i| self.config_byte(i).is_some())
+            .count() as u8
+    }
+}
+
+impl VirtioDevice for VirtioInput {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_INPUT
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0 => self.select.load(Ordering::Relaxed),
+            1 => self.subsel.load(Ordering::Relaxed),
+            2 => self.config_size(),
+            _ if offset >= CONFIG_DATA => self.config_byte(offset - CONFIG_DATA).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn write_config(&self, offset: usize, val: u8) {
+        match offset {
+            0 => self.select.store(val, Ordering::Relaxed),
+            1 => self.subsel.store(val, Ordering::Relaxed),
+            _ => {}
+        }
+    }
+
+    fn pending(&self) -> bool {
+        !self.events.lock().is_empty()
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        if index == EVENTQ {
+            let mut events = self.events.lock();
+            while let Some(event) = events.front() {
+                let Some((head, chain)) = queue.pop_avail(mem)? else {
+                    break;
+                };
+                let mut buf = [0u8; 8];
+                buf[0..2].copy_from_slice(&event.event_type.to_le_bytes());
+                buf[2..4].copy_from_slice(&event.code.to_le_bytes());
+                buf[4..8].copy_from_slice(&event.value.to_le_bytes());
+                let written = write_chain(mem, &chain, &buf)?;
+                queue.push_used(mem, head, written)?;
+                events.pop_front();
+            }
+        } else {
+            while let Some((head, _)) = queue.pop_avail(mem)? {
+                queue.push_used(mem, head, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1023 @@
+//! Emulated virtio devices on the virtio-mmio transport (version 2).
+//!
+//! `VirtioMmio` implements the transport registers and split virtqueues, and hands the queues to
+//! a `VirtioDevice` implementing the device type when the driver notifies them. A device can
+//! instead be put in polling mode, where the driver is asked not to notify its queues and the
+//! hypervisor processes them whenever it calls `VirtioMmio::poll`, on the VM's exits and between
+//! its runs, saving the `QueueNotify` traps.
+//! With `VIRTIO_F_NOTIFICATION_DATA`, notifications carry the queue's avail index, so the device
+//! takes the buffers they announce without reading the index from guest memory and drops
+//! notifications announcing nothing new.
+//!
+//! `blk_driver` is the other side of the transport, driving a virtio block device of an outer
+//! hypervisor for the host. It's only built for riscv64, the rest for any target.
+
+pub mod balloon;
+#[cfg(target_arch = "riscv64")]
+pub mod blk_driver;
+pub mod fs;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod gpu;
+pub mod input;
+mod queue;
+pub mod rng;
+#[cfg(test)]
+mod testing;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+
+pub use queue::{read_chain, write_chain, Virtq, QUEUE_SIZE_DEFAULT, QUEUE_SIZE_MAX};
+
+use crate::{
+    device::EmuDevice, logging::LogContext, GuestPhysAddr, HostVirtAddr, HyperError, HyperResult,
+};
+
+/// Size of the register region of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
+/// Start of the device-specific configuration space.
+pub const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VENDOR_ID: u32 = 0;
+
+/// Feature bit every modern device offers.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Feature bit enabling interrupt and notification suppression through event indices.
+const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// Feature bit making notifications carry the avail index along with the queue index.
+const VIRTIO_F_NOTIFICATION_DATA: u64 = 1 << 38;
+
+/// `InterruptStatus` bit signalling used buffers.
+const VIRTIO_MMIO_INT_VRING: u32 = 1 << 0;
+/// `InterruptStatus` bit signalling a configuration change.
+const VIRTIO_MMIO_INT_CONFIG: u32 = 1 << 1;
+
+/// Device status bit set by the driver once it found the device.
+const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+/// Device status bit set by the driver once it knows how to drive the device.
+const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+/// Device status bit set by the driver once it's ready to drive the device.
+const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+/// Device status bit set by the driver once it accepted the features.
+const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+/// Device status bit telling the driver the device needs a reset.
+const VIRTIO_CONFIG_S_NEEDS_RESET: u32 = 64;
+
+/// Guest RAM accessed by virtio devices on behalf of the driver.
+pub trait GuestMemory {
+    /// Copies guest RAM at `gpa` into `buf`. Fails if the range isn't the VM's RAM.
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Copies `buf` into guest RAM at `gpa`. Fails if the range isn't the VM's RAM.
+    fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()>;
+
+    /// Translates the guest RAM range `[gpa, gpa + len)` to the host virtual address it can be
+    /// accessed at, which stays valid until the VM unmaps guest RAM. Fails if the range isn't the
+    /// VM's RAM or isn't contiguous in host memory.
+    fn host_addr(&self, _gpa: GuestPhysAddr, _len: usize) -> HyperResult<HostVirtAddr> {
+        Err(HyperError::NotSupported)
+    }
+}
+
+/// A virtio device type, driven through its queues by `VirtioMmio`. Each queue is processed with
+/// only its own lock held, possibly on several harts at once, so the device locks whatever state
+/// its queues share.
+pub trait VirtioDevice: Send + Sync {
+    /// Device ID of the type, e.g. 4 for an entropy source.
+    fn device_id(&self) -> u32;
+
+    /// Number of virtqueues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Largest size the driver may give the queue `index`, a power of two no larger than
+    /// `QUEUE_SIZE_MAX`. Larger queues let the driver keep more requests in flight.
+    fn queue_size_max(&self, _index: usize) -> u16 {
+        QUEUE_SIZE_DEFAULT
+    }
+
+    /// Device-specific feature bits offered to the driver.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Reads the byte at `offset` of the device-specific configuration space.
+    fn read_config(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    /// Writes the byte at `offset` of the device-specific configuration space.
+    fn write_config(&self, _offset: usize, _val: u8) {}
+
+    /// Changes the configuration space at `offset` to `data` on behalf of the host, e.g. a
+    /// resized capacity, including fields the driver can't write. Fails with `NotSupported` if
+    /// the host can't change the device's configuration, and with `InvalidParam` if `data` covers
+    /// bytes it can't change.
+    fn update_config(&self, _offset: usize, _data: &[u8]) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+
+    /// Whether the configuration space changed since this was last called, e.g. because the host
+    /// reconfigured the device. `VirtioMmio::poll` then notifies the driver.
+    fn config_changed(&self) -> bool {
+        false
+    }
+
+    /// Whether the device has buffers to fill without being notified, e.g. input from the host.
+    /// `VirtioMmio::poll` then processes its queues.
+    fn pending(&self) -> bool {
+        false
+    }
+
+    /// Processes the buffers the driver made available on the queue `index`, `queue`. The
+    /// buffers used are published to the driver at once afterwards.
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()>;
+}
+
+/// Transport registers the driver writes while setting the device up, rarely after.
+#[derive(Default)]
+struct Selectors {
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    queue_sel: u32,
+}
+
+/// Sentinel of `VirtioMmio::deferred_since` while `poll` hasn't seen a deferral.
+const NOT_SEEN: u64 = u64::MAX;
+
+/// A virtio device on the virtio-mmio transport.
+///
+/// The driver may use the queues from several harts at once, so nothing is locked device-wide:
+/// each queue has its own lock, held while the device processes it, the selector registers have
+/// another, and the status, interrupt and interrupt moderation registers are atomics, updated
+/// without a lock as queues publish used buffers.
+pub struct VirtioMmio {
+    base: usize,
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Mutex<Virtq>>,
+    /// Also serializes the status changes and the feature negotiation they depend on.
+    selectors: Mutex<Selectors>,
+    driver_features: AtomicU64,
+    interrupt_status: AtomicU32,
+    status: AtomicU32,
+    config_generation: AtomicU32,
+    /// Completions after which a deferred used buffer interrupt is raised, 1 raising it at once.
+    moderation_completions: AtomicU32,
+    /// Time after which a deferred used buffer interrupt is raised, in ticks of the `time` CSR.
+    moderation_delay: AtomicU64,
+    /// Completions whose interrupt is deferred.
+    deferred: AtomicU32,
+    /// When the deferral was first seen by `poll`, `NOT_SEEN` if it wasn't.
+    deferred_since: AtomicU64,
+    /// The VM the device's messages are logged for.
+    log_ctx: LogContext,
+}
+
+impl VirtioMmio {
+    pub fn new(base: usize, device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.num_queues())
+            .map(|index| Mutex::new(Virtq::new(device.queue_size_max(index).min(QUEUE_SIZE_MAX))))
+            .collect();
+        Self {
+            base,
+            device,
+            queues,
+            selectors: Mutex::new(Selectors::default()),
+            driver_features: AtomicU64::new(0),
+            interrupt_status: AtomicU32::new(0),
+            status: AtomicU32::new(0),
+            config_generation: AtomicU32::new(0),
+            moderation_completions: AtomicU32::new(1),
+            moderation_delay: AtomicU64::new(0),
+            deferred: AtomicU32::new(0),
+            deferred_since: AtomicU64::new(NOT_SEEN),
+            log_ctx: LogContext::NONE,
+        }
+    }
+
+    /// Logs the device's messages as being about `ctx`, e.g. the VM it belongs to.
+    pub fn set_log_context(&mut self, ctx: LogContext) {
+        self.log_ctx = ctx;
+    }
+
+    /// Whether `addr` is in the device's register region.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + VIRTIO_MMIO_SIZE).contains(&addr)
+    }
+
+    /// Whether the device is in polling mode.
+    pub fn polling(&self) -> bool {
+        self.queues.iter().any(|queue| queue.lock().polled())
+    }
+
+    /// Puts the device in polling mode or back to processing its queues when notified. In polling
+    /// mode `poll` processes every ready queue, and the driver is asked not to notify them.
+    pub fn set_polling(&self, polling: bool, mem: &dyn GuestMemory) -> HyperResult<()> {
+        for queue in &self.queues {
+            queue.lock().set_polled(polling, mem)?;
+        }
+        Ok(())
+    }
+
+    /// Coalesces used buffer interrupts: the interrupt the driver asks for is deferred until
+    /// `max_completions` buffers are used or `max_delay` ticks of the `time` CSR have passed since
+    /// `poll` first saw it deferred, whichever comes first. A `max_completions` of 1 or less
+    /// interrupts for every completion.
+    pub fn set_irq_moderation(&self, max_completions: u32, max_delay: u64) {
+        self.moderation_completions
+            .store(max_completions.max(1), Ordering::Relaxed);
+        self.moderation_delay.store(max_delay, Ordering::Relaxed);
+    }
+
+    /// When the deferred used buffer interrupt is due, if one is deferred and `poll` saw it.
+    pub fn irq_deadline(&self) -> Option<u64> {
+        let since = self.deferred_since.load(Ordering::Acquire);
+        (since != NOT_SEEN)
+            .then(|| since.saturating_add(self.moderation_delay.load(Ordering::Relaxed)))
+    }
+
+    /// Whether the device raises its interrupt.
+    pub fn irq_pending(&self) -> bool {
+        self.interrupt_status.load(Ordering::Acquire) != 0
+    }
+
+    /// Reads `width` bytes at `addr`. Registers are 32-bit words: narrower reads return part of
+    /// one, and an aligned 64-bit read returns two consecutive registers. The configuration space
+    /// is read with any width. Reads straddling registers return 0.
+    pub fn read(&self, addr: usize, width: usize) -> u64 {
+        let offset = addr - self.base;
+        if offset >= VIRTIO_MMIO_CONFIG {
+            return (0..width).fold(0, |val, i| {
+                val | (self.device.read_config(offset - VIRTIO_MMIO_CONFIG + i) as u64) << (i * 8)
+            });
+        }
+        match width {
+            8 if offset & 0x7 == 0 => {
+                self.read_register(offset) as u64 | (self.read_register(offset + 4) as u64) << 32
+            }
+            1 | 2 | 4 if offset % 4 + width <= 4 => {
+                let reg = self.read_register(offset & !0x3) as u64;
+                reg >> (offset % 4 * 8) & width_mask(width)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes the `width` low bytes of `val` at `addr`, processing the queue the driver notifies.
+    /// Registers are 32-bit words: a narrower write updates part of one, keeping the other bytes
+    /// as last written, and an aligned 64-bit write updates two consecutive registers, low one
+    /// first. The configuration space is written with any width. Writes straddling registers are
+    /// ignored. A queue the driver set up wrongly makes the device need a reset.
+    pub fn write(&self, addr: usize, width: usize, val: u64, mem: &dyn GuestMemory) {
+        let offset = addr - self.base;
+        if offset >= VIRTIO_MMIO_CONFIG {
+            for i in 0..width {
+                let byte = (val >> (i * 8)) as u8;
+                self.device
+                    .write_config(offset - VIRTIO_MMIO_CONFIG + i, byte);
+            }
+            return;
+        }
+        match width {
+            8 if offset & 0x7 == 0 => {
+                self.write_register(offset, val as u32, mem);
+                self.write_register(offset + 4, (val >> 32) as u32, mem);
+            }
+            1 | 2 | 4 if offset % 4 + width <= 4 => {
+                let reg = offset & !0x3;
+                let shift = offset % 4 * 8;
+                let mask = (width_mask(width) << shift) as u32;
+                let old = self.written_register(reg);
+                self.write_register(reg, old & !mask | (val << shift) as u32 & mask, mem);
+            }
+            _ => {}
+        }
+    }
+
+    /// The value of the register at `offset`, as the driver reads it.
+    fn read_register(&self, offset: usize) -> u32 {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => 2,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                let features = self.device_features();
+                let sel = self.selectors.lock().device_features_sel;
+                match sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self.selected_queue().map_or(0, |q| q.size_max() as u32),
+            VIRTIO_MMIO_QUEUE_READY => self.selected_queue().map_or(0, |q| q.ready() as u32),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::Acquire),
+            VIRTIO_MMIO_STATUS => self.status.load(Ordering::Acquire),
+            VIRTIO_MMIO_CONFIG_GENERATION => self.config_generation.load(Ordering::Acquire),
+            _ => 0,
+        }
+    }
+
+    /// The value the driver last wrote to the register at `offset`, which a partial write updates.
+    /// Write-only registers without state, like `QueueNotify`, are 0.
+    fn written_register(&self, offset: usize) -> u32 {
+        let half = |val: u64, sel: u32| match sel {
+            0 => val as u32,
+            1 => (val >> 32) as u32,
+            _ => 0,
+        };
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.selectors.lock().device_features_sel,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.selectors.lock().driver_features_sel,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let sel = self.selectors.lock().driver_features_sel;
+                half(self.driver_features.load(Ordering::Relaxed), sel)
+            }
+            VIRTIO_MMIO_QUEUE_SEL => self.selectors.lock().queue_sel,
+            VIRTIO_MMIO_QUEUE_NUM => self.selected_queue().map_or(0, |q| q.size() as u32),
+            VIRTIO_MMIO_QUEUE_DESC_LOW
+            | VIRTIO_MMIO_QUEUE_DESC_HIGH
+            | VIRTIO_MMIO_QUEUE_DRIVER_LOW
+            | VIRTIO_MMIO_QUEUE_DRIVER_HIGH
+            | VIRTIO_MMIO_QUEUE_DEVICE_LOW
+            | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => self.selected_queue().map_or(0, |queue| {
+                let addr = match offset & !0xf {
+                    VIRTIO_MMIO_QUEUE_DESC_LOW => queue.desc_addr,
+                    VIRTIO_MMIO_QUEUE_DRIVER_LOW => queue.avail_addr,
+                    _ => queue.used_addr,
+                };
+                half(addr as u64, (offset & 0x4 != 0) as u32)
+            }),
+            _ => self.read_register(offset),
+        }
+    }
+
+    /// Writes `val` to the register at `offset`, processing the queue the driver notifies.
+    fn write_register(&self, offset: usize, val: u32, mem: &dyn GuestMemory) {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.selectors.lock().device_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.selectors.lock().driver_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let selectors = self.selectors.lock();
+                // The features are settled once the driver accepted them.
+                if self.status.load(Ordering::Acquire) & VIRTIO_CONFIG_S_FEATURES_OK != 0 {
+                    return;
+                }
+                let shift = match selectors.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                let mut features = self.driver_features.load(Ordering::Relaxed);
+                features &= !(0xffff_ffff << shift);
+                features |= (val as u64) << shift;
+                // Features the device doesn't offer can't be accepted.
+                features &= self.device_features();
+                self.driver_features.store(features, Ordering::Relaxed);
+            }
+            VIRTIO_MMIO_QUEUE_SEL => self.selectors.lock().queue_sel = val,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if let Some(mut queue) = self.selected_queue() {
+                    let size = u16::try_from(val).map_err(|_| HyperError::InvalidParam);
+                    if let Err(err) = size.and_then(|size| queue.set_size(size)) {
+                        hv_log!(
+                            Warn,
+                            Virtio,
+                            self.log_ctx,
+                            "virtio: queue size {} rejected: {:?}",
+                            val,
+                            err
+                        );
+                    }
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if let Some(mut queue) = self.selected_queue() {
+                    queue.set_ready(val & 1 != 0, mem);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW
+            | VIRTIO_MMIO_QUEUE_DESC_HIGH
+            | VIRTIO_MMIO_QUEUE_DRIVER_LOW
+            | VIRTIO_MMIO_QUEUE_DRIVER_HIGH
+            | VIRTIO_MMIO_QUEUE_DEVICE_LOW
+            | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                if let Some(mut queue) = self.selected_queue() {
+                    if !queue.ready() {
+                        let field = match offset & !0xf {
+                            VIRTIO_MMIO_QUEUE_DESC_LOW => &mut queue.desc_addr,
+                            VIRTIO_MMIO_QUEUE_DRIVER_LOW => &mut queue.avail_addr,
+                            _ => &mut queue.used_addr,
+                        };
+                        set_half(field, offset & 0x4 != 0, val);
+                    }
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.notify(val, mem),
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!val, Ordering::AcqRel);
+            }
+            VIRTIO_MMIO_STATUS => self.write_status(val),
+            _ => {}
+        }
+    }
+
+    /// Handles the driver's write of `val` to `Status`. The driver goes through ACKNOWLEDGE,
+    /// DRIVER, FEATURES_OK and DRIVER_OK in order: a step taken before the previous one isn't set,
+    /// so the driver reading the status back sees it failed, and steps can only be undone by a
+    /// reset. DEVICE_NEEDS_RESET is the device's to set.
+    fn write_status(&self, val: u32) {
+        if val == 0 {
+            return self.reset();
+        }
+        let _selectors = self.selectors.lock();
+        let old = self.status.load(Ordering::Acquire);
+        let driver_features = self.driver_features.load(Ordering::Relaxed);
+        let mut new = old | val & !VIRTIO_CONFIG_S_NEEDS_RESET;
+        for (step, previous) in [
+            (VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_ACKNOWLEDGE),
+            (VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_CONFIG_S_DRIVER),
+            (VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK),
+        ] {
+            // Legacy drivers aren't supported, so their features are refused.
+            let refused =
+                step == VIRTIO_CONFIG_S_FEATURES_OK && driver_features & VIRTIO_F_VERSION_1 == 0;
+            if new & step != 0 && old & step == 0 && (new & previous == 0 || refused) {
+                hv_log!(
+                    Warn,
+                    Virtio,
+                    self.log_ctx,
+                    "virtio: status {:#x} refused after {:#x}",
+                    val,
+                    old
+                );
+                new &= !step;
+            }
+        }
+        if new & VIRTIO_CONFIG_S_FEATURES_OK != 0 && old & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+            let event_idx = driver_features & VIRTIO_F_EVENT_IDX != 0;
+            self.queues
+                .iter()
+                .for_each(|q| q.lock().event_idx = event_idx);
+        }
+        // Steps are only added, so a DEVICE_NEEDS_RESET a queue sets meanwhile is kept.
+        self.status.fetch_or(new, Ordering::AcqRel);
+    }
+
+    /// Notifies the driver of configuration changes and processes the queues of a device with
+    /// buffers to fill without being notified, as well as the ready queues in polling mode. Raises
+    /// the deferred used buffer interrupt if it's due at `now`.
+    pub fn poll(&self, mem: &dyn GuestMemory, now: u64) {
+        if self.device.config_changed() {
+            self.config_changed();
+        }
+        let pending = self.device.pending();
+        for index in 0..self.queues.len() {
+            let due = pending || {
+                let queue = self.queues[index].lock();
+                queue.polled() && queue.ready()
+            };
+            if due {
+                self.process_queue(index, mem);
+            }
+        }
+        if self.deferred.load(Ordering::Acquire) > 0 {
+            let since = match self.deferred_since.compare_exchange(
+                NOT_SEEN,
+                now,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => now,
+                Err(since) => since,
+            };
+            if now.saturating_sub(since) >= self.moderation_delay.load(Ordering::Relaxed) {
+                self.raise_vring_irq();
+            }
+        }
+    }
+
+    /// Handles the driver's write of `val` to `QueueNotify`.
+    fn notify(&self, val: u32, mem: &dyn GuestMemory) {
+        if self.driver_features.load(Ordering::Relaxed) & VIRTIO_F_NOTIFICATION_DATA == 0 {
+            return self.process_queue(val as usize, mem);
+        }
+        // The queue index in the low half, the avail index in the high half.
+        let index = (val & 0xffff) as usize;
+        if let Some(queue) = self.queues.get(index) {
+            if !queue.lock().notified((val >> 16) as u16) {
+                return;
+            }
+        }
+        self.process_queue(index, mem);
+    }
+
+    fn process_queue(&self, index: usize, mem: &dyn GuestMemory) {
+        // Buffers are only used once the driver is ready, and until the device needs a reset.
+        if self.status.load(Ordering::Acquire)
+            & (VIRTIO_CONFIG_S_DRIVER_OK | VIRTIO_CONFIG_S_NEEDS_RESET)
+            != VIRTIO_CONFIG_S_DRIVER_OK
+        {
+            return;
+        }
+        let Some(queue) = self.queues.get(index) else {
+            return;
+        };
+        let mut queue = queue.lock();
+        let result = self.device.process_queue(index, &mut queue, mem);
+        let completed = queue.unpublished_used() as u32;
+        match result.and_then(|_| queue.publish_used(mem)) {
+            Ok(wanted) => {
+                drop(queue);
+                self.used_buffers(completed, wanted);
+            }
+            Err(err) => {
+                hv_log!(
+                    Warn,
+                    Virtio,
+                    self.log_ctx,
+                    "virtio: queue {} failed: {:?}",
+                    index,
+                    err
+                );
+                // Set with the queue locked: a reset waits for it, so the flag doesn't outlive the reset.
+                self.status
+                    .fetch_or(VIRTIO_CONFIG_S_NEEDS_RESET, Ordering::AcqRel);
+                self.interrupt_status
+                    .fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Accounts for `completed` buffers just published to the driver, which asked for an
+    /// interrupt if `wanted`. Queues publish concurrently, so completions are counted without a
+    /// lock: a completion counted after the interrupt was raised, but published before, makes the
+    /// next interrupt come early, which is harmless as the driver looks at every used buffer.
+    fn used_buffers(&self, completed: u32, wanted: bool) {
+        // Later completions are covered by the deferred interrupt, whether or not the driver asks
+        // for them.
+        if !wanted && self.deferred.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let deferred = self
+            .deferred
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |deferred| {
+                Some(deferred.saturating_add(completed))
+            })
+            .unwrap_or_default()
+            .saturating_add(completed);
+        if deferred >= self.moderation_completions.load(Ordering::Relaxed) {
+            self.raise_vring_irq();
+        }
+    }
+
+    /// Changes the device's configuration space at `offset` to `data` on behalf of the host, see
+    /// `VirtioDevice::update_config`, and notifies the driver.
+    pub fn update_config(&self, offset: usize, data: &[u8]) -> HyperResult<()> {
+        self.device.update_config(offset, data)?;
+        self.config_changed();
+        Ok(())
+    }
+
+    /// Tells the driver the configuration space changed: the configuration generation moves on,
+    /// so a driver reading the space across the change reads it again, and once the driver is
+    /// ready the configuration change interrupt is raised.
+    pub fn config_changed(&self) {
+        self.config_generation.fetch_add(1, Ordering::AcqRel);
+        if self.status.load(Ordering::Acquire) & VIRTIO_CONFIG_S_DRIVER_OK != 0 {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::AcqRel);
+        }
+    }
+
+    /// Forgets the host addresses of the queues' rings. Must be called when the VM unmaps guest
+    /// RAM.
+    pub fn invalidate_rings(&self) {
+        self.queues
+            .iter()
+            .for_each(|queue| queue.lock().invalidate_rings());
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features()
+            | VIRTIO_F_VERSION_1
+            | VIRTIO_F_EVENT_IDX
+            | VIRTIO_F_NOTIFICATION_DATA
+    }
+
+    /// Locks the queue `QueueSel` selects, if there's one.
+    fn selected_queue(&self) -> Option<MutexGuard<'_, Virtq>> {
+        let queue_sel = self.selectors.lock().queue_sel;
+        self.queues.get(queue_sel as usize).map(Mutex::lock)
+    }
+
+    fn raise_vring_irq(&self) {
+        self.deferred.store(0, Ordering::Release);
+        self.deferred_since.store(NOT_SEEN, Ordering::Release);
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::AcqRel);
+    }
+
+    /// Resets the transport, as the driver does by writing 0 to the status register. Queues in
+    /// polling mode stay so.
+    pub fn reset(&self) {
+        let mut selectors = self.selectors.lock();
+        // The queue locks wait for the queues being processed, which could otherwise publish
+        // buffers or set DEVICE_NEEDS_RESET after the reset.
+        self.queues.iter().for_each(|queue| queue.lock().reset());
+        *selectors = Selectors::default();
+        self.driver_features.store(0, Ordering::Relaxed);
+        self.deferred.store(0, Ordering::Release);
+        self.deferred_since.store(NOT_SEEN, Ordering::Release);
+        self.interrupt_status.store(0, Ordering::Release);
+        self.status.store(0, Ordering::Release);
+    }
+}
+
+/// A virtio-mmio device of a VM, raising the guest interrupt `irq` and timing its interrupt
+/// moderation with `clock`, which counts ticks of the `time` CSR.
+pub struct EmuVirtioMmio {
+    mmio: VirtioMmio,
+    irq: u32,
+    clock: fn() -> u64,
+}
+
+impl EmuVirtioMmio {
+    pub fn new(mmio: VirtioMmio, irq: u32, clock: fn() -> u64) -> Self {
+        Self { mmio, irq, clock }
+    }
+
+    /// The transport, e.g. for the host to configure the device.
+    pub fn mmio(&self) -> &VirtioMmio {
+        &self.mmio
+    }
+}
+
+impl EmuDevice for EmuVirtioMmio {
+    fn contains(&self, addr: GuestPhysAddr) -> bool {
+        self.mmio.contains(addr)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        Ok(self.mmio.read(addr, width))
+    }
+
+    fn handle_write(
+        &self,
+        addr: GuestPhysAddr,
+        width: usize,
+        val: u64,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        self.mmio.write(addr, width, val, mem);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.mmio.reset();
+    }
+
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        Some((self.irq, self.mmio.irq_pending()))
+    }
+
+    fn update(&self, mem: &dyn GuestMemory) {
+        self.mmio.poll(mem, (self.clock)());
+    }
+}
+
+/// Mask of the `width` low bytes of a register.
+fn width_mask(width: usize) -> u64 {
+    u64::MAX >> (64 - width * 8)
+}
+
+/// Sets the low or high half of the guest address `addr` to `val`.
+fn set_half(addr: &mut GuestPhysAddr, high: bool, val: u32) {
+    let shift = if high { 32 } else { 0 };
+    let addr64 = (*addr as u64 & !(0xffff_ffffu64 << shift)) | (val as u64) << shift;
+    *addr = addr64 as GuestPhysAddr;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rng::VirtioRng;
+    use super::testing::{TestMemory, AVAIL, BUFS, DESC, QUEUE_SIZE, USED};
+    use super::*;
+    use alloc::sync::Arc;
+
+    extern crate std;
+    use std::{thread, time::Instant};
+
+    const BASE: usize = 0x1000_0000;
+
+    const ACKNOWLEDGE: u32 = 1;
+    const DRIVER: u32 = 2;
+    const DRIVER_OK: u32 = 4;
+
+    fn fill_entropy(buf: &mut [u8]) -> HyperResult<()> {
+        buf.fill(0xa5);
+        Ok(())
+    }
+
+    fn read(dev: &VirtioMmio, offset: usize) -> u32 {
+        dev.read(BASE + offset, 4) as u32
+    }
+
+    fn write(dev: &VirtioMmio, mem: &TestMemory, offset: usize, val: u32) {
+        dev.write(BASE + offset, 4, val as u64, mem);
+    }
+
+    /// An entropy device whose driver accepted `features` and set up its queue in `mem`.
+    fn rng_device(mem: &TestMemory, features: u64) -> VirtioMmio {
+        let dev = VirtioMmio::new(BASE, Box::new(VirtioRng::new(fill_entropy)));
+        write(&dev, mem, VIRTIO_MMIO_STATUS, ACKNOWLEDGE);
+        write(&dev, mem, VIRTIO_MMIO_STATUS, ACKNOWLEDGE | DRIVER);
+        for sel in 0..2 {
+            write(&dev, mem, VIRTIO_MMIO_DRIVER_FEATURES_SEL, sel);
+            let half = (features >> (sel * 32)) as u32;
+            write(&dev, mem, VIRTIO_MMIO_DRIVER_FEATURES, half);
+        }
+        let status = ACKNOWLEDGE | DRIVER | VIRTIO_CONFIG_S_FEATURES_OK;
+        write(&dev, mem, VIRTIO_MMIO_STATUS, status);
+        if read(&dev, VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+            return dev;
+        }
+
+        write(&dev, mem, VIRTIO_MMIO_QUEUE_SEL, 0);
+        write(&dev, mem, VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE as u32);
+        // Ring addresses are written as 64-bit pairs of registers.
+        for (offset, addr) in [
+            (VIRTIO_MMIO_QUEUE_DESC_LOW, DESC),
+            (VIRTIO_MMIO_QUEUE_DRIVER_LOW, AVAIL),
+            (VIRTIO_MMIO_QUEUE_DEVICE_LOW, USED),
+        ] {
+            dev.write(BASE + offset, 8, addr as u64, mem);
+        }
+        write(&dev, mem, VIRTIO_MMIO_QUEUE_READY, 1);
+        write(&dev, mem, VIRTIO_MMIO_STATUS, status | DRIVER_OK);
+        dev
+    }
+
+    #[test]
+    fn identification() {
+        let mem = TestMemory::new(false);
+        let dev = VirtioMmio::new(BASE, Box::new(VirtioRng::new(fill_entropy)));
+        assert!(dev.contains(BASE + VIRTIO_MMIO_SIZE - 1));
+        assert!(!dev.contains(BASE + VIRTIO_MMIO_SIZE));
+        assert_eq!(read(&dev, VIRTIO_MMIO_MAGIC_VALUE), MAGIC_VALUE);
+        assert_eq!(read(&dev, VIRTIO_MMIO_DEVICE_ID), 4);
+        assert_eq!(
+            dev.read(BASE + VIRTIO_MMIO_MAGIC_VALUE, 8),
+            2 << 32 | 0x7472_6976
+        );
+        assert_eq!(dev.read(BASE + VIRTIO_MMIO_MAGIC_VALUE + 1, 1), 0x69);
+        // Reads straddling two registers.
+        assert_eq!(dev.read(BASE + VIRTIO_MMIO_MAGIC_VALUE + 2, 4), 0);
+
+        write(&dev, &mem, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        let features = read(&dev, VIRTIO_MMIO_DEVICE_FEATURES);
+        assert_ne!(features & (VIRTIO_F_VERSION_1 >> 32) as u32, 0);
+        assert_eq!(
+            read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX),
+            QUEUE_SIZE_DEFAULT as u32
+        );
+    }
+
+    #[test]
+    fn fills_notified_buffers() {
+        let mem = TestMemory::new(false);
+        let dev = rng_device(&mem, VIRTIO_F_VERSION_1);
+        assert_eq!(read(&dev, VIRTIO_MMIO_QUEUE_READY), 1);
+        mem.put_desc(0, BUFS, 8, true, Some(1));
+        mem.put_desc(1, BUFS + 8, 8, true, None);
+        mem.make_available(0, 0);
+        write(&dev, &mem, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+        assert_eq!(mem.used_idx(), 1);
+        assert_eq!(mem.used_elem(0), (0, 16));
+        let mut buf = [0u8; 16];
+        mem.read(BUFS, &mut buf).unwrap();
+        assert_eq!(buf, [0xa5; 16]);
+        assert!(dev.irq_pending());
+        let status = read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS);
+        assert_eq!(status, VIRTIO_MMIO_INT_VRING);
+        write(&dev, &mem, VIRTIO_MMIO_INTERRUPT_ACK, status);
+        assert!(!dev.irq_pending());
+    }
+
+    #[test]
+    fn refuses_legacy_drivers() {
+        let mem = TestMemory::new(false);
+        let dev = rng_device(&mem, 0);
+        let status = read(&dev, VIRTIO_MMIO_STATUS);
+        assert_eq!(status & VIRTIO_CONFIG_S_FEATURES_OK, 0);
+    }
+
+    #[test]
+    fn broken_queue_needs_reset() {
+        let mem = TestMemory::new(true);
+        let dev = rng_device(&mem, VIRTIO_F_VERSION_1);
+        mem.put_desc(0, BUFS, 8, true, Some(0));
+        mem.make_available(0, 0);
+        write(&dev, &mem, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        assert_ne!(
+            read(&dev, VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+        assert_eq!(
+            read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+
+        write(&dev, &mem, VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(read(&dev, VIRTIO_MMIO_STATUS), 0);
+        assert_eq!(read(&dev, VIRTIO_MMIO_QUEUE_READY), 0);
+        assert!(!dev.irq_pending());
+    }
+
+    #[test]
+    fn partial_register_writes() {
+        let mem = TestMemory::new(false);
+        let dev = VirtioMmio::new(BASE, Box::new(VirtioRng::new(fill_entropy)));
+        dev.write(BASE + VIRTIO_MMIO_QUEUE_SEL + 1, 1, 0x12, &mem);
+        dev.write(BASE + VIRTIO_MMIO_QUEUE_SEL, 1, 0x34, &mem);
+        // There's no queue 0x1234.
+        assert_eq!(read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+        dev.write(BASE + VIRTIO_MMIO_QUEUE_SEL, 2, 0, &mem);
+        assert_ne!(read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+    }
+
+    #[test]
+    fn emulated_device() {
+        let mem = TestMemory::new(false);
+        let mmio = rng_device(&mem, VIRTIO_F_VERSION_1);
+        let dev: Arc<dyn EmuDevice> = Arc::new(EmuVirtioMmio::new(mmio, 5, || 0));
+        assert!(dev.contains(BASE));
+        assert_eq!(dev.irq_line(), Some((5, false)));
+        mem.put_desc(0, BUFS, 8, true, None);
+        mem.make_available(0, 0);
+        dev.handle_write(BASE + VIRTIO_MMIO_QUEUE_NOTIFY, 4, 0, &mem)
+            .unwrap();
+        assert_eq!(mem.used_idx(), 1);
+        assert_eq!(dev.irq_line(), Some((5, true)));
+
+        dev.reset();
+        assert_eq!(dev.irq_line(), Some((5, false)));
+        let status = dev.handle_read(BASE + VIRTIO_MMIO_STATUS, 4).unwrap();
+        assert_eq!(status, 0);
+    }
+
+    /// Number of queues of `NullDevice`.
+    const NULL_QUEUES: u16 = 4;
+
+    /// A device using every buffer as soon as it's made available, so the transport is all a
+    /// request costs.
+    struct NullDevice;
+
+    impl VirtioDevice for NullDevice {
+        fn device_id(&self) -> u32 {
+            0
+        }
+
+        fn num_queues(&self) -> usize {
+            NULL_QUEUES as usize
+        }
+
+        fn process_queue(
+            &self,
+            _index: usize,
+            queue: &mut Virtq,
+            mem: &dyn GuestMemory,
+        ) -> HyperResult<()> {
+            while let Some((head, _)) = queue.pop_avail(mem)? {
+                queue.push_used(mem, head, 0)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A `NullDevice` whose driver set up every queue `q` with its rings at `AVAIL` and `USED`
+    /// plus `q * 0x100`, using the descriptor `q` of a shared table and wanting no interrupts.
+    fn null_device(mem: &TestMemory) -> VirtioMmio {
+        let dev = VirtioMmio::new(BASE, Box::new(NullDevice));
+        write(&dev, mem, VIRTIO_MMIO_STATUS, ACKNOWLEDGE);
+        write(&dev, mem, VIRTIO_MMIO_STATUS, ACKNOWLEDGE | DRIVER);
+        write(&dev, mem, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        let version_1 = (VIRTIO_F_VERSION_1 >> 32) as u32;
+        write(&dev, mem, VIRTIO_MMIO_DRIVER_FEATURES, version_1);
+        let status = ACKNOWLEDGE | DRIVER | VIRTIO_CONFIG_S_FEATURES_OK;
+        write(&dev, mem, VIRTIO_MMIO_STATUS, status);
+        for queue in 0..NULL_QUEUES {
+            let rings = queue as usize * 0x100;
+            write(&dev, mem, VIRTIO_MMIO_QUEUE_SEL, queue as u32);
+            write(&dev, mem, VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE as u32);
+            for (offset, addr) in [
+                (VIRTIO_MMIO_QUEUE_DESC_LOW, DESC),
+                (VIRTIO_MMIO_QUEUE_DRIVER_LOW, AVAIL + rings),
+                (VIRTIO_MMIO_QUEUE_DEVICE_LOW, USED + rings),
+            ] {
+                dev.write(BASE + offset, 8, addr as u64, mem);
+            }
+            write(&dev, mem, VIRTIO_MMIO_QUEUE_READY, 1);
+            mem.put_desc(queue, BUFS, 0, false, None);
+            // VIRTQ_AVAIL_F_NO_INTERRUPT, as a driver busy submitting requests sets it.
+            mem.put_u16(AVAIL + rings, 1);
+        }
+        write(&dev, mem, VIRTIO_MMIO_STATUS, status | DRIVER_OK);
+        dev
+    }
+
+    #[test]
+    fn queues_of_harts() {
+        let mem = TestMemory::new(true);
+        let dev = null_device(&mem);
+        thread::scope(|scope| {
+            for queue in 0..NULL_QUEUES {
+                let (dev, mem) = (&dev, &mem);
+                scope.spawn(move || {
+                    let avail = AVAIL + queue as usize * 0x100;
+                    for idx in 0..100u16 {
+                        mem.put_u16(avail + 4 + (idx % QUEUE_SIZE) as usize * 2, queue);
+                        mem.put_u16(avail + 2, idx + 1);
+                        write(dev, mem, VIRTIO_MMIO_QUEUE_NOTIFY, queue as u32);
+                    }
+                });
+            }
+        });
+        for queue in 0..NULL_QUEUES {
+            assert_eq!(mem.get_u16(USED + queue as usize * 0x100 + 2), 100);
+        }
+        assert!(!dev.irq_pending());
+    }
+
+    /// Measures how requests on separate queues of one device scale with the harts submitting
+    /// them, each thread playing a hart driving a queue of its own, against the same requests
+    /// serialized by a lock around the whole device, as the transport used to be. Prints the
+    /// requests per second of both; run with
+    /// `cargo test -- --ignored --nocapture notify_scalability`.
+    #[test]
+    #[ignore]
+    fn notify_scalability() {
+        const REQUESTS: u32 = 200_000;
+        for harts in 1..=NULL_QUEUES {
+            for device_locked in [true, false] {
+                let mem = TestMemory::new(true);
+                let dev = null_device(&mem);
+                let device_lock = Mutex::new(());
+                let start = Instant::now();
+                thread::scope(|scope| {
+                    for queue in 0..harts {
+                        let (dev, mem, device_lock) = (&dev, &mem, &device_lock);
+                        scope.spawn(move || {
+                            let avail = AVAIL + queue as usize * 0x100;
+                            for idx in 0..REQUESTS {
+                                let idx = idx as u16;
+                                mem.put_u16(avail + 4 + (idx % QUEUE_SIZE) as usize * 2, queue);
+                                mem.put_u16(avail + 2, idx.wrapping_add(1));
+                                let _guard = device_locked.then(|| device_lock.lock());
+                                write(dev, mem, VIRTIO_MMIO_QUEUE_NOTIFY, queue as u32);
+                            }
+                        });
+                    }
+                });
+                let rate = (harts as u32 * REQUESTS) as f64 / start.elapsed().as_secs_f64();
+                let lock = if device_locked { "device" } else { "queue" };
+                std::println!("{} harts, {} lock: {:.0} requests/s", harts, lock, rate);
+            }
+        }
+    }
+}
@@ -0,0 +1,726 @@
+//! Split virtqueues.
+//!
+//! Everything read from the rings and descriptor table is guest-controlled, so indices are checked
+//! against the queue size, and chains against loops and against being made available again while
+//! the device holds them, before they're used. All guest memory is accessed through
+//! `GuestMemory`, which only reaches the VM's own RAM.
+//!
+//! The rings are accessed on every request, so their host addresses are translated once when the
+//! driver makes the queue ready and cached until the VM unmaps guest RAM. Rings not contiguous in
+//! host memory are accessed through `GuestMemory` instead.
+//!
+//! The driver runs on other harts while the device accesses the rings. The ring indices, flags and
+//! event indices are accessed as single 16-bit atomics so they can't tear, and fences order them
+//! against the ring entries they cover: the avail index is read before the entries and
+//! descriptors it makes available, and used entries are written before the used index publishing
+//! them.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+
+use super::GuestMemory;
+use crate::{logging::LogContext, GuestPhysAddr, HostVirtAddr, HyperError, HyperResult};
+
+/// Largest size of a split virtqueue.
+pub const QUEUE_SIZE_MAX: u16 = 32768;
+/// Largest size of the queues of a device that doesn't choose its own, see
+/// `VirtioDevice::queue_size_max`.
+pub const QUEUE_SIZE_DEFAULT: u16 = 256;
+
+pub(super) const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub(super) const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// Avail ring flag asking the device not to interrupt the driver, ignored with `EVENT_IDX`.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Used ring flag asking the driver not to notify the device, ignored with `EVENT_IDX`.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+pub(super) const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+
+/// A buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: GuestPhysAddr,
+    /// Length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the device writes the buffer, rather than reading it.
+    pub write: bool,
+}
+
+/// The descriptor table and rings of a virtqueue.
+#[derive(Clone, Copy)]
+enum Ring {
+    Desc,
+    Avail,
+    Used,
+}
+
+/// Host addresses of the descriptor table and rings of a virtqueue.
+#[derive(Clone, Copy, Default)]
+enum RingCache {
+    /// Not translated yet, or invalidated.
+    #[default]
+    Stale,
+    /// Each is contiguous in host memory at the given address.
+    Mapped([HostVirtAddr; 3]),
+    /// One isn't contiguous in host memory.
+    Unmapped,
+}
+
+/// A split virtqueue, as configured by the driver.
+pub struct Virtq {
+    size: u16,
+    /// Largest size the driver may set.
+    size_max: u16,
+    ready: bool,
+    pub(super) desc_addr: GuestPhysAddr,
+    pub(super) avail_addr: GuestPhysAddr,
+    pub(super) used_addr: GuestPhysAddr,
+    /// Whether `VIRTIO_F_EVENT_IDX` was negotiated, so interrupts and notifications are
+    /// suppressed through the used and avail event indices.
+    pub(super) event_idx: bool,
+    last_avail_idx: u16,
+    /// Avail index carried by the driver's last notification with `VIRTIO_F_NOTIFICATION_DATA`,
+    /// while the device hasn't taken the buffers it announces yet.
+    avail_hint: Option<u16>,
+    used_idx: u16,
+    /// Used index last published to the driver.
+    published_used_idx: u16,
+    rings: RingCache,
+    /// Whether each descriptor heads a chain taken by the device and not returned yet, allocated
+    /// for the size the driver sets.
+    in_flight: Vec<bool>,
+    /// Whether the device polls the queue rather than waiting for the driver's notifications.
+    polled: bool,
+}
+
+impl Virtq {
+    /// Creates a queue the driver may make up to `size_max` entries large.
+    pub fn new(size_max: u16) -> Self {
+        Self {
+            size: 0,
+            size_max,
+            ready: false,
+            desc_addr: 0,
+            avail_addr: 0,
+            used_addr: 0,
+            event_idx: false,
+            last_avail_idx: 0,
+            avail_hint: None,
+            used_idx: 0,
+            published_used_idx: 0,
+            rings: RingCache::Stale,
+            in_flight: Vec::new(),
+            polled: false,
+        }
+    }
+
+    /// Number of entries of the queue, as set by the driver.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Largest size the driver may set.
+    pub fn size_max(&self) -> u16 {
+        self.size_max
+    }
+
+    /// Sets the number of entries of the queue, which must be a power of two no larger than
+    /// `size_max`, allocating the tracking of its chains. Fails with `InvalidParam` if the size is
+    /// invalid and `BadState` if the queue is ready.
+    pub fn set_size(&mut self, size: u16) -> HyperResult<()> {
+        if self.ready {
+            return Err(HyperError::BadState);
+        }
+        if !size.is_power_of_two() || size > self.size_max {
+            return Err(HyperError::InvalidParam);
+        }
+        self.size = size;
+        self.in_flight = vec![false; size as usize];
+        Ok(())
+    }
+
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Makes the queue ready for use or not, translating the host addresses of its rings when it
+    /// becomes ready.
+    pub fn set_ready(&mut self, ready: bool, mem: &dyn GuestMemory) {
+        self.ready = ready;
+        self.rings = RingCache::Stale;
+        self.refresh_rings(mem);
+    }
+
+    /// Forgets the host addresses of the rings, which are translated again on their next use.
+    /// Must be called when the VM unmaps guest RAM, which may hold the rings.
+    pub fn invalidate_rings(&mut self) {
+        self.rings = RingCache::Stale;
+    }
+
+    /// Takes the next descriptor chain the driver made available, returning its head index and
+    /// buffers.
+    pub fn pop_avail(
+        &mut self,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<Option<(u16, Vec<Descriptor>)>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        self.refresh_rings(mem);
+        let avail_idx = match self.avail_hint {
+            // Buffers announced by a notification are taken without reading the avail index.
+            Some(hint) if hint != self.last_avail_idx => hint,
+            _ => {
+                self.avail_hint = None;
+                // Ask to be notified of the next buffer, before looking for it so none goes
+                // unnoticed.
+                self.update_notification(mem)?;
+                fence(Ordering::SeqCst);
+                self.read_ring_u16(mem, Ring::Avail, 2)?
+            }
+        };
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        if avail_idx.wrapping_sub(self.last_avail_idx) > self.size {
+            hv_log!(
+                Warn,
+                Virtio,
+                LogContext::NONE,
+                "virtq: driver made {} buffers available",
+                avail_idx
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        // Read the ring entry only after seeing the index that covers it.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head = self.read_ring_u16(mem, Ring::Avail, 4 + slot * 2)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        let chain = self.read_chain(mem, head)?;
+        // The driver may only make a chain available again once the device has returned it.
+        if core::mem::replace(&mut self.in_flight[head as usize], true) {
+            hv_log!(
+                Warn,
+                Virtio,
+                LogContext::NONE,
+                "virtq: chain {} made available twice",
+                head
+            );
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Some((head, chain)))
+    }
+
+    /// Records the avail index `avail_idx` carried by a notification of the driver, which
+    /// negotiated `VIRTIO_F_NOTIFICATION_DATA`. Returns false if the notification is spurious, the
+    /// device having taken every buffer it announces. An index the driver can't have reached is
+    /// ignored, and the avail index is then read from the ring.
+    pub fn notified(&mut self, avail_idx: u16) -> bool {
+        let ahead = avail_idx.wrapping_sub(self.last_avail_idx);
+        if ahead == 0 {
+            return false;
+        }
+        if ahead <= self.size {
+            self.avail_hint = Some(avail_idx);
+        }
+        true
+    }
+
+    /// Returns the chain with head `head` to the driver, `len` bytes having been written to it.
+    /// The driver sees it once `publish_used` is called. Fails with `InvalidParam` if the chain
+    /// wasn't taken with `pop_avail`.
+    pub fn push_used(&mut self, mem: &dyn GuestMemory, head: u16, len: u32) -> HyperResult<()> {
+        match self.in_flight.get_mut(head as usize) {
+            Some(in_flight) if *in_flight => *in_flight = false,
+            _ => return Err(HyperError::InvalidParam),
+        }
+        let slot = (self.used_idx % self.size) as usize;
+        let mut elem = [0u8; USED_ELEM_SIZE];
+        elem[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+        self.refresh_rings(mem);
+        self.write_ring(mem, Ring::Used, 4 + slot * USED_ELEM_SIZE, &elem)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Number of chains returned since `publish_used` was last called.
+    pub fn unpublished_used(&self) -> u16 {
+        self.used_idx.wrapping_sub(self.published_used_idx)
+    }
+
+    /// Publishes the chains returned since this was last called with a single used index update.
+    /// Returns whether the driver wants to be interrupted for them.
+    pub fn publish_used(&mut self, mem: &dyn GuestMemory) -> HyperResult<bool> {
+        let old = self.published_used_idx;
+        let new = self.used_idx;
+        if new == old {
+            return Ok(false);
+        }
+        self.refresh_rings(mem);
+        // Publish the elements before the index that covers them.
+        fence(Ordering::Release);
+        self.write_ring_u16(mem, Ring::Used, 2, new)?;
+        self.published_used_idx = new;
+        // Read what the driver asks for only after it can see the new index.
+        fence(Ordering::SeqCst);
+        if self.event_idx {
+            let used_event = self.read_ring_u16(mem, Ring::Avail, 4 + self.size as usize * 2)?;
+            // Whether `used_event` lies in `[old, new)`, as in the spec's `vring_need_event`.
+            Ok(new.wrapping_sub(used_event).wrapping_sub(1) < new.wrapping_sub(old))
+        } else {
+            let flags = self.read_ring_u16(mem, Ring::Avail, 0)?;
+            Ok(flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+        }
+    }
+
+    /// Whether the device polls the queue.
+    pub fn polled(&self) -> bool {
+        self.polled
+    }
+
+    /// Makes the device poll the queue for buffers rather than wait for the driver to notify it,
+    /// and asks the driver to stop or resume notifying it.
+    pub fn set_polled(&mut self, polled: bool, mem: &dyn GuestMemory) -> HyperResult<()> {
+        self.polled = polled;
+        if !self.ready || self.size == 0 {
+            return Ok(());
+        }
+        self.refresh_rings(mem);
+        self.update_notification(mem)
+    }
+
+    /// Forgets the driver's configuration, e.g. on a device reset.
+    pub fn reset(&mut self) {
+        *self = Self {
+            polled: self.polled,
+            ..Self::new(self.size_max)
+        };
+    }
+
+    /// Asks the driver to notify the next buffer it makes available, or not to notify any if the
+    /// queue is polled. With `EVENT_IDX` the event index is then kept out of the driver's reach.
+    fn update_notification(&mut self, mem: &dyn GuestMemory) -> HyperResult<()> {
+        if self.event_idx {
+            let avail_event = 4 + self.size as usize * USED_ELEM_SIZE;
+            let event = match self.polled {
+                true => self.last_avail_idx.wrapping_add(0x8000),
+                false => self.last_avail_idx,
+            };
+            self.write_ring_u16(mem, Ring::Used, avail_event, event)
+        } else {
+            let flags = match self.polled {
+                true => VIRTQ_USED_F_NO_NOTIFY,
+                false => 0,
+            };
+            self.write_ring_u16(mem, Ring::Used, 0, flags)
+        }
+    }
+
+    fn read_chain(&self, mem: &dyn GuestMemory, head: u16) -> HyperResult<Vec<Descriptor>> {
+        let mut chain = Vec::new();
+        let mut index = head;
+        loop {
+            // A chain longer than the queue must loop.
+            if index >= self.size || chain.len() >= self.size as usize {
+                hv_log!(
+                    Warn,
+                    Virtio,
+                    LogContext::NONE,
+                    "virtq: bad descriptor chain from {}",
+                    head
+                );
+                return Err(HyperError::InvalidParam);
+            }
+            let mut desc = [0u8; DESC_SIZE];
+            self.read_ring(mem, Ring::Desc, index as usize * DESC_SIZE, &mut desc)?;
+            let flags = u16::from_le_bytes([desc[12], desc[13]]);
+            chain.push(Descriptor {
+                addr: u64::from_le_bytes(desc[0..8].try_into().unwrap()) as GuestPhysAddr,
+                len: u32::from_le_bytes(desc[8..12].try_into().unwrap()),
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = u16::from_le_bytes([desc[14], desc[15]]);
+        }
+    }
+
+    /// Guest physical address and size of `ring`, including the event index fields.
+    fn ring_range(&self, ring: Ring) -> (GuestPhysAddr, usize) {
+        let size = self.size as usize;
+        match ring {
+            Ring::Desc => (self.desc_addr, size * DESC_SIZE),
+            Ring::Avail => (self.avail_addr, 6 + size * 2),
+            Ring::Used => (self.used_addr, 6 + size * USED_ELEM_SIZE),
+        }
+    }
+
+    /// Translates the host addresses of the rings if they're stale.
+    fn refresh_rings(&mut self, mem: &dyn GuestMemory) {
+        if !self.ready || self.size == 0 || !matches!(self.rings, RingCache::Stale) {
+            return;
+        }
+        let mut addrs = [0; 3];
+        for (addr, ring) in addrs.iter_mut().zip([Ring::Desc, Ring::Avail, Ring::Used]) {
+            let (gpa, len) = self.ring_range(ring);
+            match mem.host_addr(gpa, len) {
+                Ok(hva) => *addr = hva,
+                Err(_) => {
+                    self.rings = RingCache::Unmapped;
+                    return;
+                }
+            }
+        }
+        self.rings = RingCache::Mapped(addrs);
+    }
+
+    /// Reads `buf` at `offset` in `ring`, which the caller checked is within it.
+    fn read_ring(
+        &self,
+        mem: &dyn GuestMemory,
+        ring: Ring,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> HyperResult<()> {
+        match self.rings {
+            RingCache::Mapped(addrs) => {
+                let src = (addrs[ring as usize] + offset) as *const u8;
+                // Safety: the ring is guest RAM backed by host memory assigned to the VM, which
+                // stays mapped until the cache is invalidated.
+                unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+                Ok(())
+            }
+            _ => mem.read(self.ring_range(ring).0 + offset, buf),
+        }
+    }
+
+    /// Writes `buf` at `offset` in `ring`, which the caller checked is within it.
+    fn write_ring(
+        &self,
+        mem: &dyn GuestMemory,
+        ring: Ring,
+        offset: usize,
+        buf: &[u8],
+    ) -> HyperResult<()> {
+        match self.rings {
+            RingCache::Mapped(addrs) => {
+                let dst = (addrs[ring as usize] + offset) as *mut u8;
+                // Safety: as in `read_ring`.
+                unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+                Ok(())
+            }
+            _ => mem.write(self.ring_range(ring).0 + offset, buf),
+        }
+    }
+
+    /// Reads the 16-bit field at `offset` in `ring` in a single access if the ring is cached.
+    fn read_ring_u16(&self, mem: &dyn GuestMemory, ring: Ring, offset: usize) -> HyperResult<u16> {
+        if let Some(field) = self.ring_u16(ring, offset) {
+            return Ok(u16::from_le(field.load(Ordering::Relaxed)));
+        }
+        let mut buf = [0u8; 2];
+        self.read_ring(mem, ring, offset, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Writes the 16-bit field at `offset` in `ring` in a single access if the ring is cached.
+    fn write_ring_u16(
+        &self,
+        mem: &dyn GuestMemory,
+        ring: Ring,
+        offset: usize,
+        val: u16,
+    ) -> HyperResult<()> {
+        if let Some(field) = self.ring_u16(ring, offset) {
+            field.store(val.to_le(), Ordering::Relaxed);
+            return Ok(());
+        }
+        self.write_ring(mem, ring, offset, &val.to_le_bytes())
+    }
+
+    /// The 16-bit field at `offset` in `ring`, if the ring is cached and the field aligned.
+    fn ring_u16(&self, ring: Ring, offset: usize) -> Option<&AtomicU16> {
+        let RingCache::Mapped(addrs) = self.rings else {
+            return None;
+        };
+        let addr = addrs[ring as usize] + offset;
+        // Safety: as in `read_ring`, and the field is aligned.
+        (addr & 0x1 == 0).then(|| unsafe { &*(addr as *const AtomicU16) })
+    }
+}
+
+/// Gathers the contents of the buffers of `chain` the device reads, up to `max` bytes.
+pub fn read_chain(mem: &dyn GuestMemory, chain: &[Descriptor], max: usize) -> HyperResult<Vec<u8>> {
+    let mut data = Vec::new();
+    for desc in chain.iter().filter(|desc| !desc.write) {
+        let len = core::cmp::min(desc.len as usize, max - data.len());
+        let start = data.len();
+        data.resize(start + len, 0);
+        mem.read(desc.addr, &mut data[start..])?;
+    }
+    Ok(data)
+}
+
+/// Scatters `data` over the buffers of `chain` the device writes. Returns the number of bytes
+/// written, which is less than `data.len()` if the buffers are too small.
+pub fn write_chain(mem: &dyn GuestMemory, chain: &[Descriptor], data: &[u8]) -> HyperResult<u32> {
+    let mut written = 0;
+    for desc in chain.iter().filter(|desc| desc.write) {
+        let len = core::cmp::min(desc.len as usize, data.len() - written);
+        mem.write(desc.addr, &data[written..written + len])?;
+        written += len;
+    }
+    Ok(written as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::testing::{TestMemory, AVAIL, BUFS, DESC, QUEUE_SIZE, USED};
+
+    fn ready_queue(mem: &TestMemory, event_idx: bool) -> Virtq {
+        let mut queue = Virtq::new(QUEUE_SIZE_DEFAULT);
+        queue.set_size(QUEUE_SIZE).unwrap();
+        queue.desc_addr = DESC;
+        queue.avail_addr = AVAIL;
+        queue.used_addr = USED;
+        queue.event_idx = event_idx;
+        queue.set_ready(true, mem);
+        queue
+    }
+
+    #[test]
+    fn set_size() {
+        let mem = TestMemory::new(false);
+        let mut queue = Virtq::new(QUEUE_SIZE_DEFAULT);
+        assert_eq!(queue.set_size(3), Err(HyperError::InvalidParam));
+        assert_eq!(
+            queue.set_size(QUEUE_SIZE_DEFAULT * 2),
+            Err(HyperError::InvalidParam)
+        );
+        assert_eq!(queue.set_size(QUEUE_SIZE), Ok(()));
+        queue.set_ready(true, &mem);
+        assert_eq!(queue.set_size(QUEUE_SIZE), Err(HyperError::BadState));
+    }
+
+    #[test]
+    fn not_ready() {
+        let mem = TestMemory::new(false);
+        let mut queue = Virtq::new(QUEUE_SIZE_DEFAULT);
+        queue.set_size(QUEUE_SIZE).unwrap();
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.make_available(0, 0);
+        assert!(queue.pop_avail(&mem).unwrap().is_none());
+    }
+
+    #[test]
+    fn chain_round_trip() {
+        for mapped in [false, true] {
+            let mem = TestMemory::new(mapped);
+            let mut queue = ready_queue(&mem, false);
+            mem.put_desc(0, BUFS, 4, false, Some(1));
+            mem.put_desc(1, BUFS + 0x100, 8, true, None);
+            mem.write(BUFS, b"ping").unwrap();
+            mem.make_available(0, 0);
+
+            let (head, chain) = queue.pop_avail(&mem).unwrap().unwrap();
+            assert_eq!(head, 0);
+            assert_eq!(chain.len(), 2);
+            assert!(!chain[0].write && chain[1].write);
+            assert_eq!((chain[1].addr, chain[1].len), (BUFS + 0x100, 8));
+            assert_eq!(read_chain(&mem, &chain, 64).unwrap(), b"ping");
+            assert_eq!(read_chain(&mem, &chain, 2).unwrap(), b"pi");
+            assert_eq!(write_chain(&mem, &chain, b"pong pong").unwrap(), 8);
+            let mut reply = [0u8; 8];
+            mem.read(BUFS + 0x100, &mut reply).unwrap();
+            assert_eq!(&reply, b"pong pon");
+            assert!(queue.pop_avail(&mem).unwrap().is_none());
+
+            queue.push_used(&mem, head, 8).unwrap();
+            assert_eq!(queue.unpublished_used(), 1);
+            // Not visible to the driver until published.
+            assert_eq!(mem.used_idx(), 0);
+            assert!(queue.publish_used(&mem).unwrap());
+            assert_eq!(queue.unpublished_used(), 0);
+            assert_eq!(mem.used_idx(), 1);
+            assert_eq!(mem.used_elem(0), (0, 8));
+            assert!(!queue.publish_used(&mem).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_looping_chain() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        mem.put_desc(0, BUFS, 4, false, Some(1));
+        mem.put_desc(1, BUFS, 4, false, Some(0));
+        mem.make_available(0, 0);
+        assert!(matches!(
+            queue.pop_avail(&mem),
+            Err(HyperError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn rejects_head_out_of_range() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        mem.make_available(0, QUEUE_SIZE);
+        assert!(matches!(
+            queue.pop_avail(&mem),
+            Err(HyperError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn rejects_chain_available_twice() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.make_available(0, 0);
+        mem.make_available(1, 0);
+        assert!(queue.pop_avail(&mem).unwrap().is_some());
+        assert!(matches!(
+            queue.pop_avail(&mem),
+            Err(HyperError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn rejects_avail_idx_too_far_ahead() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.put_u16(AVAIL + 2, QUEUE_SIZE + 1);
+        assert!(matches!(
+            queue.pop_avail(&mem),
+            Err(HyperError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn push_used_requires_taken_chain() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        assert_eq!(queue.push_used(&mem, 3, 0), Err(HyperError::InvalidParam));
+        assert_eq!(
+            queue.push_used(&mem, QUEUE_SIZE, 0),
+            Err(HyperError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn no_interrupt_flag() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.make_available(0, 0);
+        mem.put_u16(AVAIL, VIRTQ_AVAIL_F_NO_INTERRUPT);
+        let (head, _) = queue.pop_avail(&mem).unwrap().unwrap();
+        queue.push_used(&mem, head, 0).unwrap();
+        assert!(!queue.publish_used(&mem).unwrap());
+        assert_eq!(mem.used_idx(), 1);
+    }
+
+    #[test]
+    fn event_idx_interrupts() {
+        let mem = TestMemory::new(true);
+        let mut queue = ready_queue(&mem, true);
+        let used_event = AVAIL + 4 + QUEUE_SIZE as usize * 2;
+        for index in 0..3 {
+            mem.put_desc(index, BUFS, 4, false, None);
+            mem.make_available(index, index);
+        }
+        for _ in 0..2 {
+            let (head, _) = queue.pop_avail(&mem).unwrap().unwrap();
+            queue.push_used(&mem, head, 0).unwrap();
+        }
+        // The driver wants an interrupt once the used index passes 1.
+        mem.put_u16(used_event, 1);
+        assert!(queue.publish_used(&mem).unwrap());
+
+        let (head, _) = queue.pop_avail(&mem).unwrap().unwrap();
+        queue.push_used(&mem, head, 0).unwrap();
+        mem.put_u16(used_event, 5);
+        assert!(!queue.publish_used(&mem).unwrap());
+    }
+
+    #[test]
+    fn polling_suppresses_notifications() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        queue.set_polled(true, &mem).unwrap();
+        assert_eq!(mem.get_u16(USED), VIRTQ_USED_F_NO_NOTIFY);
+        queue.set_polled(false, &mem).unwrap();
+        assert_eq!(mem.get_u16(USED), 0);
+
+        let mut queue = ready_queue(&mem, true);
+        let avail_event = USED + 4 + QUEUE_SIZE as usize * USED_ELEM_SIZE;
+        queue.set_polled(true, &mem).unwrap();
+        assert_eq!(mem.get_u16(avail_event), 0x8000);
+        // Reset keeps the queue polled.
+        queue.reset();
+        assert!(queue.polled() && !queue.ready());
+        assert_eq!(queue.size(), 0);
+    }
+
+    #[test]
+    fn read_only_shared_buffers() {
+        for mapped in [false, true] {
+            let mut mem = TestMemory::new(mapped);
+            mem.share_read_only(BUFS, BUFS + 0x100);
+            let mut queue = ready_queue(&mem, false);
+            mem.put_desc(0, BUFS, 4, false, Some(1));
+            mem.put_desc(1, BUFS + 0x100, 8, true, None);
+            mem.fill(BUFS, b"ping");
+            mem.make_available(0, 0);
+            // A buffer the device writes in the share.
+            mem.put_desc(2, BUFS, 4, true, None);
+            mem.make_available(1, 2);
+
+            let (head, chain) = queue.pop_avail(&mem).unwrap().unwrap();
+            assert_eq!(read_chain(&mem, &chain, 64).unwrap(), b"ping");
+            assert_eq!(write_chain(&mem, &chain, b"pong").unwrap(), 4);
+            queue.push_used(&mem, head, 4).unwrap();
+            let (head, chain) = queue.pop_avail(&mem).unwrap().unwrap();
+            assert_eq!(
+                write_chain(&mem, &chain, b"pong"),
+                Err(HyperError::OutOfRange)
+            );
+            queue.push_used(&mem, head, 0).unwrap();
+            assert!(queue.publish_used(&mem).unwrap());
+            assert_eq!(mem.used_idx(), 2);
+        }
+    }
+
+    #[test]
+    fn used_ring_in_read_only_share() {
+        let mut mem = TestMemory::new(true);
+        mem.share_read_only(USED, BUFS);
+        let mut queue = ready_queue(&mem, false);
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.make_available(0, 0);
+        // The device can't even ask to be notified, which it does in the used ring.
+        assert!(matches!(queue.pop_avail(&mem), Err(HyperError::OutOfRange)));
+        assert_eq!(mem.used_idx(), 0);
+    }
+
+    #[test]
+    fn notification_data() {
+        let mem = TestMemory::new(false);
+        let mut queue = ready_queue(&mem, false);
+        assert!(!queue.notified(0));
+        mem.put_desc(0, BUFS, 4, false, None);
+        mem.make_available(0, 0);
+        assert!(queue.notified(1));
+        assert!(queue.pop_avail(&mem).unwrap().is_some());
+        // Everything the notification announced was taken.
+        assert!(!queue.notified(1));
+        assert!(queue.pop_avail(&mem).unwrap().is_none());
+    }
+}
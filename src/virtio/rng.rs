@@ -0,0 +1,55 @@
+//! virtio entropy device.
+
+use super::{GuestMemory, VirtioDevice, Virtq};
+use crate::HyperResult;
+
+const VIRTIO_ID_RNG: u32 = 4;
+
+/// Bytes of entropy generated at a time.
+const CHUNK_SIZE: usize = 64;
+
+/// Fills the buffers of its request queue with entropy from the host.
+pub struct VirtioRng {
+    fill_entropy: fn(&mut [u8]) -> HyperResult<()>,
+}
+
+impl VirtioRng {
+    /// Creates a device taking entropy from `fill_entropy`.
+    pub fn new(fill_entropy: fn(&mut [u8]) -> HyperResult<()>) -> Self {
+        Self { fill_entropy }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn process_queue(
+        &self,
+        _index: usize,
+        queue: &mut Virtq,
+        mem: &dyn GuestMemory,
+    ) -> HyperResult<()> {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        while let Some((head, chain)) = queue.pop_avail(mem)? {
+            let mut written = 0u32;
+            for desc in chain.iter().filter(|desc| desc.write) {
+                let mut offset = 0;
+                while offset < desc.len as usize {
+                    let len = core::cmp::min(CHUNK_SIZE, desc.len as usize - offset);
+                    (self.fill_entropy)(&mut chunk[..len])?;
+                    mem.write(desc.addr + offset, &chunk[..len])?;
+                    offset += len;
+                }
+                written = written.saturating_add(desc.len);
+            }
+            queue.push_used(mem, head, written)?;
+        }
+        Ok(())
+    }
+}
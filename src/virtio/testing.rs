@@ -0,0 +1,151 @@
+//! Helpers for testing virtio devices on the host: guest RAM in host memory, and the driver side
+//! of a split virtqueue laid out in it.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::GuestMemory;
+use crate::{GuestPhysAddr, HostVirtAddr, HyperError, HyperResult};
+
+/// Size of the virtqueue laid out by the helpers.
+pub(super) const QUEUE_SIZE: u16 = 8;
+
+// Guest physical addresses of the descriptor table, the available and used rings, and the buffers
+// of the virtqueue.
+pub(super) const DESC: GuestPhysAddr = 0x0;
+pub(super) const AVAIL: GuestPhysAddr = 0x1000;
+pub(super) const USED: GuestPhysAddr = 0x2000;
+pub(super) const BUFS: GuestPhysAddr = 0x3000;
+
+const RAM_SIZE: usize = 0x4000;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Guest RAM at guest physical address 0, which devices may access directly if `mapped`. The
+/// bytes are atomics so threads playing several harts can share it.
+pub(super) struct TestMemory {
+    ram: Vec<AtomicU8>,
+    mapped: bool,
+    read_only: Range<GuestPhysAddr>,
+}
+
+impl TestMemory {
+    pub(super) fn new(mapped: bool) -> Self {
+        Self {
+            ram: (0..RAM_SIZE).map(|_| AtomicU8::new(0)).collect(),
+            mapped,
+            read_only: 0..0,
+        }
+    }
+
+    /// Makes `[start, end)` memory shared into the VM read-only, which devices can neither write
+    /// nor access directly, as with a VM's `GuestRam`.
+    pub(super) fn share_read_only(&mut self, start: GuestPhysAddr, end: GuestPhysAddr) {
+        self.read_only = start..end;
+    }
+
+    fn range(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<&[AtomicU8]> {
+        let end = gpa.checked_add(len).ok_or(HyperError::OutOfRange)?;
+        self.ram.get(gpa..end).ok_or(HyperError::OutOfRange)
+    }
+
+    /// Like `range`, for a write.
+    fn writable_range(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<&[AtomicU8]> {
+        let range = self.range(gpa, len)?;
+        if gpa < self.read_only.end && gpa + len > self.read_only.start {
+            return Err(HyperError::OutOfRange);
+        }
+        Ok(range)
+    }
+
+    /// Copies `buf` to `gpa`, even if it's shared read-only, as the VM owning the memory does.
+    pub(super) fn fill(&self, gpa: GuestPhysAddr, buf: &[u8]) {
+        for (dst, src) in self.range(gpa, buf.len()).unwrap().iter().zip(buf) {
+            dst.store(*src, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn put_u16(&self, gpa: GuestPhysAddr, val: u16) {
+        self.write(gpa, &val.to_le_bytes()).unwrap();
+    }
+
+    pub(super) fn get_u16(&self, gpa: GuestPhysAddr) -> u16 {
+        let mut buf = [0u8; 2];
+        self.read(gpa, &mut buf).unwrap();
+        u16::from_le_bytes(buf)
+    }
+
+    pub(super) fn get_u32(&self, gpa: GuestPhysAddr) -> u32 {
+        let mut buf = [0u8; 4];
+        self.read(gpa, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    /// Writes the descriptor `index`, chained to `next` if any.
+    pub(super) fn put_desc(
+        &self,
+        index: u16,
+        addr: GuestPhysAddr,
+        len: u32,
+        write: bool,
+        next: Option<u16>,
+    ) {
+        let mut flags = 0;
+        if write {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if next.is_some() {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+        let mut desc = [0u8; 16];
+        desc[0..8].copy_from_slice(&(addr as u64).to_le_bytes());
+        desc[8..12].copy_from_slice(&len.to_le_bytes());
+        desc[12..14].copy_from_slice(&flags.to_le_bytes());
+        desc[14..16].copy_from_slice(&next.unwrap_or(0).to_le_bytes());
+        self.write(DESC + index as usize * desc.len(), &desc)
+            .unwrap();
+    }
+
+    /// Makes the chain headed by `head` available, as the driver's buffer number `idx`.
+    pub(super) fn make_available(&self, idx: u16, head: u16) {
+        self.put_u16(AVAIL + 4 + (idx % QUEUE_SIZE) as usize * 2, head);
+        self.put_u16(AVAIL + 2, idx.wrapping_add(1));
+    }
+
+    /// The index of the used ring.
+    pub(super) fn used_idx(&self) -> u16 {
+        self.get_u16(USED + 2)
+    }
+
+    /// The used ring entry `idx`: the head of the chain and the number of bytes written to it.
+    pub(super) fn used_elem(&self, idx: u16) -> (u32, u32) {
+        let elem = USED + 4 + (idx % QUEUE_SIZE) as usize * 8;
+        (self.get_u32(elem), self.get_u32(elem + 4))
+    }
+}
+
+impl GuestMemory for TestMemory {
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        let src = self.range(gpa, buf.len())?;
+        for (dst, src) in buf.iter_mut().zip(src) {
+            *dst = src.load(Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+        for (dst, src) in self.writable_range(gpa, buf.len())?.iter().zip(buf) {
+            dst.store(*src, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn host_addr(&self, gpa: GuestPhysAddr, len: usize) -> HyperResult<HostVirtAddr> {
+        if !self.mapped {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(self.writable_range(gpa, len)?.as_ptr() as HostVirtAddr)
+    }
+}